@@ -0,0 +1,561 @@
+// A tiny (and dev-only) HTTP file server with autoindex in styles of popular servers,
+// to test parsers end-to-end against generated trees, and to reproduce user-reported layouts locally.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use tracing::{debug, info, warn};
+
+/// Chars to escape in href. "+" is also escaped to avoid being treated as space.
+const HREF_ESCAPE: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'\'')
+    .add(b'+')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum AutoindexStyle {
+    Nginx,
+    /// Apache with HTMLTable FancyIndexed list (F=2)
+    Apache,
+    Caddy,
+}
+
+/// Raw response (status line, headers and body) to a request, by method, target and body,
+/// or None to serve files as usual. For tests of upstreams misbehaving or having APIs.
+pub type Route = Arc<dyn Fn(&str, &str, &[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub mtime: DateTime<Utc>,
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn get_href(entry: &Entry) -> String {
+    let mut href = utf8_percent_encode(&entry.name, HREF_ESCAPE).to_string();
+    if entry.is_dir {
+        href.push('/');
+    }
+    href
+}
+
+/// Humanize size like apache: "345", "3.0K", "1.2M"
+fn apache_size(size: u64) -> String {
+    if size < 1024 {
+        return size.to_string();
+    }
+    let mut size = size as f64 / 1024.0;
+    for unit in ["K", "M", "G", "T"] {
+        if size < 1024.0 || unit == "T" {
+            return format!("{:.1}{}", size, unit);
+        }
+        size /= 1024.0;
+    }
+    unreachable!()
+}
+
+/// Humanize size like caddy: "512 B", "1.2 KiB", "26 MiB"
+fn caddy_size(size: u64) -> String {
+    if size < 1024 {
+        return format!("{size} B");
+    }
+    let mut size = size as f64 / 1024.0;
+    for unit in ["KiB", "MiB", "GiB", "TiB"] {
+        if size < 1024.0 || unit == "TiB" {
+            let s = format!("{:.1}", size);
+            return format!("{} {}", s.trim_end_matches(".0"), unit);
+        }
+        size /= 1024.0;
+    }
+    unreachable!()
+}
+
+fn render_nginx(path: &str, entries: &[Entry]) -> String {
+    let path = escape_html(path);
+    let mut res = format!(
+        "<html>\r\n<head><title>Index of {path}</title></head>\r\n<body>\r\n<h1>Index of {path}</h1><hr><pre><a href=\"../\">../</a>\r\n"
+    );
+    for entry in entries {
+        let mut display = entry.name.clone();
+        if entry.is_dir {
+            display.push('/');
+        }
+        // nginx truncates long names
+        let display_len = display.chars().count();
+        let display = if display_len > 50 {
+            format!(
+                "{}..&gt;",
+                escape_html(&display.chars().take(47).collect::<String>())
+            )
+        } else {
+            escape_html(&display)
+        };
+        let padding = " ".repeat(51 - display_len.min(50));
+        let size = if entry.is_dir {
+            "-".to_string()
+        } else {
+            entry.size.to_string()
+        };
+        res.push_str(&format!(
+            "<a href=\"{}\">{}</a>{}{} {:>19}\r\n",
+            get_href(entry),
+            display,
+            padding,
+            entry.mtime.format("%d-%b-%Y %H:%M"),
+            size
+        ));
+    }
+    res.push_str("</pre><hr></body>\r\n</html>\r\n");
+    res
+}
+
+fn render_apache(path: &str, entries: &[Entry]) -> String {
+    let path = escape_html(path);
+    let mut res = format!(
+        "<!DOCTYPE HTML PUBLIC \"-//W3C//DTD HTML 3.2 Final//EN\">\n<html>\n <head>\n  <title>Index of {path}</title>\n </head>\n <body>\n<h1>Index of {path}</h1>\n  <table id=\"indexlist\">\n   <tr class=\"indexhead\"><th class=\"indexcolicon\"></th><th class=\"indexcolname\"><a href=\"?C=N;O=D\">Name</a></th><th class=\"indexcollastmod\"><a href=\"?C=M;O=A\">Last modified</a></th><th class=\"indexcolsize\"><a href=\"?C=S;O=A\">Size</a></th></tr>\n   <tr class=\"even\"><td class=\"indexcolicon\"></td><td class=\"indexcolname\"><a href=\"../\">Parent Directory</a></td><td class=\"indexcollastmod\">&nbsp;</td><td class=\"indexcolsize\">  - </td></tr>\n"
+    );
+    for (i, entry) in entries.iter().enumerate() {
+        let class = if i % 2 == 0 { "odd" } else { "even" };
+        let mut display = escape_html(&entry.name);
+        if entry.is_dir {
+            display.push('/');
+        }
+        let size = if entry.is_dir {
+            "  - ".to_string()
+        } else {
+            apache_size(entry.size)
+        };
+        res.push_str(&format!(
+            "   <tr class=\"{class}\"><td class=\"indexcolicon\"></td><td class=\"indexcolname\"><a href=\"{}\">{}</a></td><td class=\"indexcollastmod\">{}  </td><td class=\"indexcolsize\">{}</td></tr>\n",
+            get_href(entry),
+            display,
+            entry.mtime.format("%Y-%m-%d %H:%M"),
+            size
+        ));
+    }
+    res.push_str("</table>\n</body></html>\n");
+    res
+}
+
+fn render_caddy(path: &str, entries: &[Entry]) -> String {
+    let path = escape_html(path);
+    let mut res = format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>{path}</title></head>\n<body>\n<main>\n<table aria-describedby=\"summary\">\n<thead><tr><th></th><th>Name</th><th>Size</th><th class=\"hideable\">Modified</th><th class=\"hideable\"></th></tr></thead>\n<tbody>\n<tr><td></td><td><a href=\"..\"><span class=\"go-up\">Up</span></a></td><td>&mdash;</td><td class=\"hideable\">&mdash;</td><td class=\"hideable\"></td></tr>\n"
+    );
+    for entry in entries {
+        let mut display = escape_html(&entry.name);
+        if entry.is_dir {
+            display.push('/');
+        }
+        let size = if entry.is_dir {
+            "<td>&mdash;</td>".to_string()
+        } else {
+            format!(
+                "<td class=\"size\" data-size=\"{}\">\n<div class=\"sizebar\">\n<div class=\"sizebar-bar\"></div>\n<div class=\"sizebar-text\">\n{}\n</div>\n</div>\n</td>",
+                entry.size,
+                caddy_size(entry.size)
+            )
+        };
+        res.push_str(&format!(
+            "<tr class=\"file\">\n<td></td>\n<td>\n<a href=\"./{}\">\n<span class=\"name\">{}</span>\n</a>\n</td>\n{}\n<td class=\"timestamp hideable\">\n<time datetime=\"{}\">{}</time>\n</td>\n<td class=\"hideable\"></td>\n</tr>\n",
+            get_href(entry),
+            display,
+            size,
+            entry.mtime.format("%Y-%m-%dT%H:%M:%SZ"),
+            entry.mtime.format("%m/%d/%Y %I:%M:%S %p +00:00"),
+        ));
+    }
+    res.push_str("</tbody>\n</table>\n</main>\n</body>\n</html>\n");
+    res
+}
+
+/// Render an autoindex page of entries in given style. path is the (decoded) URL path of the directory.
+pub fn render(style: AutoindexStyle, path: &str, entries: &[Entry]) -> String {
+    match style {
+        AutoindexStyle::Nginx => render_nginx(path, entries),
+        AutoindexStyle::Apache => render_apache(path, entries),
+        AutoindexStyle::Caddy => render_caddy(path, entries),
+    }
+}
+
+/// Read entries of a local directory, sorted with directories first, then by name.
+pub fn read_entries(dir: &Path) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.path().metadata()?;
+        entries.push(Entry {
+            name: entry.file_name().to_string_lossy().to_string(),
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            mtime: metadata.modified()?.into(),
+        });
+    }
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+fn http_date(t: DateTime<Utc>) -> String {
+    t.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+    is_head: bool,
+) -> Result<()> {
+    let mut resp = format!("HTTP/1.1 {status}\r\nConnection: close\r\n");
+    for (k, v) in headers {
+        resp.push_str(&format!("{k}: {v}\r\n"));
+    }
+    resp.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+    stream.write_all(resp.as_bytes())?;
+    if !is_head {
+        stream.write_all(body)?;
+    }
+    Ok(())
+}
+
+/// Map URL path to local path under root, refusing to go outside root.
+fn get_local_path(root: &Path, url_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(url_path).decode_utf8().ok()?;
+    let mut path = root.to_path_buf();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            s => path.push(s),
+        }
+    }
+    Some(path)
+}
+
+fn handle(
+    mut stream: TcpStream,
+    root: &Path,
+    style: AutoindexStyle,
+    routes: &[Route],
+) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // ignore all headers, except length of body
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    debug!("{}", request_line.trim());
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+    if let Some(resp) = routes.iter().find_map(|route| route(method, target, &body)) {
+        stream.write_all(&resp)?;
+        return Ok(());
+    }
+    let is_head = method == "HEAD";
+    if method != "GET" && !is_head {
+        return write_response(&mut stream, "405 Method Not Allowed", &[], b"", false);
+    }
+    let url_path = target.split('?').next().unwrap_or_default();
+    let local = match get_local_path(root, url_path) {
+        Some(local) => local,
+        None => return write_response(&mut stream, "400 Bad Request", &[], b"", is_head),
+    };
+    let metadata = match local.metadata() {
+        Ok(m) => m,
+        Err(_) => return write_response(&mut stream, "404 Not Found", &[], b"", is_head),
+    };
+    if metadata.is_dir() {
+        if !url_path.ends_with('/') {
+            let location = format!("{url_path}/");
+            return write_response(
+                &mut stream,
+                "301 Moved Permanently",
+                &[("Location", location)],
+                b"",
+                is_head,
+            );
+        }
+        let path = percent_decode_str(url_path).decode_utf8_lossy();
+        let body = render(style, &path, &read_entries(&local)?);
+        write_response(
+            &mut stream,
+            "200 OK",
+            &[("Content-Type", "text/html".to_string())],
+            body.as_bytes(),
+            is_head,
+        )
+    } else {
+        let mut body = Vec::new();
+        if !is_head {
+            std::fs::File::open(&local)?.read_to_end(&mut body)?;
+        }
+        let mtime: DateTime<Utc> = metadata.modified()?.into();
+        let headers = [
+            ("Content-Type", "application/octet-stream".to_string()),
+            ("Last-Modified", http_date(mtime)),
+        ];
+        if is_head {
+            // Content-Length should still be the size of file
+            let resp = format!(
+                "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: {}\r\nLast-Modified: {}\r\nContent-Length: {}\r\n\r\n",
+                headers[0].1,
+                headers[1].1,
+                metadata.len()
+            );
+            stream.write_all(resp.as_bytes())?;
+            return Ok(());
+        }
+        write_response(&mut stream, "200 OK", &headers, &body, false)
+    }
+}
+
+/// Serve root directory forever.
+pub fn serve(listener: TcpListener, root: PathBuf, style: AutoindexStyle) -> Result<()> {
+    serve_with_routes(listener, root, style, Arc::new(Vec::new()))
+}
+
+/// Serve root directory forever, with requests matching routes answered by them
+fn serve_with_routes(
+    listener: TcpListener,
+    root: PathBuf,
+    style: AutoindexStyle,
+    routes: Arc<Vec<Route>>,
+) -> Result<()> {
+    info!(
+        "Serving {:?} in {:?} style at http://{}/",
+        root,
+        style,
+        listener.local_addr()?
+    );
+    if !root.is_dir() {
+        return Err(anyhow!("{:?} is not a directory", root));
+    }
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Failed to accept: {:?}", e);
+                continue;
+            }
+        };
+        let root = root.clone();
+        let routes = routes.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = handle(stream, &root, style, &routes) {
+                warn!("Failed to handle request: {:?}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Serve root directory in background at a random local port, returning its address.
+#[cfg(test)]
+pub fn spawn(root: &Path, style: AutoindexStyle) -> std::net::SocketAddr {
+    spawn_with_routes(root, style, Vec::new())
+}
+
+/// Like spawn(), with requests matching routes answered by them
+#[cfg(test)]
+pub fn spawn_with_routes(
+    root: &Path,
+    style: AutoindexStyle,
+    routes: Vec<Route>,
+) -> std::net::SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let root = root.to_path_buf();
+    let routes = Arc::new(routes);
+    std::thread::spawn(move || serve_with_routes(listener, root, style, routes).unwrap());
+    addr
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use chrono::NaiveDateTime;
+    use url::Url;
+
+    use super::*;
+    use crate::{
+        listing::{FileSize, FileType, ListItem},
+        parser::{
+            apache_f2::ApacheF2ListingParser, caddy::CaddyListingParser, nginx::NginxListingParser,
+            ListResult, Parser,
+        },
+    };
+
+    const MTIME: i64 = 1700000000; // 2023-11-14 22:13:20 UTC
+
+    fn generate_tree() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("pool/main")).unwrap();
+        std::fs::create_dir_all(root.join("dists 中文")).unwrap();
+        std::fs::write(root.join("README"), "hello").unwrap();
+        std::fs::write(root.join("a+b c.deb"), vec![0u8; 4096]).unwrap();
+        let long_name = format!("{}.tar.gz", "x".repeat(60));
+        std::fs::write(root.join(long_name), vec![0u8; 100]).unwrap();
+        for entry in walkdir::WalkDir::new(root) {
+            let entry = entry.unwrap();
+            filetime::set_file_mtime(entry.path(), filetime::FileTime::from_unix_time(MTIME, 0))
+                .unwrap();
+        }
+        dir
+    }
+
+    fn list(parser: &dyn Parser, addr: SocketAddr, path: &str) -> Vec<ListItem> {
+        let client = reqwest::blocking::Client::new();
+        let url = Url::parse(&format!("http://{addr}{path}")).unwrap();
+        match parser.get_list(&client, &url).unwrap() {
+            ListResult::List(items) => items,
+            ListResult::Redirect(_) => unreachable!(),
+        }
+    }
+
+    fn check_items(items: &[ListItem], addr: SocketAddr) {
+        let names: Vec<&str> = items.iter().map(|i| i.name.as_str()).collect();
+        let long_name = format!("{}.tar.gz", "x".repeat(60));
+        assert_eq!(
+            names,
+            vec!["dists 中文", "pool", "README", "a+b c.deb", &long_name]
+        );
+        assert_eq!(items[0].type_, FileType::Directory);
+        assert_eq!(items[1].type_, FileType::Directory);
+        assert_eq!(
+            items[1].url,
+            Url::parse(&format!("http://{addr}/pool/")).unwrap()
+        );
+        assert_eq!(items[3].type_, FileType::File);
+        assert_eq!(
+            items[3].url,
+            Url::parse(&format!("http://{addr}/a%2Bb%20c.deb")).unwrap()
+        );
+        assert_eq!(items[3].size.unwrap().get_estimated(), 4096);
+        assert_eq!(
+            items[2].mtime.unwrap().and_utc().timestamp() / 60,
+            NaiveDateTime::from_timestamp_opt(MTIME, 0)
+                .unwrap()
+                .and_utc()
+                .timestamp()
+                / 60
+        );
+    }
+
+    #[test]
+    fn test_nginx_style() {
+        let tree = generate_tree();
+        let addr = spawn(tree.path(), AutoindexStyle::Nginx);
+        let items = list(&NginxListingParser::default(), addr, "/");
+        check_items(&items, addr);
+        assert_eq!(items[2].size, Some(FileSize::Precise(5)));
+        let items = list(&NginxListingParser::default(), addr, "/pool/");
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].name, "main");
+    }
+
+    #[test]
+    fn test_apache_style() {
+        let tree = generate_tree();
+        let addr = spawn(tree.path(), AutoindexStyle::Apache);
+        let items = list(&ApacheF2ListingParser, addr, "/");
+        check_items(&items, addr);
+    }
+
+    #[test]
+    fn test_caddy_style() {
+        let tree = generate_tree();
+        let addr = spawn(tree.path(), AutoindexStyle::Caddy);
+        let items = list(&CaddyListingParser, addr, "/");
+        check_items(&items, addr);
+    }
+
+    #[test]
+    fn test_file_serving() {
+        let tree = generate_tree();
+        let addr = spawn(tree.path(), AutoindexStyle::Nginx);
+        let client = reqwest::blocking::Client::new();
+        let resp = client
+            .get(format!("http://{addr}/README"))
+            .send()
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+        assert_eq!(
+            crate::utils::get_blocking_response_mtime(&resp)
+                .unwrap()
+                .timestamp(),
+            MTIME
+        );
+        assert_eq!(resp.text().unwrap(), "hello");
+        let resp = client.get(format!("http://{addr}/pool")).send().unwrap();
+        assert_eq!(resp.url().path(), "/pool/");
+        let resp = client
+            .get(format!("http://{addr}/../etc/passwd"))
+            .send()
+            .unwrap();
+        assert!(!resp.status().is_success());
+
+        // Routes answer matching requests, and others are served as usual
+        let route: Route = Arc::new(|method, target, body| {
+            (method == "POST" && target == "/api").then(|| {
+                let mut resp = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .into_bytes();
+                resp.extend(body);
+                resp
+            })
+        });
+        let addr = spawn_with_routes(tree.path(), AutoindexStyle::Nginx, vec![route]);
+        let resp = client
+            .post(format!("http://{addr}/api"))
+            .body("echo")
+            .send()
+            .unwrap();
+        assert_eq!(resp.text().unwrap(), "echo");
+        let resp = client.get(format!("http://{addr}/README")).send().unwrap();
+        assert_eq!(resp.text().unwrap(), "hello");
+    }
+}
@@ -0,0 +1,53 @@
+// Finish cleanup of an interrupted sync from its deletion plan (see deletion_plan.rs),
+// with the same protections as sync: --max-delete, and checks of paths and their types.
+
+use chrono::Utc;
+use tracing::{error, info};
+
+use crate::{
+    deletion_list,
+    deletion_plan::{self, DeletionPlan},
+    root_guard, ApplyDeletionsArgs,
+};
+
+pub fn apply_deletions(args: &ApplyDeletionsArgs) -> ! {
+    let plan = match DeletionPlan::load(&args.plan).and_then(|plan| {
+        root_guard::check_local(&plan.local)?;
+        plan.check_not_stale(&args.state_file)?;
+        Ok(plan)
+    }) {
+        Ok(plan) => plan,
+        Err(e) => {
+            eprintln!("{e:#}");
+            std::process::exit(1);
+        }
+    };
+    info!(
+        "Applying deletion plan of {:?} created at {} ({} paths)",
+        plan.local,
+        plan.created_at,
+        plan.paths.len()
+    );
+    let mut deleted = Vec::new();
+    let exit_code = deletion_plan::apply(
+        &plan.local,
+        &plan.paths,
+        args.max_delete,
+        args.dry_run,
+        &mut |relative| deleted.push(relative.to_string()),
+    );
+    info!("{} paths deleted", deleted.len());
+    if let Some(dir) = args.deletion_list_dir.as_ref().filter(|_| !args.dry_run) {
+        let deleted: Vec<&str> = deleted.iter().map(|p| p.as_str()).collect();
+        match deletion_list::write(dir, &deleted, Utc::now()) {
+            Ok(path) => info!("Wrote {} deleted paths to {:?}", deleted.len(), path),
+            Err(e) => error!("Failed to write deletion list to {:?}: {:?}", dir, e),
+        }
+    }
+    if exit_code == 0 && !args.dry_run {
+        if let Err(e) = std::fs::remove_file(&args.plan) {
+            error!("Failed to remove deletion plan {:?}: {:?}", args.plan, e);
+        }
+    }
+    std::process::exit(exit_code);
+}
@@ -0,0 +1,365 @@
+// Offline audit of a local mirror against its own metadata: every file referenced by
+// apt Packages, yum primary.xml.gz and pypi simple indexes found in the directory is checked
+// for presence, size and digest, without any network request.
+// Useful for validating mirrors restored from backup.
+
+use std::{
+    collections::BTreeMap,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use sha2::{Digest, Sha512};
+use tracing::{info, warn};
+use url::Url;
+use walkdir::WalkDir;
+
+use crate::{
+    extensions::{apt, yum, ChecksumType},
+    AuditLocalArgs,
+};
+
+/// A file referenced by metadata
+#[derive(Debug, Clone, PartialEq)]
+struct Reference {
+    size: Option<u64>,
+    digest: Option<(ChecksumType, String)>,
+    /// Metadata file referencing it
+    source: PathBuf,
+}
+
+#[derive(Debug, PartialEq)]
+enum Gap {
+    Missing,
+    SizeMismatch { expected: u64, actual: u64 },
+    DigestMismatch(ChecksumType),
+}
+
+impl std::fmt::Display for Gap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Gap::Missing => write!(f, "missing"),
+            Gap::SizeMismatch { expected, actual } => {
+                write!(f, "size expected {}, got {}", expected, actual)
+            }
+            Gap::DigestMismatch(type_) => write!(f, "{:?} mismatch", type_),
+        }
+    }
+}
+
+/// Metadata file as seen by parsers of extensions: its parent directories and its file URL,
+/// so that referenced files are resolved to local paths by their URLs
+fn metadata_location(path: &Path) -> Result<(Vec<String>, Url)> {
+    let url = Url::from_file_path(path).map_err(|_| anyhow!("{:?} is not absolute", path))?;
+    let relative = path
+        .parent()
+        .into_iter()
+        .flat_map(|p| p.components())
+        .filter_map(|c| match c {
+            Component::Normal(s) => Some(s.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect();
+    Ok((relative, url))
+}
+
+fn local_path(url: &Url) -> Result<PathBuf> {
+    url.to_file_path()
+        .map_err(|_| anyhow!("{} is not a local path", url))
+}
+
+fn apt_references(path: &Path) -> Result<Vec<(PathBuf, Reference)>> {
+    let (relative, url) = metadata_location(path)?;
+    apt::parse_package(path, &relative, &url)?
+        .into_iter()
+        .map(|package| {
+            let reference = Reference {
+                size: Some(package.size as u64),
+                digest: package.checksum,
+                source: path.to_path_buf(),
+            };
+            Ok((local_path(&package.url)?, reference))
+        })
+        .collect()
+}
+
+fn yum_references(path: &Path) -> Result<Vec<(PathBuf, Reference)>> {
+    let (relative, url) = metadata_location(path)?;
+    yum::parse_package(path, &relative, &url)?
+        .into_iter()
+        .map(|package| {
+            let reference = Reference {
+                size: package.size,
+                digest: package.checksum,
+                source: path.to_path_buf(),
+            };
+            Ok((local_path(&package.url)?, reference))
+        })
+        .collect()
+}
+
+/// If a Packages index of another compression (preferring uncompressed one, then xz) is read instead
+fn has_preferred_packages(path: &Path) -> bool {
+    let preferred: &[&str] = match path.file_name().and_then(|n| n.to_str()) {
+        Some("Packages.xz") => &["Packages"],
+        Some("Packages.gz") => &["Packages", "Packages.xz"],
+        _ => &[],
+    };
+    preferred
+        .iter()
+        .any(|name| path.with_file_name(name).exists())
+}
+
+/// Files linked by pypi simple index (simple/<project>/index.html), with sha256 in fragment
+fn pypi_references(path: &Path) -> Result<Vec<(PathBuf, Reference)>> {
+    let href = Regex::new(r#"href="([^"]+)""#).unwrap();
+    let Ok(base) = Url::from_file_path(path) else {
+        return Ok(vec![]);
+    };
+    let data = std::fs::read_to_string(path)?;
+    let mut refs = Vec::new();
+    for caps in href.captures_iter(&data) {
+        let Ok(url) = base.join(&caps[1].replace("&amp;", "&")) else {
+            continue;
+        };
+        // links to other sites are not mirrored locally
+        if url.scheme() != "file" {
+            continue;
+        }
+        let digest = url
+            .fragment()
+            .and_then(|f| f.strip_prefix("sha256="))
+            .map(|d| (ChecksumType::Sha256, d.to_lowercase()));
+        let Ok(file) = url.to_file_path() else {
+            continue;
+        };
+        if file.is_dir() || file.ends_with("index.html") {
+            continue;
+        }
+        refs.push((
+            file,
+            Reference {
+                size: None,
+                digest,
+                source: path.to_path_buf(),
+            },
+        ));
+    }
+    Ok(refs)
+}
+
+fn is_pypi_index(path: &Path) -> bool {
+    path.file_name().map(|n| n == "index.html").unwrap_or(false)
+        && path
+            .parent()
+            .and_then(|p| p.parent())
+            .and_then(|p| p.file_name())
+            .map(|n| n == "simple")
+            .unwrap_or(false)
+}
+
+/// Collect references from all metadata files under dir (absolute), by referenced path
+fn collect_references(dir: &Path) -> Result<BTreeMap<PathBuf, Reference>> {
+    let mut refs = BTreeMap::new();
+    for entry in WalkDir::new(dir) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.path();
+        let found = if apt::is_apt_packages_index(path) {
+            if has_preferred_packages(path) {
+                continue;
+            }
+            apt_references(path)
+        } else if yum::is_yum_primary_xml(path) {
+            yum_references(path)
+        } else if is_pypi_index(path) {
+            pypi_references(path)
+        } else {
+            continue;
+        };
+        match found {
+            Ok(found) => refs.extend(found),
+            Err(e) => warn!("Failed to read metadata {:?}: {:#}", path, e),
+        }
+    }
+    Ok(refs)
+}
+
+fn digest_file(path: &Path, type_: ChecksumType) -> Result<String> {
+    match type_ {
+        ChecksumType::Sha256 => crate::utils::sha256_file(path),
+        ChecksumType::Sha512 => {
+            let mut file = std::fs::File::open(path)?;
+            let mut hasher = Sha512::new();
+            std::io::copy(&mut file, &mut hasher)?;
+            Ok(format!("{:x}", hasher.finalize()))
+        }
+    }
+}
+
+fn check(path: &Path, reference: &Reference, verify_digest: bool) -> Result<Option<Gap>> {
+    let meta = match std::fs::metadata(path) {
+        Ok(meta) if meta.is_file() => meta,
+        Ok(_) => return Ok(Some(Gap::Missing)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Some(Gap::Missing)),
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(expected) = reference.size {
+        if meta.len() != expected {
+            return Ok(Some(Gap::SizeMismatch {
+                expected,
+                actual: meta.len(),
+            }));
+        }
+    }
+    if let (true, Some((type_, expected))) = (verify_digest, &reference.digest) {
+        if digest_file(path, *type_)? != *expected {
+            return Ok(Some(Gap::DigestMismatch(*type_)));
+        }
+    }
+    Ok(None)
+}
+
+/// Audit dir (absolute), returning (referenced files, gaps by path)
+fn audit(dir: &Path, verify_digest: bool) -> Result<(usize, Vec<(PathBuf, Gap)>)> {
+    let refs = collect_references(dir)?;
+    let mut gaps = Vec::new();
+    for (path, reference) in &refs {
+        match check(path, reference, verify_digest) {
+            Ok(Some(gap)) => gaps.push((path.clone(), gap)),
+            Ok(None) => {}
+            Err(e) => warn!(
+                "Failed to check {:?} (referenced by {:?}): {:#}",
+                path, reference.source, e
+            ),
+        }
+    }
+    Ok((refs.len(), gaps))
+}
+
+pub fn audit_local(args: &AuditLocalArgs) -> ! {
+    let local = std::path::absolute(&args.local).unwrap();
+    let (total, gaps) = audit(&local, !args.no_digest).unwrap();
+    for (path, gap) in &gaps {
+        let path = path.strip_prefix(&local).unwrap_or(path);
+        println!("{}: {}", path.display(), gap);
+    }
+    info!(
+        "{} files referenced by metadata, {} gaps found",
+        total,
+        gaps.len()
+    );
+    std::process::exit(if gaps.is_empty() { 0 } else { 1 });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Sha256;
+
+    fn sha256(data: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(data))
+    }
+
+    #[test]
+    fn test_audit() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let write = |path: &str, data: &[u8]| {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, data).unwrap();
+        };
+
+        // apt
+        write("debian/pool/main/a/a.deb", b"aaaa");
+        write("debian/pool/main/b/b.deb", b"bbbX");
+        write(
+            "debian/dists/stable/main/binary-amd64/Packages",
+            format!(
+                "Package: a\nVersion: 1.0\nArchitecture: amd64\nFilename: pool/main/a/a.deb\nSize: 4\nSHA256: {}\n\n\
+                 Package: b\nVersion: 1.0\nArchitecture: amd64\nFilename: pool/main/b/b.deb\nSize: 4\nSHA256: {}\n\n\
+                 Package: c\nVersion: 1.0\nArchitecture: amd64\nFilename: pool/main/c/c.deb\nSize: 4\nSHA256: {}\n",
+                sha256(b"aaaa"),
+                sha256(b"bbbb"),
+                sha256(b"cccc")
+            )
+            .as_bytes(),
+        );
+
+        // Packages.xz is read only when Packages is not there
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        std::io::Write::write_all(
+            &mut xz,
+            format!(
+                "Package: f\nVersion: 1.0\nArchitecture: amd64\nFilename: pool/main/f/f.deb\nSize: 4\nSHA256: {}\n",
+                sha256(b"ffff")
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let xz = xz.finish().unwrap();
+        write("debian/dists/stable/main/binary-amd64/Packages.xz", &xz);
+        write("debian/dists/testing/main/binary-amd64/Packages.xz", &xz);
+
+        // yum
+        write("centos/Packages/d.rpm", b"ddd");
+        let primary = format!(
+            r#"<metadata><package type="rpm"><name>d</name>
+<checksum type="sha256" pkgid="YES">{}</checksum>
+<size package="4" installed="10" archive="12"/>
+<location href="Packages/d.rpm"/></package></metadata>"#,
+            sha256(b"ddd")
+        );
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut gz, primary.as_bytes()).unwrap();
+        write("centos/repodata/abc-primary.xml.gz", &gz.finish().unwrap());
+
+        // pypi
+        write("pypi/packages/ab/e-1.0.whl", b"eeee");
+        write(
+            "pypi/simple/e/index.html",
+            format!(
+                r#"<a href="../../packages/ab/e-1.0.whl#sha256={}">e-1.0.whl</a>
+<a href="../../packages/ab/e-0.9.tar.gz#sha256={}">e-0.9.tar.gz</a>
+<a href="https://example.com/e-0.8.tar.gz">e-0.8.tar.gz</a>"#,
+                sha256(b"eeee"),
+                sha256(b"old")
+            )
+            .as_bytes(),
+        );
+
+        let (total, gaps) = audit(root, true).unwrap();
+        assert_eq!(total, 7);
+        let gaps: Vec<_> = gaps
+            .into_iter()
+            .map(|(p, g)| (p.strip_prefix(root).unwrap().to_path_buf(), g))
+            .collect();
+        assert_eq!(
+            gaps,
+            vec![
+                (
+                    PathBuf::from("centos/Packages/d.rpm"),
+                    Gap::SizeMismatch {
+                        expected: 4,
+                        actual: 3
+                    }
+                ),
+                (
+                    PathBuf::from("debian/pool/main/b/b.deb"),
+                    Gap::DigestMismatch(ChecksumType::Sha256)
+                ),
+                (PathBuf::from("debian/pool/main/c/c.deb"), Gap::Missing),
+                (PathBuf::from("debian/pool/main/f/f.deb"), Gap::Missing),
+                (PathBuf::from("pypi/packages/ab/e-0.9.tar.gz"), Gap::Missing),
+            ]
+        );
+
+        // digests are not verified
+        let (_, gaps) = audit(root, false).unwrap();
+        assert_eq!(gaps.len(), 4);
+    }
+}
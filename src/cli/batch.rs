@@ -0,0 +1,323 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+use anyhow::{bail, Result};
+use tracing::{error, info};
+
+use super::sync::run_sync;
+use crate::{
+    config::{Config, OnFailure, Profile},
+    limiter::Limiter,
+    panic_guard::guard,
+    version_check::VersionChecker,
+    BatchArgs, SyncArgs,
+};
+
+/// Threads of batch (`batch.threads`) split by weights among profiles running at the same time, like
+/// bandwidth (see limiter.rs). As threads of a running sync are fixed, a profile takes its share of free
+/// threads when it starts, and gives them back when it finishes, for profiles starting later.
+#[derive(Debug)]
+struct ThreadPool {
+    total: usize,
+    state: Mutex<PoolState>,
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    in_use: usize,
+    /// Profiles about to start, yet to take their threads
+    pending: usize,
+    pending_weight: u64,
+}
+
+impl ThreadPool {
+    fn new(total: usize) -> Self {
+        Self {
+            total: total.max(1),
+            state: Mutex::new(PoolState::default()),
+        }
+    }
+
+    /// Reserve a share for a profile about to start, so that profiles starting together split free threads
+    fn reserve(&self, weight: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.pending += 1;
+        state.pending_weight += weight.max(1);
+    }
+
+    /// Take threads of a reserved profile. The last one to take gets the rest, so that shares add up to total.
+    fn take(&self, weight: u64) -> usize {
+        let weight = weight.max(1);
+        let mut state = self.state.lock().unwrap();
+        let free = self.total.saturating_sub(state.in_use);
+        let others = state.pending.saturating_sub(1);
+        let threads = if others == 0 {
+            free
+        } else {
+            // Leaving at least one thread for each of others
+            ((free as u64 * weight / state.pending_weight.max(1)) as usize)
+                .min(free.saturating_sub(others))
+        }
+        .max(1);
+        state.pending = others;
+        state.pending_weight = state.pending_weight.saturating_sub(weight);
+        state.in_use += threads;
+        threads
+    }
+
+    fn give_back(&self, threads: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.in_use = state.in_use.saturating_sub(threads);
+    }
+}
+
+fn prepare(config: &Config, selected: &[String]) -> Result<Vec<(Profile, SyncArgs)>> {
+    for name in selected {
+        if !config.profiles.iter().any(|p| &p.name == name) {
+            bail!("Profile {name} is not found in config");
+        }
+    }
+    let profiles: Vec<_> = config
+        .profiles
+        .iter()
+        .filter(|p| selected.is_empty() || selected.contains(&p.name))
+        .collect();
+    let mut prepared = Vec::new();
+    for profile in &profiles {
+        let args = profile.to_sync_args()?;
+        if args.record.is_some() {
+            bail!(
+                "--record is not supported in batch (profile {})",
+                profile.name
+            );
+        }
+        prepared.push(((*profile).clone(), args));
+    }
+    Ok(prepared)
+}
+
+/// Run a profile, with its panics (outside of sync tasks) contained as exit code 3,
+/// so that one profile going wrong does not take down others.
+fn run_profile(
+    profile: &Profile,
+    sync_args: &SyncArgs,
+    limiter: &Arc<Limiter>,
+    threads: Option<&ThreadPool>,
+    bind_address: &Option<String>,
+) -> i32 {
+    let mut sync_args = sync_args.clone();
+    if let Some(threads) = threads {
+        sync_args.threads = threads.take(profile.threads_weight());
+    }
+    let sync_args = &sync_args;
+    info!(
+        "Profile {}: syncing with {} threads",
+        profile.name, sync_args.threads
+    );
+    let share = limiter.share(profile.bandwidth_weight(), sync_args.bandwidth_limit);
+    let exit_code = match guard(|| run_sync(sync_args, bind_address.clone(), Some(share))) {
+        Ok(exit_code) => exit_code,
+        Err(e) => {
+            error!("Profile {}: panicked: {}", profile.name, e);
+            3
+        }
+    };
+    if let Some(threads) = threads {
+        threads.give_back(sync_args.threads);
+    }
+    if exit_code == 0 {
+        info!("Profile {}: done", profile.name);
+    } else {
+        error!(
+            "Profile {}: failed with exit code {}",
+            profile.name, exit_code
+        );
+        if profile.on_failure == OnFailure::Abort {
+            error!(
+                "Profile {}: aborting batch (on_failure = abort)",
+                profile.name
+            );
+            std::process::exit(exit_code);
+        }
+    }
+    exit_code
+}
+
+/// Like "3 profiles: 1 ok, 2 failed (debian: 1, small: 3)"
+fn summary(profiles: &[(Profile, SyncArgs)], exit_codes: &[i32]) -> String {
+    let failed: Vec<String> = profiles
+        .iter()
+        .zip(exit_codes)
+        .filter(|(_, code)| **code != 0)
+        .map(|((profile, _), code)| format!("{}: {}", profile.name, code))
+        .collect();
+    let mut res = format!(
+        "{} profiles: {} ok, {} failed",
+        profiles.len(),
+        profiles.len() - failed.len(),
+        failed.len()
+    );
+    if !failed.is_empty() {
+        res.push_str(&format!(" ({})", failed.join(", ")));
+    }
+    res
+}
+
+/// Run all profiles once, returning exit code of the first failed profile (in config order).
+fn run_batch(
+    config: &Config,
+    profiles: &[(Profile, SyncArgs)],
+    limiter: &Arc<Limiter>,
+    bind_address: &Option<String>,
+) -> i32 {
+    let threads = config.batch.threads.map(ThreadPool::new);
+    // Each running profile needs a thread at least
+    let parallel = config
+        .batch
+        .parallel
+        .unwrap_or(profiles.len())
+        .min(config.batch.threads.unwrap_or(usize::MAX))
+        .clamp(1, profiles.len().max(1));
+    if let Some(threads) = &threads {
+        for (profile, _) in profiles.iter().take(parallel) {
+            threads.reserve(profile.threads_weight());
+        }
+    }
+    let next = AtomicUsize::new(0);
+    let exit_codes = Mutex::new(vec![0; profiles.len()]);
+    std::thread::scope(|scope| {
+        for _ in 0..parallel {
+            scope.spawn(|| loop {
+                let idx = next.fetch_add(1, Ordering::SeqCst);
+                let Some((profile, sync_args)) = profiles.get(idx) else {
+                    break;
+                };
+                // Profiles starting together are reserved above
+                if let (Some(threads), true) = (&threads, idx >= parallel) {
+                    threads.reserve(profile.threads_weight());
+                }
+                let exit_code =
+                    run_profile(profile, sync_args, limiter, threads.as_ref(), bind_address);
+                exit_codes.lock().unwrap()[idx] = exit_code;
+            });
+        }
+    });
+
+    let exit_codes = exit_codes.into_inner().unwrap();
+    info!("Batch finished: {}", summary(profiles, &exit_codes));
+    exit_codes.into_iter().find(|c| *c != 0).unwrap_or(0)
+}
+
+pub fn batch(args: &BatchArgs, bind_address: Option<String>) -> ! {
+    let (config, profiles) = match Config::load(&args.config)
+        .and_then(|config| prepare(&config, &args.profile).map(|profiles| (config, profiles)))
+    {
+        Ok(res) => res,
+        Err(e) => {
+            error!("{:?}", e);
+            std::process::exit(1);
+        }
+    };
+    let limiter = Limiter::new(config.batch.bandwidth);
+
+    let Some(interval) = args.interval else {
+        std::process::exit(run_batch(&config, &profiles, &limiter, &bind_address));
+    };
+    let version_check =
+        !args.no_version_check && (args.version_check || config.batch.version_check);
+    let mut version_checker = VersionChecker::new(version_check);
+    info!("Running as daemon, syncing every {} seconds", interval);
+    loop {
+        version_checker.check_if_due();
+        run_batch(&config, &profiles, &limiter, &bind_address);
+        std::thread::sleep(std::time::Duration::from_secs(interval));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thread_pool() {
+        let take_together = |pool: &ThreadPool, weights: &[u64]| {
+            for w in weights {
+                pool.reserve(*w);
+            }
+            weights.iter().map(|w| pool.take(*w)).collect::<Vec<_>>()
+        };
+        assert_eq!(take_together(&ThreadPool::new(8), &[1, 1]), [4, 4]);
+        assert_eq!(take_together(&ThreadPool::new(8), &[3, 1]), [6, 2]);
+        // Shares add up to total
+        assert_eq!(take_together(&ThreadPool::new(10), &[1, 1, 1]), [3, 3, 4]);
+        assert_eq!(take_together(&ThreadPool::new(3), &[10, 1, 1]), [1, 1, 1]);
+        assert_eq!(take_together(&ThreadPool::new(12), &[10, 1, 1]), [10, 1, 1]);
+
+        // Profiles running one by one get all threads
+        let pool = ThreadPool::new(8);
+        for _ in 0..4 {
+            assert_eq!(take_together(&pool, &[1]), [8]);
+            pool.give_back(8);
+        }
+        // A profile starting later gets threads given back
+        let pool = ThreadPool::new(8);
+        assert_eq!(take_together(&pool, &[1, 1]), [4, 4]);
+        pool.give_back(4);
+        assert_eq!(take_together(&pool, &[3]), [4]);
+    }
+
+    #[test]
+    fn test_summary() {
+        let config = Config::parse(
+            r#"
+[[profile]]
+name = "a"
+upstream = "http://localhost/a/"
+local = "/tmp/a"
+
+[[profile]]
+name = "b"
+upstream = "http://localhost/b/"
+local = "/tmp/b"
+"#,
+        )
+        .unwrap();
+        let prepared = prepare(&config, &[]).unwrap();
+        assert_eq!(summary(&prepared, &[0, 0]), "2 profiles: 2 ok, 0 failed");
+        assert_eq!(
+            summary(&prepared, &[0, 3]),
+            "2 profiles: 1 ok, 1 failed (b: 3)"
+        );
+    }
+
+    #[test]
+    fn test_prepare() {
+        let config = Config::parse(
+            r#"
+[batch]
+threads = 8
+
+[[profile]]
+name = "a"
+upstream = "http://localhost/a/"
+local = "/tmp/a"
+weight = 3
+
+[[profile]]
+name = "b"
+upstream = "http://localhost/b/"
+local = "/tmp/b"
+"#,
+        )
+        .unwrap();
+        let prepared = prepare(&config, &[]).unwrap();
+        assert_eq!(prepared.len(), 2);
+        assert_eq!(prepared[0].0.threads_weight(), 3);
+        let prepared = prepare(&config, &["b".to_string()]).unwrap();
+        assert_eq!(prepared.len(), 1);
+        assert_eq!(prepared[0].0.name, "b");
+        assert!(prepare(&config, &["c".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,34 @@
+use crate::{
+    config::{schema, Config},
+    ConfigArgs, ConfigCommands,
+};
+
+pub fn config(args: &ConfigArgs) -> ! {
+    match &args.command {
+        ConfigCommands::Schema => {
+            println!("{}", serde_json::to_string_pretty(&schema()).unwrap());
+        }
+        ConfigCommands::Check { config } => match Config::load(config) {
+            Ok(parsed) => println!("{:?}: OK ({} profiles)", config, parsed.profiles.len()),
+            Err(e) => {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        },
+        ConfigCommands::Show { config, profile } => {
+            let parsed = match Config::load(config) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    eprintln!("{e:#}");
+                    std::process::exit(1);
+                }
+            };
+            let Some(profile) = parsed.profiles.iter().find(|p| &p.name == profile) else {
+                eprintln!("No profile named {profile} in {config:?}");
+                std::process::exit(1);
+            };
+            print!("{}", toml::to_string(profile).unwrap());
+        }
+    }
+    std::process::exit(0);
+}
@@ -0,0 +1,266 @@
+// Compare listings of two upstreams (like two tier-1 mirrors of the same repository), reporting files
+// missing on either side and size or mtime differences, to choose a trustworthy upstream
+// and detect stale mirrors before pointing a sync at them.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use anyhow::{Context, Result};
+use chrono::Duration;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::{
+    build_client,
+    listing::{self, FileType, ListItem, NameDecoding},
+    parser::{build_parser, ListResult, Parser, ParserSources},
+    regex_process::{Comparison, ExclusionManager},
+    CrossCheckArgs,
+};
+
+/// Recursively list upstream, returning items by relative path (directories end with "/")
+fn list_tree(
+    parser: &dyn Parser,
+    client: &reqwest::blocking::Client,
+    upstream: &Url,
+    manager: &ExclusionManager,
+) -> Result<BTreeMap<String, ListItem>> {
+    let mut tree = BTreeMap::new();
+    let mut queue = VecDeque::from([(String::new(), upstream.clone())]);
+    while let Some((relative, url)) = queue.pop_front() {
+        info!("Listing {}", url);
+        let items = match parser
+            .get_list(client, &url)
+            .with_context(|| format!("Failed to list {}", url))?
+        {
+            ListResult::List(items) => listing::apply_name_decoding(items, NameDecoding::Auto),
+            ListResult::Redirect(target) => {
+                warn!("{} is redirected to {}, not compared", url, target);
+                continue;
+            }
+        };
+        let list_only = manager.match_str(relative.trim_end_matches('/')) == Comparison::ListOnly;
+        for item in items {
+            let path = format!("{}{}", relative, item.name);
+            if manager.match_str(&path) == Comparison::Stop {
+                continue;
+            }
+            if item.type_ == FileType::Directory {
+                queue.push_back((format!("{path}/"), item.url.clone()));
+                tree.insert(format!("{path}/"), item);
+            } else if !list_only {
+                tree.insert(path, item);
+            }
+        }
+    }
+    Ok(tree)
+}
+
+#[derive(Debug, PartialEq)]
+enum Divergence {
+    OnlyInA,
+    OnlyInB,
+    /// File in one, directory in the other
+    Type,
+    Size(String, String),
+    /// Mtime of B minus A
+    Mtime(Duration),
+}
+
+struct Options {
+    /// Offsets to UTC of listed mtimes
+    timezones: (Duration, Duration),
+    mtime_tolerance: Duration,
+    ignore_mtime: bool,
+}
+
+fn compare_items(a: &ListItem, b: &ListItem, options: &Options) -> Option<Divergence> {
+    if a.type_ != b.type_ {
+        return Some(Divergence::Type);
+    }
+    if a.type_ == FileType::Directory {
+        return None;
+    }
+    if let (Some(sa), Some(sb)) = (a.size, b.size) {
+        let tolerance = sa.tolerance().max(sb.tolerance());
+        if sa.get_estimated().abs_diff(sb.get_estimated()) > tolerance {
+            return Some(Divergence::Size(sa.to_string(), sb.to_string()));
+        }
+    }
+    if options.ignore_mtime {
+        return None;
+    }
+    let (Some(ma), Some(mb)) = (a.mtime, b.mtime) else {
+        return None;
+    };
+    let diff = (mb - options.timezones.1) - (ma - options.timezones.0);
+    (diff.abs() > options.mtime_tolerance).then_some(Divergence::Mtime(diff))
+}
+
+fn compare(
+    a: &BTreeMap<String, ListItem>,
+    b: &BTreeMap<String, ListItem>,
+    options: &Options,
+) -> Vec<(String, Divergence)> {
+    let mut res = Vec::new();
+    for (path, item_a) in a {
+        match b.get(path) {
+            None => res.push((path.clone(), Divergence::OnlyInA)),
+            Some(item_b) => {
+                if let Some(d) = compare_items(item_a, item_b, options) {
+                    res.push((path.clone(), d));
+                }
+            }
+        }
+    }
+    for path in b.keys().filter(|p| !a.contains_key(*p)) {
+        res.push((path.clone(), Divergence::OnlyInB));
+    }
+    res.sort_by(|x, y| x.0.cmp(&y.0));
+    res
+}
+
+fn format_divergence(d: &Divergence) -> String {
+    match d {
+        Divergence::OnlyInA => "only-in-a".to_string(),
+        Divergence::OnlyInB => "only-in-b".to_string(),
+        Divergence::Type => "type".to_string(),
+        Divergence::Size(a, b) => format!("size\t{a}\t{b}"),
+        Divergence::Mtime(diff) => format!(
+            "mtime\t{} newer by {}s",
+            if *diff > Duration::zero() { "b" } else { "a" },
+            diff.num_seconds().abs()
+        ),
+    }
+}
+
+fn summary(divergences: &[(String, Divergence)]) -> String {
+    let count = |f: &dyn Fn(&Divergence) -> bool| divergences.iter().filter(|(_, d)| f(d)).count();
+    format!(
+        "{} only in A, {} only in B, {} type differences, {} size differences, {} mtime differences (newer in A: {}, newer in B: {})",
+        count(&|d| *d == Divergence::OnlyInA),
+        count(&|d| *d == Divergence::OnlyInB),
+        count(&|d| *d == Divergence::Type),
+        count(&|d| matches!(d, Divergence::Size(..))),
+        count(&|d| matches!(d, Divergence::Mtime(_))),
+        count(&|d| matches!(d, Divergence::Mtime(diff) if *diff < Duration::zero())),
+        count(&|d| matches!(d, Divergence::Mtime(diff) if *diff > Duration::zero())),
+    )
+}
+
+fn list_upstream(
+    args: &CrossCheckArgs,
+    upstream: &Url,
+    parser_type: &crate::parser::ParserType,
+    manager: &ExclusionManager,
+) -> Result<BTreeMap<String, ListItem>> {
+    let parser = build_parser(&ParserSources::new(parser_type, upstream))?;
+    let client = build_client!(reqwest::blocking::Client, args, parser, None::<String>);
+    list_tree(&*parser, &client, upstream, manager)
+}
+
+pub fn cross_check(args: &CrossCheckArgs) -> ! {
+    let manager = ExclusionManager::new(&args.exclude, &args.include);
+    let parser_b = args.parser_b.as_ref().unwrap_or(&args.parser);
+    let trees = list_upstream(args, &args.upstream_a, &args.parser, &manager)
+        .and_then(|a| list_upstream(args, &args.upstream_b, parser_b, &manager).map(|b| (a, b)));
+    let (a, b) = match trees {
+        Ok(trees) => trees,
+        Err(e) => {
+            eprintln!("{e:#}");
+            std::process::exit(2);
+        }
+    };
+    let options = Options {
+        timezones: (
+            Duration::hours(args.timezone_a.into()),
+            Duration::hours(args.timezone_b.into()),
+        ),
+        mtime_tolerance: Duration::seconds(args.mtime_tolerance),
+        ignore_mtime: args.ignore_mtime,
+    };
+    let divergences = compare(&a, &b, &options);
+    for (path, d) in &divergences {
+        println!("{}\t{}", path, format_divergence(d));
+    }
+    eprintln!(
+        "A: {} items, B: {} items; {}",
+        a.len(),
+        b.len(),
+        summary(&divergences)
+    );
+    // Like diff: 1 if different
+    std::process::exit(if divergences.is_empty() { 0 } else { 1 });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::{
+        autoindex::{spawn, AutoindexStyle},
+        test_tree::write_tree,
+    };
+
+    #[test]
+    fn test_cross_check() {
+        let (a, b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let t = 1_700_000_000;
+        write_tree(
+            a.path(),
+            &[
+                ("README", "hello", t),
+                ("pool/a.deb", "aaaa", t),
+                ("pool/b.deb", "bb", t),
+                ("dists/Release", "old", t),
+                ("debug/x.deb", "x", t),
+            ],
+        );
+        write_tree(
+            b.path(),
+            &[
+                ("README", "hello", t + 30),
+                ("pool/a.deb", "aaaaaa", t),
+                ("pool/c.deb", "cc", t),
+                ("dists/Release", "new", t + 3600),
+            ],
+        );
+        let url = |dir: &Path| {
+            Url::parse(&format!("http://{}/", spawn(dir, AutoindexStyle::Nginx))).unwrap()
+        };
+        let manager = ExclusionManager::new(&["^debug".parse().unwrap()], &[]);
+        let client = reqwest::blocking::Client::new();
+        let parser = crate::parser::nginx::NginxListingParser::default();
+        let tree_a = list_tree(&parser, &client, &url(a.path()), &manager).unwrap();
+        let tree_b = list_tree(&parser, &client, &url(b.path()), &manager).unwrap();
+        assert!(tree_a.contains_key("pool/"));
+        assert!(!tree_a.contains_key("debug/"));
+
+        let options = Options {
+            timezones: (Duration::zero(), Duration::zero()),
+            mtime_tolerance: Duration::seconds(60),
+            ignore_mtime: false,
+        };
+        let divergences = compare(&tree_a, &tree_b, &options);
+        assert_eq!(
+            divergences,
+            vec![
+                (
+                    "dists/Release".to_string(),
+                    Divergence::Mtime(Duration::hours(1))
+                ),
+                (
+                    "pool/a.deb".to_string(),
+                    Divergence::Size("4".to_string(), "6".to_string())
+                ),
+                ("pool/b.deb".to_string(), Divergence::OnlyInA),
+                ("pool/c.deb".to_string(), Divergence::OnlyInB),
+            ]
+        );
+        assert_eq!(
+            format_divergence(&divergences[0].1),
+            "mtime\tb newer by 3600s"
+        );
+        assert!(summary(&divergences).ends_with("(newer in A: 0, newer in B: 1)"));
+    }
+}
@@ -0,0 +1,301 @@
+// Translation of rsync filter rules (exclude-from files, or --exclude/--include/--filter flags of ftpsync-like scripts)
+// to tsumugu rules, for operators moving mirror jobs from rsync. Constructs without an equivalent
+// (rule modifiers, merge files, catch-all exclusions, etc.) are reported instead of being guessed.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::ImportRsyncExcludesArgs;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Exclude,
+    Include,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Exclude => "exclude",
+            Kind::Include => "include",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Translated {
+    kind: Kind,
+    regex: String,
+    /// The rule as written in source
+    source: String,
+    /// rsync applies it to directories only, which is not expressible with tsumugu regexes
+    dir_only: bool,
+}
+
+/// Translate an rsync pattern to a regex on relative paths (without leading or trailing slash)
+fn translate_pattern(pattern: &str) -> Result<(String, bool), String> {
+    let anchored = pattern.starts_with('/');
+    let mut pattern = pattern.trim_start_matches('/');
+    let dir_only = pattern.ends_with('/');
+    pattern = pattern.trim_end_matches('/');
+    if pattern.chars().all(|c| c == '*') {
+        return Err("catch-all pattern has no equivalent, as tsumugu syncs everything not excluded (exclude unwanted paths instead)".to_string());
+    }
+    // "dir/***" is the directory and everything inside
+    let (pattern, suffix) = match pattern.strip_suffix("/***") {
+        Some(dir) => (dir, "(/|$)"),
+        None => (pattern, "$"),
+    };
+    let mut regex = String::from(if anchored { "^" } else { "(^|/)" });
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                let mut class = String::from("[");
+                if chars.next_if(|c| *c == '!' || *c == '^').is_some() {
+                    class.push('^');
+                }
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        Some('\\') => class.push_str("\\\\"),
+                        Some(c) => class.push(c),
+                        None => return Err("unclosed character class".to_string()),
+                    }
+                }
+                class.push(']');
+                regex.push_str(&class);
+            }
+            '\\' => match chars.next() {
+                Some(c) => regex.push_str(&regex::escape(&c.to_string())),
+                None => return Err("trailing backslash".to_string()),
+            },
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push_str(suffix);
+    Ok((regex, dir_only))
+}
+
+/// Parse a rule: `- pattern`, `+ pattern`, `exclude pattern`, `include pattern`, flags like `--exclude=pattern`
+/// or `--filter='- pattern'`, or a bare pattern (exclusion, as in files of --exclude-from).
+/// None for comments and blank lines.
+fn parse_rule(line: &str) -> Option<Result<(Kind, String), String>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+        return None;
+    }
+    for (flag, kind) in [("--exclude", Kind::Exclude), ("--include", Kind::Include)] {
+        if let Some(rest) = line.strip_prefix(flag) {
+            if let Some(pattern) = rest.strip_prefix('=').or_else(|| rest.strip_prefix(' ')) {
+                return Some(Ok((kind, unquote(pattern).to_string())));
+            }
+        }
+    }
+    for flag in ["--filter=", "--filter ", "-f "] {
+        if let Some(rule) = line.strip_prefix(flag) {
+            return parse_rule(unquote(rule));
+        }
+    }
+    let (prefix, pattern) = line.split_once(' ').unwrap_or((line, ""));
+    let kind = match prefix {
+        "-" | "exclude" => Kind::Exclude,
+        "+" | "include" => Kind::Include,
+        "P" | "protect" | "R" | "risk" => {
+            return Some(Err(
+                "protect/risk rules only affect deletion on receiver".to_string()
+            ))
+        }
+        "H" | "hide" | "S" | "show" => {
+            return Some(Err("hide/show rules only affect sender".to_string()))
+        }
+        "." | "merge" | ":" | "dir-merge" => {
+            return Some(Err(
+                "merge files are not followed (import them separately)".to_string()
+            ))
+        }
+        "!" | "clear" => return Some(Err("clearing rules has no equivalent".to_string())),
+        _ if prefix.len() > 1 && (prefix.starts_with('-') || prefix.starts_with('+')) => {
+            return Some(Err(format!("rule modifiers ({prefix}) are not supported")))
+        }
+        _ => return Some(Ok((Kind::Exclude, line.to_string()))),
+    };
+    if pattern.is_empty() {
+        return Some(Err("missing pattern".to_string()));
+    }
+    Some(Ok((kind, pattern.to_string())))
+}
+
+fn unquote(s: &str) -> &str {
+    let s = s.trim();
+    for quote in ['\'', '"'] {
+        if let Some(inner) = s.strip_prefix(quote).and_then(|s| s.strip_suffix(quote)) {
+            return inner;
+        }
+    }
+    s
+}
+
+/// Translated rules, and untranslatable ones (line number, rule and why)
+fn import(content: &str) -> (Vec<Translated>, Vec<(usize, String, String)>) {
+    let mut translated = Vec::new();
+    let mut failed = Vec::new();
+    for (idx, line) in content.lines().enumerate() {
+        let res = match parse_rule(line) {
+            None => continue,
+            Some(res) => res.and_then(|(kind, pattern)| {
+                translate_pattern(&pattern).map(|(regex, dir_only)| Translated {
+                    kind,
+                    regex,
+                    source: line.trim().to_string(),
+                    dir_only,
+                })
+            }),
+        };
+        match res {
+            Ok(t) => translated.push(t),
+            Err(reason) => failed.push((idx + 1, line.trim().to_string(), reason)),
+        }
+    }
+    (translated, failed)
+}
+
+/// As rules file of match-rules, with source rules in comments
+fn format_rules(translated: &[Translated]) -> String {
+    let mut res = String::new();
+    for t in translated {
+        res.push_str(&format!("# {}", t.source));
+        if t.dir_only {
+            res.push_str(" (directories only in rsync)");
+        }
+        res.push_str(&format!("\n{} {}\n", t.kind.as_str(), t.regex));
+    }
+    res
+}
+
+/// As keys of a batch profile
+fn format_toml(translated: &[Translated]) -> String {
+    let mut res = String::new();
+    for kind in [Kind::Exclude, Kind::Include] {
+        let rules: Vec<_> = translated.iter().filter(|t| t.kind == kind).collect();
+        if rules.is_empty() {
+            continue;
+        }
+        res.push_str(&format!("{} = [\n", kind.as_str()));
+        for t in rules {
+            res.push_str(&format!(
+                "    {},  # {}\n",
+                toml::Value::String(t.regex.clone()),
+                t.source
+            ));
+        }
+        res.push_str("]\n");
+    }
+    res
+}
+
+fn read(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))
+}
+
+pub fn import_rsync_excludes(args: &ImportRsyncExcludesArgs) -> ! {
+    let content = read(&args.file).unwrap_or_else(|e| {
+        eprintln!("{e:#}");
+        std::process::exit(1);
+    });
+    let (translated, failed) = import(&content);
+    if args.toml {
+        print!("{}", format_toml(&translated));
+    } else {
+        print!("{}", format_rules(&translated));
+    }
+    for (line, rule, reason) in &failed {
+        eprintln!("line {line}: {rule:?} is not translated: {reason}");
+    }
+    if translated.iter().any(|t| t.kind == Kind::Include) {
+        eprintln!("Note: rsync uses the first matching rule, while tsumugu checks inclusions after exclusions (see README), so check the result with `tsumugu match-rules`");
+    }
+    eprintln!(
+        "{} rules translated, {} not translated",
+        translated.len(),
+        failed.len()
+    );
+    std::process::exit(if failed.is_empty() { 0 } else { 1 });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::regex_process::{Comparison, ExclusionManager, ExpandedRegex};
+
+    #[test]
+    fn test_translate_pattern() {
+        let regex = |p| translate_pattern(p).map(|(r, _)| r);
+        assert_eq!(regex("/pool/*/debug/").unwrap(), "^pool/[^/]*/debug$");
+        assert_eq!(regex("*.iso").unwrap(), r"(^|/)[^/]*\.iso$");
+        assert_eq!(regex("/dists/**/i18n").unwrap(), "^dists/.*/i18n$");
+        assert_eq!(regex("/project/***").unwrap(), "^project(/|$)");
+        assert_eq!(regex("file?[!0-9]").unwrap(), "(^|/)file[^/][^0-9]$");
+        assert!(translate_pattern("/pool/*/debug/").unwrap().1);
+        assert!(regex("*").is_err());
+        assert!(regex("a[bc").is_err());
+    }
+
+    #[test]
+    fn test_import() {
+        let (translated, failed) = import(
+            "# ftpsync excludes\n\
+             - /pool/*/debug/\n\
+             --exclude=*.iso\n\
+             --filter='+ /dists/bookworm/'\n\
+             Packages.diff/\n\
+             P /project/\n\
+             -! /pool/\n\
+             . extra.rules\n",
+        );
+        let rules: Vec<_> = translated
+            .iter()
+            .map(|t| (t.kind, t.regex.as_str()))
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                (Kind::Exclude, "^pool/[^/]*/debug$"),
+                (Kind::Exclude, r"(^|/)[^/]*\.iso$"),
+                (Kind::Include, "^dists/bookworm$"),
+                (Kind::Exclude, r"(^|/)Packages\.diff$"),
+            ]
+        );
+        let failed_lines: Vec<_> = failed.iter().map(|(line, _, _)| *line).collect();
+        assert_eq!(failed_lines, vec![6, 7, 8]);
+        assert!(format_rules(&translated).starts_with(
+            "# - /pool/*/debug/ (directories only in rsync)\nexclude ^pool/[^/]*/debug$\n"
+        ));
+        assert!(format_toml(&translated).contains("include = [\n"));
+
+        // Translated rules work as in rsync on paths
+        let exclusions: Vec<_> = translated
+            .iter()
+            .filter(|t| t.kind == Kind::Exclude)
+            .map(|t| ExpandedRegex::from_str(&t.regex).unwrap())
+            .collect();
+        let manager = ExclusionManager::new(&exclusions, &[]);
+        for (path, expected) in [
+            ("pool/main/debug", Comparison::Stop),
+            ("pool/main/a.deb", Comparison::Ok),
+            ("cd/debian-12.iso", Comparison::Stop),
+            ("dists/bookworm/main/Packages.diff", Comparison::Stop),
+        ] {
+            assert_eq!(manager.match_str(path), expected, "{path}");
+        }
+    }
+}
@@ -1,12 +1,48 @@
 use std::path::PathBuf;
 
-use crate::{build_client, parser::ListResult, regex_process::ExclusionManager, ListArgs};
+use chrono::FixedOffset;
+use url::Url;
+
+use crate::{
+    build_client, har,
+    item_schema::{ItemList, ItemRecord, OutputFormat, SCHEMA_VERSION},
+    listing::{
+        apply_name_decoding, apply_type_overrides, check_name_encoding, detect_slashless_dirs,
+        ListItem,
+    },
+    parser::{build_parser, ListResult, ParserSources},
+    regex_process::ExclusionManager,
+    utils::RetryBudget,
+    ListArgs,
+};
 
 // TODO: clean code
 pub fn list(args: &ListArgs, bind_address: Option<String>) -> ! {
-    let parser = args.parser.build();
+    if args.record.is_some() {
+        har::start_recording();
+    }
+    if let Some(replay) = &args.replay {
+        har::start_replaying(replay).unwrap();
+    }
+    let mut http_base = args.upstream_folder.clone();
+    http_base.set_path(&args.upstream_base);
+    let parser = build_parser(&ParserSources {
+        parser: &args.parser,
+        http_base: &http_base,
+        rsync_upstream: args.rsync_upstream.as_ref(),
+        sitemap: args.sitemap.as_ref(),
+        gitlab_project: args.gitlab_project.as_ref(),
+        huggingface_repo: args.huggingface_repo.as_ref(),
+        manifest: args.manifest.as_ref(),
+        fields: &args.manifest_field,
+        embedded_json_selector: args.embedded_json_selector.as_deref(),
+    })
+    .unwrap();
     let client = build_client!(reqwest::blocking::Client, args, parser, bind_address);
-    let exclusion_manager = ExclusionManager::new(&args.exclude, &args.include);
+    let mut exclusion_manager = ExclusionManager::new(&args.exclude, &args.include);
+    if let Some(trace) = &args.filter_trace {
+        exclusion_manager.set_trace(trace).unwrap();
+    }
     // get relative
     let upstream = &args.upstream_folder;
     let upstream_path = PathBuf::from(upstream.path());
@@ -18,14 +54,87 @@ pub fn list(args: &ListArgs, bind_address: Option<String>) -> ! {
         .to_owned();
     let list = parser.get_list(&client, upstream).unwrap();
 
+    if args.output == OutputFormat::Json {
+        print_json(args, &client, upstream, &relative, list);
+    } else {
+        print_text(args, &client, &exclusion_manager, &relative, list);
+    }
+
+    if let Some(record) = &args.record {
+        har::save(record).unwrap();
+    }
+    // flush filter trace before exit
+    drop(exclusion_manager);
+    std::process::exit(0);
+}
+
+/// Items after name decoding and type overrides, as in sync
+fn process_items(
+    args: &ListArgs,
+    client: &reqwest::blocking::Client,
+    relative: &str,
+    list: Vec<ListItem>,
+) -> Vec<ListItem> {
+    let list = apply_name_decoding(list, args.name_decoding);
+    let list = if args.check_name_encoding {
+        check_name_encoding(list, args.name_decoding)
+    } else {
+        list
+    };
+    // Like listing pages, HEADs are not retried by list
+    let list = detect_slashless_dirs(
+        client,
+        list,
+        args.slashless_dirs,
+        0,
+        &RetryBudget::default(),
+    );
+    apply_type_overrides(list, relative, &args.force_dir, &args.force_file)
+}
+
+fn print_json(
+    args: &ListArgs,
+    client: &reqwest::blocking::Client,
+    upstream: &Url,
+    relative: &str,
+    list: ListResult,
+) {
+    let timezone = args
+        .timezone
+        .map(|tz| FixedOffset::east_opt(tz * 3600).unwrap());
+    let items = match list {
+        ListResult::Redirect(url) => {
+            eprintln!("Redirect to {url}");
+            Vec::new()
+        }
+        ListResult::List(list) => process_items(args, client, relative, list)
+            .iter()
+            .map(|item| ItemRecord::new(item, relative, timezone))
+            .collect(),
+    };
+    let list = ItemList {
+        schema_version: SCHEMA_VERSION,
+        url: upstream.to_string(),
+        items,
+    };
+    println!("{}", serde_json::to_string_pretty(&list).unwrap());
+}
+
+fn print_text(
+    args: &ListArgs,
+    client: &reqwest::blocking::Client,
+    exclusion_manager: &ExclusionManager,
+    relative: &str,
+    list: ListResult,
+) {
     println!("Relative: {relative}");
-    println!("Exclusion: {:?}", exclusion_manager.match_str(&relative));
+    println!("Exclusion: {:?}", exclusion_manager.match_str(relative));
     match list {
         ListResult::Redirect(url) => {
             println!("Redirect to {url}");
         }
         ListResult::List(list) => {
-            for item in list {
+            for item in process_items(args, client, relative, list) {
                 print!("{item}");
                 let new_relative = format!("{}/{}", relative, item.name);
                 tracing::debug!("new_relative: {new_relative}");
@@ -40,6 +149,4 @@ pub fn list(args: &ListArgs, bind_address: Option<String>) -> ! {
             }
         }
     }
-
-    std::process::exit(0);
 }
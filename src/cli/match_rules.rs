@@ -0,0 +1,216 @@
+// Dry matching of include/exclude rules against a list of paths (like `find` output of a local mirror),
+// showing which paths would be kept and which would be excluded (and so deleted locally) by a sync,
+// before trying a new rule set in a live run.
+
+use std::{
+    io::{BufRead, BufReader},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    regex_process::{Comparison, ExclusionManager, ExpandedRegex, Rule},
+    MatchRulesArgs,
+};
+
+/// Exclusions and inclusions in rules file: `exclude <regex>` or `include <regex>` per line, `#` for comments
+fn load_rules(path: &Path) -> Result<(Vec<ExpandedRegex>, Vec<ExpandedRegex>)> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let mut exclusions = Vec::new();
+    let mut inclusions = Vec::new();
+    for (idx, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (kind, regex) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let regex = ExpandedRegex::from_str(regex.trim())
+            .with_context(|| format!("Invalid regex at line {} of {:?}", idx + 1, path))?;
+        match kind {
+            "exclude" => exclusions.push(regex),
+            "include" => inclusions.push(regex),
+            _ => bail!(
+                "Unknown rule {:?} at line {} of {:?} (expected exclude or include)",
+                kind,
+                idx + 1,
+                path
+            ),
+        }
+    }
+    Ok((exclusions, inclusions))
+}
+
+/// What a sync does to a path
+#[derive(Debug, PartialEq)]
+enum Fate {
+    Keep,
+    /// Excluded by rule, when checking the path given (itself or a parent directory)
+    Exclude {
+        rule: Rule,
+        at: String,
+    },
+    /// Not downloaded, as parent directory is list only (by rule)
+    ListOnly {
+        rule: Rule,
+        at: String,
+    },
+}
+
+/// Decide like sync: every directory from root is checked before it is listed (and excluded
+/// directories are never listed), then the file itself. Files in list only directories are not downloaded.
+/// Directories end with '/'.
+fn fate(manager: &ExclusionManager, path: &str) -> Fate {
+    let path = path.trim_start_matches("./").trim_start_matches('/');
+    let is_dir = path.ends_with('/');
+    let path = path.trim_end_matches('/');
+    let components: Vec<&str> = path.split('/').collect();
+    // Root is checked too, as the first listing of sync
+    let mut parent = manager.explain("");
+    let mut parent_relative = String::new();
+    if let (Comparison::Stop, Some(rule)) = parent {
+        return Fate::Exclude {
+            rule,
+            at: parent_relative,
+        };
+    }
+    for idx in 0..components.len() {
+        let relative = components[..=idx].join("/");
+        let decision = manager.explain(&relative);
+        if let (Comparison::Stop, Some(rule)) = decision {
+            return Fate::Exclude { rule, at: relative };
+        }
+        if idx + 1 < components.len() {
+            parent = decision;
+            parent_relative = relative;
+        }
+    }
+    match parent {
+        (Comparison::ListOnly, Some(rule)) if !is_dir => Fate::ListOnly {
+            rule,
+            at: parent_relative,
+        },
+        _ => Fate::Keep,
+    }
+}
+
+fn format_fate(manager: &ExclusionManager, fate: &Fate) -> String {
+    match fate {
+        Fate::Keep => "keep".to_string(),
+        Fate::Exclude { rule, at } => format!(
+            "exclude ({} {:?} on {:?})",
+            rule,
+            manager.rule_regex(*rule),
+            at
+        ),
+        Fate::ListOnly { rule, at } => format!(
+            "list-only ({} {:?} on {:?})",
+            rule,
+            manager.rule_regex(*rule),
+            at
+        ),
+    }
+}
+
+pub fn match_rules(args: &MatchRulesArgs) -> ! {
+    let (mut exclusions, mut inclusions) = match &args.rules {
+        Some(rules) => load_rules(rules).unwrap_or_else(|e| {
+            eprintln!("{e:#}");
+            std::process::exit(1);
+        }),
+        None => (Vec::new(), Vec::new()),
+    };
+    exclusions.extend(args.exclude.iter().cloned());
+    inclusions.extend(args.include.iter().cloned());
+    let manager = ExclusionManager::new(&exclusions, &inclusions);
+
+    let paths: Box<dyn Iterator<Item = String>> = if args.stdin {
+        Box::new(std::io::stdin().lock().lines().map_while(Result::ok))
+    } else {
+        Box::new(args.paths.clone().into_iter())
+    };
+    let (mut kept, mut excluded) = (0, 0);
+    for path in paths {
+        if path.trim().is_empty() {
+            continue;
+        }
+        let fate = fate(&manager, path.trim());
+        match fate {
+            Fate::Keep => kept += 1,
+            _ => excluded += 1,
+        }
+        if !(args.excluded_only && fate == Fate::Keep) {
+            println!("{}\t{}", path.trim(), format_fate(&manager, &fate));
+        }
+    }
+    eprintln!("{kept} kept, {excluded} excluded (deleted locally by sync unless --no-delete)");
+    std::process::exit(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fate() {
+        let regexes = |v: &[&str]| -> Vec<ExpandedRegex> {
+            v.iter()
+                .map(|r| ExpandedRegex::from_str(r).unwrap())
+                .collect()
+        };
+        let manager = ExclusionManager::new(
+            &regexes(&["^pool/debug", "^dists/", "changelog$"]),
+            &regexes(&["^dists/${DEBIAN_CURRENT}"]),
+        );
+        assert_eq!(fate(&manager, "pool/main/a.deb"), Fate::Keep);
+        assert_eq!(
+            fate(&manager, "./pool/debug/main/a.deb"),
+            Fate::Exclude {
+                rule: Rule::Exclude(0),
+                at: "pool/debug".to_string()
+            }
+        );
+        assert_eq!(
+            fate(&manager, "pool/main/a.changelog"),
+            Fate::Exclude {
+                rule: Rule::Exclude(2),
+                at: "pool/main/a.changelog".to_string()
+            }
+        );
+        assert_eq!(fate(&manager, "dists/"), Fate::Keep);
+        assert_eq!(fate(&manager, "dists/bookworm/Release"), Fate::Keep);
+        assert_eq!(
+            fate(&manager, "dists/wheezy/Release"),
+            Fate::Exclude {
+                rule: Rule::IncludeOthers(0),
+                at: "dists/wheezy".to_string()
+            }
+        );
+
+        // "^pool/" is list only, as it is a prefix of an inclusion
+        let manager = ExclusionManager::new(&regexes(&["^pool/"]), &regexes(&["^pool/main"]));
+        assert_eq!(fate(&manager, "pool/main/a.deb"), Fate::Keep);
+        assert_eq!(fate(&manager, "pool/contrib/"), Fate::Keep);
+        assert_eq!(
+            fate(&manager, "pool/contrib/a.deb"),
+            Fate::ListOnly {
+                rule: Rule::Exclude(0),
+                at: "pool/contrib".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_load_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rules");
+        std::fs::write(&path, "# comment\nexclude ^pool/\n\ninclude ^pool/main\n").unwrap();
+        let (exclusions, inclusions) = load_rules(&path).unwrap();
+        assert_eq!((exclusions.len(), inclusions.len()), (1, 1));
+        std::fs::write(&path, "exclude ^pool/\nskip ^dists/\n").unwrap();
+        let e = format!("{:#}", load_rules(&path).unwrap_err());
+        assert!(e.contains("line 2"), "{e}");
+    }
+}
@@ -1,4 +1,32 @@
+mod apply_deletions;
+mod audit_local;
+mod batch;
+mod config;
+mod cross_check;
+mod import_rsync_excludes;
 mod list;
+mod match_rules;
+mod probe_server;
+mod repair;
+mod report_diff;
+mod serve_fixture;
+mod stats;
+mod suggest_rules;
 mod sync;
+mod test_parsers;
+pub use apply_deletions::apply_deletions;
+pub use audit_local::audit_local;
+pub use batch::batch;
+pub use config::config;
+pub use cross_check::cross_check;
+pub use import_rsync_excludes::import_rsync_excludes;
 pub use list::list;
+pub use match_rules::match_rules;
+pub use probe_server::probe_server;
+pub use repair::repair;
+pub use report_diff::report_diff;
+pub use serve_fixture::serve_fixture;
+pub use stats::stats;
+pub use suggest_rules::suggest_rules;
 pub use sync::sync;
+pub use test_parsers::test_parsers;
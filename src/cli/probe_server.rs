@@ -0,0 +1,243 @@
+// Check what an upstream supports, and suggest tsumugu flags for it.
+
+use anyhow::Result;
+use clap::ValueEnum;
+use reqwest::{blocking::Client, header, redirect::Policy, StatusCode};
+use url::Url;
+
+use crate::{
+    listing::{self, FileType, ListItem},
+    parser::{try_parse_page, ParserType},
+    utils::{get_blocking_response_mtime, naive_to_utc},
+    ProbeServerArgs,
+};
+
+fn parser_name(parser: &ParserType) -> String {
+    parser.to_possible_value().unwrap().get_name().to_string()
+}
+
+/// Guess parser by trying all parsers on the page. The one giving most items wins.
+fn guess_parser(url: &Url, body: &str) -> Option<(ParserType, Vec<ListItem>)> {
+    let mut best: Option<(ParserType, Vec<ListItem>)> = None;
+    for parser_type in ParserType::value_variants() {
+        let items = match try_parse_page(&*parser_type.build(), url, body) {
+            Ok(items) if !items.is_empty() => items,
+            _ => continue,
+        };
+        if best
+            .as_ref()
+            .map(|(_, b)| items.len() > b.len())
+            .unwrap_or(true)
+        {
+            best = Some((parser_type.clone(), items));
+        }
+    }
+    best
+}
+
+fn yes_no(b: bool) -> &'static str {
+    if b {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+/// Access directory URL without trailing slash, to see how the server redirects.
+fn probe_redirect(args: &ProbeServerArgs, url: &Url) -> Result<String> {
+    let path = url.path().trim_end_matches('/');
+    if path.is_empty() {
+        return Ok("(skipped for root)".to_string());
+    }
+    let client = Client::builder()
+        .user_agent(&args.user_agent)
+        .redirect(Policy::none())
+        .build()?;
+    let mut no_slash = url.clone();
+    no_slash.set_path(path);
+    let resp = client.get(no_slash).send()?;
+    let location = resp
+        .headers()
+        .get(header::LOCATION)
+        .and_then(|v| v.to_str().ok());
+    Ok(match location {
+        Some(location) => format!("{} to {}", resp.status(), location),
+        None => format!("{} (no redirection)", resp.status()),
+    })
+}
+
+pub fn probe_server(args: &ProbeServerArgs) -> ! {
+    let url = &args.upstream;
+    let client = Client::builder()
+        .user_agent(&args.user_agent)
+        .build()
+        .unwrap();
+    let mut suggestions = Vec::new();
+
+    let resp = client
+        .get(url.clone())
+        .header(header::ACCEPT_ENCODING, "gzip, br")
+        .send()
+        .and_then(|r| r.error_for_status())
+        .unwrap();
+    let final_url = resp.url().clone();
+    let headers = resp.headers().clone();
+    let get_header = |name: header::HeaderName| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+    let encoding = get_header(header::CONTENT_ENCODING);
+    // HTTP/1.1 connections are persistent unless "Connection: close"
+    let keep_alive = resp.version() >= reqwest::Version::HTTP_2
+        || !get_header(header::CONNECTION)
+            .unwrap_or_default()
+            .eq_ignore_ascii_case("close");
+    let redirect = match probe_redirect(args, url) {
+        Ok(s) => s,
+        Err(e) => format!("failed ({e})"),
+    };
+    println!("Upstream: {url}");
+    println!(
+        "Server: {}",
+        get_header(header::SERVER).unwrap_or("(none)".to_string())
+    );
+    println!("HTTP version: {:?}", resp.version());
+    println!("Keep-alive: {}", yes_no(keep_alive));
+    println!(
+        "Compression of listing (with Accept-Encoding: gzip, br): {}",
+        encoding.as_deref().unwrap_or("(none)")
+    );
+    println!("Redirect style (directory without trailing slash): {redirect}");
+    if final_url != *url {
+        println!("Listing is redirected to {final_url}");
+    }
+
+    // Compressed body could not be decoded by reqwest without related features
+    let body = match encoding {
+        None => resp.text().unwrap(),
+        Some(_) => client.get(url.clone()).send().unwrap().text().unwrap(),
+    };
+    let (parser_type, items) = match guess_parser(&final_url, &body) {
+        Some(x) => x,
+        None => {
+            println!("Parser: no parser could parse this page");
+            std::process::exit(1);
+        }
+    };
+    println!(
+        "Parser: {} ({} items)",
+        parser_name(&parser_type),
+        items.len()
+    );
+    suggestions.push(format!("--parser {}", parser_name(&parser_type)));
+
+    match items.iter().find(|i| i.type_ == FileType::File) {
+        None => println!("No files in this directory, skipping file checks."),
+        Some(item) => probe_file(&client, &parser_type, item, &mut suggestions),
+    }
+
+    println!();
+    println!("Suggested flags: {}", suggestions.join(" "));
+    std::process::exit(0);
+}
+
+fn probe_file(
+    client: &Client,
+    parser_type: &ParserType,
+    item: &ListItem,
+    suggestions: &mut Vec<String>,
+) {
+    println!("File for checks: {}", item.url);
+    let head = client
+        .head(item.url.clone())
+        .send()
+        .and_then(|r| r.error_for_status());
+    let head_ok = match &head {
+        Ok(resp) => {
+            println!("HEAD: {}", resp.status());
+            true
+        }
+        Err(e) => {
+            println!("HEAD: failed ({e})");
+            false
+        }
+    };
+    let get = client
+        .get(item.url.clone())
+        .header(header::RANGE, "bytes=0-0")
+        .send()
+        .and_then(|r| r.error_for_status());
+    let (range, mtime) = match &get {
+        Ok(resp) => (
+            resp.status() == StatusCode::PARTIAL_CONTENT,
+            get_blocking_response_mtime(resp).ok(),
+        ),
+        Err(e) => {
+            println!("GET: failed ({e})");
+            (false, None)
+        }
+    };
+    println!("Range: {}", yes_no(range));
+    println!(
+        "Last-Modified: {}",
+        match mtime {
+            Some(mtime) => mtime.to_rfc2822(),
+            None => "(none)".to_string(),
+        }
+    );
+    if mtime.is_none() {
+        suggestions.push("--allow-mtime-from-parser".to_string());
+    }
+    if !head_ok {
+        println!("HEAD is not supported, do not use --head-before-get.");
+    }
+    if item.size.is_none() {
+        // Listings like Go FileServer and SVN have no sizes and mtimes, leaving HEAD as the only way to compare
+        println!("Listing has no file sizes.");
+        if head_ok {
+            suggestions.push("--head-before-get".to_string());
+        }
+        return;
+    }
+    let Some(item_mtime) = item.mtime else {
+        println!("Listing has no file mtimes.");
+        return;
+    };
+    if let Some(mtime) = mtime {
+        let parser = parser_type.build();
+        match listing::guess_remote_timezone(&*parser, client, item.url.clone()) {
+            Ok(timezone) => {
+                println!("Timezone of listing: {timezone}");
+                let diff = (naive_to_utc(&item_mtime, Some(timezone)) - mtime).num_seconds();
+                if diff.abs() >= 60 {
+                    println!("Listing mtime differs from Last-Modified by {diff} seconds after timezone adjustment.");
+                }
+                if timezone.local_minus_utc() % 3600 == 0 {
+                    suggestions.push(format!("--timezone {}", timezone.local_minus_utc() / 3600));
+                }
+            }
+            Err(e) => println!("Timezone of listing: failed to guess ({e})"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guess_parser() {
+        for (case, expected) in [
+            ("nginx-monitoring-plugins", "nginx"),
+            ("apache-f2-wine-builds", "apache-f2"),
+            ("lighttpd-buildroot-acl", "lighttpd"),
+        ] {
+            let body = std::fs::read_to_string(format!("corpus/{case}.html")).unwrap();
+            let url = Url::parse("http://localhost/").unwrap();
+            let (parser_type, _) = guess_parser(&url, &body).unwrap();
+            assert_eq!(parser_name(&parser_type), expected, "{case}");
+        }
+    }
+}
@@ -0,0 +1,134 @@
+// Repair a single local file or directory after a corruption report: the path is mapped back to
+// upstream of its profile, and only it is fetched again (regardless of local size and mtime),
+// without running a whole-profile sync.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use tracing::{error, info};
+
+use super::sync::run_sync;
+use crate::{config::Config, RepairArgs, SyncArgs};
+
+/// Sync args of the profile containing path
+fn profile_args(args: &RepairArgs, path: &Path) -> Result<SyncArgs> {
+    let Some(config) = &args.config else {
+        return SyncArgs::try_parse_from(
+            std::iter::once("sync".to_string()).chain(args.sync.iter().cloned()),
+        )
+        .map_err(|e| anyhow!("{}", e));
+    };
+    let config = Config::load(config)?;
+    let mut candidates = Vec::new();
+    for profile in &config.profiles {
+        if args.profile.as_ref().is_some_and(|p| p != &profile.name) {
+            continue;
+        }
+        let sync_args = profile.to_sync_args()?;
+        if args.profile.is_some() || path.starts_with(std::path::absolute(&sync_args.local)?) {
+            candidates.push(sync_args);
+        }
+    }
+    // The innermost local directory wins, if profiles are nested
+    candidates
+        .into_iter()
+        .max_by_key(|a| a.local.components().count())
+        .ok_or_else(|| {
+            anyhow!(
+                "No profile in config has local directory containing {:?}",
+                path
+            )
+        })
+}
+
+/// Sync args to fetch only the relative path (as directory or file) again
+fn repair_args(mut args: SyncArgs, path: &Path, is_dir: bool) -> Result<SyncArgs> {
+    let local = std::path::absolute(&args.local)?;
+    let relative = path
+        .strip_prefix(&local)
+        .with_context(|| format!("{:?} is not under local directory {:?}", path, local))?
+        .to_string_lossy()
+        .to_string();
+    let dir = match is_dir {
+        true => Some(relative.as_str()),
+        false => Path::new(&relative).parent().and_then(|p| p.to_str()),
+    };
+    args.only = dir
+        .filter(|d| !d.is_empty())
+        .map(|d| d.to_string())
+        .into_iter()
+        .collect();
+    // Other files in the same directory are kept as is
+    if !is_dir {
+        args.no_delete = true;
+    }
+    args.repair = Some(relative);
+    // A partial run should not be taken as the whole profile's result
+    args.metrics_file = None;
+    args.state_file = None;
+    // Listings of other directories would be dropped from cache
+    args.listing_cache = None;
+    Ok(args)
+}
+
+pub fn repair(args: &RepairArgs, bind_address: Option<String>) -> ! {
+    let path = std::path::absolute(&args.path).unwrap();
+    let is_dir = path.is_dir() || args.path.to_string_lossy().ends_with('/');
+    let sync_args = match profile_args(args, &path).and_then(|a| repair_args(a, &path, is_dir)) {
+        Ok(a) => a,
+        Err(e) => {
+            error!("Cannot repair {:?}: {:?}", args.path, e);
+            std::process::exit(1);
+        }
+    };
+    info!(
+        "Repairing {:?} from {}",
+        sync_args.repair.as_deref().unwrap_or_default(),
+        sync_args.upstream
+    );
+    std::process::exit(run_sync(&sync_args, bind_address, None));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn sync_args(local: &str) -> SyncArgs {
+        SyncArgs::try_parse_from([
+            "sync",
+            "--metrics-file",
+            "/tmp/metrics.prom",
+            "https://example.com/debian/",
+            local,
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_repair_args() {
+        let path = PathBuf::from("/srv/debian/pool/main/a/a.deb");
+        let args = repair_args(sync_args("/srv/debian/"), &path, false).unwrap();
+        assert_eq!(args.only, vec!["pool/main/a"]);
+        assert_eq!(args.repair.as_deref(), Some("pool/main/a/a.deb"));
+        assert!(args.no_delete);
+        assert!(args.metrics_file.is_none());
+
+        let args = repair_args(
+            sync_args("/srv/debian"),
+            Path::new("/srv/debian/README"),
+            false,
+        )
+        .unwrap();
+        assert!(args.only.is_empty());
+
+        let path = PathBuf::from("/srv/debian/pool/main/a");
+        let args = repair_args(sync_args("/srv/debian"), &path, true).unwrap();
+        assert_eq!(args.only, vec!["pool/main/a"]);
+        assert!(!args.no_delete);
+
+        assert!(repair_args(sync_args("/srv/ubuntu"), &path, true).is_err());
+    }
+}
@@ -0,0 +1,257 @@
+// Trends between two sync reports (see report.rs): new and resolved failures, growth in downloaded bytes
+// (in total and by top-level directory) and newly deleted paths, instead of ad-hoc jq over them.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::Serialize;
+
+use crate::{
+    report::{Action, Report},
+    ReportDiffArgs,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+struct Change<T> {
+    old: T,
+    new: T,
+}
+
+impl Change<u64> {
+    /// Like "1.2 MiB -> 3.4 MiB (+2.2 MiB)"
+    fn format_size(&self) -> String {
+        let size = |v| humansize::format_size(v, humansize::BINARY);
+        let delta = match self.new >= self.old {
+            true => format!("+{}", size(self.new - self.old)),
+            false => format!("-{}", size(self.old - self.new)),
+        };
+        format!("{} -> {} ({})", size(self.old), size(self.new), delta)
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Failure {
+    path: String,
+    error: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ReportDiff {
+    exit_code: Change<i32>,
+    downloaded_files: Change<u64>,
+    downloaded_bytes: Change<u64>,
+    /// Downloaded bytes by top-level directory ("." for files directly under local directory)
+    bytes_by_top_dir: BTreeMap<String, Change<u64>>,
+    /// Failed to download or list in new report, but not in old one
+    new_failures: Vec<Failure>,
+    /// Failed in old report, but not in new one
+    resolved_failures: Vec<String>,
+    /// Deleted in new report, but not in old one
+    newly_deleted: Vec<String>,
+}
+
+fn failures(report: &Report) -> BTreeMap<&str, &str> {
+    report
+        .actions
+        .iter()
+        .filter_map(|action| match action {
+            Action::DownloadFailed { path, error, .. } | Action::ListFailed { path, error, .. } => {
+                Some((path.as_str(), error.as_str()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn deleted(report: &Report) -> BTreeSet<&str> {
+    report
+        .actions
+        .iter()
+        .filter_map(|action| match action {
+            Action::Delete { path } => Some(path.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn diff(old: &Report, new: &Report) -> ReportDiff {
+    let top_dirs: BTreeSet<&String> = old
+        .bandwidth
+        .by_top_dir
+        .keys()
+        .chain(new.bandwidth.by_top_dir.keys())
+        .collect();
+    let bytes_by_top_dir = top_dirs
+        .into_iter()
+        .map(|dir| {
+            let bytes = |report: &Report| report.bandwidth.by_top_dir.get(dir).copied();
+            let change = Change {
+                old: bytes(old).unwrap_or_default(),
+                new: bytes(new).unwrap_or_default(),
+            };
+            (dir.clone(), change)
+        })
+        .collect();
+    let total = |report: &Report| report.bandwidth.by_top_dir.values().sum();
+
+    let (old_failures, new_failures) = (failures(old), failures(new));
+    let old_deleted = deleted(old);
+    ReportDiff {
+        exit_code: Change {
+            old: old.exit_code,
+            new: new.exit_code,
+        },
+        downloaded_files: Change {
+            old: old.stats.downloaded,
+            new: new.stats.downloaded,
+        },
+        downloaded_bytes: Change {
+            old: total(old),
+            new: total(new),
+        },
+        bytes_by_top_dir,
+        new_failures: new_failures
+            .iter()
+            .filter(|(path, _)| !old_failures.contains_key(*path))
+            .map(|(path, error)| Failure {
+                path: path.to_string(),
+                error: error.to_string(),
+            })
+            .collect(),
+        resolved_failures: old_failures
+            .keys()
+            .filter(|path| !new_failures.contains_key(*path))
+            .map(|path| path.to_string())
+            .collect(),
+        newly_deleted: deleted(new)
+            .difference(&old_deleted)
+            .map(|path| path.to_string())
+            .collect(),
+    }
+}
+
+fn print_text(diff: &ReportDiff) {
+    println!(
+        "Exit code: {} -> {}",
+        diff.exit_code.old, diff.exit_code.new
+    );
+    println!(
+        "Downloaded: {} -> {} files, {}",
+        diff.downloaded_files.old,
+        diff.downloaded_files.new,
+        diff.downloaded_bytes.format_size()
+    );
+    for (dir, change) in diff.bytes_by_top_dir.iter().filter(|(_, c)| c.old != c.new) {
+        println!("  {}: {}", dir, change.format_size());
+    }
+    if !diff.new_failures.is_empty() {
+        println!("New failures ({}):", diff.new_failures.len());
+        for failure in &diff.new_failures {
+            println!("  {}: {}", failure.path, failure.error);
+        }
+    }
+    if !diff.resolved_failures.is_empty() {
+        println!("Resolved failures ({}):", diff.resolved_failures.len());
+        for path in &diff.resolved_failures {
+            println!("  {}", path);
+        }
+    }
+    if !diff.newly_deleted.is_empty() {
+        println!("Newly deleted ({}):", diff.newly_deleted.len());
+        for path in &diff.newly_deleted {
+            println!("  {}", path);
+        }
+    }
+}
+
+pub fn report_diff(args: &ReportDiffArgs) -> ! {
+    let (old, new) = match (Report::load(&args.old), Report::load(&args.new)) {
+        (Ok(old), Ok(new)) => (old, new),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("{e:#}");
+            std::process::exit(1);
+        }
+    };
+    let diff = diff(&old, &new);
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&diff).unwrap());
+    } else {
+        print_text(&diff);
+    }
+    std::process::exit(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_diff() {
+        let old = r#"{"upstream": "http://localhost/", "local": "/srv/a", "dry_run": false,
+            "started_at": "2024-03-01T00:00:00Z", "finished_at": "2024-03-01T00:10:00Z", "exit_code": 0,
+            "stats": {"listed_dirs": 1, "listed_files": 2, "listed_size": 10, "failed_listings": 0,
+                "enqueued_downloads": 1, "skipped": {"up-to-date": 1}, "downloaded": 1, "failed_downloads": 1, "deleted": 1},
+            "bandwidth": {"by_top_dir": {"pool": 2048, "dists": 100}, "by_origin": {"listing": 2148}},
+            "actions": [
+                {"action": "download-failed", "path": "pool/a.deb", "url": "http://localhost/pool/a.deb",
+                    "reason": "new", "error": "404"},
+                {"action": "delete", "path": "pool/old.deb"}
+            ]}"#;
+        // Written before schema_version, compare_tiers and resources are added
+        let old: Report = serde_json::from_str(old).unwrap();
+        assert_eq!(old.schema_version, 1);
+
+        let new = Report {
+            exit_code: 25,
+            stats: crate::sync_stats::StatsSnapshot {
+                downloaded: 3,
+                ..Default::default()
+            },
+            bandwidth: crate::report::Bandwidth {
+                by_top_dir: BTreeMap::from([
+                    ("pool".to_string(), 1024),
+                    ("nightly".to_string(), 4096),
+                ]),
+                ..Default::default()
+            },
+            actions: vec![
+                Action::ListFailed {
+                    path: "nightly".to_string(),
+                    url: "http://localhost/nightly/".to_string(),
+                    error: "500".to_string(),
+                },
+                Action::Delete {
+                    path: "pool/old.deb".to_string(),
+                },
+                Action::Delete {
+                    path: "pool/older/".to_string(),
+                },
+            ],
+            ..serde_json::from_value(serde_json::to_value(&old).unwrap()).unwrap()
+        };
+
+        let diff = diff(&old, &new);
+        assert_eq!(diff.exit_code, Change { old: 0, new: 25 });
+        assert_eq!(diff.downloaded_files, Change { old: 1, new: 3 });
+        assert_eq!(
+            diff.downloaded_bytes,
+            Change {
+                old: 2148,
+                new: 5120
+            }
+        );
+        assert_eq!(diff.bytes_by_top_dir["dists"], Change { old: 100, new: 0 });
+        assert_eq!(
+            diff.bytes_by_top_dir["pool"].format_size(),
+            "2 KiB -> 1 KiB (-1 KiB)"
+        );
+        assert_eq!(
+            diff.new_failures,
+            vec![Failure {
+                path: "nightly".to_string(),
+                error: "500".to_string()
+            }]
+        );
+        assert_eq!(diff.resolved_failures, vec!["pool/a.deb"]);
+        assert_eq!(diff.newly_deleted, vec!["pool/older/"]);
+    }
+}
@@ -0,0 +1,9 @@
+use std::net::TcpListener;
+
+use crate::{autoindex, ServeFixtureArgs};
+
+pub fn serve_fixture(args: &ServeFixtureArgs) -> ! {
+    let listener = TcpListener::bind(&args.bind).unwrap();
+    autoindex::serve(listener, args.dir.clone(), args.style).unwrap();
+    std::process::exit(0);
+}
@@ -0,0 +1,159 @@
+// Offline statistics of a local mirror: object counts, total size, mtime range and breakdown by
+// top-level directory, plus temporary files left by interrupted downloads (`.tmp.<name>`).
+// No network access, so it is cheap to run for status pages.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use walkdir::WalkDir;
+
+use crate::StatsArgs;
+
+/// Prefix of temporary files of ongoing (or interrupted) downloads
+const TMP_PREFIX: &str = ".tmp.";
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+struct Counts {
+    files: u64,
+    directories: u64,
+    symlinks: u64,
+    /// Total size of files in bytes
+    size: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Stats {
+    #[serde(flatten)]
+    total: Counts,
+    oldest_mtime: Option<DateTime<Utc>>,
+    newest_mtime: Option<DateTime<Utc>>,
+    /// By top-level directory ("." for files directly under local directory)
+    top_dirs: BTreeMap<String, Counts>,
+    /// Relative paths of temporary files left by downloads
+    tmp_files: Vec<PathBuf>,
+}
+
+fn collect(dir: &Path) -> Result<Stats> {
+    let mut stats = Stats::default();
+    for entry in WalkDir::new(dir).min_depth(1) {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(dir)?;
+        let top = match relative.components().count() {
+            1 if !entry.file_type().is_dir() => ".".to_string(),
+            _ => relative
+                .components()
+                .next()
+                .unwrap()
+                .as_os_str()
+                .to_string_lossy()
+                .to_string(),
+        };
+        let top = stats.top_dirs.entry(top).or_default();
+        let file_type = entry.file_type();
+        if file_type.is_symlink() {
+            stats.total.symlinks += 1;
+            top.symlinks += 1;
+        } else if file_type.is_dir() {
+            stats.total.directories += 1;
+            top.directories += 1;
+        } else {
+            let meta = entry.metadata()?;
+            stats.total.files += 1;
+            top.files += 1;
+            stats.total.size += meta.len();
+            top.size += meta.len();
+            if entry.file_name().to_string_lossy().starts_with(TMP_PREFIX) {
+                stats.tmp_files.push(relative.to_path_buf());
+            }
+            if let Ok(mtime) = meta.modified() {
+                let mtime: DateTime<Utc> = mtime.into();
+                stats.oldest_mtime = Some(stats.oldest_mtime.map_or(mtime, |t| t.min(mtime)));
+                stats.newest_mtime = Some(stats.newest_mtime.map_or(mtime, |t| t.max(mtime)));
+            }
+        }
+    }
+    Ok(stats)
+}
+
+fn format_counts(counts: &Counts) -> String {
+    format!(
+        "{} files ({}), {} directories, {} symlinks",
+        counts.files,
+        humansize::format_size(counts.size, humansize::BINARY),
+        counts.directories,
+        counts.symlinks
+    )
+}
+
+pub fn stats(args: &StatsArgs) -> ! {
+    let stats = collect(&args.local).unwrap();
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&stats).unwrap());
+        std::process::exit(0);
+    }
+    println!("Total: {}", format_counts(&stats.total));
+    if let (Some(oldest), Some(newest)) = (stats.oldest_mtime, stats.newest_mtime) {
+        println!("Mtime: {} ~ {}", oldest, newest);
+    }
+    for (dir, counts) in &stats.top_dirs {
+        println!("  {}: {}", dir, format_counts(counts));
+    }
+    if !stats.tmp_files.is_empty() {
+        println!("Temporary files of interrupted downloads:");
+        for path in &stats.tmp_files {
+            println!("  {}", path.display());
+        }
+    }
+    std::process::exit(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("pool/main")).unwrap();
+        std::fs::create_dir_all(root.join("dists")).unwrap();
+        std::fs::write(root.join("pool/main/a.deb"), "aaaa").unwrap();
+        std::fs::write(root.join("pool/main/.tmp.b.deb"), "bb").unwrap();
+        std::fs::write(root.join("README"), "r").unwrap();
+        std::os::unix::fs::symlink("pool", root.join("dists/pool")).unwrap();
+        filetime::set_file_mtime(
+            root.join("README"),
+            filetime::FileTime::from_unix_time(1_700_000_000, 0),
+        )
+        .unwrap();
+
+        let stats = collect(root).unwrap();
+        assert_eq!(
+            stats.total,
+            Counts {
+                files: 3,
+                directories: 3,
+                symlinks: 1,
+                size: 7
+            }
+        );
+        assert_eq!(
+            stats.top_dirs["pool"],
+            Counts {
+                files: 2,
+                directories: 2,
+                symlinks: 0,
+                size: 6
+            }
+        );
+        assert_eq!(stats.top_dirs["."].files, 1);
+        assert_eq!(stats.top_dirs["dists"].symlinks, 1);
+        assert_eq!(stats.oldest_mtime.unwrap().timestamp(), 1_700_000_000);
+        assert_eq!(stats.tmp_files, vec![PathBuf::from("pool/main/.tmp.b.deb")]);
+    }
+}
@@ -0,0 +1,267 @@
+// Exclusion candidates from history of sync reports (--report) and item lists (list --output json, or
+// manifests in the same form), for operators trimming mirrors with data instead of reactively: paths
+// failing in most runs, and first-level directories taking a large share of downloaded bytes or listed
+// size. Purely local analysis. Suggestions are printed as a rules file of match-rules, to be reviewed.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use crate::{
+    item_schema::{ItemList, ItemType},
+    report::{Action, Report},
+    SuggestRulesArgs,
+};
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Input {
+    Items(ItemList),
+    Report(Box<Report>),
+}
+
+#[derive(Debug, Default)]
+struct Failures {
+    /// Number of reports it failed in
+    count: usize,
+    /// Failed to list (as a directory)
+    listing: bool,
+    last_error: String,
+}
+
+#[derive(Debug, Default)]
+struct History {
+    reports: usize,
+    failures: BTreeMap<String, Failures>,
+    /// Downloaded bytes of all reports by first-level directory
+    downloaded: BTreeMap<String, u64>,
+    /// Listed size of files by first-level directory, of the last item list
+    listed: BTreeMap<String, u64>,
+}
+
+impl History {
+    fn add(&mut self, input: Input) {
+        match input {
+            Input::Report(report) => {
+                self.reports += 1;
+                for action in &report.actions {
+                    let (path, error, listing) = match action {
+                        Action::DownloadFailed { path, error, .. } => (path, error, false),
+                        Action::ListFailed { path, error, .. } => (path, error, true),
+                        _ => continue,
+                    };
+                    let failures = self.failures.entry(path.clone()).or_default();
+                    failures.count += 1;
+                    failures.listing |= listing;
+                    failures.last_error.clone_from(error);
+                }
+                for (dir, bytes) in &report.bandwidth.by_top_dir {
+                    *self.downloaded.entry(dir.clone()).or_default() += bytes;
+                }
+            }
+            Input::Items(list) => {
+                self.listed.clear();
+                for item in list.items.iter().filter(|i| i.type_ == ItemType::File) {
+                    let top_dir = match item.path.split_once('/') {
+                        Some((dir, _)) => dir,
+                        None => ".",
+                    };
+                    *self.listed.entry(top_dir.to_string()).or_default() +=
+                        item.size.unwrap_or_default();
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Suggestion {
+    regex: String,
+    reason: String,
+}
+
+/// Regex of a relative path, matching everything under it for directories
+fn path_regex(path: &str, dir: bool) -> String {
+    let path = regex::escape(path.trim_matches('/'));
+    match dir {
+        true => format!("^{path}(/|$)"),
+        false => format!("^{path}$"),
+    }
+}
+
+/// First-level directories with at least min_share (percent) of total
+fn large_dirs(sizes: &BTreeMap<String, u64>, min_share: f64) -> Vec<(&str, u64, f64)> {
+    let total: u64 = sizes.values().sum();
+    sizes
+        .iter()
+        .filter(|(dir, _)| *dir != ".")
+        .map(|(dir, size)| (dir.as_str(), *size, *size as f64 * 100.0 / total as f64))
+        .filter(|(_, size, share)| *size > 0 && *share >= min_share)
+        .collect()
+}
+
+fn suggest(history: &History, min_failures: usize, min_share: f64) -> Vec<Suggestion> {
+    let mut res = Vec::new();
+    let mut failed_dirs: Vec<&str> = Vec::new();
+    for (path, failures) in &history.failures {
+        // Files under a suggested directory are excluded with it
+        let under = |dir: &&str| path.starts_with(&format!("{dir}/"));
+        if failures.count < min_failures || failed_dirs.iter().any(under) {
+            continue;
+        }
+        if failures.listing {
+            failed_dirs.push(path);
+        }
+        res.push(Suggestion {
+            regex: path_regex(path, failures.listing),
+            reason: format!(
+                "Failed in {} of {} reports, last: {}",
+                failures.count, history.reports, failures.last_error
+            ),
+        });
+    }
+    let size = |size| humansize::format_size(size, humansize::BINARY);
+    for (dir, bytes, share) in large_dirs(&history.downloaded, min_share) {
+        res.push(Suggestion {
+            regex: path_regex(dir, true),
+            reason: format!(
+                "{} ({:.0}%) of bytes downloaded in {} reports",
+                size(bytes),
+                share,
+                history.reports
+            ),
+        });
+    }
+    for (dir, bytes, share) in large_dirs(&history.listed, min_share) {
+        res.push(Suggestion {
+            regex: path_regex(dir, true),
+            reason: format!("{} ({:.0}%) of listed size", size(bytes), share),
+        });
+    }
+    res
+}
+
+/// Given files, and JSON files directly under given directories (sorted by name, so that dated names work)
+fn input_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut res = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            res.push(path.clone());
+            continue;
+        }
+        let mut files: Vec<PathBuf> = std::fs::read_dir(path)
+            .with_context(|| format!("Failed to read {:?}", path))?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.is_file() && p.extension().is_some_and(|e| e == "json"))
+            .collect();
+        files.sort();
+        res.extend(files);
+    }
+    Ok(res)
+}
+
+fn load(path: &Path) -> Result<Input> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    serde_json::from_reader(std::io::BufReader::new(file))
+        .map_err(|_| anyhow!("{:?} is neither a sync report nor an item list", path))
+}
+
+pub fn suggest_rules(args: &SuggestRulesArgs) -> ! {
+    let mut history = History::default();
+    let res = input_files(&args.inputs).and_then(|files| {
+        for file in files {
+            history.add(load(&file)?);
+        }
+        Ok(())
+    });
+    if let Err(e) = res {
+        eprintln!("{e:#}");
+        std::process::exit(1);
+    }
+    let suggestions = suggest(&history, args.min_failures, args.min_share);
+    for suggestion in &suggestions {
+        println!("# {}\nexclude {}", suggestion.reason, suggestion.regex);
+    }
+    eprintln!(
+        "{} suggestions from {} reports, review them before use (like with `tsumugu match-rules`)",
+        suggestions.len(),
+        history.reports
+    );
+    std::process::exit(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(actions: &str, by_top_dir: &str) -> Input {
+        serde_json::from_str(&format!(
+            r#"{{"upstream": "http://localhost/", "local": "/srv/a", "dry_run": false,
+                "started_at": "2024-03-01T00:00:00Z", "finished_at": "2024-03-01T00:10:00Z", "exit_code": 25,
+                "bandwidth": {{"by_top_dir": {by_top_dir}, "by_origin": {{}}}}, "actions": [{actions}]}}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_suggest_rules() {
+        let list_failed = r#"{"action": "list-failed", "path": "pool/bad dir", "url": "http://localhost/pool/bad%20dir/", "error": "500"}"#;
+        let download_failed = |path| {
+            format!(
+                r#"{{"action": "download-failed", "path": "{path}", "url": "http://localhost/{path}", "reason": "new", "error": "404"}}"#
+            )
+        };
+        let mut history = History::default();
+        for i in 0..3 {
+            let actions = match i {
+                0 => vec![download_failed("a.iso"), download_failed("pool/x.deb")],
+                _ => vec![
+                    list_failed.to_string(),
+                    download_failed("a.iso"),
+                    download_failed("pool/bad dir/b.deb"),
+                ],
+            };
+            history.add(report(
+                &actions.join(","),
+                r#"{"pool": 100, "nightly": 900, ".": 10}"#,
+            ));
+        }
+        assert!(matches!(
+            serde_json::from_str::<Input>(
+                r#"{"schema_version": 1, "url": "http://localhost/", "items": [
+                    {"path": "iso/a.iso", "url": "http://localhost/iso/a.iso", "type": "file", "size": 3000, "size_exact": true, "mtime": null, "sha256": null, "source": "listing"},
+                    {"path": "pool/", "url": "http://localhost/pool/", "type": "directory", "size": null, "size_exact": false, "mtime": null, "sha256": null, "source": "listing"},
+                    {"path": "pool/a.deb", "url": "http://localhost/pool/a.deb", "type": "file", "size": 1000, "size_exact": true, "mtime": null, "sha256": null, "source": "listing"}
+                ]}"#
+            )
+            .map(|input| history.add(input)),
+            Ok(())
+        ));
+
+        let regexes: Vec<String> = suggest(&history, 2, 50.0)
+            .into_iter()
+            .map(|s| s.regex)
+            .collect();
+        assert_eq!(
+            regexes,
+            [
+                r"^a\.iso$",
+                r"^pool/bad dir(/|$)",
+                "^nightly(/|$)",
+                "^iso(/|$)"
+            ]
+        );
+        let suggestions = suggest(&history, 3, 95.0);
+        assert_eq!(
+            suggestions,
+            [Suggestion {
+                regex: r"^a\.iso$".to_string(),
+                reason: "Failed in 3 of 3 reports, last: 404".to_string()
+            }]
+        );
+    }
+}
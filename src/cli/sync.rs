@@ -1,17 +1,18 @@
 use std::{
+    borrow::Cow,
     collections::HashSet,
     fs::File,
     io::Write,
     os::unix::fs::symlink,
     path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering},
         Arc, Mutex,
     },
 };
 
-use anyhow::Result;
-use chrono::{FixedOffset, NaiveDateTime};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, FixedOffset, Utc};
 use crossbeam_deque::{Injector, Worker};
 use futures_util::StreamExt;
 use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
@@ -20,20 +21,52 @@ use url::Url;
 
 use crate::{
     build_client,
-    compare::{should_download_by_head, should_download_by_list},
-    extensions::{extension_handler, ExtensionPackage},
-    listing::{self, ListItem},
-    parser::ListResult,
+    compare::{
+        is_size_unreliable, is_unknown_by_list, should_download_by_head, should_download_by_list,
+        CompareTier, DownloadReason, RemoteMtime,
+    },
+    deletion_list,
+    deletion_plan::{self, DeletionPlan},
+    error::DownloadError,
+    events::{EventSink, SyncEvent},
+    extensions::{self, extension_handler, ExtensionPackage},
+    fd_budget::{self, FdBudget},
+    har,
+    host_state::{self, StateFile},
+    invalidate::Invalidator,
+    lfs,
+    limiter::{Limiter, Share},
+    listing::{self, FileSize, ListItem},
+    listing_cache,
+    metrics::{self, Freshness},
+    mount_guard::DirIdentity,
+    panic_guard,
+    parser::{build_parser, ListResult, ParserSources},
+    prefix_limit::PrefixLimiter,
+    recompress,
     regex_process::{self, ExclusionManager},
+    report::{Action, Report, Reporter},
+    resources::{ResourceMonitor, ResourceUsage, TmpFile},
+    rewrite, root_guard,
+    run_scope::{RunScope, ScopeId},
+    sidecar::{self, Provenance},
+    snapshot,
+    storm::StormDetector,
+    sync_stats::{SkipReason, StatsSnapshot, SyncStats},
     term::AlternativeTerm,
-    utils::{self, again, again_async, get_async, head, is_symlink, naive_to_utc},
-    SyncArgs,
+    utils::{
+        self, again, again_async, get_async, head, is_symlink, naive_to_utc, resolve_timezone,
+        RetryBudget, RetryKind,
+    },
+    validator_cache, SyncArgs,
 };
 
 #[derive(Debug, Clone)]
 enum TaskType {
     Listing,
     Download(ListItem),
+    /// Compressed siblings of the same file, downloaded together
+    DownloadSiblings(Vec<ListItem>),
 }
 
 #[derive(Debug, Clone)]
@@ -48,7 +81,13 @@ fn worker_add_task(worker: &Worker<Task>, wake: &AtomicUsize, task: Task) {
     wake.fetch_add(1, Ordering::SeqCst);
 }
 
-fn extension_push_task(worker: &Worker<Task>, wake: &AtomicUsize, package: &ExtensionPackage) {
+fn extension_push_task(
+    worker: &Worker<Task>,
+    wake: &AtomicUsize,
+    stats: &SyncStats,
+    package: &ExtensionPackage,
+) {
+    stats.enqueued(1);
     worker_add_task(
         worker,
         wake,
@@ -59,7 +98,9 @@ fn extension_push_task(worker: &Worker<Task>, wake: &AtomicUsize, package: &Exte
                 type_: listing::FileType::File,
                 // size and mtime would be ignored as skip_check is set
                 size: None,
-                mtime: NaiveDateTime::default(),
+                mtime: None,
+                original_href: None,
+                sha256: None,
                 skip_check: true,
             }),
             relative: package.relative.clone(),
@@ -72,6 +113,7 @@ fn determinate_timezone(
     args: &SyncArgs,
     parser: &dyn crate::parser::Parser,
     client: &reqwest::blocking::Client,
+    retry_budget: &RetryBudget,
 ) -> Option<FixedOffset> {
     match args.timezone {
         None => {
@@ -85,14 +127,23 @@ fn determinate_timezone(
                     }
                 },
                 None => {
-                    // eek, try getting first file in root index
-                    let list =
-                        again(|| parser.get_list(client, &args.upstream), args.retry).unwrap();
+                    // eek, try getting first file in root index (of first subtree with --only)
+                    let (_, root) = sync_roots(args).swap_remove(0);
+                    let list = again(
+                        || parser.get_list(client, &root),
+                        args.retry,
+                        retry_budget,
+                        RetryKind::Listing,
+                    )
+                    .unwrap();
                     match list {
                         ListResult::List(list) => {
-                            match list.iter().find(|x| x.type_ == listing::FileType::File) {
+                            match list
+                                .iter()
+                                .find(|x| x.type_ == listing::FileType::File && x.mtime.is_some())
+                            {
                                 None => {
-                                    warn!("No files in root index, disabling timezone guessing");
+                                    warn!("No files with mtime in root index, disabling timezone guessing");
                                     None
                                 }
                                 Some(x) => Some(x.url.clone()),
@@ -128,25 +179,230 @@ fn determinate_timezone(
     }
 }
 
+/// URLs to download a file from: itself, and then the same file under each mirror.
+fn get_mirror_urls(upstream: &Url, mirrors: &[Url], url: &Url) -> Vec<Url> {
+    let mut urls = vec![url.clone()];
+    let Some(rest) = url.as_str().strip_prefix(upstream.as_str()) else {
+        return urls;
+    };
+    for mirror in mirrors {
+        let mut mirror = mirror.as_str().to_string();
+        if !mirror.ends_with('/') {
+            mirror.push('/');
+        }
+        match Url::parse(&format!("{mirror}{rest}")) {
+            Ok(url) => urls.push(url),
+            Err(e) => warn!("Invalid mirror URL {}{}: {:?}", mirror, rest, e),
+        }
+    }
+    urls
+}
+
+/// Write response body to file. If speed_floor (min bytes/s, period) is given,
+/// DownloadError::SlowTransfer is returned when average transfer rate in a period is lower than it.
+/// Bytes received so far are reported to on_progress at most once per second, and at the end.
+async fn stream_to_file(
+    resp: reqwest::Response,
+    dest_file: &mut File,
+    pb: &ProgressBar,
+    on_progress: &dyn Fn(u64),
+    speed_floor: Option<(u64, std::time::Duration)>,
+    bandwidth: &Share,
+    tmp: &TmpFile<'_>,
+) -> Result<()> {
+    let url = resp.url().clone();
+    let mut stream = resp.bytes_stream();
+    let mut window_start = std::time::Instant::now();
+    let mut window_bytes = 0;
+    // Time spent on waiting for bandwidth limiter, which should not count for speed floor
+    let mut window_throttled = std::time::Duration::ZERO;
+    let total_size = pb.length().unwrap_or(u64::MAX);
+    let mut last_progress = std::time::Instant::now();
+    loop {
+        let next = match speed_floor {
+            None => stream.next().await,
+            Some((_, period)) => {
+                let remaining =
+                    period.saturating_sub(window_start.elapsed().saturating_sub(window_throttled));
+                // Stalled: nothing received in this period
+                tokio::time::timeout(remaining, stream.next())
+                    .await
+                    .unwrap_or_else(|_| Some(Ok(Default::default())))
+            }
+        };
+        let Some(item) = next else {
+            on_progress(pb.position());
+            return Ok(());
+        };
+        let chunk = item.unwrap();
+        dest_file.write_all(&chunk).unwrap();
+        tmp.grow(chunk.len() as u64);
+        let new = std::cmp::min(pb.position() + (chunk.len() as u64), total_size);
+        pb.set_position(new);
+        if last_progress.elapsed() >= std::time::Duration::from_secs(1) {
+            last_progress = std::time::Instant::now();
+            on_progress(new);
+        }
+
+        window_bytes += chunk.len() as u64;
+        window_throttled += bandwidth.consume(chunk.len()).await;
+        if let Some((min_speed, period)) = speed_floor {
+            let elapsed = window_start.elapsed().saturating_sub(window_throttled);
+            if elapsed >= period {
+                let rate = (window_bytes as f64 / elapsed.as_secs_f64()) as u64;
+                if rate < min_speed {
+                    return Err(DownloadError::SlowTransfer { url, rate }.into());
+                }
+                window_start = std::time::Instant::now();
+                window_bytes = 0;
+                window_throttled = std::time::Duration::ZERO;
+            }
+        }
+    }
+}
+
 async fn download_file(
-    client: &reqwest::Client,
+    async_context: &AsyncDownloadContext<'_>,
     item: &ListItem,
     path: &Path,
     args: &SyncArgs,
-    mprogress: &MultiProgress,
+    retry_budget: &RetryBudget,
     timezone: Option<FixedOffset>,
     cwd: &Path,
-) -> Result<()> {
+) -> Result<u64> {
+    let urls = get_mirror_urls(&args.upstream, &args.mirror, &item.url);
+    let mut urls = urls.iter().peekable();
+    loop {
+        let url = urls.next().unwrap();
+        // Speed floor is only checked when there is another mirror to switch to
+        let speed_floor = match urls.peek() {
+            Some(_) => args
+                .min_speed
+                .map(|s| (s, std::time::Duration::from_secs(args.min_speed_time))),
+            None => None,
+        };
+        // Retried as a whole, so that a corrupted transfer (checksum or size mismatch) is fetched again
+        let res = again_async(
+            || {
+                download_file_from(
+                    async_context,
+                    item,
+                    url,
+                    path,
+                    args,
+                    timezone,
+                    speed_floor,
+                    cwd,
+                )
+            },
+            args.retry,
+            retry_budget,
+            RetryKind::Download,
+        )
+        .await;
+        match res {
+            Err(e) if urls.peek().is_some() => {
+                warn!(
+                    "Failed to download from {}: {}, switching to {}",
+                    url,
+                    e,
+                    urls.peek().unwrap()
+                );
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Download the object of a Git LFS pointer (downloaded to tmp_path) to path instead, by --lfs-endpoint.
+/// The pointer never replaces local file, so that a failure leaves local file as is. Returns size of object.
+#[allow(clippy::too_many_arguments)]
+async fn resolve_lfs_pointer(
+    async_context: &AsyncDownloadContext<'_>,
+    item: &ListItem,
+    pointer: lfs::Pointer,
+    endpoint: &Url,
+    mtime: DateTime<Utc>,
+    path: &Path,
+    args: &SyncArgs,
+    cwd: &Path,
+) -> Result<u64> {
+    // Not retried here, as the whole download of pointer is (see download_file())
+    let href = lfs::resolve(async_context.async_client, endpoint, &pointer)
+        .await
+        .map_err(|e| e.context(format!("Failed to resolve Git LFS pointer {}", item.url)))?;
+    info!("Resolved Git LFS pointer {} to {}", item.url, href);
+    // Mtime of pointer is kept, and verified by oid
+    let object = ListItem {
+        url: href.clone(),
+        size: Some(FileSize::Precise(pointer.size)),
+        mtime: Some(mtime.naive_utc()),
+        sha256: Some(pointer.oid),
+        ..item.clone()
+    };
+    // Downloaded to the same tmp file, replacing the pointer
+    let size = Box::pin(download_file_from(
+        async_context,
+        &object,
+        &href,
+        path,
+        args,
+        FixedOffset::east_opt(0),
+        None,
+        cwd,
+    ))
+    .await?;
+    if use_xattr_cache(args) {
+        validator_cache::update(path, |v| {
+            v.lfs_pointer = Some(validator_cache::listing_key(item))
+        });
+    }
+    Ok(size)
+}
+
+/// Verify size of a downloaded file, with Content-Length and precise size in listing (if any)
+fn verify_size(url: &Url, item: &ListItem, content_length: u64, actual: u64) -> Result<()> {
+    let listed = match item.size {
+        Some(FileSize::Precise(size)) => Some(size),
+        _ => None,
+    };
+    for expected in std::iter::once(content_length).chain(listed) {
+        if actual != expected {
+            return Err(DownloadError::SizeMismatch {
+                url: url.clone(),
+                expected,
+                actual,
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn download_file_from(
+    async_context: &AsyncDownloadContext<'_>,
+    item: &ListItem,
+    url: &Url,
+    path: &Path,
+    args: &SyncArgs,
+    timezone: Option<FixedOffset>,
+    speed_floor: Option<(u64, std::time::Duration)>,
+    cwd: &Path,
+) -> Result<u64> {
+    let mprogress = async_context.mprogress;
     // Here we use async to allow streaming and progress bar
     // Ref: https://gist.github.com/giuliano-oliveira/4d11d6b3bb003dba3a1b53f43d81b30d
-    let resp = match again_async(|| get_async(client, item.url.clone()), args.retry).await {
+    let resp = match get_async(async_context.async_client, url.clone()).await {
         Ok(resp) => resp,
         Err(e) => {
-            error!("Failed to GET {}: {:?}", item.url, e);
+            let e = fd_budget::explain(e);
+            error!("Failed to GET {}: {:?}", url, e);
             return Err(e);
         }
     };
     let total_size = resp.content_length().unwrap();
+    let (etag, last_modified) = sidecar::validators(resp.headers());
     let pb = mprogress.add(ProgressBar::new(total_size));
     pb.set_style(
         ProgressStyle::default_bar()
@@ -154,30 +410,85 @@ async fn download_file(
             .unwrap()
             .progress_chars("#>-"),
     );
-    pb.set_message(format!("Downloading {}", item.url));
+    pb.set_message(format!("Downloading {}", url));
 
-    let mtime = match utils::get_async_response_mtime(&resp) {
-        Ok(mtime) => mtime,
-        Err(e) => {
-            if args.allow_mtime_from_parser {
-                naive_to_utc(&item.mtime, timezone)
-            } else {
-                error!("Failed to get mtime of {}: {:?}", item.url, e);
-                return Err(e);
-            }
+    // Items with checksum come from APIs with exact mtimes, while Last-Modified of CDN they redirect to is unrelated
+    let parser_mtime = item.mtime.map(|m| naive_to_utc(&m, timezone));
+    let mtime = match (utils::get_async_response_mtime(&resp), parser_mtime) {
+        (_, Some(mtime)) if item.sha256.is_some() => mtime,
+        (Ok(mtime), _) => mtime,
+        (Err(_), Some(mtime)) if args.allow_mtime_from_parser => mtime,
+        // Nothing to compare with later (by size only), so time of download is fine
+        (Err(_), None) if args.allow_mtime_from_parser => Utc::now(),
+        (Err(e), _) => {
+            let e = DownloadError::NoMtime {
+                url: url.clone(),
+                source: e,
+            };
+            error!("{:?}", e);
+            return Err(e.into());
         }
     };
 
+    let relative = path
+        .strip_prefix(&args.local)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .to_string();
+    let on_progress = |downloaded| {
+        async_context.events.emit(|| SyncEvent::DownloadProgress {
+            path: relative.clone(),
+            downloaded,
+            total: total_size,
+        })
+    };
     let tmp_path = cwd.join(format!(".tmp.{}", item.name));
-    {
-        let mut dest_file = File::create(&tmp_path).unwrap();
-        let mut stream = resp.bytes_stream();
-
-        while let Some(item) = stream.next().await {
-            let chunk = item.unwrap();
-            dest_file.write_all(&chunk).unwrap();
-            let new = std::cmp::min(pb.position() + (chunk.len() as u64), total_size);
-            pb.set_position(new);
+    let tmp = async_context.resources.tmp_file();
+    let digest = {
+        let mut dest_file = File::create(&tmp_path)
+            .map_err(|source| DownloadError::CreateTmp {
+                path: tmp_path.clone(),
+                source,
+            })
+            .map_err(|e| fd_budget::explain(e.into()))?;
+        if let Err(e) = stream_to_file(
+            resp,
+            &mut dest_file,
+            &pb,
+            &on_progress,
+            speed_floor,
+            async_context.bandwidth,
+            &tmp,
+        )
+        .await
+        {
+            pb.finish_and_clear();
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        // Repaired files are fully verified: size, and checksum if listed
+        let verify = args.repair.is_some();
+        if verify {
+            let actual = dest_file.metadata()?.len();
+            if let Err(e) = verify_size(url, item, total_size, actual) {
+                let _ = std::fs::remove_file(&tmp_path);
+                return Err(e);
+            }
+        }
+        let digest = match item.sha256.is_some() || args.sidecar.is_some() || verify {
+            true => Some(utils::sha256_file(&tmp_path)?),
+            false => None,
+        };
+        if let (Some(expected), Some(actual)) = (&item.sha256, &digest) {
+            if !actual.eq_ignore_ascii_case(expected) {
+                let _ = std::fs::remove_file(&tmp_path);
+                return Err(DownloadError::ChecksumMismatch {
+                    url: url.clone(),
+                    expected: expected.clone(),
+                    actual: actual.clone(),
+                }
+                .into());
+            }
         }
         filetime::set_file_handle_times(
             &dest_file,
@@ -185,20 +496,88 @@ async fn download_file(
             Some(filetime::FileTime::from_system_time(mtime.into())),
         )
         .unwrap();
+        digest
+    };
+    // Checked before the pointer replaces local file
+    if let Some(pointer) = lfs::read_pointer(&tmp_path) {
+        match &args.lfs_endpoint {
+            Some(endpoint) => {
+                let res = resolve_lfs_pointer(
+                    async_context,
+                    item,
+                    pointer,
+                    endpoint,
+                    mtime,
+                    path,
+                    args,
+                    cwd,
+                )
+                .await;
+                if res.is_err() {
+                    let _ = std::fs::remove_file(&tmp_path);
+                }
+                return res;
+            }
+            None => warn!(
+                "{} is a Git LFS pointer, add --lfs-endpoint to resolve it",
+                url
+            ),
+        }
     }
     // move tmp file to expected path
     std::fs::rename(&tmp_path, path).unwrap();
-    Ok(())
+    if let (Some(_), Some(digest)) = (&args.repair, &digest) {
+        info!(
+            "Repaired {:?} from {} ({} bytes, SHA-256 {}{})",
+            path,
+            url,
+            total_size,
+            digest,
+            match item.sha256 {
+                Some(_) => ", as listed",
+                None => "",
+            }
+        );
+    }
+    if use_xattr_cache(args) {
+        validator_cache::update(path, |v| {
+            v.etag = etag.clone();
+            v.sha256 = digest.clone();
+        });
+    }
+    if let (Some(mode), Some(sha256)) = (args.sidecar, digest) {
+        let provenance = Provenance {
+            schema_version: sidecar::SCHEMA_VERSION,
+            url: url.to_string(),
+            retrieved_at: Utc::now(),
+            etag,
+            last_modified,
+            size: total_size,
+            sha256,
+        };
+        if let Err(e) = sidecar::write(mode, path, &provenance) {
+            warn!("{:?}", e);
+        }
+    }
+    Ok(total_size)
 }
 
 struct ThreadsContext<'a> {
     bind_address: Option<String>,
     download_dir: &'a Path,
     remote_list: &'a Arc<Mutex<HashSet<PathBuf>>>,
-    stat_objects: &'a AtomicUsize,
-    stat_size: &'a AtomicU64,
+    stats: &'a SyncStats,
+    events: &'a EventSink,
+    /// Newest remote mtime (UTC timestamp) seen, for freshness metrics
+    stat_newest_remote: &'a AtomicI64,
     failure_listing: &'a AtomicBool,
     failure_downloading: &'a AtomicBool,
+    reporter: &'a Reporter,
+    storm_detector: &'a StormDetector,
+    retry_budget: &'a RetryBudget,
+    bandwidth: &'a Share,
+    resources: &'a ResourceMonitor,
+    prefix_limiter: &'a PrefixLimiter,
 }
 
 struct TaskContext<'a> {
@@ -212,14 +591,33 @@ struct TaskContext<'a> {
     exclusion_result: regex_process::Comparison,
     exclusion_manager: &'a ExclusionManager,
     timezone: Option<FixedOffset>,
+    /// Queue of large files, if they are downloaded separately
+    large_lane: Option<&'a Injector<Task>>,
+}
+
+/// Things shared by all threads in sync_threads()
+struct WorkerContext<'a> {
+    blocking_client: &'a reqwest::blocking::Client,
+    async_client: &'a reqwest::Client,
+    mprogress: &'a MultiProgress,
+    runtime: &'a tokio::runtime::Runtime,
+    exclusion_manager: &'a ExclusionManager,
+    timezone: Option<FixedOffset>,
+    wake: &'a AtomicUsize,
+    large_lane: Option<&'a Injector<Task>>,
 }
 
 struct AsyncDownloadContext<'a> {
     async_client: &'a reqwest::Client,
     mprogress: &'a MultiProgress,
     runtime: &'a tokio::runtime::Runtime,
+    bandwidth: &'a Share,
+    resources: &'a ResourceMonitor,
+    events: &'a EventSink,
 }
 
+const REFUSED_HTTP: &str = "plain http refused by --https-only";
+
 fn list_handler(
     args: &SyncArgs,
     parser: &dyn crate::parser::Parser,
@@ -229,6 +627,13 @@ fn list_handler(
     let task = task_context.task;
     let cwd = task_context.cwd;
     info!("Listing {}", task.url);
+    thr_context.events.emit(|| SyncEvent::ListingStarted {
+        path: match task_context.relative {
+            "" => String::new(),
+            relative => format!("{relative}/"),
+        },
+        url: task.url.to_string(),
+    });
     {
         thr_context
             .remote_list
@@ -237,25 +642,75 @@ fn list_handler(
             .insert(cwd.to_path_buf());
     }
 
+    if is_out_of_repair(args, task_context.relative) {
+        debug!("Skipping listing {:?} out of repaired path", cwd);
+        return;
+    }
+
     if is_symlink(cwd) && !task_context.relative.is_empty() {
         info!("{:?} is a symlink, ignored", cwd);
         return;
     }
 
+    if args.https_only && task.url.scheme() != "https" {
+        error!(
+            "Refusing to list {} over plain http (--https-only)",
+            task.url
+        );
+        failed_listing(thr_context, task_context, REFUSED_HTTP.to_string());
+        return;
+    }
+
     let items = match again(
         || parser.get_list(task_context.blocking_client, &task.url),
         args.retry,
+        thr_context.retry_budget,
+        RetryKind::Listing,
     ) {
         Ok(items) => items,
         Err(e) => {
+            let e = fd_budget::explain(e);
             error!("Failed to list {}: {:?}", task.url, e);
-            thr_context.failure_listing.store(true, Ordering::SeqCst);
+            failed_listing(thr_context, task_context, format!("{e:#}"));
             return;
         }
     };
+    thr_context.stats.listed_dir();
     match items {
         ListResult::List(items) => {
+            let items = listing::apply_name_decoding(items, args.name_decoding);
+            let items = if args.check_name_encoding {
+                listing::check_name_encoding(items, args.name_decoding)
+            } else {
+                items
+            };
+            let items = listing::detect_slashless_dirs(
+                task_context.blocking_client,
+                items,
+                args.slashless_dirs,
+                args.retry,
+                thr_context.retry_budget,
+            );
+            let items = listing::apply_type_overrides(
+                items,
+                task_context.relative,
+                &args.force_dir,
+                &args.force_file,
+            );
+            let mut files = Vec::new();
             for item in items {
+                if !item.has_valid_name() {
+                    warn!("Skipping {} with invalid name {:?}", item.url, item.name);
+                    skip(
+                        thr_context,
+                        &join_relative(task_context.relative, &item.name),
+                        SkipReason::InvalidName,
+                    );
+                    continue;
+                }
+                if item.url.scheme() != task.url.scheme() {
+                    warn!("Mixed-scheme link in {}: {}", task.url, item.url);
+                }
                 if item.type_ == listing::FileType::Directory {
                     let mut relative = task.relative.clone();
                     relative.push(item.name);
@@ -269,29 +724,23 @@ fn list_handler(
                         },
                     );
                 } else {
+                    thr_context
+                        .stats
+                        .listed_file(item.size.map_or(0, |s| s.get_estimated()));
                     if task_context.exclusion_result == regex_process::Comparison::ListOnly {
                         info!("Skipping (by list only) {}", item.url);
+                        skip(
+                            thr_context,
+                            &join_relative(task_context.relative, &item.name),
+                            SkipReason::ListOnly,
+                        );
                         continue;
                     }
-                    worker_add_task(
-                        task_context.worker,
-                        task_context.wake,
-                        Task {
-                            task: TaskType::Download(item.clone()),
-                            relative: task.relative.clone(),
-                            url: item.url,
-                        },
-                    );
-                    thr_context.stat_size.fetch_add(
-                        match item.size {
-                            Some(size) => size.get_estimated(),
-                            None => 0,
-                        },
-                        Ordering::SeqCst,
-                    );
+                    files.push(item);
                 }
-                thr_context.stat_objects.fetch_add(1, Ordering::SeqCst);
             }
+            thr_context.stats.enqueued(files.len());
+            push_downloads(args, task_context, files);
         }
         ListResult::Redirect(target_url) => {
             // This "Redirect" only supports creating symlink of current directory
@@ -322,24 +771,403 @@ fn list_handler(
     }
 }
 
-fn download_handler(
+fn failed_listing(thr_context: &ThreadsContext, task_context: &TaskContext, error: String) {
+    thr_context.failure_listing.store(true, Ordering::SeqCst);
+    thr_context.stats.failed_listing();
+    thr_context.reporter.record(Action::ListFailed {
+        path: task_context.relative.to_string(),
+        url: task_context.task.url.to_string(),
+        error,
+    });
+}
+
+/// Push download tasks of files in a listing, grouped with compressed siblings
+fn push_downloads(args: &SyncArgs, task_context: &TaskContext, files: Vec<ListItem>) {
+    let task = task_context.task;
+    let groups = if args.no_sibling_consistency {
+        files.into_iter().map(|item| vec![item]).collect()
+    } else {
+        listing::group_compressed_siblings(files)
+    };
+    for mut group in groups {
+        let is_large = is_large(args.large_file_threshold, &group);
+        let download_task = Task {
+            url: group[0].url.clone(),
+            task: if group.len() == 1 {
+                TaskType::Download(group.pop().unwrap())
+            } else {
+                TaskType::DownloadSiblings(group)
+            },
+            relative: task.relative.clone(),
+        };
+        match task_context.large_lane {
+            Some(large_lane) if is_large => large_lane.push(download_task),
+            _ => worker_add_task(task_context.worker, task_context.wake, download_task),
+        }
+    }
+}
+
+/// If a group of files goes to the large file lane: any of them is larger than threshold, as estimated
+/// from listing. Files without size are never taken as large.
+fn is_large(threshold: Option<u64>, group: &[ListItem]) -> bool {
+    group.iter().any(|item| match (threshold, item.size) {
+        (Some(threshold), Some(size)) => size.get_estimated() > threshold,
+        _ => false,
+    })
+}
+
+fn join_relative(dir: &str, name: &str) -> String {
+    match dir {
+        "" => name.to_string(),
+        _ => format!("{dir}/{name}"),
+    }
+}
+
+fn downloaded(
+    thr_context: &ThreadsContext,
+    relative: &str,
+    reason: DownloadReason,
+    size: Option<u64>,
+) {
+    thr_context.stats.downloaded();
+    thr_context.events.emit(|| SyncEvent::Downloaded {
+        path: relative.to_string(),
+        reason,
+        size,
+    });
+}
+
+fn failed_download(thr_context: &ThreadsContext, relative: &str, error: &dyn std::fmt::Display) {
+    thr_context.stats.failed_download();
+    thr_context.events.emit(|| SyncEvent::DownloadFailed {
+        path: relative.to_string(),
+        error: error.to_string(),
+    });
+}
+
+fn skip(thr_context: &ThreadsContext, relative: &str, reason: SkipReason) {
+    thr_context.stats.skipped(reason);
+    thr_context.events.emit(|| SyncEvent::ItemSkipped {
+        path: relative.to_string(),
+        reason,
+    });
+}
+
+/// If relative path is dir itself or under it ("" for the root)
+fn is_under(relative: &str, dir: &str) -> bool {
+    dir.is_empty()
+        || relative
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// If directory is neither under repaired path, nor on the way to it
+fn is_out_of_repair(args: &SyncArgs, relative: &str) -> bool {
+    args.repair
+        .as_deref()
+        .is_some_and(|repair| !is_under(relative, repair) && !is_under(repair, relative))
+}
+
+/// Compare local file with list (and HEAD if required), returning why it should be downloaded.
+fn get_download_reason(
     item: &ListItem,
     args: &SyncArgs,
     thr_context: &ThreadsContext,
     task_context: &TaskContext,
-    async_context: &AsyncDownloadContext,
-) {
-    let task = task_context.task;
-    let cwd = task_context.cwd;
-    // create path in case for first sync
+    expected_path: &Path,
+    relative_filepath: &str,
+    timezone: Option<FixedOffset>,
+) -> Option<DownloadReason> {
+    if let Some(repair) = &args.repair {
+        let under = is_under(relative_filepath, repair);
+        if !under {
+            skip(thr_context, relative_filepath, SkipReason::OutOfRepair);
+        }
+        return under.then_some(DownloadReason::Repair);
+    }
+    if is_resolved_lfs_pointer(args, item, expected_path) {
+        thr_context.reporter.count_compare(CompareTier::Cached);
+        info!("Skipping (resolved from Git LFS pointer) {}", item.url);
+        skip(thr_context, relative_filepath, SkipReason::UpToDate);
+        return None;
+    }
+    let skip_if_exists = args
+        .skip_if_exists
+        .iter()
+        .any(|i| i.is_match(relative_filepath));
+
+    // Following code requires real filesystem path (expected_path) to work
+    let reason = should_download_by_list(expected_path, item, timezone, skip_if_exists, false);
+    if reason == Some(DownloadReason::MtimeMismatch)
+        && args.touch_up
+        && touch_up(
+            item,
+            args,
+            thr_context,
+            expected_path,
+            relative_filepath,
+            timezone,
+        )
+    {
+        return None;
+    }
+    if !thr_context.storm_detector.observe(reason) {
+        info!(
+            "Pausing re-download of {} due to re-download storm",
+            item.url
+        );
+        skip(thr_context, relative_filepath, SkipReason::StormPaused);
+        return None;
+    }
+    let reason = match reason {
+        Some(reason) => reason,
+        None if !skip_if_exists
+            && !item.skip_check
+            && (is_size_unreliable(item.size) || is_unknown_by_list(item)) =>
+        {
+            let reason = recheck_unreliable(item, args, thr_context, task_context, expected_path);
+            if reason.is_none() {
+                skip(thr_context, relative_filepath, SkipReason::UpToDate);
+            }
+            return reason;
+        }
+        None => {
+            thr_context
+                .reporter
+                .count_compare(CompareTier::of_list(item));
+            info!("Skipping {}", item.url);
+            skip(thr_context, relative_filepath, SkipReason::UpToDate);
+            return None;
+        }
+    };
+
+    if !args.head_before_get {
+        thr_context
+            .reporter
+            .count_compare(CompareTier::of_list(item));
+        return Some(reason);
+    }
+    match check_by_head(item, args, thr_context, task_context, expected_path) {
+        Ok(reason) => {
+            if reason.is_none() {
+                info!("Skipping (by HEAD) {}", item.url);
+                skip(thr_context, relative_filepath, SkipReason::UpToDate);
+            }
+            reason
+        }
+        Err(e) => {
+            error!("Failed to HEAD {}: {:?}", item.url, e);
+            thr_context
+                .failure_downloading
+                .store(true, Ordering::SeqCst);
+            failed_download(thr_context, relative_filepath, &e);
+            thr_context.reporter.record(Action::DownloadFailed {
+                path: relative_filepath.to_string(),
+                url: item.url.to_string(),
+                reason,
+                error: format!("{e:#}"),
+            });
+            None
+        }
+    }
+}
+
+fn check_by_head(
+    item: &ListItem,
+    args: &SyncArgs,
+    thr_context: &ThreadsContext,
+    task_context: &TaskContext,
+    expected_path: &Path,
+) -> Result<Option<DownloadReason>> {
+    let cacheable = is_head_cacheable(args, item);
+    let cached = cacheable
+        .then(|| validator_cache::load(expected_path))
+        .flatten();
+    if cached.is_some_and(|v| v.is_fresh(item, args.xattr_cache_ttl)) {
+        thr_context.reporter.count_compare(CompareTier::Cached);
+        debug!("HEAD result of {} is cached", item.url);
+        return Ok(None);
+    }
+    thr_context.reporter.count_compare(CompareTier::Head);
+    let compare_size_only = args
+        .compare_size_only
+        .iter()
+        .any(|i| i.is_match(&expected_path.to_string_lossy()));
+    let resp = again(
+        || {
+            let res = head(task_context.blocking_client, item.url.clone());
+            host_state::observe_head(&item.url, &res);
+            res
+        },
+        args.retry,
+        thr_context.retry_budget,
+        RetryKind::Download,
+    )?;
+    let reason = should_download_by_head(expected_path, &resp, compare_size_only);
+    if reason.is_none() && cacheable {
+        let (etag, _) = sidecar::validators(resp.headers());
+        validator_cache::update(expected_path, |v| {
+            v.etag = etag;
+            v.listing = Some(validator_cache::listing_key(item));
+            v.checked_at = Some(Utc::now());
+        });
+    }
+    Ok(reason)
+}
+
+/// Validators are cached only when files are actually synced
+fn use_xattr_cache(args: &SyncArgs) -> bool {
+    !args.no_xattr_cache && !args.dry_run
+}
+
+/// HEAD results are cached for the listing entry (see validator_cache::listing_key), which tells nothing
+/// of a change when it has only a humanized size (or no size) and no mtime
+fn is_head_cacheable(args: &SyncArgs, item: &ListItem) -> bool {
+    use_xattr_cache(args)
+        && !(item.mtime.is_none() && (item.size.is_none() || is_size_unreliable(item.size)))
+}
+
+/// Humanized size of a large file (like "3.4G") matches local one, which is too rough to trust, or listing
+/// has neither size nor mtime: compare with checksum from upstream if any, or with HEAD.
+fn recheck_unreliable(
+    item: &ListItem,
+    args: &SyncArgs,
+    thr_context: &ThreadsContext,
+    task_context: &TaskContext,
+    expected_path: &Path,
+) -> Option<DownloadReason> {
+    if let Some(sha256) = &item.sha256 {
+        thr_context.reporter.count_compare(CompareTier::Checksum);
+        return match local_sha256(args, expected_path) {
+            Ok(local) if local.eq_ignore_ascii_case(sha256) => {
+                info!("Skipping (by checksum) {}", item.url);
+                None
+            }
+            _ => Some(DownloadReason::ChecksumMismatch),
+        };
+    }
+    match check_by_head(item, args, thr_context, task_context, expected_path) {
+        Ok(None) => {
+            info!("Skipping (by HEAD) {}", item.url);
+            None
+        }
+        Ok(reason) => reason,
+        Err(e) => {
+            warn!(
+                "Failed to HEAD {} to check it beyond listing, keeping local file: {:?}",
+                item.url, e
+            );
+            None
+        }
+    }
+}
+
+/// Set mtime of local file to remote one instead of downloading it again (--touch-up), if its content is
+/// confirmed to be the same: by checksum if listed, or by exact size of a local file newer than remote otherwise.
+/// Returns if it is touched up.
+fn touch_up(
+    item: &ListItem,
+    args: &SyncArgs,
+    thr_context: &ThreadsContext,
+    path: &Path,
+    relative_filepath: &str,
+    timezone: Option<FixedOffset>,
+) -> bool {
+    let Some(mtime) = item.mtime.map(|m| naive_to_utc(&m, timezone)) else {
+        return false;
+    };
+    let same_content = match &item.sha256 {
+        Some(sha256) => {
+            thr_context.reporter.count_compare(CompareTier::Checksum);
+            local_sha256(args, path).is_ok_and(|local| local.eq_ignore_ascii_case(sha256))
+        }
+        // Exact size alone is trusted only for a seeded copy, newer than remote. An older one of the same size
+        // might be an earlier version of the file, replaced in place by upstream.
+        None => {
+            matches!(item.size, Some(FileSize::Precise(_)))
+                && path
+                    .metadata()
+                    .and_then(|m| m.modified())
+                    .is_ok_and(|local| DateTime::<Utc>::from(local) > mtime)
+        }
+    };
+    if !same_content {
+        return false;
+    }
     if !args.dry_run {
-        std::fs::create_dir_all(cwd).unwrap();
+        let mtime = filetime::FileTime::from_unix_time(mtime.timestamp(), 0);
+        if let Err(e) = filetime::set_file_mtime(path, mtime) {
+            warn!("Failed to touch up mtime of {:?}: {}", path, e);
+            return false;
+        }
     }
-    // Absolute filesystem path of expected file
-    let expected_path = cwd.join(&item.name);
+    info!("Touched up mtime of {:?} to {}", path, mtime);
+    skip(thr_context, relative_filepath, SkipReason::TouchedUp);
+    thr_context.reporter.record(Action::TouchUp {
+        path: relative_filepath.to_string(),
+        remote_mtime: item.mtime.map(|m| RemoteMtime::new(m, timezone)),
+    });
+    true
+}
+
+/// If local file is resolved from the same Git LFS pointer, whose size is certainly different
+fn is_resolved_lfs_pointer(args: &SyncArgs, item: &ListItem, path: &Path) -> bool {
+    args.lfs_endpoint.is_some()
+        && use_xattr_cache(args)
+        && validator_cache::load(path).and_then(|v| v.lfs_pointer)
+            == Some(validator_cache::listing_key(item))
+}
+
+/// SHA-256 of local file, from cached validators if possible
+fn local_sha256(args: &SyncArgs, path: &Path) -> Result<String> {
+    if !use_xattr_cache(args) {
+        return utils::sha256_file(path);
+    }
+    if let Some(sha256) = validator_cache::load(path).and_then(|v| v.sha256) {
+        return Ok(sha256);
+    }
+    let sha256 = utils::sha256_file(path)?;
+    validator_cache::update(path, |v| v.sha256 = Some(sha256.clone()));
+    Ok(sha256)
+}
+
+/// A file checked against local, before downloading
+struct DownloadCheck {
+    /// Absolute filesystem path of expected file
+    expected_path: PathBuf,
+    relative_filepath: String,
+    timezone: Option<FixedOffset>,
+    /// None if local one is up to date
+    reason: Option<DownloadReason>,
+}
+
+/// Check if the file should be downloaded. Returns None if it is excluded or already handled by others.
+fn check_download(
+    item: &ListItem,
+    args: &SyncArgs,
+    thr_context: &ThreadsContext,
+    task_context: &TaskContext,
+) -> Option<DownloadCheck> {
     // Here relative filepath is only used to check exclusion
     let relative_filepath = PathBuf::from(&task_context.relative).join(&item.name);
-    let relative_filepath = relative_filepath.to_string_lossy();
+    let relative_filepath = relative_filepath.to_string_lossy().to_string();
+    // Absolute filesystem path of expected file
+    let expected_path = match rewrite::rewrite(&args.rewrite, &relative_filepath) {
+        Ok(Cow::Borrowed(_)) => task_context.cwd.join(&item.name),
+        Ok(Cow::Owned(local)) => thr_context.download_dir.join(local),
+        Err(e) => {
+            error!("{:#}", e);
+            thr_context
+                .failure_downloading
+                .store(true, Ordering::SeqCst);
+            failed_download(thr_context, &relative_filepath, &e);
+            return None;
+        }
+    };
+    // create path in case for first sync
+    if !args.dry_run {
+        std::fs::create_dir_all(expected_path.parent().unwrap()).unwrap();
+    }
     debug!(
         "expected_path: {:?}, relative: {:?}",
         expected_path, relative_filepath
@@ -352,105 +1180,285 @@ fn download_handler(
         // This should be run before inserting remote_list.
         // Otherwise newly excluded files will not be deleted later.
         info!("Skipping excluded {:?}", &relative_filepath);
-        return;
+        skip(thr_context, &relative_filepath, SkipReason::Excluded);
+        return None;
     }
 
     {
-        if !thr_context
-            .remote_list
-            .lock()
-            .unwrap()
-            .insert(expected_path.clone())
+        let mut remote_list = thr_context.remote_list.lock().unwrap();
+        // Directories of rewritten paths might not be listed, and are kept as well
+        for dir in expected_path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(thr_context.download_dir))
         {
+            if !remote_list.insert(dir.to_path_buf()) {
+                break;
+            }
+        }
+        if !remote_list.insert(expected_path.clone()) {
             // It is possible that multiple tasks might download the same file
             // (generated by apt/yum parser, etc.)
             // skip when we find that some threads has already downloaded it
             info!("Skipping already handled {:?}", &expected_path);
-            return;
+            skip(thr_context, &relative_filepath, SkipReason::AlreadyHandled);
+            return None;
         }
     }
-
-    let mut should_download = true;
-    let mut skip_if_exists = false;
-    for i in &args.skip_if_exists {
-        if i.is_match(&relative_filepath) {
-            skip_if_exists = true;
-            break;
-        }
+    // Payloads are kept in remote list, so that existing local ones are not deleted
+    if args.metadata_only && !extensions::is_metadata(&relative_filepath) {
+        debug!("Skipping (not metadata) {:?}", &relative_filepath);
+        skip(thr_context, &relative_filepath, SkipReason::NotMetadata);
+        return None;
+    }
+    // Likewise, local metadata is kept until stage 2 replaces it
+    if args.ftpsync_stage1 && extensions::is_ftpsync_stage2(&relative_filepath) {
+        debug!("Deferring to stage 2 {:?}", &relative_filepath);
+        skip(thr_context, &relative_filepath, SkipReason::Deferred);
+        return None;
     }
 
-    // Following code requires real filesystem path (expected_path) to work
-    if !should_download_by_list(
-        &expected_path,
-        item,
+    let timezone = resolve_timezone(
+        &args.timezone_prefix,
+        &relative_filepath,
         task_context.timezone,
-        skip_if_exists,
-        false,
-    ) {
-        info!("Skipping {}", task.url);
-        should_download = false;
+    );
+    let mtime = item
+        .mtime
+        .filter(|_| !item.skip_check)
+        .map(|mtime| naive_to_utc(&mtime, timezone));
+    if let Some(mtime) = mtime {
+        thr_context
+            .stat_newest_remote
+            .fetch_max(mtime.timestamp(), Ordering::SeqCst);
     }
-
-    let mut compare_size_only = false;
-    for i in &args.compare_size_only {
-        if i.is_match(&expected_path.to_string_lossy()) {
-            compare_size_only = true;
-            break;
-        }
+    // Kept in remote list as well, as a catch-up sync does not touch others
+    if mtime.is_some_and(|mtime| !in_mtime_window(args, mtime)) {
+        debug!("Skipping out of mtime window {:?}", &relative_filepath);
+        skip(thr_context, &relative_filepath, SkipReason::OutOfWindow);
+        return None;
+    }
+    // Kept in remote list as well, so that the local copy is not deleted
+    if args.https_only && item.url.scheme() != "https" {
+        refuse_http_download(
+            item,
+            thr_context,
+            &expected_path,
+            &relative_filepath,
+            timezone,
+        );
+        return None;
     }
 
-    if should_download && args.head_before_get {
-        match again(
-            || head(task_context.blocking_client, item.url.clone()),
-            args.retry,
-        ) {
-            Ok(resp) => {
-                if !should_download_by_head(&expected_path, &resp, compare_size_only) {
-                    info!("Skipping (by HEAD) {}", task.url);
-                    should_download = false;
-                }
-            }
-            Err(e) => {
-                error!("Failed to HEAD {}: {:?}", task.url, e);
-                thr_context
-                    .failure_downloading
-                    .store(true, Ordering::SeqCst);
-                should_download = false;
-            }
-        };
+    let reason = get_download_reason(
+        item,
+        args,
+        thr_context,
+        task_context,
+        &expected_path,
+        &relative_filepath,
+        timezone,
+    );
+    Some(DownloadCheck {
+        expected_path,
+        relative_filepath,
+        timezone,
+        reason,
+    })
+}
+
+/// Refuse to download over plain http (--https-only). A local copy up to date by listing is left as is,
+/// without any request to compare it further.
+fn refuse_http_download(
+    item: &ListItem,
+    thr_context: &ThreadsContext,
+    expected_path: &Path,
+    relative_filepath: &str,
+    timezone: Option<FixedOffset>,
+) {
+    let Some(reason) = should_download_by_list(expected_path, item, timezone, false, false) else {
+        skip(thr_context, relative_filepath, SkipReason::UpToDate);
+        return;
+    };
+    error!(
+        "Refusing to download {} over plain http (--https-only)",
+        item.url
+    );
+    thr_context
+        .failure_downloading
+        .store(true, Ordering::SeqCst);
+    failed_download(thr_context, relative_filepath, &REFUSED_HTTP);
+    thr_context.reporter.record(Action::DownloadFailed {
+        path: relative_filepath.to_string(),
+        url: item.url.to_string(),
+        reason,
+        error: REFUSED_HTTP.to_string(),
+    });
+}
+
+/// If remote mtime is within --modified-since and --modified-before
+fn in_mtime_window(args: &SyncArgs, mtime: DateTime<Utc>) -> bool {
+    args.modified_since.is_none_or(|since| mtime >= since)
+        && args.modified_before.is_none_or(|before| mtime < before)
+}
+
+/// Download the file if required by check, and then run extensions on it.
+fn finish_download(
+    item: &ListItem,
+    check: DownloadCheck,
+    args: &SyncArgs,
+    thr_context: &ThreadsContext,
+    task_context: &TaskContext,
+    async_context: &AsyncDownloadContext,
+) {
+    let task = task_context.task;
+    let DownloadCheck {
+        expected_path,
+        relative_filepath,
+        timezone,
+        reason,
+    } = check;
+    let Some(reason) = reason else {
+        extension_handler(args, &expected_path, &task.relative, &item.url, |package| {
+            extension_push_task(
+                task_context.worker,
+                task_context.wake,
+                thr_context.stats,
+                package,
+            );
+        });
+        return;
+    };
+    let remote_mtime = item.mtime.map(|m| RemoteMtime::new(m, timezone));
+    match &remote_mtime {
+        Some(mtime) => debug!(
+            "Download reason of {}: {} (remote {})",
+            item.url, reason, mtime
+        ),
+        None => debug!("Download reason of {}: {}", item.url, reason),
     }
 
-    if should_download && !args.dry_run {
+    if !args.dry_run {
+        thr_context
+            .reporter
+            .start_download(&relative_filepath, item.url.as_str(), reason);
+        let _permit = FdBudget::global().acquire();
         let future = async {
-            if (download_file(
-                async_context.async_client,
+            match download_file(
+                async_context,
                 item,
                 &expected_path,
                 args,
-                async_context.mprogress,
-                task_context.timezone,
-                cwd,
+                thr_context.retry_budget,
+                timezone,
+                expected_path.parent().unwrap(),
             )
-            .await)
-                .is_err()
+            .await
             {
-                thr_context
-                    .failure_downloading
-                    .store(true, Ordering::SeqCst);
+                Ok(size) => {
+                    downloaded(thr_context, &relative_filepath, reason, Some(size));
+                    thr_context.reporter.record(Action::Download {
+                        path: relative_filepath.to_string(),
+                        url: item.url.to_string(),
+                        reason,
+                        size: Some(size),
+                        remote_mtime: remote_mtime.clone(),
+                    })
+                }
+                Err(e) => {
+                    thr_context
+                        .failure_downloading
+                        .store(true, Ordering::SeqCst);
+                    failed_download(thr_context, &relative_filepath, &e);
+                    thr_context.reporter.record(Action::DownloadFailed {
+                        path: relative_filepath.to_string(),
+                        url: item.url.to_string(),
+                        reason,
+                        error: format!("{e:#}"),
+                    });
+                }
             }
         };
         async_context.runtime.block_on(future);
-    } else if should_download {
-        info!("Dry run, not downloading {}", task.url);
+    } else {
+        info!("Dry run, not downloading {} ({})", item.url, reason);
+        downloaded(
+            thr_context,
+            &relative_filepath,
+            reason,
+            item.size.map(|s| s.get_estimated()),
+        );
+        thr_context.reporter.record(Action::Download {
+            path: relative_filepath.to_string(),
+            url: item.url.to_string(),
+            reason,
+            size: item.size.map(|s| s.get_estimated()),
+            remote_mtime,
+        });
     }
 
     extension_handler(args, &expected_path, &task.relative, &item.url, |package| {
-        extension_push_task(task_context.worker, task_context.wake, package);
+        extension_push_task(
+            task_context.worker,
+            task_context.wake,
+            thr_context.stats,
+            package,
+        );
     });
 }
 
+fn download_handler(
+    item: &ListItem,
+    args: &SyncArgs,
+    thr_context: &ThreadsContext,
+    task_context: &TaskContext,
+    async_context: &AsyncDownloadContext,
+) {
+    if let Some(check) = check_download(item, args, thr_context, task_context) {
+        finish_download(item, check, args, thr_context, task_context, async_context);
+    }
+}
+
+/// Download compressed siblings (like Packages, Packages.gz and Packages.xz) together:
+/// when any of them is going to be downloaded, all others are re-fetched too,
+/// so that the local mirror never has a mixed generation of them.
+fn siblings_handler(
+    items: &[ListItem],
+    args: &SyncArgs,
+    thr_context: &ThreadsContext,
+    task_context: &TaskContext,
+    async_context: &AsyncDownloadContext,
+) {
+    let mut checks: Vec<_> = items
+        .iter()
+        .map(|item| check_download(item, args, thr_context, task_context))
+        .collect();
+    if checks.iter().flatten().any(|c| c.reason.is_some()) {
+        for check in checks.iter_mut().flatten() {
+            if check.reason.is_none() {
+                info!(
+                    "Re-fetching {:?} as its sibling is updated",
+                    check.relative_filepath
+                );
+                check.reason = Some(DownloadReason::SiblingUpdated);
+            }
+        }
+    }
+    for (item, check) in items.iter().zip(checks) {
+        if let Some(check) = check {
+            finish_download(item, check, args, thr_context, task_context, async_context);
+        }
+    }
+}
+
 fn sync_threads(args: &SyncArgs, parser: &dyn crate::parser::Parser, thr_context: &ThreadsContext) {
-    let exclusion_manager = ExclusionManager::new(&args.exclude, &args.include);
+    let mut exclusion_manager = ExclusionManager::new(&args.exclude, &args.include);
+    exclusion_manager.set_companions(!args.no_companions);
+    if let Some(trace) = &args.filter_trace {
+        if let Err(e) = exclusion_manager.set_trace(trace) {
+            warn!("Failed to create filter trace {:?}: {}", trace, e);
+        }
+    }
 
     let client = build_client!(
         reqwest::blocking::Client,
@@ -472,30 +1480,69 @@ fn sync_threads(args: &SyncArgs, parser: &dyn crate::parser::Parser, thr_context
         1,
     ));
 
-    let timezone = determinate_timezone(args, parser, &client);
+    let timezone = determinate_timezone(args, parser, &client, thr_context.retry_budget);
 
     if !args.dry_run {
         std::fs::create_dir_all(thr_context.download_dir).unwrap();
     }
 
+    let wake = AtomicUsize::new(0);
+    let large_lane = Injector::<Task>::new();
+    let wctx = WorkerContext {
+        blocking_client: &client,
+        async_client: &async_client,
+        mprogress: &mprogress,
+        runtime: &runtime,
+        exclusion_manager: &exclusion_manager,
+        timezone,
+        wake: &wake,
+        large_lane: args
+            .large_file_threshold
+            .filter(|_| args.large_file_threads > 0)
+            .map(|_| &large_lane),
+    };
+
     let workers: Vec<_> = (0..args.threads)
         .map(|_| Worker::<Task>::new_fifo())
         .collect();
     let stealers: Vec<_> = workers.iter().map(|w| w.stealer()).collect();
     let global = Injector::<Task>::new();
 
-    global.push(Task {
-        task: TaskType::Listing,
-        relative: vec![],
-        url: args.upstream.clone(),
-    });
+    for (relative, url) in sync_roots(args) {
+        global.push(Task {
+            task: TaskType::Listing,
+            relative,
+            url,
+        });
+    }
 
     let active_cnt = AtomicUsize::new(0);
-    let wake = AtomicUsize::new(0);
+    // Set by the last active worker, to release sleeping ones
+    let all_done = AtomicBool::new(false);
+    // Large file lane threads exit only after all normal workers exit
+    let workers_running = AtomicUsize::new(args.threads);
+    // Threads of this run share state of its scope (like listing cache), but not of other runs in batch
+    let run_scope = ScopeId::current();
 
     std::thread::scope(|scope| {
+        if let Some(large_lane) = wctx.large_lane {
+            for _ in 0..args.large_file_threads {
+                scope.spawn(|| {
+                    run_scope.enter();
+                    large_lane_loop(
+                        args,
+                        parser,
+                        thr_context,
+                        &wctx,
+                        large_lane,
+                        &workers_running,
+                    );
+                });
+            }
+        }
         for worker in workers {
             scope.spawn(|| {
+                run_scope.enter();
                 loop {
                     active_cnt.fetch_add(1, Ordering::SeqCst);
                     while let Some(task) = worker.pop().or_else(|| {
@@ -507,59 +1554,21 @@ fn sync_threads(args: &SyncArgs, parser: &dyn crate::parser::Parser, thr_context
                         .find(|s| !s.is_retry())
                         .and_then(|s| s.success())
                     }) {
-                        let relative = task.relative.join("/");
-                        let cwd = thr_context.download_dir.join(&relative);
-                        debug!("cwd: {:?}, relative: {:?}", cwd, relative);
-                        // exclude this?
-                        // note that it only checks the relative folder!
-                        // Downloading files will still be checked again.
-                        let exclusion_result = exclusion_manager.match_str(&relative);
-                        if exclusion_result == regex_process::Comparison::Stop {
-                            info!("Skipping excluded {:?}", &relative);
-                            continue;
-                        } else if exclusion_result == regex_process::Comparison::ListOnly {
-                            info!("List only in {:?}", &relative);
-                        }
-                        let task_context = TaskContext {
-                            task: &task,
-                            cwd: &cwd,
-                            relative: &relative,
-                            worker: &worker,
-                            wake: &wake,
-                            blocking_client: &client,
-                            exclusion_result,
-                            exclusion_manager: &exclusion_manager,
-                            timezone,
-                        };
-                        match &task.task {
-                            TaskType::Listing => {
-                                list_handler(args, parser, thr_context, &task_context);
-                            }
-                            TaskType::Download(item) => {
-                                let async_context = AsyncDownloadContext {
-                                    async_client: &async_client,
-                                    mprogress: &mprogress,
-                                    runtime: &runtime,
-                                };
-                                download_handler(
-                                    item,
-                                    args,
-                                    thr_context,
-                                    &task_context,
-                                    &async_context,
-                                );
-                            }
-                        }
+                        run_task(args, parser, thr_context, &wctx, &worker, &task);
                     }
                     let active = active_cnt.fetch_sub(1, Ordering::SeqCst);
-                    if active == 1 {
-                        // only self is active before this
+                    if active == 1 || all_done.load(Ordering::SeqCst) {
+                        // only self is active before this: no one could add tasks anymore
+                        all_done.store(true, Ordering::SeqCst);
                         break;
                     } else {
                         // sleep and wait for waking up
                         debug!("Sleep and wait for waking up");
                         loop {
                             std::thread::sleep(std::time::Duration::from_millis(100));
+                            if all_done.load(Ordering::SeqCst) {
+                                break;
+                            }
                             let old_wake = wake.load(Ordering::SeqCst);
                             if old_wake > 0 {
                                 let new_wake = old_wake - 1;
@@ -579,6 +1588,7 @@ fn sync_threads(args: &SyncArgs, parser: &dyn crate::parser::Parser, thr_context
                     }
                 }
                 info!("This thread finished");
+                workers_running.fetch_sub(1, Ordering::SeqCst);
                 // drop worker to let rustc know it moves inside the closure
                 std::mem::drop(worker);
             });
@@ -586,20 +1596,793 @@ fn sync_threads(args: &SyncArgs, parser: &dyn crate::parser::Parser, thr_context
     });
 }
 
+/// Relative paths and URLs of directories to start listing from: subtrees of --only (nested ones skipped), or the root
+fn sync_roots(args: &SyncArgs) -> Vec<(Vec<String>, Url)> {
+    let mut roots: Vec<Vec<String>> = args
+        .only
+        .iter()
+        .map(|s| s.split('/').map(|s| s.to_string()).collect())
+        .collect();
+    roots.sort();
+    roots.dedup_by(|b, a| b.starts_with(a));
+    if roots.is_empty() {
+        return vec![(vec![], args.upstream.clone())];
+    }
+    roots
+        .into_iter()
+        .map(|relative| {
+            let mut url = args.upstream.clone();
+            url.path_segments_mut()
+                .unwrap()
+                .pop_if_empty()
+                .extend(&relative)
+                .push("");
+            (relative, url)
+        })
+        .collect()
+}
+
+/// Relative path of task for prefix caps (directories end with "/")
+fn task_path(relative: &str, task: &TaskType) -> String {
+    let name = match task {
+        TaskType::Listing => "",
+        TaskType::Download(item) => &item.name,
+        TaskType::DownloadSiblings(items) => items.first().map_or("", |i| i.name.as_str()),
+    };
+    if relative.is_empty() {
+        name.to_string()
+    } else {
+        format!("{relative}/{name}")
+    }
+}
+
+/// Handle task, and contain its panic (unless --fail-fast) as a failure of the task
+fn run_task(
+    args: &SyncArgs,
+    parser: &dyn crate::parser::Parser,
+    thr_context: &ThreadsContext,
+    wctx: &WorkerContext,
+    worker: &Worker<Task>,
+    task: &Task,
+) {
+    if args.fail_fast {
+        handle_task(args, parser, thr_context, wctx, worker, task);
+        return;
+    }
+    let attempts = if args.retry_panicked { 2 } else { 1 };
+    let mut error = String::new();
+    for attempt in 1..=attempts {
+        match panic_guard::guard(|| handle_task(args, parser, thr_context, wctx, worker, task)) {
+            Ok(()) => return,
+            Err(e) => {
+                error!(
+                    "Task of {} panicked (attempt {}/{}): {}",
+                    task.url, attempt, attempts, e
+                );
+                error = e;
+            }
+        }
+    }
+    match task.task {
+        TaskType::Listing => {
+            thr_context.failure_listing.store(true, Ordering::SeqCst);
+            thr_context.stats.failed_listing();
+            thr_context.reporter.record(Action::ListFailed {
+                path: task.relative.join("/"),
+                url: task.url.to_string(),
+                error: format!("panicked: {error}"),
+            });
+        }
+        TaskType::Download(_) | TaskType::DownloadSiblings(_) => {
+            thr_context
+                .failure_downloading
+                .store(true, Ordering::SeqCst);
+            let items = match &task.task {
+                TaskType::Download(item) => std::slice::from_ref(item),
+                TaskType::DownloadSiblings(items) => items.as_slice(),
+                TaskType::Listing => unreachable!(),
+            };
+            let relative = task.relative.join("/");
+            let paths: Vec<String> = items
+                .iter()
+                .map(|item| {
+                    PathBuf::from(&relative)
+                        .join(&item.name)
+                        .to_string_lossy()
+                        .to_string()
+                })
+                .collect();
+            let error = format!("panicked: {error}");
+            let failed = thr_context
+                .reporter
+                .fail_unfinished(paths.iter().map(|p| p.as_str()), &error);
+            if failed.is_empty() {
+                // Panicked before downloading, like when comparing with local file
+                failed_download(thr_context, &relative, &error);
+            }
+            for path in failed {
+                failed_download(thr_context, path, &error);
+            }
+        }
+    }
+}
+
+fn handle_task(
+    args: &SyncArgs,
+    parser: &dyn crate::parser::Parser,
+    thr_context: &ThreadsContext,
+    wctx: &WorkerContext,
+    worker: &Worker<Task>,
+    task: &Task,
+) {
+    if thr_context.storm_detector.should_abort() {
+        // drain the queue
+        return;
+    }
+    let relative = task.relative.join("/");
+    let Some(_permit) = thr_context
+        .prefix_limiter
+        .try_acquire(&task_path(&relative, &task.task))
+    else {
+        // Defer it to the end of own queue, and avoid spinning when only capped tasks are left
+        debug!(
+            "Too many concurrent tasks under prefix, deferring {}",
+            task.url
+        );
+        worker.push(task.clone());
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        return;
+    };
+    let cwd = thr_context.download_dir.join(&relative);
+    debug!("cwd: {:?}, relative: {:?}", cwd, relative);
+    // exclude this?
+    // note that it only checks the relative folder!
+    // Downloading files will still be checked again.
+    let exclusion_result = wctx.exclusion_manager.match_str(&relative);
+    if exclusion_result == regex_process::Comparison::Stop {
+        info!("Skipping excluded {:?}", &relative);
+        return;
+    } else if exclusion_result == regex_process::Comparison::ListOnly {
+        info!("List only in {:?}", &relative);
+    }
+    let task_context = TaskContext {
+        task,
+        cwd: &cwd,
+        relative: &relative,
+        worker,
+        wake: wctx.wake,
+        blocking_client: wctx.blocking_client,
+        exclusion_result,
+        exclusion_manager: wctx.exclusion_manager,
+        timezone: wctx.timezone,
+        large_lane: wctx.large_lane,
+    };
+    match &task.task {
+        TaskType::Listing => {
+            list_handler(args, parser, thr_context, &task_context);
+        }
+        TaskType::Download(item) => {
+            let async_context = AsyncDownloadContext {
+                async_client: wctx.async_client,
+                mprogress: wctx.mprogress,
+                runtime: wctx.runtime,
+                bandwidth: thr_context.bandwidth,
+                resources: thr_context.resources,
+                events: thr_context.events,
+            };
+            download_handler(item, args, thr_context, &task_context, &async_context);
+        }
+        TaskType::DownloadSiblings(items) => {
+            let async_context = AsyncDownloadContext {
+                async_client: wctx.async_client,
+                mprogress: wctx.mprogress,
+                runtime: wctx.runtime,
+                bandwidth: thr_context.bandwidth,
+                resources: thr_context.resources,
+                events: thr_context.events,
+            };
+            siblings_handler(items, args, thr_context, &task_context, &async_context);
+        }
+    }
+}
+
+/// Download large files in a separate lane, so that they won't block small files.
+/// Tasks created here (by extensions) are handled by this thread itself.
+fn large_lane_loop(
+    args: &SyncArgs,
+    parser: &dyn crate::parser::Parser,
+    thr_context: &ThreadsContext,
+    wctx: &WorkerContext,
+    large_lane: &Injector<Task>,
+    workers_running: &AtomicUsize,
+) {
+    let worker = Worker::<Task>::new_fifo();
+    loop {
+        let task = worker.pop().or_else(|| {
+            std::iter::repeat_with(|| large_lane.steal())
+                .find(|s| !s.is_retry())
+                .and_then(|s| s.success())
+        });
+        match task {
+            Some(task) => run_task(args, parser, thr_context, wctx, &worker, &task),
+            None => {
+                if workers_running.load(Ordering::SeqCst) == 0 && large_lane.is_empty() {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+        }
+    }
+    info!("Large file lane thread finished");
+}
+
+/// Remove local files that are not in remote list, and return exit code.
+/// Newest mtime of files kept is also collected here, to avoid walking the whole tree again.
+fn cleanup(
+    args: &SyncArgs,
+    download_dir: &Path,
+    remote_list: &HashSet<PathBuf>,
+    reporter: &Reporter,
+    stats: &SyncStats,
+    events: &EventSink,
+    newest_local: &mut Option<DateTime<Utc>>,
+) -> i32 {
+    let mut exit_code = 0;
+    let mut candidates = Vec::new();
+    // Compared with both sides canonical, as they could be given in different ways (like with symlinks)
+    let canonical_root = utils::canonical(download_dir);
+    let deletion_list_dir = args.deletion_list_dir.as_deref().map(utils::canonical);
+    let deletion_plan = args.deletion_plan.as_deref().map(utils::canonical);
+    let walks = sync_roots(args).into_iter().filter_map(|(relative, _)| {
+        let root = download_dir.join(relative.join("/"));
+        // Subtrees of --only might not exist locally
+        (relative.is_empty() || root.exists())
+            .then(|| walkdir::WalkDir::new(root).contents_first(true))
+    });
+    // Don't even walkdir when dry_run, to prevent no dir error
+    for entry in walks.flatten() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                error!("Failed to walkdir: {:?}", e);
+                if !args.dry_run {
+                    exit_code = 1;
+                }
+                break;
+            }
+        };
+        let path = entry.path();
+        let canonical_path = canonical_root.join(path.strip_prefix(download_dir).unwrap());
+        if deletion_list_dir
+            .as_ref()
+            .is_some_and(|dir| canonical_path.starts_with(dir) || dir.starts_with(&canonical_path))
+            || deletion_plan.as_ref().is_some_and(|plan| {
+                canonical_path == *plan || canonical_path == plan.with_extension("tmp")
+            })
+        {
+            continue;
+        }
+        let in_remote = remote_list.contains(&path.to_path_buf())
+            || sidecar::is_sidecar_of_kept(path, remote_list);
+        if in_remote && entry.file_type().is_file() {
+            if let Ok(mtime) = path.metadata().and_then(|m| m.modified()) {
+                let mtime: DateTime<Utc> = mtime.into();
+                *newest_local = Some(newest_local.map_or(mtime, |t| t.max(mtime)));
+            }
+        }
+        if !in_remote {
+            if args.no_delete {
+                info!("{:?} not in remote", path);
+            } else {
+                assert!(path.starts_with(download_dir));
+                let mut relative = path
+                    .strip_prefix(download_dir)
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string();
+                if entry.file_type().is_dir() {
+                    relative.push('/');
+                }
+                candidates.push(relative);
+            }
+        }
+    }
+    if args.no_delete {
+        return exit_code;
+    }
+    match delete(args, download_dir, candidates, reporter, stats, events) {
+        0 => exit_code,
+        code => code,
+    }
+}
+
+/// Delete candidates (saving them to --deletion-plan first), up to --max-delete
+fn delete(
+    args: &SyncArgs,
+    download_dir: &Path,
+    candidates: Vec<String>,
+    reporter: &Reporter,
+    stats: &SyncStats,
+    events: &EventSink,
+) -> i32 {
+    let plan_path = args.deletion_plan.as_ref().filter(|_| !args.dry_run);
+    if let Some(plan_path) = plan_path.filter(|_| !candidates.is_empty()) {
+        let plan = DeletionPlan {
+            schema_version: deletion_plan::SCHEMA_VERSION,
+            local: download_dir.to_path_buf(),
+            created_at: Utc::now(),
+            paths: candidates.clone(),
+        };
+        if let Err(e) = plan.save(plan_path) {
+            error!(
+                "Failed to save deletion plan to {:?}, not to delete anything: {:?}",
+                plan_path, e
+            );
+            return 4;
+        }
+    }
+    let exit_code = deletion_plan::apply(
+        download_dir,
+        &candidates,
+        args.max_delete,
+        args.dry_run,
+        &mut |relative| {
+            stats.deleted();
+            events.emit(|| SyncEvent::Deleted {
+                path: relative.to_string(),
+            });
+            reporter.record(Action::Delete {
+                path: relative.to_string(),
+            });
+        },
+    );
+    match plan_path {
+        Some(plan_path) if exit_code == 0 && plan_path.exists() => {
+            if let Err(e) = std::fs::remove_file(plan_path) {
+                error!("Failed to remove deletion plan {:?}: {:?}", plan_path, e);
+            }
+        }
+        Some(plan_path) if exit_code != 0 => {
+            info!(
+                "Deletion is not finished, run `tsumugu apply-deletions --state-file {} {}` to resume",
+                args.state_file.as_deref().unwrap_or(Path::new("")).display(),
+                plan_path.display()
+            );
+        }
+        _ => (),
+    }
+    exit_code
+}
+
+fn build_invalidator(args: &SyncArgs) -> Result<Option<Invalidator>> {
+    args.invalidate_url
+        .as_ref()
+        .map(|url| {
+            Invalidator::new(
+                url,
+                &args.invalidate_method,
+                args.invalidate_body.as_deref(),
+                args.invalidate_batch_size,
+                &args.invalidate_path_prefix,
+            )
+        })
+        .transpose()
+}
+
+/// Write report and deletion list of this run, and invalidate changed paths in CDN, if required
+fn write_report(args: &SyncArgs, report: &Report, invalidator: Option<&Invalidator>) {
+    if let Some(report_file) = &args.report {
+        if let Err(e) = report.write(report_file) {
+            error!("Failed to write report to {:?}: {:?}", report_file, e);
+        }
+    }
+    if let Some(dir) = &args.deletion_list_dir {
+        if args.dry_run {
+            info!("Dry run, not writing deletion list");
+        } else {
+            write_deletion_list(args, dir, report);
+        }
+    }
+    if let Some(invalidator) = invalidator {
+        let changed: Vec<&str> = report
+            .actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::Download { path, .. } | Action::Delete { path } => Some(path.as_str()),
+                _ => None,
+            })
+            .collect();
+        if let Err(e) = invalidator.run(&args.user_agent, &changed, args.dry_run, args.retry) {
+            error!("Failed to invalidate CDN cache: {:?}", e);
+        }
+    }
+}
+
+fn write_deletion_list(args: &SyncArgs, dir: &Path, report: &Report) {
+    let deleted: Vec<&str> = report
+        .actions
+        .iter()
+        .filter_map(|action| match action {
+            Action::Delete { path } => Some(path.as_str()),
+            _ => None,
+        })
+        .collect();
+    match deletion_list::write(dir, &deleted, report.finished_at) {
+        Ok(path) => info!("Wrote {} deleted paths to {:?}", deleted.len(), path),
+        Err(e) => {
+            error!("Failed to write deletion list to {:?}: {:?}", dir, e);
+            return;
+        }
+    }
+    if let Some(keep) = args.deletion_list_keep {
+        match deletion_list::prune(dir, keep) {
+            Ok(removed) => {
+                for path in removed {
+                    info!("Removed expired deletion list {:?}", path);
+                }
+            }
+            Err(e) => error!("Failed to remove expired deletion lists: {:?}", e),
+        }
+    }
+}
+
+fn write_freshness_metrics(
+    args: &SyncArgs,
+    metrics_file: &Path,
+    newest_remote: i64,
+    newest_local: Option<DateTime<Utc>>,
+    stats: &StatsSnapshot,
+    success: bool,
+) {
+    let freshness = Freshness {
+        newest_remote: if newest_remote == i64::MIN {
+            None
+        } else {
+            DateTime::from_timestamp(newest_remote, 0)
+        },
+        newest_local,
+    };
+    let labels = [
+        ("upstream", args.upstream.as_str()),
+        ("local", &*args.local.to_string_lossy()),
+    ];
+    if let Err(e) = metrics::write_metrics(metrics_file, &labels, &freshness, stats, success) {
+        error!("Failed to write metrics to {:?}: {:?}", metrics_file, e);
+    }
+}
+
+/// Remember start of this sync for the local directory (so that older deletion plans are refused), and its identity.
+/// Returns false if it is not the same directory as in last run.
+fn record_sync_start(
+    args: &SyncArgs,
+    started_at: DateTime<Utc>,
+    identity: Option<DirIdentity>,
+) -> bool {
+    let Some(path) = args.state_file.as_ref().filter(|_| !args.dry_run) else {
+        return true;
+    };
+    let mut same = true;
+    if let Err(e) = host_state::update_local(path, &args.local, |state| {
+        state.last_sync = Some(started_at);
+        same = state.identity.zip(identity).is_none_or(|(a, b)| a == b);
+        // Kept until it is removed from state file by hand, if the directory is moved on purpose
+        if same && identity.is_some() {
+            state.identity = identity;
+        }
+    }) {
+        error!("Failed to save state file {:?}: {:?}", path, e);
+    }
+    same
+}
+
+/// Load remembered state of upstream host, and adjust args with it.
+fn load_host_state(args: &SyncArgs) -> SyncArgs {
+    let mut args = args.clone();
+    let Some(path) = &args.state_file else {
+        return args;
+    };
+    let state_file = match StateFile::load(path) {
+        Ok(state_file) => state_file,
+        Err(e) => {
+            warn!(
+                "Failed to load state file {:?}, starting over: {:?}",
+                path, e
+            );
+            StateFile::default()
+        }
+    };
+    let host = host_state::host_key(&args.upstream);
+    let state = state_file.get(&host);
+    host_state::show(&host, &state);
+    args.threads = state.threads(args.threads);
+    if state.head_unsupported(Utc::now()) && args.head_before_get {
+        warn!("Ignoring --head-before-get as {host} does not support HEAD");
+        args.head_before_get = false;
+    }
+    args
+}
+
+/// Replace upstream with the snapshot at --snapshot time
+fn pin_snapshot(args: &SyncArgs) -> Result<SyncArgs> {
+    let mut args = args.clone();
+    let Some(time) = args.snapshot else {
+        return Ok(args);
+    };
+    let client = reqwest::blocking::Client::builder()
+        .user_agent(args.user_agent.clone())
+        .build()?;
+    let upstream = snapshot::resolve(&client, &args.upstream, time)?;
+    info!("Pinned to snapshot {} (for {})", upstream, time);
+    args.upstream = upstream;
+    Ok(args)
+}
+
+/// With --https-only, upstream and mirrors must be https (links in listings are checked while syncing)
+fn check_https_only(args: &SyncArgs) -> Result<()> {
+    if !args.https_only {
+        return Ok(());
+    }
+    for url in std::iter::once(&args.upstream).chain(&args.mirror) {
+        if url.scheme() != "https" {
+            return Err(anyhow!("{} is not https (--https-only)", url));
+        }
+    }
+    Ok(())
+}
+
+/// Enable reusing parsed listings of last run kept in --listing-cache
+fn load_listing_cache(args: &SyncArgs) {
+    let Some(path) = &args.listing_cache else {
+        return;
+    };
+    let listings = listing_cache::load(path).unwrap_or_else(|e| {
+        warn!(
+            "Failed to load listing cache {:?}, ignoring it: {:?}",
+            path, e
+        );
+        Default::default()
+    });
+    debug!("{} listings cached in {:?}", listings.len(), path);
+    listing_cache::enable(&args.upstream, listings);
+}
+
+fn save_listing_cache(args: &SyncArgs) {
+    let Some(path) = &args.listing_cache else {
+        return;
+    };
+    let listings = listing_cache::finish();
+    if let Err(e) = listing_cache::save(path, &listings) {
+        error!("Failed to save listing cache {:?}: {:?}", path, e);
+    }
+}
+
+fn log_summary(
+    stats: &StatsSnapshot,
+    resource_usage: &ResourceUsage,
+    reporter: &Reporter,
+    storm_detector: &StormDetector,
+) {
+    info!("Stats: {}", stats.summary());
+    info!("Resource usage: {}", resource_usage);
+    info!("Compared files by: {}", reporter.compare_summary());
+    if storm_detector.paused() > 0 {
+        warn!(
+            "{} re-downloads for mtime mismatch are paused due to re-download storm. Check --timezone (or use --touch-up), or run again with a higher --storm-threshold if upstream has really changed them",
+            storm_detector.paused()
+        );
+    }
+    info!(
+        "Downloaded by top-level directory: {}",
+        reporter.bandwidth().summary(10)
+    );
+}
+
+fn save_record(args: &SyncArgs) {
+    if let Some(record) = &args.record {
+        if let Err(e) = har::save(record) {
+            error!("Failed to save recorded archive to {:?}: {:?}", record, e);
+        }
+    }
+}
+
+fn save_host_state(args: &SyncArgs, requested_threads: usize) {
+    let Some(path) = &args.state_file else {
+        return;
+    };
+    let host = host_state::host_key(&args.upstream);
+    let observation = host_state::observation(&host);
+    if let Err(e) = host_state::update(path, &host, |state| {
+        state.learn(requested_threads, args.threads, &observation)
+    }) {
+        error!("Failed to save state file {:?}: {:?}", path, e);
+    }
+}
+
 pub fn sync(args: &SyncArgs, bind_address: Option<String>) -> ! {
+    std::process::exit(run_sync(args, bind_address, None));
+}
+
+/// Run a sync, returning exit code. In batch mode, bandwidth is a share of the shared limiter.
+/// Events are written to --events-file, if any.
+pub fn run_sync(args: &SyncArgs, bind_address: Option<String>, bandwidth: Option<Share>) -> i32 {
+    let Some(path) = &args.events_file else {
+        return run_sync_with_events(args, bind_address, bandwidth, &EventSink::default());
+    };
+    let (events, writer) = match EventSink::to_file(path) {
+        Ok(res) => res,
+        Err(e) => {
+            error!("{:?}", e);
+            return 1;
+        }
+    };
+    let exit_code = run_sync_with_events(args, bind_address, bandwidth, &events);
+    // Writer ends after all events are written
+    drop(events);
+    let _ = writer.join();
+    exit_code
+}
+
+/// Exit code of run, from exit code of cleanup (or why it is skipped) and failures of other phases
+fn final_exit_code(
+    mut exit_code: i32,
+    failure_downloading: bool,
+    retry_budget_exhausted: bool,
+) -> i32 {
+    if failure_downloading && !matches!(exit_code, 5 | 7) {
+        error!("Failed to download some files");
+        exit_code = 2;
+    }
+    if retry_budget_exhausted && (exit_code == 1 || exit_code == 2) {
+        error!("Retry budget is exhausted");
+        exit_code = 6;
+    }
+    exit_code
+}
+
+/// Args of both stages of --debian-ftpsync. Like ftpsync of Debian push mirrors, stage 1 syncs everything
+/// but metadata without deleting, and stage 2 syncs metadata (checked by APT extension) and deletes,
+/// so that clients never see indices referring to packages not there yet.
+fn ftpsync_stages(args: &SyncArgs) -> (SyncArgs, SyncArgs) {
+    let mut stage1 = args.clone();
+    stage1.debian_ftpsync = false;
+    stage1.ftpsync_stage1 = true;
+    stage1.no_delete = true;
+    // Outputs describing the whole run are left to stage 2
+    stage1.report = None;
+    stage1.metrics_file = None;
+    stage1.record = None;
+    stage1.invalidate_url = None;
+    stage1.deletion_list_dir = None;
+    stage1.events_file = None;
+    let mut stage2 = args.clone();
+    stage2.debian_ftpsync = false;
+    stage2.apt_packages = true;
+    (stage1, stage2)
+}
+
+/// Run a sync like run_sync(), sending events of it to events.
+/// RunFinished is the last one (of each stage with --debian-ftpsync), unless sync fails to start.
+fn run_sync_with_events(
+    args: &SyncArgs,
+    bind_address: Option<String>,
+    bandwidth: Option<Share>,
+    events: &EventSink,
+) -> i32 {
     debug!("{:?}", args);
-    let parser = args.parser.build();
+    let bandwidth = bandwidth.unwrap_or_else(|| Limiter::new(None).share(1, args.bandwidth_limit));
+    if !args.debian_ftpsync {
+        return sync_once(args, bind_address, &bandwidth, events);
+    }
+    let (stage1, stage2) = ftpsync_stages(args);
+    info!("Stage 1: syncing everything but metadata");
+    let exit_code = sync_once(&stage1, bind_address.clone(), &bandwidth, events);
+    if exit_code != 0 {
+        error!("Stage 1 failed, not to sync metadata");
+        return exit_code;
+    }
+    info!("Stage 2: syncing metadata and deleting");
+    sync_once(&stage2, bind_address, &bandwidth, events)
+}
+
+/// Refuse --sidecar xattr, and fall back from validator cache, without extended attributes on local filesystem
+fn check_xattrs(args: &mut SyncArgs) -> Result<()> {
+    if !args.local.exists() {
+        return Ok(());
+    }
+    if let Some(mode) = args.sidecar {
+        sidecar::check_mode(mode, &args.local)?;
+    }
+    if use_xattr_cache(args) {
+        if let Err(e) = sidecar::probe_xattr(&args.local) {
+            info!("Validator cache disabled: {}", e);
+            args.no_xattr_cache = true;
+        }
+    }
+    Ok(())
+}
+
+fn sync_once(
+    args: &SyncArgs,
+    bind_address: Option<String>,
+    bandwidth: &Share,
+    events: &EventSink,
+) -> i32 {
+    let requested_threads = args.threads;
+    let _run_scope = RunScope::begin();
+    let args = match pin_snapshot(args) {
+        Ok(args) => args,
+        Err(e) => {
+            error!("Failed to resolve snapshot: {:?}", e);
+            return 1;
+        }
+    };
+    let mut args = load_host_state(&args);
+    if let Err(e) = root_guard::check_local(&args.local)
+        .and_then(|_| check_https_only(&args))
+        .and_then(|_| check_xattrs(&mut args))
+    {
+        error!("{:?}", e);
+        return 1;
+    }
+    // Verified before cleanup, in case it is unmounted during the run
+    let root_identity = DirIdentity::of(&args.local);
+    let same_as_last_run = record_sync_start(&args, Utc::now(), root_identity);
+    let args = &args;
+    let parser = match build_parser(&ParserSources {
+        parser: &args.parser,
+        http_base: &args.upstream,
+        rsync_upstream: args.rsync_upstream.as_ref(),
+        sitemap: args.sitemap.as_ref(),
+        gitlab_project: args.gitlab_project.as_ref(),
+        huggingface_repo: args.huggingface_repo.as_ref(),
+        manifest: args.manifest.as_ref(),
+        fields: &args.manifest_field,
+        embedded_json_selector: args.embedded_json_selector.as_deref(),
+    }) {
+        Ok(parser) => parser,
+        Err(e) => {
+            error!("Failed to build parser: {:?}", e);
+            return 1;
+        }
+    };
+
+    let large_file_threads = match args.large_file_threshold {
+        Some(_) => args.large_file_threads,
+        None => 0,
+    };
+    let invalidator = match build_invalidator(args) {
+        Ok(invalidator) => invalidator,
+        Err(e) => {
+            error!("Invalid CDN invalidation options: {:?}", e);
+            return 1;
+        }
+    };
+
+    FdBudget::global().check_threads(args.threads + large_file_threads);
+    load_listing_cache(args);
 
     let download_dir = args.local.as_path();
 
     let remote_list = Arc::new(Mutex::new(HashSet::new()));
 
-    let stat_objects = AtomicUsize::new(0);
-    let stat_size = AtomicU64::new(0);
+    let stats = SyncStats::default();
+    let stat_newest_remote = AtomicI64::new(i64::MIN);
 
     let failure_listing = AtomicBool::new(false);
     let failure_downloading = AtomicBool::new(false);
 
+    if args.record.is_some() {
+        har::start_recording();
+    }
+    let reporter = Reporter::new(
+        args.report.is_some() || args.deletion_list_dir.is_some() || invalidator.is_some(),
+        args.itemize_changes,
+    );
+    let storm_detector = StormDetector::new(args.storm_threshold, args.strict_storm);
+    let retry_budget = RetryBudget::new(args.retry_budget, args.dns_retry);
+    let resources = ResourceMonitor::start();
+    let prefix_limiter = PrefixLimiter::new(&args.max_concurrent_per_prefix);
+
     sync_threads(
         args,
         &*parser,
@@ -607,87 +2390,155 @@ pub fn sync(args: &SyncArgs, bind_address: Option<String>) -> ! {
             bind_address,
             download_dir,
             remote_list: &remote_list,
-            stat_objects: &stat_objects,
-            stat_size: &stat_size,
+            stats: &stats,
+            events,
+            stat_newest_remote: &stat_newest_remote,
             failure_listing: &failure_listing,
             failure_downloading: &failure_downloading,
+            reporter: &reporter,
+            storm_detector: &storm_detector,
+            retry_budget: &retry_budget,
+            bandwidth,
+            resources: &resources,
+            prefix_limiter: &prefix_limiter,
         },
     );
 
-    let mut exit_code = 0;
-
     // Removing files that are not in remote list
-    let mut del_cnt = 0;
-    let remote_list = remote_list.lock().unwrap();
-    if failure_listing.load(Ordering::SeqCst) {
+    let mut newest_local: Option<DateTime<Utc>> = None;
+    let mut remote_list = remote_list.lock().unwrap();
+    let root_changed = root_identity.is_some_and(|identity| !identity.matches(download_dir));
+    // Generated before cleanup, which would delete them otherwise
+    let failed = match root_changed || !same_as_last_run {
+        true => 0,
+        false => recompress::recompress(
+            &args.recompress,
+            download_dir,
+            &mut remote_list,
+            args.dry_run,
+        ),
+    };
+    if failed > 0 {
+        failure_downloading.store(true, Ordering::SeqCst);
+    }
+    let exit_code = if storm_detector.should_abort() {
+        error!("Aborted due to re-download storm, not to delete anything");
+        5
+    } else if !same_as_last_run {
+        error!(
+            "{:?} is not the same directory as in last run (unmounted?), not to delete anything. Remove it from {:?} if it is moved on purpose",
+            download_dir,
+            args.state_file.as_deref().unwrap_or(Path::new(""))
+        );
+        7
+    } else if root_changed {
+        error!(
+            "{:?} is not the same directory as when sync started (unmounted?), not to delete anything",
+            download_dir
+        );
+        7
+    } else if failure_listing.load(Ordering::SeqCst) {
         error!("Failed to list remote, not to delete anything");
-        exit_code = 1;
+        1
+    } else if let Err(e) = root_guard::check_local(download_dir) {
+        // Symlinks might be changed during the run
+        error!("{:?}, not to delete anything", e);
+        7
     } else {
-        // Don't even walkdir when dry_run, to prevent no dir error
-        for entry in walkdir::WalkDir::new(download_dir).contents_first(true) {
-            let entry = match entry {
-                Ok(entry) => entry,
-                Err(e) => {
-                    error!("Failed to walkdir: {:?}", e);
-                    if !args.dry_run {
-                        exit_code = 1;
-                    }
-                    break;
-                }
-            };
-            let path = entry.path();
-            if !remote_list.contains(&path.to_path_buf()) {
-                if args.no_delete {
-                    info!("{:?} not in remote", path);
-                } else {
-                    // always make sure that we are deleting the right thing
-                    if del_cnt >= args.max_delete {
-                        info!("Exceeding max delete count, aborting");
-                        // exit with 25 to indicate that the deletion has been aborted
-                        // this is the same as rsync
-                        exit_code = 25;
-                        break;
-                    }
-                    del_cnt += 1;
-                    assert!(path.starts_with(download_dir));
-                    if args.dry_run {
-                        info!("Dry run, not deleting {:?}", path);
-                        continue;
-                    }
+        cleanup(
+            args,
+            download_dir,
+            &remote_list,
+            &reporter,
+            &stats,
+            events,
+            &mut newest_local,
+        )
+    };
 
-                    info!("Deleting {:?}", path);
-                    if entry.file_type().is_dir() {
-                        if let Err(e) = std::fs::remove_dir(path) {
-                            error!("Failed to remove {:?}: {:?}", path, e);
-                            exit_code = 4;
-                        }
-                    } else if let Err(e) = std::fs::remove_file(path) {
-                        error!("Failed to remove {:?}: {:?}", path, e);
-                        exit_code = 4;
-                    }
-                }
-            }
-        }
-    }
+    let exit_code = final_exit_code(
+        exit_code,
+        failure_downloading.load(Ordering::SeqCst),
+        retry_budget.is_exhausted(),
+    );
 
-    if failure_downloading.load(Ordering::SeqCst) {
-        error!("Failed to download some files");
-        exit_code = 2;
+    // Show stat
+    let stats = stats.snapshot();
+    let resource_usage = resources.finish();
+    log_summary(&stats, &resource_usage, &reporter, &storm_detector);
+
+    if let Some(metrics_file) = &args.metrics_file {
+        write_freshness_metrics(
+            args,
+            metrics_file,
+            stat_newest_remote.load(Ordering::SeqCst),
+            newest_local,
+            &stats,
+            exit_code == 0,
+        );
     }
 
-    // Show stat
-    info!(
-        "(Estimated) Total objects: {}, total size: {}",
-        stat_objects.load(Ordering::SeqCst),
-        humansize::format_size(stat_size.load(Ordering::SeqCst), humansize::BINARY)
+    events.emit(|| SyncEvent::RunFinished {
+        exit_code,
+        stats: stats.clone(),
+    });
+    let report = reporter.finish(
+        args.upstream.as_str(),
+        download_dir,
+        args.dry_run,
+        exit_code,
+        resource_usage,
+        stats,
     );
+    write_report(args, &report, invalidator.as_ref());
 
-    std::process::exit(exit_code);
+    save_record(args);
+    save_listing_cache(args);
+    save_host_state(args, requested_threads);
+
+    exit_code
 }
 
 #[cfg(test)]
 mod tests {
-    // use super::*;
+    use clap::Parser;
+
+    use super::*;
+    use crate::test_tree::{create_tree, list_tree, MTIME};
+
+    #[test]
+    fn test_mirror_urls() {
+        let upstream = Url::parse("https://example.com/debian/").unwrap();
+        let mirrors = [
+            Url::parse("https://mirror1.example.com/debian").unwrap(),
+            Url::parse("https://mirror2.example.com/").unwrap(),
+        ];
+        let url = Url::parse("https://example.com/debian/pool/a%20b.deb").unwrap();
+        let urls: Vec<String> = get_mirror_urls(&upstream, &mirrors, &url)
+            .iter()
+            .map(|u| u.to_string())
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://example.com/debian/pool/a%20b.deb",
+                "https://mirror1.example.com/debian/pool/a%20b.deb",
+                "https://mirror2.example.com/pool/a%20b.deb",
+            ]
+        );
+        // Not under upstream (e.g. from extensions)
+        let url = Url::parse("https://other.example.com/a.deb").unwrap();
+        assert_eq!(get_mirror_urls(&upstream, &mirrors, &url).len(), 1);
+    }
+
+    #[test]
+    fn test_is_under() {
+        assert!(is_under("pool/main/a.deb", "pool/main"));
+        assert!(is_under("pool/main", "pool/main"));
+        assert!(is_under("pool", ""));
+        assert!(!is_under("pool/mainline/a.deb", "pool/main"));
+        assert!(!is_under("pool", "pool/main"));
+    }
 
     #[test]
     fn test_relative() {
@@ -698,4 +2549,916 @@ mod tests {
         relative.push("dists".to_string());
         assert_eq!(relative.join("/"), "debian/dists");
     }
+
+    // Scenarios of deletion: sync from a fixture server to a temp dir, and check what is left locally.
+
+    /// Sync upstream dir (served in nginx style) to local dir, returning exit code
+    fn sync_fixture(upstream: &Path, local: &Path, extra_args: &[&str]) -> i32 {
+        let addr = crate::autoindex::spawn(upstream, crate::autoindex::AutoindexStyle::Nginx);
+        let url = format!("http://{addr}/");
+        let args = SyncArgs::try_parse_from(
+            ["sync", "--timezone", "0", "--retry", "0"]
+                .iter()
+                .chain(extra_args)
+                .chain(&[url.as_str(), local.to_str().unwrap()]),
+        )
+        .unwrap();
+        run_sync(&args, None, None)
+    }
+
+    #[test]
+    fn test_delete_gone_files() {
+        let (upstream, local) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        create_tree(upstream.path(), &["pool/a.deb", "README"]);
+        create_tree(
+            local.path(),
+            &["pool/a.deb", "pool/old.deb", "old/b.deb", "README"],
+        );
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &[]), 0);
+        assert_eq!(list_tree(local.path()), ["README", "pool/", "pool/a.deb"]);
+    }
+
+    #[test]
+    fn test_delete_upstream_empty() {
+        let (upstream, local) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        create_tree(local.path(), &["pool/a.deb", "pool/b.deb", "README"]);
+        // An empty listing (like a parser returning zero items) is taken as is,
+        // so only --max-delete stops wiping local
+        assert_eq!(
+            sync_fixture(upstream.path(), local.path(), &["--max-delete", "2"]),
+            25
+        );
+        assert_eq!(list_tree(local.path()).len(), 2);
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &[]), 0);
+        assert!(list_tree(local.path()).is_empty());
+    }
+
+    #[test]
+    fn test_local_replaced() {
+        let (upstream, local) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        create_tree(upstream.path(), &["README"]);
+        let state = local.path().join("state.json");
+        let args = ["--state-file", state.to_str().unwrap()];
+        let local = local.path().join("mirror");
+        assert_eq!(sync_fixture(upstream.path(), &local, &args), 0);
+        assert_eq!(sync_fixture(upstream.path(), &local, &args), 0);
+        // Like another filesystem mounted over it between runs
+        std::fs::rename(&local, local.with_extension("old")).unwrap();
+        create_tree(&local, &["old.deb"]);
+        assert_eq!(sync_fixture(upstream.path(), &local, &args), 7);
+        assert_eq!(list_tree(&local), ["README", "old.deb"]);
+        assert_eq!(sync_fixture(upstream.path(), &local, &args), 7);
+    }
+
+    #[test]
+    fn test_deletion_list_dir_kept() {
+        let (upstream, local) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        create_tree(upstream.path(), &["README"]);
+        create_tree(local.path(), &["README", "pool/a.deb", "lists/old.list"]);
+        // Given through a symlink and "..", unlike walked paths
+        let link_dir = tempfile::tempdir().unwrap();
+        let link = link_dir.path().join("local");
+        std::os::unix::fs::symlink(local.path(), &link).unwrap();
+        let lists = link.join("pool/../lists");
+        let args = ["--deletion-list-dir", lists.to_str().unwrap()];
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &args), 0);
+        let tree = list_tree(local.path());
+        assert!(tree.contains(&"lists/old.list".to_string()));
+        assert!(!tree.contains(&"pool/a.deb".to_string()));
+        assert_eq!(
+            utils::canonical(&lists),
+            utils::canonical(&local.path().join("lists"))
+        );
+    }
+
+    #[test]
+    fn test_deletion_plan() {
+        let (upstream, local) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        create_tree(upstream.path(), &["README"]);
+        create_tree(local.path(), &["pool/a.deb", "pool/b.deb", "README"]);
+        // Plan inside local directory, given by another path
+        let dir = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(local.path(), dir.path().join("link")).unwrap();
+        let plan_path = dir.path().join("link/plan.json");
+        let state_path = dir.path().join("state.json");
+        let args = [
+            "--deletion-plan",
+            plan_path.to_str().unwrap(),
+            "--state-file",
+            state_path.to_str().unwrap(),
+        ];
+        let max_delete = [&["--max-delete", "1"], &args[..]].concat();
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &max_delete), 25);
+        let plan = DeletionPlan::load(&plan_path).unwrap();
+        // Children before their parents, and the plan itself is kept
+        assert_eq!(plan.paths.len(), 3);
+        assert_eq!(plan.paths[2], "pool/");
+        assert!(!plan.paths.contains(&"plan.json".to_string()));
+        // Resumed without listing
+        plan.check_not_stale(&state_path).unwrap();
+        let code = deletion_plan::apply(&plan.local, &plan.paths, 100, false, &mut |_| ());
+        assert_eq!(code, 0);
+        assert_eq!(list_tree(local.path()), ["README", "plan.json"]);
+        // Stale after another sync
+        let no_delete = ["--no-delete", "--state-file", state_path.to_str().unwrap()];
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &no_delete), 0);
+        assert!(plan.check_not_stale(&state_path).is_err());
+        // Nothing left to delete, and the plan is removed
+        std::fs::write(&plan_path, "stale").unwrap();
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &args), 0);
+        assert_eq!(list_tree(local.path()), ["README"]);
+    }
+
+    #[test]
+    fn test_sidecar() {
+        let (upstream, local) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        create_tree(upstream.path(), &["pool/a.deb", "README"]);
+        let args = ["--sidecar", "file"];
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &args), 0);
+        let provenance =
+            sidecar::read(sidecar::SidecarMode::File, &local.path().join("pool/a.deb")).unwrap();
+        assert!(provenance.url.ends_with("/pool/a.deb"));
+        assert_eq!(provenance.size, "pool/a.deb".len() as u64);
+        assert!(provenance.last_modified.is_some());
+        // Sidecars are kept with their files, and deleted with them
+        std::fs::remove_file(upstream.path().join("README")).unwrap();
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &args), 0);
+        assert_eq!(
+            list_tree(local.path()),
+            ["pool/", "pool/a.deb", "pool/a.deb.tsumugu.meta"]
+        );
+    }
+
+    #[test]
+    fn test_head_cacheable() {
+        let args =
+            SyncArgs::try_parse_from(["sync", "http://example.com/", "/srv/mirror"]).unwrap();
+        let item = |size, mtime| {
+            ListItem::new(
+                Url::parse("http://example.com/a.iso").unwrap(),
+                "a.iso".to_string(),
+                crate::listing::FileType::File,
+                size,
+                mtime,
+            )
+        };
+        let rough = Some(FileSize::HumanizedBinary(3.4, crate::listing::SizeUnit::G));
+        let mtime = chrono::DateTime::from_timestamp(1_700_000_000, 0).map(|t| t.naive_utc());
+        assert!(is_head_cacheable(&args, &item(rough, mtime)));
+        assert!(is_head_cacheable(
+            &args,
+            &item(Some(FileSize::Precise(1)), None)
+        ));
+        assert!(is_head_cacheable(&args, &item(None, mtime)));
+        // Listing entry stays the same while the file changes
+        assert!(!is_head_cacheable(&args, &item(rough, None)));
+        assert!(!is_head_cacheable(&args, &item(None, None)));
+        let mut dry_run = args.clone();
+        dry_run.dry_run = true;
+        assert!(!is_head_cacheable(&dry_run, &item(rough, mtime)));
+    }
+
+    #[test]
+    fn test_https_only() {
+        let (upstream, local) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        create_tree(upstream.path(), &["README"]);
+        assert_eq!(
+            sync_fixture(upstream.path(), local.path(), &["--https-only"]),
+            1
+        );
+        assert!(list_tree(local.path()).is_empty());
+
+        let args = SyncArgs::try_parse_from([
+            "sync",
+            "--https-only",
+            "--mirror",
+            "http://mirror.example.com/debian/",
+            "https://example.com/debian/",
+            "/srv/debian",
+        ])
+        .unwrap();
+        assert!(check_https_only(&args).is_err());
+        assert!(SyncArgs::try_parse_from([
+            "sync",
+            "--https-only",
+            "--allow-https-downgrade",
+            "https://example.com/debian/",
+            "/srv/debian",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_modified_since() {
+        let (upstream, local) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        create_tree(upstream.path(), &["stable/a.deb", "nightly/b.deb"]);
+        // 2024-07-03
+        filetime::set_file_mtime(
+            upstream.path().join("nightly/b.deb"),
+            filetime::FileTime::from_unix_time(1_720_000_000, 0),
+        )
+        .unwrap();
+        create_tree(local.path(), &["stable/a.deb"]);
+        std::fs::write(local.path().join("stable/a.deb"), "outdated").unwrap();
+
+        let args = ["--modified-since", "2024-01-01"];
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &args), 0);
+        assert_eq!(
+            list_tree(local.path()),
+            ["nightly/", "nightly/b.deb", "stable/", "stable/a.deb"]
+        );
+        // Old files are neither updated nor deleted
+        assert_eq!(
+            std::fs::read_to_string(local.path().join("stable/a.deb")).unwrap(),
+            "outdated"
+        );
+
+        let args = ["--modified-before", "2024-01-01T00:00:00+08:00"];
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &args), 0);
+        assert_eq!(
+            std::fs::read_to_string(local.path().join("stable/a.deb")).unwrap(),
+            "stable/a.deb"
+        );
+        assert!(utils::parse_date_time("2024-13-01").is_err());
+    }
+
+    #[test]
+    fn test_large_file_lane() {
+        let item = |name: &str, size| {
+            ListItem::new(
+                Url::parse("http://localhost/").unwrap().join(name).unwrap(),
+                name.to_string(),
+                crate::listing::FileType::File,
+                size,
+                None,
+            )
+        };
+        let small = item("Packages.xz", Some(FileSize::Precise(100)));
+        let large = item("Packages", Some(FileSize::Precise(1000)));
+        let unknown = item("a.iso", None);
+        assert!(!is_large(None, std::slice::from_ref(&large)));
+        assert!(is_large(Some(500), std::slice::from_ref(&large)));
+        // Siblings stay together
+        assert!(is_large(Some(500), &[small.clone(), large]));
+        assert!(!is_large(Some(500), &[small, unknown]));
+
+        // Large files are all downloaded by the lane, which exits after normal workers
+        let (upstream, local) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let files = [
+            "a",
+            "dists/b",
+            "pool/main/a-very-long-name-of-large-file.iso",
+            "pool/main/another-long-name-of-large-file.iso",
+        ];
+        create_tree(upstream.path(), &files);
+        let args = ["--large-file-threshold", "10", "--threads", "1"];
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &args), 0);
+        for file in files {
+            assert_eq!(
+                std::fs::read_to_string(local.path().join(file)).unwrap(),
+                file
+            );
+        }
+        // Or by normal workers without lane threads
+        let local = tempfile::tempdir().unwrap();
+        let args = ["--large-file-threshold", "10", "--large-file-threads", "0"];
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &args), 0);
+        assert_eq!(list_tree(local.path()).len(), 7);
+    }
+
+    #[test]
+    fn test_sibling_consistency() {
+        let (upstream, local) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let files = ["Packages", "Packages.gz", "linux.tar", "linux.tar.gz"];
+        create_tree(upstream.path(), &files);
+        create_tree(local.path(), &files);
+        // Updated upstream, while compressed siblings look up to date
+        std::fs::write(local.path().join("Packages"), "old").unwrap();
+        std::fs::write(local.path().join("linux.tar"), "old").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let report = dir.path().join("report.json");
+        let args = ["--report", report.to_str().unwrap()];
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &args), 0);
+        let mut downloads: Vec<(&str, DownloadReason)> = Vec::new();
+        let res = Report::load(&report).unwrap();
+        for action in &res.actions {
+            if let Action::Download { path, reason, .. } = action {
+                downloads.push((path, *reason));
+            }
+        }
+        downloads.sort_by_key(|(path, _)| path.to_string());
+        // Only the index is kept in the same generation with its sibling
+        assert_eq!(
+            downloads,
+            [
+                ("Packages", DownloadReason::SizeMismatch),
+                ("Packages.gz", DownloadReason::SiblingUpdated),
+                ("linux.tar", DownloadReason::SizeMismatch),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_repair_verification() {
+        let item = |size| {
+            ListItem::new(
+                Url::parse("http://localhost/a.deb").unwrap(),
+                "a.deb".to_string(),
+                crate::listing::FileType::File,
+                size,
+                None,
+            )
+        };
+        let url = Url::parse("http://localhost/a.deb").unwrap();
+        verify_size(&url, &item(Some(FileSize::Precise(5))), 5, 5).unwrap();
+        verify_size(&url, &item(None), 5, 5).unwrap();
+        let e = verify_size(&url, &item(None), 5, 4).unwrap_err();
+        assert!(crate::error::is_retryable(&e));
+        assert_eq!(
+            e.to_string(),
+            "Size mismatch of http://localhost/a.deb: expected 5 bytes, got 4"
+        );
+        // Listing is stale or upstream is changing
+        assert!(verify_size(&url, &item(Some(FileSize::Precise(6))), 5, 5).is_err());
+
+        // Corrupted file looking up to date is fetched again
+        let (upstream, local) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        create_tree(upstream.path(), &["pool/a.deb", "pool/b.deb"]);
+        create_tree(local.path(), &["pool/a.deb", "pool/b.deb"]);
+        for file in ["pool/a.deb", "pool/b.deb"] {
+            let path = local.path().join(file);
+            std::fs::write(&path, "corrupted!").unwrap();
+            filetime::set_file_mtime(&path, filetime::FileTime::from_unix_time(MTIME, 0)).unwrap();
+        }
+        let addr =
+            crate::autoindex::spawn(upstream.path(), crate::autoindex::AutoindexStyle::Nginx);
+        let url = format!("http://{addr}/");
+        let mut args = SyncArgs::try_parse_from([
+            "sync",
+            "--timezone",
+            "0",
+            "--retry",
+            "0",
+            &url,
+            local.path().to_str().unwrap(),
+        ])
+        .unwrap();
+        args.only = vec!["pool".to_string()];
+        args.no_delete = true;
+        args.repair = Some("pool/a.deb".to_string());
+        assert_eq!(run_sync(&args, None, None), 0);
+        let read = |file| std::fs::read_to_string(local.path().join(file)).unwrap();
+        assert_eq!(read("pool/a.deb"), "pool/a.deb");
+        assert_eq!(read("pool/b.deb"), "corrupted!");
+    }
+
+    #[test]
+    fn test_panicked_download() {
+        let (upstream, local) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        create_tree(upstream.path(), &["a.deb", "b.deb"]);
+        // Connection closed before the whole body is sent
+        let route: crate::autoindex::Route = Arc::new(|_, target, _| {
+            (target == "/a.deb").then(|| {
+                b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\nLast-Modified: Tue, 14 Nov 2023 22:13:20 GMT\r\nConnection: close\r\n\r\na.deb".to_vec()
+            })
+        });
+        let addr = crate::autoindex::spawn_with_routes(
+            upstream.path(),
+            crate::autoindex::AutoindexStyle::Nginx,
+            vec![route],
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let report = dir.path().join("report.json");
+        let url = format!("http://{addr}/");
+        let args = SyncArgs::try_parse_from([
+            "sync",
+            "--timezone",
+            "0",
+            "--retry",
+            "0",
+            "--report",
+            report.to_str().unwrap(),
+            &url,
+            local.path().to_str().unwrap(),
+        ])
+        .unwrap();
+        assert_ne!(run_sync(&args, None, None), 0);
+        let res = Report::load(&report).unwrap();
+        assert_eq!(res.stats.failed_downloads, 1);
+        assert_eq!(res.stats.downloaded, 1);
+        let failed: Vec<_> = res
+            .actions
+            .iter()
+            .filter_map(|a| match a {
+                Action::DownloadFailed {
+                    path,
+                    reason,
+                    error,
+                    ..
+                } => Some((path.as_str(), *reason, error.starts_with("panicked: "))),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(failed, [("a.deb", DownloadReason::New, true)]);
+    }
+
+    #[test]
+    fn test_touch_up() {
+        let (upstream, local) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        create_tree(upstream.path(), &["a.deb", "b.deb", "c.deb"]);
+        create_tree(local.path(), &["a.deb", "b.deb", "c.deb"]);
+        std::fs::write(local.path().join("b.deb"), "changed").unwrap();
+        // Same size, but an earlier version
+        std::fs::write(local.path().join("c.deb"), "C.DEB").unwrap();
+        // Seeded copy is newer than remote
+        for (file, mtime) in [("a.deb", MTIME + 3600), ("b.deb", 0), ("c.deb", 0)] {
+            let path = local.path().join(file);
+            filetime::set_file_mtime(&path, filetime::FileTime::from_unix_time(mtime, 0)).unwrap();
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let report = dir.path().join("report.json");
+        let args = ["--touch-up", "--report", report.to_str().unwrap()];
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &args), 0);
+        let mtime = |file| {
+            let metadata = local.path().join(file).metadata().unwrap();
+            filetime::FileTime::from_last_modification_time(&metadata).unix_seconds()
+        };
+        // Listed mtime is in minutes, while downloaded one follows Last-Modified
+        assert_eq!(mtime("a.deb"), MTIME - MTIME % 60);
+        assert_eq!(mtime("b.deb"), MTIME);
+        // Size mismatch, and older file of the same size, are downloaded as usual
+        for file in ["b.deb", "c.deb"] {
+            assert_eq!(
+                std::fs::read_to_string(local.path().join(file)).unwrap(),
+                file
+            );
+            assert_eq!(mtime(file), MTIME);
+        }
+        let res = Report::load(&report).unwrap();
+        assert_eq!(res.stats.skipped[&SkipReason::TouchedUp], 1);
+        assert_eq!(res.stats.downloaded, 2);
+        let Some(Action::TouchUp { path, remote_mtime }) = res
+            .actions
+            .iter()
+            .find(|a| matches!(a, Action::TouchUp { .. }))
+        else {
+            panic!("No touch-up in {:?}", res.actions);
+        };
+        assert_eq!(path, "a.deb");
+        // Listed and normalized mtimes, with the offset of --timezone
+        let remote_mtime = remote_mtime.as_ref().unwrap();
+        assert_eq!(remote_mtime.utc.timestamp(), MTIME - MTIME % 60);
+        assert_eq!(remote_mtime.listed, remote_mtime.utc.naive_utc());
+        assert_eq!(remote_mtime.offset.as_deref(), Some("+00:00"));
+        assert_eq!(res.actions.len(), 3);
+        // And up to date after touched up
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &args), 0);
+        assert!(Report::load(&report).unwrap().actions.is_empty());
+    }
+
+    #[test]
+    fn test_lfs_pointer() {
+        let (upstream, objects, local) = (
+            tempfile::tempdir().unwrap(),
+            tempfile::tempdir().unwrap(),
+            tempfile::tempdir().unwrap(),
+        );
+        let oid = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        create_tree(objects.path(), &["object"]);
+        std::fs::write(objects.path().join("object"), "hello").unwrap();
+        create_tree(upstream.path(), &["release/a.bin"]);
+        std::fs::write(
+            upstream.path().join("release/a.bin"),
+            format!("version https://git-lfs.github.com/spec/v1\noid sha256:{oid}\nsize 5\n"),
+        )
+        .unwrap();
+        let objects =
+            crate::autoindex::spawn(objects.path(), crate::autoindex::AutoindexStyle::Nginx);
+        // Batch API answers only once, so that resolving again fails
+        let answered = Arc::new(AtomicBool::new(false));
+        let batch: crate::autoindex::Route = Arc::new({
+            let answered = answered.clone();
+            move |method, target, body| {
+                if method != "POST" || target != "/repo.git/info/lfs/objects/batch" {
+                    return None;
+                }
+                assert!(String::from_utf8_lossy(body).contains(oid));
+                if answered.swap(true, Ordering::SeqCst) {
+                    return Some(b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec());
+                }
+                let body = format!(
+                    r#"{{"objects": [{{"oid": "{oid}", "size": 5, "actions": {{"download": {{"href": "http://{objects}/object"}}}}}}]}}"#
+                );
+                Some(
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    )
+                    .into_bytes(),
+                )
+            }
+        });
+        let addr = crate::autoindex::spawn_with_routes(
+            upstream.path(),
+            crate::autoindex::AutoindexStyle::Nginx,
+            vec![batch],
+        );
+        let url = format!("http://{addr}/release/");
+        let endpoint = format!("http://{addr}/repo.git/info/lfs");
+        let sync = |extra_args: &[&str]| {
+            let args = SyncArgs::try_parse_from(
+                ["sync", "--timezone", "0", "--retry", "0", "--lfs-endpoint"]
+                    .iter()
+                    .chain(&[endpoint.as_str()])
+                    .chain(extra_args)
+                    .chain(&[url.as_str(), local.path().to_str().unwrap()]),
+            )
+            .unwrap();
+            run_sync(&args, None, None)
+        };
+        let content = || std::fs::read_to_string(local.path().join("a.bin")).unwrap();
+        assert_eq!(sync(&[]), 0);
+        assert_eq!(content(), "hello");
+        // Not downloaded again, as it is resolved from the same pointer (in validator cache)
+        if sidecar::probe_xattr(local.path()).is_ok() {
+            assert_eq!(sync(&[]), 0);
+        }
+        // Without validator cache, the pointer is downloaded again, but never replaces the object
+        assert_ne!(sync(&["--no-xattr-cache"]), 0);
+        assert_eq!(content(), "hello");
+        assert!(!local.path().join(".tmp.a.bin").exists());
+    }
+
+    #[test]
+    fn test_host_state_rounds() {
+        let (upstream, local, dir) = (
+            tempfile::tempdir().unwrap(),
+            tempfile::tempdir().unwrap(),
+            tempfile::tempdir().unwrap(),
+        );
+        create_tree(upstream.path(), &["pool/a.deb"]);
+        // Listing is throttled once, in the first round only
+        let throttled = Arc::new(AtomicBool::new(false));
+        let route: crate::autoindex::Route = Arc::new({
+            let throttled = throttled.clone();
+            move |method, target, _| {
+                if method != "GET" || target != "/" || throttled.swap(true, Ordering::SeqCst) {
+                    return None;
+                }
+                Some(b"HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec())
+            }
+        });
+        let addr = crate::autoindex::spawn_with_routes(
+            upstream.path(),
+            crate::autoindex::AutoindexStyle::Nginx,
+            vec![route],
+        );
+        let url = format!("http://{addr}/");
+        let state_file = dir.path().join("state.json");
+        let sync = || {
+            let args = SyncArgs::try_parse_from([
+                "sync",
+                "--timezone",
+                "0",
+                "--retry",
+                "1",
+                "--threads",
+                "4",
+                "--state-file",
+                state_file.to_str().unwrap(),
+                url.as_str(),
+                local.path().to_str().unwrap(),
+            ])
+            .unwrap();
+            run_sync(&args, None, None)
+        };
+        let preferred_threads = || {
+            StateFile::load(&state_file)
+                .unwrap()
+                .get(&addr.to_string())
+                .preferred_threads
+        };
+        assert_eq!(sync(), 0);
+        assert_eq!(preferred_threads(), Some(2));
+        // Later rounds in the same process do not see 429 of the first one
+        assert_eq!(sync(), 0);
+        assert_eq!(preferred_threads(), Some(3));
+        assert_eq!(sync(), 0);
+        assert_eq!(preferred_threads(), None);
+    }
+
+    #[test]
+    fn test_download_retry() {
+        let (upstream, objects, local) = (
+            tempfile::tempdir().unwrap(),
+            tempfile::tempdir().unwrap(),
+            tempfile::tempdir().unwrap(),
+        );
+        let oid = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        create_tree(objects.path(), &["object"]);
+        std::fs::write(objects.path().join("object"), "hello").unwrap();
+        create_tree(upstream.path(), &["release/a.bin"]);
+        std::fs::write(
+            upstream.path().join("release/a.bin"),
+            format!("version https://git-lfs.github.com/spec/v1\noid sha256:{oid}\nsize 5\n"),
+        )
+        .unwrap();
+        // Object is corrupted in the first transfer
+        let corrupted = Arc::new(AtomicUsize::new(0));
+        let object: crate::autoindex::Route = Arc::new({
+            let corrupted = corrupted.clone();
+            move |method, target, _| {
+                if method != "GET"
+                    || target != "/object"
+                    || corrupted.fetch_add(1, Ordering::SeqCst) > 0
+                {
+                    return None;
+                }
+                Some(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhellx"
+                        .to_vec(),
+                )
+            }
+        });
+        let objects = crate::autoindex::spawn_with_routes(
+            objects.path(),
+            crate::autoindex::AutoindexStyle::Nginx,
+            vec![object],
+        );
+        let batch: crate::autoindex::Route = Arc::new(move |method, target, _| {
+            if method != "POST" || target != "/repo.git/info/lfs/objects/batch" {
+                return None;
+            }
+            let body = format!(
+                r#"{{"objects": [{{"oid": "{oid}", "size": 5, "actions": {{"download": {{"href": "http://{objects}/object"}}}}}}]}}"#
+            );
+            Some(
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .into_bytes(),
+            )
+        });
+        let addr = crate::autoindex::spawn_with_routes(
+            upstream.path(),
+            crate::autoindex::AutoindexStyle::Nginx,
+            vec![batch],
+        );
+        let url = format!("http://{addr}/release/");
+        let endpoint = format!("http://{addr}/repo.git/info/lfs");
+        let sync = |retry: &str| {
+            let args = SyncArgs::try_parse_from([
+                "sync",
+                "--timezone",
+                "0",
+                "--no-xattr-cache",
+                "--retry",
+                retry,
+                "--lfs-endpoint",
+                endpoint.as_str(),
+                url.as_str(),
+                local.path().to_str().unwrap(),
+            ])
+            .unwrap();
+            run_sync(&args, None, None)
+        };
+        // Checksum mismatch fails the download without retries
+        assert_ne!(sync("0"), 0);
+        assert!(!local.path().join("a.bin").exists());
+        // And it is downloaded again (with the pointer) when retried
+        corrupted.store(0, Ordering::SeqCst);
+        assert_eq!(sync("1"), 0);
+        assert_eq!(
+            std::fs::read_to_string(local.path().join("a.bin")).unwrap(),
+            "hello"
+        );
+        assert_eq!(corrupted.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_debian_ftpsync() {
+        let (upstream, local) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        create_tree(
+            upstream.path(),
+            &[
+                "pool/main/a.deb",
+                "dists/stable/Release",
+                "indices/override.stable.main",
+                "ls-lR.gz",
+            ],
+        );
+        create_tree(local.path(), &["pool/main/old.deb", "dists/stable/Release"]);
+        std::fs::write(local.path().join("dists/stable/Release"), "old").unwrap();
+
+        let url = format!(
+            "http://{}/",
+            crate::autoindex::spawn(upstream.path(), crate::autoindex::AutoindexStyle::Nginx)
+        );
+        let args = SyncArgs::try_parse_from([
+            "sync",
+            "--timezone",
+            "0",
+            "--retry",
+            "0",
+            "--debian-ftpsync",
+            &url,
+            local.path().to_str().unwrap(),
+        ])
+        .unwrap();
+        let (stage1, stage2) = ftpsync_stages(&args);
+        assert!(stage2.apt_packages && !stage2.ftpsync_stage1);
+        let outputs = SyncArgs::try_parse_from([
+            "sync",
+            "--debian-ftpsync",
+            "--report=report.json",
+            "--invalidate-url=http://cdn.example.com/purge?path={path}",
+            "--deletion-list-dir=lists",
+            "--events-file=events.jsonl",
+            &url,
+            "local",
+        ])
+        .unwrap();
+        let (outputs1, outputs2) = ftpsync_stages(&outputs);
+        assert!(outputs1.report.is_none() && outputs1.invalidate_url.is_none());
+        assert!(outputs1.deletion_list_dir.is_none() && outputs1.events_file.is_none());
+        assert!(outputs2.report.is_some() && outputs2.invalidate_url.is_some());
+        assert!(outputs2.deletion_list_dir.is_some() && outputs2.events_file.is_some());
+        // Payloads only (directories are still created), and nothing is deleted
+        assert_eq!(run_sync(&stage1, None, None), 0);
+        assert_eq!(
+            list_tree(local.path()),
+            [
+                "dists/",
+                "dists/stable/",
+                "dists/stable/Release",
+                "indices/",
+                "pool/",
+                "pool/main/",
+                "pool/main/a.deb",
+                "pool/main/old.deb"
+            ]
+        );
+        assert_eq!(
+            std::fs::read_to_string(local.path().join("dists/stable/Release")).unwrap(),
+            "old"
+        );
+
+        assert_eq!(run_sync(&args, None, None), 0);
+        assert_eq!(
+            list_tree(local.path()),
+            [
+                "dists/",
+                "dists/stable/",
+                "dists/stable/Release",
+                "indices/",
+                "indices/override.stable.main",
+                "ls-lR.gz",
+                "pool/",
+                "pool/main/",
+                "pool/main/a.deb"
+            ]
+        );
+        assert_eq!(
+            std::fs::read_to_string(local.path().join("dists/stable/Release")).unwrap(),
+            "dists/stable/Release"
+        );
+    }
+
+    #[test]
+    fn test_delete_upstream_missing() {
+        let (upstream, local) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        create_tree(upstream.path(), &["pool/a.deb"]);
+        create_tree(local.path(), &["debian/pool/a.deb", "debian/README"]);
+        // Root of upstream is gone (404): listing fails, and nothing is deleted
+        let local_debian = local.path().join("debian");
+        let addr =
+            crate::autoindex::spawn(upstream.path(), crate::autoindex::AutoindexStyle::Nginx);
+        let args = SyncArgs::try_parse_from([
+            "sync",
+            "--timezone",
+            "0",
+            "--retry",
+            "0",
+            &format!("http://{addr}/debian/"),
+            local_debian.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert_eq!(run_sync(&args, None, None), 1);
+        assert_eq!(
+            list_tree(local.path()),
+            [
+                "debian/",
+                "debian/README",
+                "debian/pool/",
+                "debian/pool/a.deb"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_delete_excluded_then_included() {
+        let (upstream, local) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        create_tree(
+            upstream.path(),
+            &["pool/main/a.deb", "pool/contrib/b.deb", "pool/debug/c.deb"],
+        );
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &[]), 0);
+        // Newly excluded paths are deleted locally, unless included again.
+        // Directories under "^pool/" are still listed (as list only), so they are kept empty.
+        assert_eq!(
+            sync_fixture(
+                upstream.path(),
+                local.path(),
+                &["--exclude", "^pool/", "--include", "^pool/main"]
+            ),
+            0
+        );
+        assert_eq!(
+            list_tree(local.path()),
+            [
+                "pool/",
+                "pool/contrib/",
+                "pool/debug/",
+                "pool/main/",
+                "pool/main/a.deb"
+            ]
+        );
+        // And downloaded again when no longer excluded
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &[]), 0);
+        assert_eq!(list_tree(local.path()).len(), 7);
+    }
+
+    #[test]
+    fn test_companions_excluded() {
+        let (upstream, local) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        create_tree(
+            upstream.path(),
+            &[
+                "a.iso",
+                "a.iso.asc",
+                "a.iso.zsync",
+                "SHA256SUMS",
+                "SHA256SUMS.gpg",
+            ],
+        );
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &[]), 0);
+        // Orphan companions of excluded payload are deleted as well
+        assert_eq!(
+            sync_fixture(upstream.path(), local.path(), &["--exclude", "@iso"]),
+            0
+        );
+        assert_eq!(list_tree(local.path()), ["SHA256SUMS", "SHA256SUMS.gpg"]);
+        assert_eq!(
+            sync_fixture(
+                upstream.path(),
+                local.path(),
+                &["--exclude", "@iso", "--no-companions"]
+            ),
+            0
+        );
+        // a.iso.zsync is still excluded by @iso itself
+        assert_eq!(
+            list_tree(local.path()),
+            ["SHA256SUMS", "SHA256SUMS.gpg", "a.iso.asc"]
+        );
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let (upstream, local) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        create_tree(
+            upstream.path(),
+            &[
+                "releases/download/v1.0/a.tar.gz",
+                "releases/download/v1.1/a.tar.gz",
+                "README",
+            ],
+        );
+        create_tree(local.path(), &["v0.9/a.tar.gz"]);
+        let rewrite = ["--rewrite", "^releases/download/([^/]+)/(.+)$=$1/$2"];
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &rewrite), 0);
+        // Remote directories are not created, and the old release not rewritten to is deleted
+        let expected = ["README", "v1.0/", "v1.0/a.tar.gz", "v1.1/", "v1.1/a.tar.gz"];
+        assert_eq!(list_tree(local.path()), expected);
+        // Already up to date
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &rewrite), 0);
+        assert_eq!(list_tree(local.path()), expected);
+
+        let escape = ["--rewrite", "^README$=../README"];
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &escape), 2);
+    }
+
+    #[test]
+    fn test_delete_symlinked_dirs() {
+        let (upstream, local) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        create_tree(upstream.path(), &["pool/a.deb", "current/a.deb"]);
+        create_tree(local.path(), &["pool/a.deb", "stable/b.deb"]);
+        // Local symlink of a directory in upstream is kept (and not listed),
+        // and one not in upstream is deleted itself, not what it points to
+        symlink("pool", local.path().join("current")).unwrap();
+        symlink("stable", local.path().join("old")).unwrap();
+        assert_eq!(sync_fixture(upstream.path(), local.path(), &[]), 0);
+        assert_eq!(list_tree(local.path()), ["current", "pool/", "pool/a.deb"]);
+    }
 }
@@ -0,0 +1,206 @@
+// Conformance test runner for parsers: a corpus directory contains pairs of
+// `<case>.html` (the listing page) and `<case>.json` (expected result).
+
+use std::path::Path;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Deserialize;
+use url::Url;
+
+use crate::{
+    listing::{FileType, ListItem},
+    parser::{try_parse_page, Parser, ParserType},
+    TestParsersArgs,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ExpectedType {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExpectedItem {
+    name: String,
+    #[serde(rename = "type")]
+    type_: ExpectedType,
+    /// Estimated size in bytes, null for directories
+    size: Option<u64>,
+    /// "%Y-%m-%d %H:%M:%S", as shown on the page. null if not shown.
+    mtime: Option<String>,
+    /// Checked only when given
+    #[serde(default)]
+    url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Expected {
+    /// URL of the page, to resolve relative links
+    url: String,
+    /// Parser (in command line format) expected to handle this page. All parsers are tried if not given.
+    #[serde(default)]
+    parser: Option<String>,
+    items: Vec<ExpectedItem>,
+}
+
+impl From<&ListItem> for ExpectedItem {
+    fn from(item: &ListItem) -> Self {
+        Self {
+            name: item.name.clone(),
+            type_: match item.type_ {
+                FileType::File => ExpectedType::File,
+                FileType::Directory => ExpectedType::Directory,
+            },
+            size: item.size.map(|s| s.get_estimated()),
+            mtime: item
+                .mtime
+                .map(|m| m.format("%Y-%m-%d %H:%M:%S").to_string()),
+            url: Some(item.url.to_string()),
+        }
+    }
+}
+
+fn parser_name(parser: &ParserType) -> String {
+    parser.to_possible_value().unwrap().get_name().to_string()
+}
+
+/// Compare actual items with expected ones, returning all differences.
+fn compare_items(expected: &[ExpectedItem], actual: &[ListItem]) -> Vec<String> {
+    let mut diffs = Vec::new();
+    if expected.len() != actual.len() {
+        diffs.push(format!(
+            "expected {} items, got {}",
+            expected.len(),
+            actual.len()
+        ));
+    }
+    for (idx, (e, a)) in expected.iter().zip(actual.iter()).enumerate() {
+        let a = ExpectedItem::from(a);
+        let mut check = |field: &str, e: String, a: String| {
+            if e != a {
+                diffs.push(format!("item {idx}: {field} expected {e:?}, got {a:?}"));
+            }
+        };
+        check("name", e.name.clone(), a.name.clone());
+        check("type", format!("{:?}", e.type_), format!("{:?}", a.type_));
+        check("size", format!("{:?}", e.size), format!("{:?}", a.size));
+        check("mtime", format!("{:?}", e.mtime), format!("{:?}", a.mtime));
+        if let Some(url) = &e.url {
+            check("url", url.clone(), a.url.clone().unwrap_or_default());
+        }
+    }
+    diffs
+}
+
+/// Run a case against one parser, returning failure reasons (empty for pass).
+fn run_case(parser: &dyn Parser, expected: &Expected, body: &str) -> Vec<String> {
+    let url = match Url::parse(&expected.url) {
+        Ok(url) => url,
+        Err(e) => return vec![format!("invalid url {:?}: {e}", expected.url)],
+    };
+    match try_parse_page(parser, &url, body) {
+        Ok(items) => compare_items(&expected.items, &items),
+        Err(e) => vec![format!("{e:#}")],
+    }
+}
+
+/// Run all cases in corpus directory. Returns (passed, total) cases.
+pub fn run_corpus(corpus: &Path, filter: Option<&ParserType>) -> Result<(usize, usize)> {
+    let mut cases: Vec<_> = std::fs::read_dir(corpus)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().map(|e| e == "html").unwrap_or(false))
+        .collect();
+    cases.sort();
+    let parsers: Vec<(String, Box<dyn Parser>)> = ParserType::value_variants()
+        .iter()
+        .filter(|p| {
+            filter
+                .map(|f| parser_name(f) == parser_name(p))
+                .unwrap_or(true)
+        })
+        .map(|p| (parser_name(p), p.build()))
+        .collect();
+
+    let (mut passed, mut total) = (0, 0);
+    for case in cases {
+        let case_name = case.file_stem().unwrap().to_string_lossy().to_string();
+        let expected_path = case.with_extension("json");
+        let expected: Expected = match std::fs::read_to_string(&expected_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|s| Ok(serde_json::from_str(&s)?))
+        {
+            Ok(e) => e,
+            Err(e) => {
+                println!("SKIP {case_name}: cannot load {:?}: {e}", expected_path);
+                continue;
+            }
+        };
+        let candidates: Vec<_> = parsers
+            .iter()
+            .filter(|(name, _)| expected.parser.as_ref().map(|p| p == name).unwrap_or(true))
+            .collect();
+        if candidates.is_empty() {
+            continue;
+        }
+        let body = std::fs::read_to_string(&case)?;
+        total += 1;
+        let mut case_passed = false;
+        for (name, parser) in candidates {
+            let diffs = run_case(parser.as_ref(), &expected, &body);
+            if diffs.is_empty() {
+                case_passed = true;
+                println!("PASS {case_name} [{name}]");
+            } else {
+                println!("FAIL {case_name} [{name}]");
+                for diff in diffs {
+                    println!("    {diff}");
+                }
+            }
+        }
+        if case_passed {
+            passed += 1;
+        }
+    }
+    Ok((passed, total))
+}
+
+pub fn test_parsers(args: &TestParsersArgs) -> ! {
+    let (passed, total) = run_corpus(&args.corpus, args.parser.as_ref()).unwrap();
+    println!("{passed}/{total} cases passed");
+    std::process::exit(if passed == total { 0 } else { 1 });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_corpus() {
+        assert_eq!(run_corpus(Path::new("corpus"), None).unwrap(), (5, 5));
+    }
+
+    #[test]
+    fn test_mismatch() {
+        let expected: Expected = serde_json::from_str(
+            r#"{
+                "url": "http://localhost/",
+                "items": [{"name": "a b", "type": "file", "size": 1024, "mtime": "2023-01-01 00:00:00"}]
+            }"#,
+        )
+        .unwrap();
+        let body = r#"<html><body><pre><a href="../">../</a>
+<a href="a%20b">a b</a>                                                01-Jan-2023 00:00                1025
+</pre></body></html>"#;
+        let nginx = ParserType::Nginx.build();
+        assert_eq!(
+            run_case(nginx.as_ref(), &expected, body),
+            vec!["item 0: size expected \"Some(1024)\", got \"Some(1025)\""]
+        );
+        // apache f2 parser panics as #indexlist is not found
+        let apache = ParserType::ApacheF2.build();
+        assert!(run_case(apache.as_ref(), &expected, body)[0].starts_with("panicked"));
+    }
+}
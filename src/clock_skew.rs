@@ -0,0 +1,129 @@
+// Upstream clock skew detection, by comparing Date header of responses with local time.
+// Mtimes of files just modified upstream are near the boundary of mtime tolerance (1 min),
+// so they are compared exactly (with "now" of upstream's clock), instead of being missed or re-downloaded.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use reqwest::header::{HeaderMap, DATE};
+use tracing::warn;
+use url::Url;
+
+use crate::{host_state::host_key, run_scope::ScopeId};
+
+/// Samples needed before trusting the estimate
+const MIN_SAMPLES: usize = 3;
+/// Only recent samples are kept
+const MAX_SAMPLES: usize = 15;
+/// Skew above this is warned. Date header is in seconds, and latency adds a bit.
+const WARN_SECONDS: i64 = 60;
+/// Files modified upstream within this window are compared exactly
+const RECENT_WINDOW_SECONDS: i64 = 300;
+
+#[derive(Debug, Default)]
+struct HostSkew {
+    /// Upstream time minus local time, in seconds
+    samples: Vec<i64>,
+    warned: bool,
+}
+
+impl HostSkew {
+    fn estimate(&self) -> Option<i64> {
+        if self.samples.len() < MIN_SAMPLES {
+            return None;
+        }
+        let mut samples = self.samples.clone();
+        samples.sort();
+        Some(samples[samples.len() / 2])
+    }
+}
+
+/// Global as responses are received deep inside utils. Keyed by scope of run (see run_scope.rs) and host.
+static SKEWS: Mutex<Option<HashMap<(ScopeId, String), HostSkew>>> = Mutex::new(None);
+
+fn observe_at(url: &Url, headers: &HeaderMap, now: DateTime<Utc>) {
+    let Some(date) = headers
+        .get(DATE)
+        .and_then(|d| d.to_str().ok())
+        .and_then(|d| DateTime::parse_from_rfc2822(d).ok())
+    else {
+        return;
+    };
+    let host = host_key(url);
+    let mut skews = SKEWS.lock().unwrap();
+    let skew = skews
+        .get_or_insert_with(HashMap::new)
+        .entry((ScopeId::current(), host.clone()))
+        .or_default();
+    if skew.samples.len() >= MAX_SAMPLES {
+        skew.samples.remove(0);
+    }
+    skew.samples
+        .push((date.with_timezone(&Utc) - now).num_seconds());
+    if let Some(estimate) = skew.estimate() {
+        if estimate.abs() > WARN_SECONDS && !skew.warned {
+            skew.warned = true;
+            warn!(
+                "Clock of {} is {}s {} local clock (by Date header). Mtimes of files modified recently are compared with upstream's clock.",
+                host,
+                estimate.abs(),
+                if estimate > 0 { "ahead of" } else { "behind" }
+            );
+        }
+    }
+}
+
+/// Observe Date header of a response from url
+pub fn observe(url: &Url, headers: &HeaderMap) {
+    observe_at(url, headers, Utc::now());
+}
+
+/// Estimated skew of upstream clock (upstream minus local), 0 if unknown
+pub fn skew(url: &Url) -> Duration {
+    let skews = SKEWS.lock().unwrap();
+    let estimate = skews
+        .as_ref()
+        .and_then(|s| s.get(&(ScopeId::current(), host_key(url))))
+        .and_then(|s| s.estimate());
+    Duration::seconds(estimate.unwrap_or(0))
+}
+
+pub fn forget(scope: ScopeId) {
+    if let Some(skews) = SKEWS.lock().unwrap().as_mut() {
+        skews.retain(|(s, _), _| *s != scope);
+    }
+}
+
+/// If remote mtime is so recent (by upstream's clock) that the file may be modified again within mtime tolerance
+pub fn is_recent(url: &Url, mtime: DateTime<Utc>) -> bool {
+    let upstream_now = Utc::now() + skew(url);
+    (upstream_now - mtime).num_seconds() < RECENT_WINDOW_SECONDS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_skew() {
+        let url = Url::parse("http://skew.example.com/debian/").unwrap();
+        let now = DateTime::parse_from_rfc3339("2024-03-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let mut headers = HeaderMap::new();
+        headers.insert(DATE, "Fri, 01 Mar 2024 00:10:00 GMT".parse().unwrap());
+        observe_at(&url, &headers, now);
+        observe_at(&url, &headers, now);
+        // not enough samples
+        assert_eq!(skew(&url), Duration::zero());
+        observe_at(&url, &headers, now + Duration::seconds(2));
+        observe_at(&url, &HeaderMap::new(), now);
+        assert_eq!(skew(&url), Duration::seconds(600));
+
+        // upstream is 10 min ahead, so a file modified 7 min later than local now is still recent
+        assert!(is_recent(&url, Utc::now() + Duration::minutes(7)));
+        assert!(!is_recent(&url, Utc::now() - Duration::minutes(1)));
+        let other = Url::parse("http://other.example.com/").unwrap();
+        assert!(!is_recent(&other, Utc::now() - Duration::minutes(6)));
+    }
+}
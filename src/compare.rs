@@ -1,13 +1,135 @@
-use std::path::Path;
+use std::{fmt::Display, path::Path};
 
-use chrono::{DateTime, FixedOffset, Utc};
+use chrono::{DateTime, Duration, FixedOffset, NaiveDateTime, Timelike, Utc};
+use serde::{Deserialize, Serialize};
 use tracing::{debug, warn};
 
 use crate::{
+    clock_skew,
     listing::{FileSize, FileType, ListItem},
     utils::{self, naive_to_utc},
 };
 
+/// Remote mtime of a file as listed (naive) and normalized to UTC, with the offset used for it,
+/// so that mtime mismatches could be told from logs and reports alone
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RemoteMtime {
+    pub listed: NaiveDateTime,
+    pub utc: DateTime<Utc>,
+    /// Like "+08:00", or None if unknown (taken as UTC, with a tolerance of 24 hours)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<String>,
+}
+
+impl RemoteMtime {
+    pub fn new(listed: NaiveDateTime, offset: Option<FixedOffset>) -> Self {
+        Self {
+            listed,
+            utc: naive_to_utc(&listed, offset),
+            offset: offset.map(|o| o.to_string()),
+        }
+    }
+}
+
+impl Display for RemoteMtime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} as listed, {} in UTC (offset {})",
+            self.listed,
+            self.utc.format("%Y-%m-%dT%H:%M:%SZ"),
+            self.offset.as_deref().unwrap_or("unknown")
+        )
+    }
+}
+
+/// Why a file is (going to be) downloaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DownloadReason {
+    /// Not existing locally
+    New,
+    /// Not existing locally, and it is required by extensions (apt/yum packages)
+    ForcedByExtension,
+    /// Local one is a directory (or others) but remote is a file
+    TypeChange,
+    SizeMismatch,
+    MtimeMismatch,
+    /// Up to date, but a compressed sibling (like Packages.gz of Packages.xz) is updated
+    SiblingUpdated,
+    /// Fetched again by `repair` command regardless of local one
+    Repair,
+    /// Local one differs from checksum given by upstream
+    ChecksumMismatch,
+}
+
+impl Display for DownloadReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            DownloadReason::New => "new",
+            DownloadReason::ForcedByExtension => "forced by extension",
+            DownloadReason::TypeChange => "type change",
+            DownloadReason::SizeMismatch => "size mismatch",
+            DownloadReason::MtimeMismatch => "mtime mismatch",
+            DownloadReason::SiblingUpdated => "sibling updated",
+            DownloadReason::Repair => "repair",
+            DownloadReason::ChecksumMismatch => "checksum mismatch",
+        };
+        write!(f, "{reason}")
+    }
+}
+
+/// How a file is compared with local one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CompareTier {
+    /// Precise size (and mtime) in listing
+    List,
+    /// Humanized size (like "1.2M", within 2 units) and mtime in listing
+    Humanized,
+    /// Size and mtime in HEAD response
+    Head,
+    /// SHA-256 given by upstream
+    Checksum,
+    /// Up-to-date HEAD result cached in extended attributes (see validator_cache.rs)
+    Cached,
+}
+
+impl std::fmt::Display for CompareTier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            CompareTier::List => "list",
+            CompareTier::Humanized => "humanized",
+            CompareTier::Head => "head",
+            CompareTier::Checksum => "checksum",
+            CompareTier::Cached => "cached",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl CompareTier {
+    /// Tier of comparing with listing only
+    pub fn of_list(item: &ListItem) -> Self {
+        match item.size {
+            Some(FileSize::Precise(_)) | None => CompareTier::List,
+            Some(_) => CompareTier::Humanized,
+        }
+    }
+}
+
+/// Humanized sizes of large files (like "3.4G") are too rough to tell an update, whose tolerance spans hundreds of MB
+const UNRELIABLE_TOLERANCE: u64 = 100 * 1000 * 1000;
+
+pub fn is_size_unreliable(size: Option<FileSize>) -> bool {
+    size.is_some_and(|s| s.tolerance() > UNRELIABLE_TOLERANCE)
+}
+
+/// Listing has neither size nor mtime of a file (like some sitemaps), so it tells nothing of a change
+pub fn is_unknown_by_list(item: &ListItem) -> bool {
+    item.size.is_none() && item.mtime.is_none()
+}
+
 pub fn compare_filetype(fstype: std::fs::FileType, tsumugu_type: FileType) -> bool {
     match tsumugu_type {
         FileType::File => fstype.is_file(),
@@ -15,18 +137,28 @@ pub fn compare_filetype(fstype: std::fs::FileType, tsumugu_type: FileType) -> bo
     }
 }
 
+/// Compare mtime exactly, at resolution of remote one (listings often show minutes only).
+/// Offset is remote mtime minus local mtime.
+fn is_exact_mtime_mismatch(remote: &NaiveDateTime, offset: Duration) -> bool {
+    if remote.second() == 0 && remote.nanosecond() == 0 {
+        offset > Duration::zero() || offset <= -Duration::minutes(1)
+    } else {
+        offset.num_seconds() != 0
+    }
+}
+
 pub fn should_download_by_list(
     path: &Path,
     remote: &ListItem,
     remote_timezone: Option<FixedOffset>,
     skip_if_exists: bool,
     size_only: bool,
-) -> bool {
+) -> Option<DownloadReason> {
     let local_metadata = match path.metadata() {
         Ok(m) => {
             if skip_if_exists || remote.skip_check {
                 debug!("Skipping {:?} because it exists", path);
-                return false;
+                return None;
             }
             m
         }
@@ -34,25 +166,31 @@ pub fn should_download_by_list(
             if e.kind() != std::io::ErrorKind::NotFound {
                 warn!("Failed to get metadata of {:?}: {:?}", path, e);
             }
-            return true;
+            return Some(if remote.skip_check {
+                DownloadReason::ForcedByExtension
+            } else {
+                DownloadReason::New
+            });
         }
     };
     if !compare_filetype(local_metadata.file_type(), remote.type_) {
         // TODO: delete old file which type is not correct
         warn!("Type mismatch: {:?} remote {:?}", path, remote.type_);
-        return true;
+        return Some(DownloadReason::TypeChange);
     }
     let local_size = local_metadata.len();
-    let is_size_match = match remote.size.unwrap_or(FileSize::Precise(0)) {
-        FileSize::Precise(size) => local_size == size,
+    let is_size_match = match remote.size {
+        // Unknown, compared by mtime only (if any)
+        None => true,
+        Some(FileSize::Precise(size)) => local_size == size,
         // A very rough size check is used here,
         // as it looks like size returned by server may not be very accurate
-        FileSize::HumanizedBinary(size, unit) => {
+        Some(FileSize::HumanizedBinary(size, unit)) => {
             let base = 1024_f64.powf(unit.get_exp().into());
             let lsize = local_size as f64 / base;
             (lsize - size).abs() < 2.0
         }
-        FileSize::HumanizedDecimal(size, unit) => {
+        Some(FileSize::HumanizedDecimal(size, unit)) => {
             let base = 1000_f64.powf(unit.get_exp().into());
             let lsize = local_size as f64 / base;
             (lsize - size).abs() < 2.0
@@ -63,11 +201,21 @@ pub fn should_download_by_list(
             "Size mismatch: {:?} local {:?} remote {:?}",
             path, local_size, remote.size
         );
-        return true;
+        return Some(DownloadReason::SizeMismatch);
     }
     if size_only {
-        return false;
+        return None;
     }
+    let Some(remote_naive_mtime) = remote.mtime else {
+        match remote.size {
+            Some(_) => debug!("No remote mtime, compared by size only: {:?}", path),
+            None => debug!(
+                "No remote size or mtime, not compared by listing: {:?}",
+                path
+            ),
+        }
+        return None;
+    };
     let local_mtime: DateTime<Utc> = match local_metadata.modified() {
         Ok(m) => m,
         Err(_) => {
@@ -76,18 +224,33 @@ pub fn should_download_by_list(
         }
     }
     .into();
-    let remote_mtime = naive_to_utc(&remote.mtime, remote_timezone);
+    let remote_mtime = naive_to_utc(&remote_naive_mtime, remote_timezone);
     let offset = remote_mtime - local_mtime;
-    debug!("DateTime offset: {:?} {:?}", path, offset);
-    match remote_timezone {
+    debug!(
+        "Mtime of {:?}: remote {}, local {}, differing by {}s",
+        path,
+        RemoteMtime::new(remote_naive_mtime, remote_timezone),
+        local_mtime.format("%Y-%m-%dT%H:%M:%SZ"),
+        offset.num_seconds()
+    );
+    let is_mtime_mismatch = match remote_timezone {
         None => {
             // allow an offset to up to 24hrs
             offset.num_hours().abs() > 24
         }
+        // files modified just now upstream could be modified again within the tolerance
+        Some(_) if clock_skew::is_recent(&remote.url, remote_mtime) => {
+            is_exact_mtime_mismatch(&remote_naive_mtime, offset)
+        }
         Some(_) => {
             // allow an offset up to 1min
             offset.num_minutes().abs() > 1
         }
+    };
+    if is_mtime_mismatch {
+        Some(DownloadReason::MtimeMismatch)
+    } else {
+        None
     }
 }
 
@@ -95,7 +258,7 @@ pub fn should_download_by_head(
     path: &Path,
     resp: &reqwest::blocking::Response,
     size_only: bool,
-) -> bool {
+) -> Option<DownloadReason> {
     // Construct a valid "ListItem" and pass to should_download_by_list
     debug!("Checking {:?} by HEAD: {:?}", path, resp);
     let item = ListItem {
@@ -116,9 +279,176 @@ pub fn should_download_by_head(
                 .unwrap(),
         )),
         mtime: utils::get_blocking_response_mtime(resp)
-            .unwrap()
-            .naive_utc(),
+            .ok()
+            .map(|m| m.naive_utc()),
+        original_href: None,
+        sha256: None,
         skip_check: false,
     };
     should_download_by_list(path, &item, FixedOffset::east_opt(0), false, size_only)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_size_unreliable() {
+        use crate::listing::SizeUnit;
+        assert!(!is_size_unreliable(None));
+        assert!(!is_size_unreliable(Some(FileSize::Precise(u64::MAX))));
+        assert!(!is_size_unreliable(Some(FileSize::HumanizedBinary(
+            900.0,
+            SizeUnit::M
+        ))));
+        assert!(is_size_unreliable(Some(FileSize::HumanizedDecimal(
+            1.2,
+            SizeUnit::G
+        ))));
+    }
+
+    #[test]
+    fn test_exact_mtime_mismatch() {
+        let minute =
+            NaiveDateTime::parse_from_str("2024-03-01 10:05:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        assert!(!is_exact_mtime_mismatch(&minute, Duration::seconds(-42)));
+        assert!(is_exact_mtime_mismatch(&minute, Duration::seconds(-60)));
+        assert!(is_exact_mtime_mismatch(&minute, Duration::seconds(30)));
+        let second =
+            NaiveDateTime::parse_from_str("2024-03-01 10:05:42", "%Y-%m-%d %H:%M:%S").unwrap();
+        assert!(!is_exact_mtime_mismatch(&second, Duration::zero()));
+        assert!(is_exact_mtime_mismatch(&second, Duration::seconds(-30)));
+    }
+
+    #[test]
+    fn test_no_remote_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, "aaaa").unwrap();
+        let item = |size| {
+            ListItem::new(
+                url::Url::parse("http://localhost/a.txt").unwrap(),
+                "a.txt".to_string(),
+                FileType::File,
+                Some(FileSize::Precise(size)),
+                None,
+            )
+        };
+        let timezone = FixedOffset::east_opt(0);
+        // compared by size only, instead of with an epoch mtime
+        assert_eq!(
+            should_download_by_list(&path, &item(4), timezone, false, false),
+            None
+        );
+        assert_eq!(
+            should_download_by_list(&path, &item(5), timezone, false, false),
+            Some(DownloadReason::SizeMismatch)
+        );
+    }
+
+    #[test]
+    fn test_no_remote_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, "aaaa").unwrap();
+        let listed =
+            NaiveDateTime::parse_from_str("2024-03-01 10:05:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        filetime::set_file_mtime(
+            &path,
+            filetime::FileTime::from_unix_time(listed.and_utc().timestamp(), 0),
+        )
+        .unwrap();
+        let item = |mtime| {
+            ListItem::new(
+                url::Url::parse("http://localhost/a.txt").unwrap(),
+                "a.txt".to_string(),
+                FileType::File,
+                None,
+                mtime,
+            )
+        };
+        let timezone = FixedOffset::east_opt(0);
+        let reason = |item: &ListItem| should_download_by_list(&path, item, timezone, false, false);
+        // compared by mtime only, instead of with a zero size
+        assert!(!is_unknown_by_list(&item(Some(listed))));
+        assert_eq!(reason(&item(Some(listed))), None);
+        assert_eq!(
+            reason(&item(Some(listed + Duration::hours(1)))),
+            Some(DownloadReason::MtimeMismatch)
+        );
+        // Nothing to compare by listing (left to HEAD by sync)
+        assert!(is_unknown_by_list(&item(None)));
+        assert_eq!(reason(&item(None)), None);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reason(&item(None)), Some(DownloadReason::New));
+    }
+
+    #[test]
+    fn test_download_reason() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        let listed =
+            NaiveDateTime::parse_from_str("2024-03-01 10:05:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let item = |size, mtime| {
+            ListItem::new(
+                url::Url::parse("http://localhost/a.txt").unwrap(),
+                "a.txt".to_string(),
+                FileType::File,
+                Some(FileSize::Precise(size)),
+                Some(mtime),
+            )
+        };
+        let timezone = FixedOffset::east_opt(0);
+        let reason = |item: &ListItem| should_download_by_list(&path, item, timezone, false, false);
+
+        assert_eq!(reason(&item(4, listed)), Some(DownloadReason::New));
+        let mut forced = item(4, listed);
+        forced.skip_check = true;
+        assert_eq!(reason(&forced), Some(DownloadReason::ForcedByExtension));
+
+        std::fs::create_dir(&path).unwrap();
+        assert_eq!(reason(&item(4, listed)), Some(DownloadReason::TypeChange));
+        std::fs::remove_dir(&path).unwrap();
+
+        std::fs::write(&path, "aaaa").unwrap();
+        filetime::set_file_mtime(
+            &path,
+            filetime::FileTime::from_unix_time(listed.and_utc().timestamp(), 0),
+        )
+        .unwrap();
+        assert_eq!(reason(&item(4, listed)), None);
+        assert_eq!(reason(&item(5, listed)), Some(DownloadReason::SizeMismatch));
+        assert_eq!(
+            reason(&item(4, listed + Duration::hours(1))),
+            Some(DownloadReason::MtimeMismatch)
+        );
+        // Existing files are never compared with skip_if_exists
+        assert_eq!(
+            should_download_by_list(&path, &item(5, listed), timezone, true, false),
+            None
+        );
+
+        // Kebab-case in reports
+        assert_eq!(
+            serde_json::to_string(&DownloadReason::ForcedByExtension).unwrap(),
+            "\"forced-by-extension\""
+        );
+        assert_eq!(DownloadReason::MtimeMismatch.to_string(), "mtime mismatch");
+    }
+
+    #[test]
+    fn test_remote_mtime() {
+        let listed =
+            NaiveDateTime::parse_from_str("2024-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mtime = RemoteMtime::new(listed, FixedOffset::east_opt(8 * 3600));
+        assert_eq!(
+            mtime.to_string(),
+            "2024-01-01 08:00:00 as listed, 2024-01-01T00:00:00Z in UTC (offset +08:00)"
+        );
+        let mtime = RemoteMtime::new(listed, None);
+        assert!(mtime.to_string().ends_with("(offset unknown)"));
+        let json = serde_json::to_string(&mtime).unwrap();
+        assert!(!json.contains("offset"));
+        assert_eq!(serde_json::from_str::<RemoteMtime>(&json).unwrap(), mtime);
+    }
+}
@@ -0,0 +1,687 @@
+// Config file (TOML) for batch mode: each [[profile]] is a sync with its own options.
+// Options in a profile are the same as `sync` command line flags (like `parser = "apache-f2"`,
+// `exclude = ["^debug/"]`), and are parsed by clap, so they behave exactly as on command line.
+// Keys shared by many profiles could be put in `[defaults]`, or in `[template.<name>]` (or another profile)
+// which is inherited with `extends = "<name>"`. `{name}` in string values is replaced with profile name,
+// and `${VAR}` (or `${VAR:-default}`) with environment variable, so the same file works on different hosts.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{error::ContextKind, error::ContextValue, ArgAction, CommandFactory, Parser};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::SyncArgs;
+
+fn default_weight() -> u64 {
+    1
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchConfig {
+    /// Total bandwidth (bytes/s) of all running profiles, split by bandwidth weights
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+    /// Total threads of running profiles, split by threads weights (overrides threads in profiles)
+    #[serde(default)]
+    pub threads: Option<usize>,
+    /// How many profiles run at the same time (default: all)
+    #[serde(default)]
+    pub parallel: Option<usize>,
+    /// Log a notice when a newer tsumugu release exists (daemon mode only, disabled by default)
+    #[serde(default)]
+    pub version_check: bool,
+    /// Default log filter (like "info" or "warn,tsumugu=info"), overridden by --log and extended by RUST_LOG
+    #[serde(default)]
+    pub log: Option<String>,
+}
+
+/// What batch does when a profile fails (exits with non-zero code, or panics)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnFailure {
+    /// Log it, and go on with other profiles
+    #[default]
+    Continue,
+    /// Exit the whole process with exit code of the profile right away
+    Abort,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Profile {
+    pub name: String,
+    pub upstream: String,
+    pub local: String,
+    /// Weight for both bandwidth and threads shares
+    #[serde(default = "default_weight")]
+    pub weight: u64,
+    #[serde(default)]
+    pub bandwidth_weight: Option<u64>,
+    #[serde(default)]
+    pub threads_weight: Option<u64>,
+    #[serde(default)]
+    pub on_failure: OnFailure,
+    /// Other keys are sync options
+    #[serde(flatten)]
+    pub options: toml::Table,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub batch: BatchConfig,
+    #[serde(rename = "profile", default)]
+    pub profiles: Vec<Profile>,
+}
+
+fn option_to_args(key: &str, value: &toml::Value, argv: &mut Vec<String>) -> Result<()> {
+    let flag = format!("--{}", key.replace('_', "-"));
+    match value {
+        toml::Value::Boolean(true) => argv.push(flag),
+        toml::Value::Boolean(false) => {}
+        toml::Value::String(s) => argv.extend([flag, s.clone()]),
+        toml::Value::Integer(i) => argv.extend([flag, i.to_string()]),
+        toml::Value::Float(f) => argv.extend([flag, f.to_string()]),
+        toml::Value::Array(values) => {
+            for value in values {
+                if value.is_array() {
+                    bail!("nested array is not supported for {key}");
+                }
+                option_to_args(key, value, argv)?;
+            }
+        }
+        _ => bail!("unsupported value for {key}: {value}"),
+    }
+    Ok(())
+}
+
+/// Keys of base not in table are inherited. Values (including arrays) are replaced as a whole, not merged.
+fn inherit(table: &mut toml::Table, base: &toml::Table) {
+    for (key, value) in base {
+        if key != "name" && key != "extends" {
+            table.entry(key).or_insert_with(|| value.clone());
+        }
+    }
+}
+
+/// Replace `${VAR}` with environment variable, or with default in `${VAR:-default}` if it is unset or empty.
+/// `$${` is kept as `${`, and other `$` (like regex anchors) are left as is.
+fn interpolate_env(s: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String> {
+    let mut result = String::new();
+    let mut rest = s;
+    while let Some(pos) = rest.find('$') {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            result.push_str("${");
+            rest = after;
+            continue;
+        }
+        let Some(after) = rest.strip_prefix("${") else {
+            result.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let end = after
+            .find('}')
+            .ok_or_else(|| anyhow!("unclosed ${{ in {:?}", s))?;
+        let (var, default) = match after[..end].split_once(":-") {
+            Some((var, default)) => (var, Some(default)),
+            None => (&after[..end], None),
+        };
+        if var.is_empty() {
+            bail!("empty variable name in {:?}", s);
+        }
+        let value = match (lookup(var), default) {
+            (Some(v), Some(default)) if v.is_empty() => default.to_string(),
+            (Some(v), _) => v,
+            (None, Some(default)) => default.to_string(),
+            (None, None) => bail!("environment variable {} is not set", var),
+        };
+        result.push_str(&value);
+        rest = &after[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Interpolate environment variables, and replace `{name}` in string values (including those in arrays)
+fn substitute(value: &mut toml::Value, name: &str) -> Result<()> {
+    match value {
+        toml::Value::String(s) => {
+            *s = interpolate_env(s, &|var| std::env::var(var).ok())?.replace("{name}", name);
+        }
+        toml::Value::Array(values) => {
+            for value in values {
+                substitute(value, name)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn take_table(raw: &mut toml::Table, key: &str) -> Result<toml::Table> {
+    match raw.remove(key) {
+        None => Ok(toml::Table::new()),
+        Some(toml::Value::Table(table)) => Ok(table),
+        Some(_) => bail!("{key} must be a table"),
+    }
+}
+
+/// Resolve `[defaults]`, `[template.<name>]` and `extends` of raw config, so that every profile has all its keys.
+/// Precedence (high to low): profile itself, profiles or templates it extends (nearest first), defaults.
+fn resolve(mut raw: toml::Table) -> Result<toml::Table> {
+    let defaults = take_table(&mut raw, "defaults")?;
+    let templates = take_table(&mut raw, "template")?;
+    let Some(toml::Value::Array(profiles)) = raw.get("profile") else {
+        return Ok(raw);
+    };
+    let named: HashMap<&str, &toml::Table> = profiles
+        .iter()
+        .filter_map(|p| p.as_table())
+        .filter_map(|p| Some((p.get("name")?.as_str()?, p)))
+        .collect();
+    let mut resolved = Vec::new();
+    for profile in profiles {
+        let Some(mut table) = profile.as_table().cloned() else {
+            // left to deserialization to report
+            resolved.push(profile.clone());
+            continue;
+        };
+        let name = table
+            .get("name")
+            .and_then(|n| n.as_str())
+            .unwrap_or_default()
+            .to_string();
+        let mut chain = vec![name.clone()];
+        let mut extends = table.get("extends").cloned();
+        while let Some(base_name) = extends {
+            let base_name = base_name
+                .as_str()
+                .ok_or_else(|| anyhow!("extends of {} must be a string", chain.join(" -> ")))?;
+            if chain.iter().any(|n| n == base_name) {
+                bail!("Cyclic extends: {} -> {}", chain.join(" -> "), base_name);
+            }
+            let base = templates
+                .get(base_name)
+                .and_then(|t| t.as_table())
+                .or_else(|| named.get(base_name).copied())
+                .ok_or_else(|| {
+                    anyhow!(
+                        "{} extends {}, which is neither a template nor a profile",
+                        chain.last().unwrap(),
+                        base_name
+                    )
+                })?;
+            inherit(&mut table, base);
+            chain.push(base_name.to_string());
+            extends = base.get("extends").cloned();
+        }
+        inherit(&mut table, &defaults);
+        table.remove("extends");
+        for (key, value) in table.iter_mut() {
+            substitute(value, &name)
+                .with_context(|| format!("Invalid value of {} in profile {}", key, name))?;
+        }
+        resolved.push(toml::Value::Table(table));
+    }
+    raw.insert("profile".to_string(), toml::Value::Array(resolved));
+    Ok(raw)
+}
+
+impl Profile {
+    pub fn bandwidth_weight(&self) -> u64 {
+        self.bandwidth_weight.unwrap_or(self.weight)
+    }
+
+    pub fn threads_weight(&self) -> u64 {
+        self.threads_weight.unwrap_or(self.weight)
+    }
+
+    /// Command line equivalent of this profile
+    pub fn to_argv(&self) -> Result<Vec<String>> {
+        let mut argv = vec!["sync".to_string()];
+        for (key, value) in &self.options {
+            option_to_args(key, value, &mut argv)?;
+        }
+        argv.extend(["--".to_string(), self.upstream.clone(), self.local.clone()]);
+        Ok(argv)
+    }
+
+    pub fn to_sync_args(&self) -> Result<SyncArgs> {
+        SyncArgs::try_parse_from(self.to_argv()?)
+            .map_err(|e| anyhow!("{}", clap_message(&e)))
+            .with_context(|| format!("Invalid options in profile {}", self.name))
+    }
+
+    /// Key of this profile which is the cause of clap error, like "parser" for "--parser <PARSER>"
+    fn error_key(&self, e: &clap::Error) -> Option<String> {
+        let Some(ContextValue::String(arg)) = e.get(ContextKind::InvalidArg) else {
+            return None;
+        };
+        let arg = arg.split([' ', '=']).next()?;
+        if let Some(flag) = arg.strip_prefix("--") {
+            self.options
+                .keys()
+                .find(|key| key.replace('_', "-") == flag)
+                .cloned()
+        } else {
+            // positional args: <UPSTREAM> and <LOCAL>
+            Some(arg.trim_matches(['<', '>']).to_lowercase())
+        }
+    }
+}
+
+/// Error message of clap, without the hint of --help which does not apply to config
+fn clap_message(e: &clap::Error) -> String {
+    let rendered = e.render().to_string();
+    rendered
+        .lines()
+        .filter(|l| !l.starts_with("For more information"))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim_end()
+        .to_string()
+}
+
+/// Profiles with positions of them and their keys in source, for error messages
+#[derive(Deserialize)]
+struct ProfileSpans {
+    #[serde(rename = "profile", default)]
+    profiles: Vec<toml::Spanned<BTreeMap<toml::Spanned<String>, toml::Value>>>,
+}
+
+/// 1-based line and column of byte offset in source
+fn position(source: &str, offset: usize) -> String {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+    format!("line {line}, column {column}")
+}
+
+impl Config {
+    /// Parse and validate config. Errors point to line and column of the offending key.
+    pub fn parse(s: &str) -> Result<Self> {
+        let config: Self = toml::Value::Table(resolve(toml::from_str(s)?)?).try_into()?;
+        let spans: ProfileSpans = toml::from_str(s)?;
+        let mut names = std::collections::HashSet::new();
+        for (profile, spanned) in config.profiles.iter().zip(&spans.profiles) {
+            let at = position(s, spanned.span().start);
+            if !names.insert(&profile.name) {
+                bail!("Duplicated profile name {} at {}", profile.name, at);
+            }
+            let argv = profile.to_argv().with_context(|| {
+                format!("Invalid options in profile {} at {}", profile.name, at)
+            })?;
+            if let Err(e) = SyncArgs::try_parse_from(argv) {
+                let at = match profile.error_key(&e) {
+                    Some(key) => match spanned.get_ref().keys().find(|k| *k.get_ref() == key) {
+                        Some(k) => format!("{} (key {})", position(s, k.span().start), key),
+                        None => format!("{} (inherited key {})", at, key),
+                    },
+                    None => at,
+                };
+                bail!(
+                    "Invalid options in profile {} at {}: {}",
+                    profile.name,
+                    at,
+                    clap_message(&e)
+                );
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let s = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config {:?}", path))?;
+        Self::parse(&s).with_context(|| format!("Failed to parse config {:?}", path))
+    }
+}
+
+/// JSON Schema of a sync option, from its clap definition
+fn option_schema(arg: &clap::Arg) -> Value {
+    let mut schema = Map::new();
+    let possible_values = arg.get_possible_values();
+    let value_type = arg.get_value_parser().type_id();
+    let value = if matches!(arg.get_action(), ArgAction::SetTrue) {
+        json!({ "type": "boolean" })
+    } else if !possible_values.is_empty() {
+        let names: Vec<_> = possible_values.iter().map(|v| v.get_name()).collect();
+        json!({ "enum": names })
+    } else if [
+        std::any::TypeId::of::<u64>(),
+        std::any::TypeId::of::<usize>(),
+    ]
+    .iter()
+    .any(|t| value_type == *t)
+    {
+        json!({ "type": "integer", "minimum": 0 })
+    } else if value_type == std::any::TypeId::of::<f64>() {
+        json!({ "type": "number" })
+    } else {
+        json!({ "type": "string" })
+    };
+    if matches!(arg.get_action(), ArgAction::Append) {
+        // A single value is also accepted for options supporting multiple
+        schema.insert(
+            "anyOf".to_string(),
+            json!([value, { "type": "array", "items": value }]),
+        );
+    } else if let Value::Object(value) = value {
+        schema.extend(value);
+    }
+    if let Some(help) = arg.get_help() {
+        schema.insert("description".to_string(), json!(help.to_string()));
+    }
+    Value::Object(schema)
+}
+
+/// JSON Schema (draft 2020-12) of config file. Sync options are generated from `sync` command line flags.
+pub fn schema() -> Value {
+    let mut profile_properties = Map::new();
+    profile_properties.insert(
+        "name".to_string(),
+        json!({ "type": "string", "description": "Unique name of profile" }),
+    );
+    profile_properties.insert(
+        "upstream".to_string(),
+        json!({ "type": "string", "description": "The upstream URL" }),
+    );
+    profile_properties.insert(
+        "local".to_string(),
+        json!({ "type": "string", "description": "The local directory" }),
+    );
+    for (key, description) in [
+        ("weight", "Weight for both bandwidth and threads shares"),
+        (
+            "bandwidth_weight",
+            "Weight for bandwidth share (default: weight)",
+        ),
+        (
+            "threads_weight",
+            "Weight for threads share (default: weight)",
+        ),
+    ] {
+        profile_properties.insert(
+            key.to_string(),
+            json!({ "type": "integer", "minimum": 0, "description": description }),
+        );
+    }
+    profile_properties.insert(
+        "on_failure".to_string(),
+        json!({
+            "enum": ["continue", "abort"],
+            "description": "When this profile fails, go on with other profiles (default), or exit the whole batch with its exit code"
+        }),
+    );
+    profile_properties.insert(
+        "extends".to_string(),
+        json!({ "type": "string", "description": "Name of template or profile to inherit keys from" }),
+    );
+    for arg in SyncArgs::command().get_arguments() {
+        let id = arg.get_id().as_str();
+        // --record is not supported in batch
+        if arg.is_positional() || ["help", "version", "record"].contains(&id) {
+            continue;
+        }
+        let schema = option_schema(arg);
+        let dashed = id.replace('_', "-");
+        if dashed != id {
+            profile_properties.insert(dashed, schema.clone());
+        }
+        profile_properties.insert(id.to_string(), schema);
+    }
+    let table = json!({
+        "type": "object",
+        "additionalProperties": false,
+        "properties": profile_properties
+    });
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "tsumugu batch config",
+        "type": "object",
+        "additionalProperties": false,
+        "properties": {
+            "batch": {
+                "type": "object",
+                "additionalProperties": false,
+                "properties": {
+                    "bandwidth": {
+                        "type": "integer", "minimum": 0,
+                        "description": "Total bandwidth (bytes/s) of all running profiles, split by bandwidth weights"
+                    },
+                    "threads": {
+                        "type": "integer", "minimum": 1,
+                        "description": "Total threads of all profiles, split by threads weights (overrides threads in profiles)"
+                    },
+                    "parallel": {
+                        "type": "integer", "minimum": 1,
+                        "description": "How many profiles run at the same time (default: all)"
+                    },
+                    "version_check": {
+                        "type": "boolean",
+                        "description": "Log a notice when a newer tsumugu release exists (daemon mode only, disabled by default)"
+                    },
+                    "log": {
+                        "type": "string",
+                        "description": "Default log filter (like \"info\" or \"warn,tsumugu=info\"), overridden by --log and extended by RUST_LOG"
+                    }
+                }
+            },
+            "defaults": table,
+            "template": {
+                "type": "object",
+                "additionalProperties": table
+            },
+            "profile": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": ["name"],
+                    "additionalProperties": false,
+                    "properties": profile_properties
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+[batch]
+bandwidth = 10000000
+threads = 6
+
+[[profile]]
+name = "debian"
+upstream = "https://example.com/debian/"
+local = "/srv/debian"
+weight = 2
+parser = "apache-f2"
+exclude = ["^pool/", "^dists/sid/"]
+no_delete = true
+storm-threshold = 30.5
+
+[[profile]]
+name = "small"
+upstream = "https://example.com/small/"
+local = "/srv/small"
+threads_weight = 3
+on_failure = "abort"
+"#;
+
+    #[test]
+    fn test_config() {
+        let config = Config::parse(CONFIG).unwrap();
+        assert_eq!(config.batch.threads, Some(6));
+        let debian = &config.profiles[0];
+        assert_eq!(debian.bandwidth_weight(), 2);
+        assert_eq!(
+            debian.to_argv().unwrap(),
+            vec![
+                "sync",
+                "--exclude",
+                "^pool/",
+                "--exclude",
+                "^dists/sid/",
+                "--no-delete",
+                "--parser",
+                "apache-f2",
+                "--storm-threshold",
+                "30.5",
+                "--",
+                "https://example.com/debian/",
+                "/srv/debian"
+            ]
+        );
+        let args = debian.to_sync_args().unwrap();
+        assert_eq!(args.exclude.len(), 2);
+        assert!(args.no_delete);
+        let small = &config.profiles[1];
+        assert_eq!((small.bandwidth_weight(), small.threads_weight()), (1, 3));
+        assert_eq!(
+            (debian.on_failure, small.on_failure),
+            (OnFailure::Continue, OnFailure::Abort)
+        );
+        assert!(small.to_sync_args().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let bad = CONFIG.replace("parser = \"apache-f2\"", "parser = \"iis\"");
+        let e = format!("{:#}", Config::parse(&bad).unwrap_err());
+        assert!(e.contains("Invalid options in profile debian"));
+        assert!(e.contains("line 11, column 1 (key parser)"), "{e}");
+        let unknown = CONFIG.replace("threads_weight = 3", "  no_such_option = 3");
+        let e = format!("{:#}", Config::parse(&unknown).unwrap_err());
+        assert!(e.contains("line 20, column 3 (key no_such_option)"), "{e}");
+        let dup = CONFIG.replace("name = \"small\"", "name = \"debian\"");
+        let e = format!("{:#}", Config::parse(&dup).unwrap_err());
+        assert!(
+            e.contains("Duplicated profile name debian at line 16, column 1"),
+            "{e}"
+        );
+        let syntax = CONFIG.replace("weight = 2", "weight = ");
+        let e = format!("{:#}", Config::parse(&syntax).unwrap_err());
+        assert!(e.contains("line 10, column 10"), "{e}");
+    }
+
+    #[test]
+    fn test_inheritance() {
+        let config = Config::parse(
+            r#"
+[defaults]
+local = "/srv/{name}"
+threads = 2
+
+[template.debian-base]
+parser = "apache-f2"
+exclude = ["^pool/"]
+
+[[profile]]
+name = "debian"
+upstream = "https://example.com/debian/"
+extends = "debian-base"
+
+[[profile]]
+name = "debian-security"
+upstream = "https://example.com/debian-security/"
+extends = "debian"
+threads = 4
+exclude = ["^dists/"]
+"#,
+        )
+        .unwrap();
+        let debian = config.profiles[0].to_sync_args().unwrap();
+        assert_eq!(debian.local.to_str(), Some("/srv/debian"));
+        assert_eq!(debian.exclude.len(), 1);
+        assert_eq!(debian.threads, 2);
+        let security = &config.profiles[1];
+        assert_eq!(security.upstream, "https://example.com/debian-security/");
+        assert_eq!(security.local, "/srv/debian-security");
+        assert_eq!(security.options["parser"].as_str(), Some("apache-f2"));
+        assert!(security.options.get("extends").is_none());
+        let args = security.to_sync_args().unwrap();
+        assert_eq!(args.threads, 4);
+        assert!(args.exclude[0].is_match("dists/sid/"));
+        assert!(!args.exclude[0].is_match("pool/main/"));
+
+        let cyclic = r#"
+[template.a]
+extends = "b"
+[template.b]
+extends = "a"
+[[profile]]
+name = "c"
+upstream = "https://example.com/"
+local = "/srv/c"
+extends = "a"
+"#;
+        let e = format!("{:#}", Config::parse(cyclic).unwrap_err());
+        assert!(e.contains("Cyclic extends: c -> a -> b -> a"), "{e}");
+        let missing = cyclic.replace("extends = \"b\"", "extends = \"x\"");
+        let e = format!("{:#}", Config::parse(&missing).unwrap_err());
+        assert!(e.contains("a extends x, which is neither"), "{e}");
+        let bad = r#"
+[template.t]
+parser = "iis"
+[[profile]]
+name = "p"
+upstream = "https://example.com/"
+local = "/srv/p"
+extends = "t"
+"#;
+        let e = format!("{:#}", Config::parse(bad).unwrap_err());
+        assert!(e.contains("line 4, column 1 (inherited key parser)"), "{e}");
+    }
+
+    #[test]
+    fn test_interpolate_env() {
+        let lookup = |var: &str| match var {
+            "MIRROR_ROOT" => Some("/srv/mirror".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        let interpolate = |s| interpolate_env(s, &lookup);
+        assert_eq!(
+            interpolate("${MIRROR_ROOT}/debian").unwrap(),
+            "/srv/mirror/debian"
+        );
+        assert_eq!(
+            interpolate("${UPSTREAM:-https://example.com}/debian/").unwrap(),
+            "https://example.com/debian/"
+        );
+        assert_eq!(interpolate("${EMPTY:-x}${EMPTY}").unwrap(), "x");
+        assert_eq!(interpolate("changelog$").unwrap(), "changelog$");
+        assert_eq!(interpolate("$${MIRROR_ROOT}").unwrap(), "${MIRROR_ROOT}");
+        assert!(format!("{:#}", interpolate("${TOKEN}").unwrap_err()).contains("TOKEN is not set"));
+        assert!(interpolate("${MIRROR_ROOT").is_err());
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = schema();
+        let properties = &schema["properties"]["profile"]["items"]["properties"];
+        assert_eq!(properties["no_delete"]["type"], "boolean");
+        assert_eq!(properties["threads"]["type"], "integer");
+        assert_eq!(properties["storm-threshold"]["type"], "number");
+        assert!(properties["parser"]["enum"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("apache-f2")));
+        assert_eq!(properties["exclude"]["anyOf"][1]["type"], "array");
+        assert!(properties.get("record").is_none());
+        assert!(properties.get("upstream").is_some());
+    }
+}
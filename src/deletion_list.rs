@@ -0,0 +1,102 @@
+// Lists of paths deleted by sync runs (deleted-files-<date>.txt, one relative path per line, directories ending with "/"),
+// for downstream mirrors and CDN invalidation jobs. Runs on the same day (UTC) append to the same list.
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, Utc};
+
+const PREFIX: &str = "deleted-files-";
+const SUFFIX: &str = ".txt";
+
+fn list_name(date: NaiveDate) -> String {
+    format!("{PREFIX}{}{SUFFIX}", date.format("%Y-%m-%d"))
+}
+
+fn parse_list_name(name: &str) -> Option<NaiveDate> {
+    let date = name.strip_prefix(PREFIX)?.strip_suffix(SUFFIX)?;
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+}
+
+/// Append deleted paths to the list of the day, returning its path.
+/// The list is created even if nothing is deleted, so that consumers know the run has finished.
+pub fn write(dir: &Path, deleted: &[&str], now: DateTime<Utc>) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(list_name(now.date_naive()));
+    let mut content = String::new();
+    for path in deleted {
+        content.push_str(path);
+        content.push('\n');
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    file.write_all(content.as_bytes())?;
+    Ok(path)
+}
+
+/// Remove lists except the newest `keep` ones, returning removed paths
+pub fn prune(dir: &Path, keep: usize) -> Result<Vec<PathBuf>> {
+    let mut lists = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if let Some(date) = entry.file_name().to_str().and_then(parse_list_name) {
+            lists.push((date, entry.path()));
+        }
+    }
+    lists.sort();
+    let expired = lists.len().saturating_sub(keep);
+    let mut removed = Vec::new();
+    for (_, path) in lists.into_iter().take(expired) {
+        std::fs::remove_file(&path)?;
+        removed.push(path);
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().into()
+    }
+
+    #[test]
+    fn test_deletion_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write(
+            dir.path(),
+            &["pool/a.deb", "pool/old/"],
+            time("2024-03-01T01:00:00Z"),
+        )
+        .unwrap();
+        assert_eq!(path, dir.path().join("deleted-files-2024-03-01.txt"));
+        write(dir.path(), &["b.deb"], time("2024-03-01T23:00:00Z")).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "pool/a.deb\npool/old/\nb.deb\n"
+        );
+        write(dir.path(), &[], time("2024-03-02T00:00:00Z")).unwrap();
+        write(dir.path(), &[], time("2024-03-10T00:00:00Z")).unwrap();
+        std::fs::write(dir.path().join("other.txt"), "").unwrap();
+
+        let removed = prune(dir.path(), 2).unwrap();
+        assert_eq!(removed, vec![path]);
+        let mut names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "deleted-files-2024-03-02.txt",
+                "deleted-files-2024-03-10.txt",
+                "other.txt"
+            ]
+        );
+    }
+}
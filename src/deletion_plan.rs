@@ -0,0 +1,201 @@
+// Deletion plans: paths a sync is going to delete, saved before deleting anything (with --deletion-plan),
+// so that a run interrupted (or stopped by --max-delete) during cleanup could be finished by
+// `tsumugu apply-deletions <plan>` without listing upstream again. The plan is removed when cleanup completes.
+
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+/// Version of plan format, bumped when a field is renamed, removed or changes its meaning
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// For plans written before schema_version is added
+fn default_schema_version() -> u32 {
+    1
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeletionPlan {
+    #[serde(default = "default_schema_version")]
+    pub schema_version: u32,
+    /// The local directory
+    pub local: PathBuf,
+    pub created_at: DateTime<Utc>,
+    /// Relative to local, directories ending with "/", children before their parents
+    pub paths: Vec<String>,
+}
+
+impl DeletionPlan {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read deletion plan {:?}", path))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse deletion plan {:?}", path))
+    }
+
+    /// Refuse the plan if local directory has been synced since it was created (as recorded in state file),
+    /// as paths in it might be in upstream again
+    pub fn check_not_stale(&self, state_file: &Path) -> Result<()> {
+        let state = crate::host_state::StateFile::load(state_file)
+            .with_context(|| format!("Failed to load state file {:?}", state_file))?;
+        match state.get_local(&self.local).last_sync {
+            Some(last_sync) if last_sync > self.created_at => Err(anyhow!(
+                "Deletion plan created at {} is stale, as {:?} has been synced at {}",
+                self.created_at,
+                self.local,
+                last_sync
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Save atomically, so that an interrupted run never leaves a partial plan
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Check that relative path stays inside local directory (plans could be edited by hand)
+fn check_relative(relative: &str) -> Result<()> {
+    let path = Path::new(relative.trim_end_matches('/'));
+    if relative.is_empty()
+        || path
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+    {
+        return Err(anyhow!(
+            "{:?} is not a path inside local directory",
+            relative
+        ));
+    }
+    Ok(())
+}
+
+/// Delete paths (relative to local, directories ending with "/") in order, calling on_deleted for each.
+/// At most max_delete paths are deleted (missing ones are not counted), returning 25 if there are more,
+/// 4 if any fails, and 0 otherwise. Directories are deleted only if empty, and a path is skipped if its type changed.
+pub fn apply(
+    local: &Path,
+    paths: &[String],
+    max_delete: usize,
+    dry_run: bool,
+    on_deleted: &mut dyn FnMut(&str),
+) -> i32 {
+    let mut exit_code = 0;
+    let mut del_cnt = 0;
+    for relative in paths {
+        if let Err(e) = check_relative(relative) {
+            error!("Not deleting: {:?}", e);
+            exit_code = 4;
+            continue;
+        }
+        let path = local.join(relative.trim_end_matches('/'));
+        let Ok(metadata) = path.symlink_metadata() else {
+            // Deleted by an earlier run
+            continue;
+        };
+        let is_dir = relative.ends_with('/');
+        if metadata.is_dir() != is_dir {
+            warn!("{:?} has changed its type, not deleting", path);
+            continue;
+        }
+        // always make sure that we are deleting the right thing
+        if del_cnt >= max_delete {
+            info!("Exceeding max delete count, aborting");
+            // exit with 25 to indicate that the deletion has been aborted
+            // this is the same as rsync
+            return 25;
+        }
+        del_cnt += 1;
+        if dry_run {
+            info!("Dry run, not deleting {:?}", path);
+            on_deleted(relative);
+            continue;
+        }
+
+        info!("Deleting {:?}", path);
+        let res = if is_dir {
+            std::fs::remove_dir(&path)
+        } else {
+            std::fs::remove_file(&path)
+        };
+        match res {
+            Ok(_) => on_deleted(relative),
+            Err(e) => {
+                error!("Failed to remove {:?}: {:?}", path, e);
+                exit_code = 4;
+            }
+        }
+    }
+    exit_code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path();
+        std::fs::create_dir_all(local.join("pool/old")).unwrap();
+        std::fs::write(local.join("pool/old/a.deb"), "a").unwrap();
+        std::fs::write(local.join("pool/b.deb"), "b").unwrap();
+        std::fs::create_dir(local.join("c.deb")).unwrap();
+
+        let paths: Vec<String> = [
+            "pool/old/a.deb",
+            "gone.deb",
+            "../outside",
+            "pool/old/",
+            "c.deb",
+            "pool/b.deb",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        let mut deleted = Vec::new();
+        // 3 are to be deleted, as gone.deb is missing and c.deb is a directory now
+        assert_eq!(
+            apply(local, &paths, 2, false, &mut |p| deleted
+                .push(p.to_string())),
+            25
+        );
+        assert_eq!(deleted, ["pool/old/a.deb", "pool/old/"]);
+        assert!(local.join("pool/b.deb").exists());
+
+        // Resumed
+        deleted.clear();
+        assert_eq!(
+            apply(local, &paths, 2, false, &mut |p| deleted
+                .push(p.to_string())),
+            4
+        );
+        assert_eq!(deleted, ["pool/b.deb"]);
+        assert!(local.join("c.deb").is_dir());
+
+        let plan = DeletionPlan {
+            schema_version: SCHEMA_VERSION,
+            local: local.to_path_buf(),
+            created_at: Utc::now(),
+            paths,
+        };
+        let path = local.join("plan.json");
+        plan.save(&path).unwrap();
+        assert_eq!(DeletionPlan::load(&path).unwrap(), plan);
+
+        let state_file = local.join("state.json");
+        plan.check_not_stale(&state_file).unwrap();
+        crate::host_state::update_local(&state_file, local, |state| {
+            state.last_sync = Some(Utc::now())
+        })
+        .unwrap();
+        assert!(plan.check_not_stale(&state_file).is_err());
+    }
+}
@@ -0,0 +1,189 @@
+// Typed errors of listing, downloading and extensions, with URL or path of what fails, and whether
+// retrying could help. They are wrapped in anyhow::Error like other errors, and could be told apart
+// with downcast_ref() (see is_retryable(), used by utils::again() for requests, listings and downloads).
+// Extensions run on downloaded files and are never retried. Invalid options (like of a parser) stay
+// plain anyhow errors, as they are reported before anything is requested.
+
+use std::path::PathBuf;
+
+use reqwest::StatusCode;
+use thiserror::Error;
+use url::Url;
+
+#[derive(Debug, Error)]
+pub enum ListError {
+    #[error("Listing of {url} looks truncated (unterminated <table> or <pre>) with no items")]
+    Truncated { url: Url },
+    #[error(
+        "Listing of {url} has no items and seems rendered by JavaScript (found {marker}). \
+         Use --parser embedded-json if its data is in a JSON <script> tag, or list with its API instead"
+    )]
+    ScriptRendered { url: Url, marker: &'static str },
+    #[error("panicked on {url}: {message}")]
+    Panicked { url: Url, message: String },
+    #[error("This parser does not support parsing a single page")]
+    Unsupported,
+    #[error("Pages of {url} link back to {page}")]
+    PageLoop { url: Url, page: Url },
+    #[error("{url} has more than {max} pages")]
+    TooManyPages { url: Url, max: usize },
+    #[error("Cannot find {element} in listing of {url}")]
+    MissingElement { url: Url, element: &'static str },
+    #[error("No array of files found in {url} ({place})")]
+    NoFiles { url: Url, place: &'static str },
+    #[error("{url} is not under {base}")]
+    NotUnder { url: Url, base: Url },
+    #[error("{url} cannot be a base")]
+    CannotBeBase { url: Url },
+    #[error("rsync --list-only {url} failed ({status}): {stderr}")]
+    RsyncFailed {
+        url: String,
+        status: std::process::ExitStatus,
+        stderr: String,
+    },
+}
+
+impl ListError {
+    pub fn is_retryable(&self) -> bool {
+        // A truncated page (or an error page served as a listing) could be complete next time, and
+        // rsync fails for network errors as well, while others are about what upstream serves
+        matches!(
+            self,
+            ListError::Truncated { .. }
+                | ListError::MissingElement { .. }
+                | ListError::RsyncFailed { .. }
+        )
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DownloadError {
+    #[error("SHA-256 mismatch of {url}: expected {expected}, got {actual}")]
+    ChecksumMismatch {
+        url: Url,
+        expected: String,
+        actual: String,
+    },
+    /// Size of a repaired file differs from Content-Length or listing
+    #[error("Size mismatch of {url}: expected {expected} bytes, got {actual}")]
+    SizeMismatch {
+        url: Url,
+        expected: u64,
+        actual: u64,
+    },
+    /// Transfer rate stays below --min-speed for --min-speed-time. Only checked when there is another
+    /// mirror, which is switched to instead of retrying the slow one
+    #[error("transfer rate ({rate} B/s) of {url} is too slow")]
+    SlowTransfer { url: Url, rate: u64 },
+    #[error("Failed to get mtime of {url}")]
+    NoMtime {
+        url: Url,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("Failed to create {path:?}")]
+    CreateTmp {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+impl DownloadError {
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            // Corrupted in transfer, or caught in the middle of an update
+            DownloadError::ChecksumMismatch { .. } | DownloadError::SizeMismatch { .. } => true,
+            DownloadError::SlowTransfer { .. }
+            | DownloadError::NoMtime { .. }
+            | DownloadError::CreateTmp { .. } => false,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ExtensionError {
+    #[error("Cannot find debian root of {path:?} ({reason})")]
+    NoDebianRoot { path: PathBuf, reason: String },
+}
+
+/// If retrying could help: false for typed errors known to be permanent, and responses telling a file
+/// does not exist (404 Not Found and 410 Gone). Others (like timeouts and 5xx) are taken as transient.
+pub fn is_retryable(e: &anyhow::Error) -> bool {
+    e.chain().all(|cause| {
+        if let Some(e) = cause.downcast_ref::<ListError>() {
+            return e.is_retryable();
+        }
+        if let Some(e) = cause.downcast_ref::<DownloadError>() {
+            return e.is_retryable();
+        }
+        !matches!(
+            cause
+                .downcast_ref::<reqwest::Error>()
+                .and_then(|e| e.status()),
+            Some(StatusCode::NOT_FOUND | StatusCode::GONE)
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn test_is_retryable() {
+        let url = Url::parse("http://localhost/pool/").unwrap();
+        assert!(is_retryable(&anyhow::anyhow!("connection reset")));
+        assert!(is_retryable(
+            &ListError::Truncated { url: url.clone() }.into()
+        ));
+        let e: anyhow::Error = ListError::ScriptRendered {
+            url: url.clone(),
+            marker: "<noscript>",
+        }
+        .into();
+        assert!(!is_retryable(&e));
+        // Also when wrapped with context
+        let e = Err::<(), _>(e).context("Failed to list").unwrap_err();
+        assert!(!is_retryable(&e));
+        assert!(matches!(
+            e.downcast_ref::<ListError>(),
+            Some(ListError::ScriptRendered { .. })
+        ));
+
+        let e: anyhow::Error = DownloadError::ChecksumMismatch {
+            url: url.clone(),
+            expected: "ab".to_string(),
+            actual: "cd".to_string(),
+        }
+        .into();
+        assert!(is_retryable(&e));
+        assert_eq!(
+            e.to_string(),
+            "SHA-256 mismatch of http://localhost/pool/: expected ab, got cd"
+        );
+        assert!(!is_retryable(
+            &DownloadError::SlowTransfer {
+                url: url.clone(),
+                rate: 1
+            }
+            .into()
+        ));
+        assert!(is_retryable(
+            &ListError::MissingElement {
+                url: url.clone(),
+                element: "<tbody>"
+            }
+            .into()
+        ));
+        assert!(!is_retryable(
+            &ListError::NotUnder {
+                url,
+                base: Url::parse("http://localhost/debian/").unwrap()
+            }
+            .into()
+        ));
+    }
+}
@@ -0,0 +1,140 @@
+// Events of a sync run (listing, skipping, downloading, deleting and finishing), for consumers other than
+// progress bars and logs: `--events-file` streams them as JSON lines (to a file or FIFO) for alternative
+// UIs like a TUI or web status page.
+
+use std::{io::Write, path::Path, sync::mpsc, thread::JoinHandle};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{compare::DownloadReason, sync_stats::SkipReason, sync_stats::StatsSnapshot};
+
+/// Paths are relative to local directory, and directories end with "/"
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum SyncEvent {
+    ListingStarted {
+        path: String,
+        url: String,
+    },
+    ItemSkipped {
+        path: String,
+        reason: SkipReason,
+    },
+    /// Sent at most once per second per file, and when the file is fully received
+    DownloadProgress {
+        path: String,
+        downloaded: u64,
+        total: u64,
+    },
+    /// Downloaded (or would be downloaded in dry run)
+    Downloaded {
+        path: String,
+        reason: DownloadReason,
+        size: Option<u64>,
+    },
+    DownloadFailed {
+        path: String,
+        error: String,
+    },
+    /// Deleted (or would be deleted in dry run)
+    Deleted {
+        path: String,
+    },
+    RunFinished {
+        exit_code: i32,
+        stats: StatsSnapshot,
+    },
+}
+
+/// Events waiting to be written. When the file (like a FIFO) is read slowly, sync threads wait for it.
+const QUEUE_SIZE: usize = 1024;
+
+/// Where events go. Events are not even built when nobody listens.
+#[derive(Debug, Clone, Default)]
+pub struct EventSink {
+    sender: Option<mpsc::SyncSender<SyncEvent>>,
+}
+
+impl EventSink {
+    pub fn emit(&self, event: impl FnOnce() -> SyncEvent) {
+        if let Some(sender) = &self.sender {
+            // Writer gone (failed to write): nobody is interested anymore
+            let _ = sender.send(event());
+        }
+    }
+
+    /// Write events as JSON lines to path in a background thread, which ends when all clones of sink are dropped
+    pub fn to_file(path: &Path) -> Result<(Self, JoinHandle<()>)> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open events file {:?}", path))?;
+        let (sender, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        let path = path.to_path_buf();
+        let handle = std::thread::spawn(move || {
+            let mut file = std::io::LineWriter::new(file);
+            for event in rx {
+                let res = serde_json::to_string(&event)
+                    .map_err(std::io::Error::from)
+                    .and_then(|line| writeln!(file, "{line}"));
+                if let Err(e) = res {
+                    warn!("Failed to write events to {:?}: {:?}", path, e);
+                    return;
+                }
+            }
+        });
+        Ok((
+            Self {
+                sender: Some(sender),
+            },
+            handle,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events() {
+        // Nothing is built without listeners
+        EventSink::default().emit(|| unreachable!());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        let (sink, handle) = EventSink::to_file(&path).unwrap();
+        let cloned = sink.clone();
+        std::thread::spawn(move || {
+            cloned.emit(|| SyncEvent::Deleted {
+                path: "pool/old/".to_string(),
+            })
+        })
+        .join()
+        .unwrap();
+        sink.emit(|| SyncEvent::ItemSkipped {
+            path: "README".to_string(),
+            reason: SkipReason::UpToDate,
+        });
+        // More than the queue holds
+        for _ in 0..QUEUE_SIZE * 2 {
+            sink.emit(|| SyncEvent::RunFinished {
+                exit_code: 0,
+                stats: StatsSnapshot::default(),
+            });
+        }
+        drop(sink);
+        handle.join().unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2 + QUEUE_SIZE * 2);
+        assert_eq!(lines[0], r#"{"event":"deleted","path":"pool/old/"}"#);
+        assert_eq!(
+            lines[1],
+            r#"{"event":"item-skipped","path":"README","reason":"up-to-date"}"#
+        );
+    }
+}
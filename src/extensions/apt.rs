@@ -3,6 +3,9 @@ use std::path::{Path, PathBuf};
 use tracing::warn;
 use url::Url;
 
+use super::ChecksumType;
+use crate::error::ExtensionError;
+
 pub fn is_apt_package(p: &Path) -> bool {
     // check if basename is Packages
     let basename = p.file_name().unwrap().to_str().unwrap();
@@ -20,6 +23,27 @@ pub fn is_apt_package(p: &Path) -> bool {
     false
 }
 
+/// Packages index in any compression under dists/, as read by audit-local
+pub fn is_apt_packages_index(p: &Path) -> bool {
+    let name = p.file_name().and_then(|n| n.to_str());
+    matches!(name, Some("Packages" | "Packages.gz" | "Packages.xz"))
+        && p.ancestors()
+            .any(|a| a.file_name().is_some_and(|n| n == "dists"))
+}
+
+/// Everything under dists/ (Release, Packages, Contents, i18n, etc.) is metadata of APT repository
+pub fn is_apt_metadata(relative: &str) -> bool {
+    relative.split('/').any(|c| c == "dists")
+}
+
+/// Files synced in stage 2 of Debian ftpsync: dists/, indices/ and ls-lR listings
+pub fn is_ftpsync_stage2(relative: &str) -> bool {
+    let name = relative.rsplit('/').next().unwrap_or(relative);
+    is_apt_metadata(relative)
+        || relative.split('/').next() == Some("indices")
+        || name.starts_with("ls-lR")
+}
+
 // In every iter packages_path and packages_url be updated to their parents
 // When they reach the dists directory, return the root of debian
 // Otherwise when one of them reach the root, return error
@@ -30,23 +54,26 @@ fn get_debian_root(
 ) -> Result<(PathBuf, Vec<String>, Url)> {
     fn pop(p: &mut PathBuf, r: Option<&mut Vec<String>>, u: &mut Url) -> Result<()> {
         if !p.pop() {
-            return Err(anyhow::anyhow!(
-                "Cannot find debian root (path can not be popped, path = {:?})",
-                p
-            ));
+            return Err(ExtensionError::NoDebianRoot {
+                path: p.clone(),
+                reason: "path can not be popped".to_string(),
+            }
+            .into());
         }
         if u.path() == "/" {
-            return Err(anyhow::anyhow!(
-                "Cannot find debian root (url can not be popped, url = {:?})",
-                u
-            ));
+            return Err(ExtensionError::NoDebianRoot {
+                path: p.clone(),
+                reason: format!("url can not be popped, url = {}", u),
+            }
+            .into());
         }
         if let Some(r) = r {
             if r.pop().is_none() {
-                return Err(anyhow::anyhow!(
-                    "Cannot find debian root (relative can not be popped, relative = {:?})",
-                    r
-                ));
+                return Err(ExtensionError::NoDebianRoot {
+                    path: p.clone(),
+                    reason: format!("relative can not be popped, relative = {:?}", r),
+                }
+                .into());
             }
         }
         u.path_segments_mut().unwrap().pop();
@@ -59,7 +86,7 @@ fn get_debian_root(
     pop(&mut packages_path, None, &mut packages_url)?;
     loop {
         let basename = packages_path.file_name().unwrap().to_str().unwrap();
-        let url_basename = packages_url.path_segments().unwrap().last().unwrap();
+        let url_basename = packages_url.path_segments().unwrap().next_back().unwrap();
         if basename == "dists" && url_basename == "dists" {
             // we don't wanna dists folder in return value
             pop(&mut packages_path, Some(&mut relative), &mut packages_url)?;
@@ -83,6 +110,8 @@ pub struct AptPackage {
     pub relative: Vec<String>,
     pub size: usize,
     pub filename: String,
+    /// SHA256, or SHA512 if only it is given
+    pub checksum: Option<(ChecksumType, String)>,
 }
 
 impl From<AptPackage> for super::ExtensionPackage {
@@ -100,7 +129,7 @@ pub fn parse_package(
     relative: &[String],
     packages_url: &Url,
 ) -> Result<Vec<AptPackage>> {
-    let data = std::fs::read_to_string(packages_path)?;
+    let data = super::read_metadata(packages_path)?;
     let packages = apt_parser::Packages::from(&data);
     let (_, root_relative, debian_root_url) =
         get_debian_root(packages_path, relative, packages_url)?;
@@ -109,6 +138,11 @@ pub fn parse_package(
     for package in packages {
         let pool_url = package.filename;
         let size = package.size;
+        let checksum = match (package.sha256sum, package.sha512sum) {
+            (Some(sha256), _) => Some((ChecksumType::Sha256, sha256.to_lowercase())),
+            (None, Some(sha512)) => Some((ChecksumType::Sha512, sha512.to_lowercase())),
+            _ => None,
+        };
         let url = debian_root_url.join(&pool_url)?;
 
         let mut pool_splited: Vec<String> = pool_url.split('/').map(|s| s.to_string()).collect();
@@ -122,6 +156,7 @@ pub fn parse_package(
             relative,
             size: size as usize,
             filename: basename,
+            checksum,
         })
     }
 
@@ -1,10 +1,62 @@
 use crate::SyncArgs;
-use std::path::Path;
+use anyhow::Result;
+use flate2::read::GzDecoder;
+use std::{io::Read, path::Path};
 use tracing::{info, warn};
 use url::Url;
+use xz2::read::XzDecoder;
 
-mod apt;
-mod yum;
+pub mod apt;
+pub mod yum;
+
+pub use apt::is_ftpsync_stage2;
+
+/// Names of indexes, checksums and signatures, wherever they are
+const METADATA_NAMES: &[&str] = &[
+    "index.html",
+    "SHA256SUMS",
+    "SHA512SUMS",
+    "SHA1SUMS",
+    "MD5SUMS",
+    "CHECKSUM",
+    ".treeinfo",
+    "ls-lR.gz",
+];
+const METADATA_SUFFIXES: &[&str] = &[
+    ".sha256", ".sha512", ".sha1", ".md5", ".asc", ".sig", ".sign", ".gpg", ".repo", ".key",
+];
+
+/// If the file (by relative path) is repository metadata rather than payload, for --metadata-only
+pub fn is_metadata(relative: &str) -> bool {
+    if apt::is_apt_metadata(relative) || yum::is_yum_metadata(relative) {
+        return true;
+    }
+    // PyPI simple index
+    if relative.split('/').any(|c| c == "simple") {
+        return true;
+    }
+    let name = relative.rsplit('/').next().unwrap_or(relative);
+    METADATA_NAMES.contains(&name) || METADATA_SUFFIXES.iter().any(|s| name.ends_with(s))
+}
+
+/// Checksum types of packages in metadata that are verified (like by audit-local)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChecksumType {
+    Sha256,
+    Sha512,
+}
+
+/// Read metadata file, decompressed by its extension (.gz or .xz)
+pub fn read_metadata(path: &Path) -> Result<String> {
+    let file = std::fs::File::open(path)?;
+    let mut s = String::new();
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("gz") => GzDecoder::new(file).read_to_string(&mut s)?,
+        Some("xz") => XzDecoder::new(file).read_to_string(&mut s)?,
+        _ => std::io::BufReader::new(file).read_to_string(&mut s)?,
+    };
+    Ok(s)
+}
 
 pub struct ExtensionPackage {
     pub url: Url,
@@ -50,3 +102,31 @@ pub fn extension_handler<F>(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_metadata() {
+        for path in [
+            "debian/dists/bookworm/InRelease",
+            "debian/dists/bookworm/main/binary-amd64/Packages.xz",
+            "centos/9/BaseOS/x86_64/os/repodata/repomd.xml",
+            "pypi/simple/requests/index.html",
+            "releases/v1.0/SHA256SUMS",
+            "iso/debian.iso.sha256",
+            "docker-ce.repo",
+        ] {
+            assert!(is_metadata(path), "{path}");
+        }
+        for path in [
+            "debian/pool/main/a/apt/apt_2.6.1_amd64.deb",
+            "centos/9/BaseOS/x86_64/os/Packages/bash.rpm",
+            "iso/debian.iso",
+            "packages/ab/cd/requests-2.31.0.tar.gz",
+        ] {
+            assert!(!is_metadata(path), "{path}");
+        }
+    }
+}
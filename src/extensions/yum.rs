@@ -1,10 +1,12 @@
-use std::{io::Read, path::Path};
+use std::path::Path;
 
 use anyhow::Result;
-use flate2::read::GzDecoder;
+use regex::Regex;
 use tracing::info;
 use url::Url;
 
+use super::ChecksumType;
+
 pub fn is_yum_primary_xml(p: &Path) -> bool {
     p.file_name()
         .map(|f| f.to_str().unwrap())
@@ -12,22 +14,45 @@ pub fn is_yum_primary_xml(p: &Path) -> bool {
         .unwrap_or(false)
 }
 
-// read and extract location
-pub fn read_primary_xml(p: &Path) -> Result<Vec<String>> {
-    let re = regex::Regex::new(r#"<location href="(.+?)".*/>"#).unwrap();
-    let bytes = std::fs::read(p)?;
-    let mut gzd = GzDecoder::new(&bytes[..]);
-    let mut s = String::new();
-    gzd.read_to_string(&mut s)?;
+/// Everything under repodata/ (repomd.xml, primary.xml.gz, etc.) is metadata of YUM repository
+pub fn is_yum_metadata(relative: &str) -> bool {
+    relative.split('/').any(|c| c == "repodata")
+}
 
-    let mut urls = Vec::new();
-    for line in s.lines() {
-        if let Some(caps) = re.captures(line) {
-            let url = caps.get(1).unwrap().as_str();
-            urls.push(url.to_string());
-        }
+/// A package in primary.xml
+#[derive(Debug)]
+pub struct PrimaryPackage {
+    pub location: String,
+    pub size: Option<u64>,
+    pub checksum: Option<(ChecksumType, String)>,
+}
+
+// read and extract location, size and checksum of packages
+pub fn read_primary_xml(p: &Path) -> Result<Vec<PrimaryPackage>> {
+    let location = Regex::new(r#"<location [^>]*href="([^"]+)""#).unwrap();
+    let size = Regex::new(r#"<size [^>]*package="(\d+)""#).unwrap();
+    let checksum =
+        Regex::new(r#"<checksum [^>]*type="(sha256|sha512)"[^>]*>([0-9a-fA-F]+)<"#).unwrap();
+    let s = super::read_metadata(p)?;
+
+    let mut packages = Vec::new();
+    for package in s.split("<package ").skip(1) {
+        let Some(href) = location.captures(package) else {
+            continue;
+        };
+        packages.push(PrimaryPackage {
+            location: href[1].to_string(),
+            size: size.captures(package).and_then(|c| c[1].parse().ok()),
+            checksum: checksum.captures(package).map(|c| {
+                let type_ = match &c[1] {
+                    "sha512" => ChecksumType::Sha512,
+                    _ => ChecksumType::Sha256,
+                };
+                (type_, c[2].to_lowercase())
+            }),
+        });
     }
-    Ok(urls)
+    Ok(packages)
 }
 
 #[derive(Debug)]
@@ -35,6 +60,8 @@ pub struct YumPackage {
     pub url: Url,
     pub relative: Vec<String>,
     pub filename: String,
+    pub size: Option<u64>,
+    pub checksum: Option<(ChecksumType, String)>,
 }
 
 impl From<YumPackage> for super::ExtensionPackage {
@@ -63,8 +90,8 @@ pub fn parse_package(
 
     let mut res = vec![];
     for package in packages {
-        let url = base_url.join(&package)?;
-        let splited: Vec<String> = package.split('/').map(|s| s.to_string()).collect();
+        let url = base_url.join(&package.location)?;
+        let splited: Vec<String> = package.location.split('/').map(|s| s.to_string()).collect();
         let mut relative = relative.clone();
         relative.append(&mut splited.clone());
 
@@ -73,6 +100,8 @@ pub fn parse_package(
             url,
             relative,
             filename: basename,
+            size: package.size,
+            checksum: package.checksum,
         })
     }
 
@@ -0,0 +1,186 @@
+// Budget of open file descriptors derived from RLIMIT_NOFILE, shared by all runs in this process.
+// Each download takes a tmp file and a socket. Downloads are counted in memory, and when their fds
+// would get near the limit, new downloads wait for ongoing ones to finish (backpressure), instead of
+// failing with EMFILE.
+
+use std::{
+    io,
+    sync::{Condvar, Mutex, OnceLock},
+};
+
+use tracing::{debug, info, warn};
+
+use crate::resources::open_fds;
+
+/// Kept for stdio, logs, async runtimes, listing connections and keep-alive sockets
+const RESERVED_FDS: u64 = 64;
+/// A tmp file and a connection
+const FDS_PER_DOWNLOAD: u64 = 2;
+/// Rough cost of a worker thread (HTTP clients, async runtime)
+const FDS_PER_THREAD: u64 = 8;
+
+static BUDGET: OnceLock<FdBudget> = OnceLock::new();
+
+#[derive(Debug)]
+pub struct FdBudget {
+    /// Soft limit of open files, None if unlimited or unknown
+    limit: Option<u64>,
+    /// Downloads allowed at the same time, besides fds already open when the budget is created
+    permits: usize,
+    in_use: Mutex<usize>,
+    released: Condvar,
+}
+
+/// Raise soft limit of open files to hard limit, returning the soft limit afterwards
+fn raise_nofile_limit() -> Option<u64> {
+    let mut rlim: libc::rlimit = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } != 0 {
+        return None;
+    }
+    if rlim.rlim_cur < rlim.rlim_max {
+        let raised = libc::rlimit {
+            rlim_cur: rlim.rlim_max,
+            rlim_max: rlim.rlim_max,
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+            info!(
+                "Raised open file limit (RLIMIT_NOFILE) from {} to {}",
+                rlim.rlim_cur, rlim.rlim_max
+            );
+            rlim = raised;
+        } else {
+            warn!(
+                "Failed to raise open file limit (RLIMIT_NOFILE) from {} to {}: {}",
+                rlim.rlim_cur,
+                rlim.rlim_max,
+                io::Error::last_os_error()
+            );
+        }
+    }
+    (rlim.rlim_cur != libc::RLIM_INFINITY).then_some(rlim.rlim_cur as u64)
+}
+
+impl FdBudget {
+    fn new(limit: Option<u64>, open: u64) -> Self {
+        let permits = match limit {
+            Some(limit) => {
+                (limit.saturating_sub(RESERVED_FDS + open) / FDS_PER_DOWNLOAD).max(1) as usize
+            }
+            None => usize::MAX,
+        };
+        Self {
+            limit,
+            permits,
+            in_use: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// The budget of this process. The soft limit of open files is raised to hard limit on first call.
+    pub fn global() -> &'static Self {
+        BUDGET.get_or_init(|| {
+            let limit = raise_nofile_limit();
+            Self::new(limit, open_fds().unwrap_or(0) as u64)
+        })
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    /// Warn if worker threads alone could exhaust the limit
+    pub fn check_threads(&self, threads: usize) {
+        if let Some(limit) = self.limit {
+            let needed = RESERVED_FDS + threads as u64 * (FDS_PER_THREAD + FDS_PER_DOWNLOAD);
+            if needed > limit {
+                warn!(
+                    "{} threads may need about {} open files, more than the limit ({}). Downloads would wait for each other; consider lowering --threads or raising `ulimit -n`",
+                    threads, needed, limit
+                );
+            }
+        }
+    }
+
+    /// Wait until a download could be started. The permit is returned on drop.
+    pub fn acquire(&self) -> FdPermit<'_> {
+        let mut in_use = self.in_use.lock().unwrap();
+        if *in_use >= self.permits {
+            debug!("Near open file limit, waiting for other downloads");
+        }
+        while *in_use >= self.permits {
+            in_use = self.released.wait(in_use).unwrap();
+        }
+        *in_use += 1;
+        FdPermit { budget: self }
+    }
+}
+
+#[derive(Debug)]
+pub struct FdPermit<'a> {
+    budget: &'a FdBudget,
+}
+
+impl Drop for FdPermit<'_> {
+    fn drop(&mut self) {
+        *self.budget.in_use.lock().unwrap() -= 1;
+        self.budget.released.notify_one();
+    }
+}
+
+/// If error is caused by running out of fds (EMFILE or ENFILE)
+pub fn is_fd_exhausted(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .and_then(|e| e.raw_os_error())
+            .is_some_and(|code| code == libc::EMFILE || code == libc::ENFILE)
+    })
+}
+
+/// Add a hint to error if it is caused by running out of fds
+pub fn explain(e: anyhow::Error) -> anyhow::Error {
+    if is_fd_exhausted(&e) {
+        let limit = FdBudget::global()
+            .limit()
+            .map(|l| l.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        e.context(format!(
+            "Too many open files (limit: {limit}), consider lowering --threads or raising `ulimit -n`"
+        ))
+    } else {
+        e
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fd_budget() {
+        let budget = FdBudget::new(Some(RESERVED_FDS + 2 * FDS_PER_DOWNLOAD), 0);
+        assert_eq!(budget.permits, 2);
+        // Fds open when created are not for downloads
+        let budget = FdBudget::new(Some(RESERVED_FDS + 20 + 2 * FDS_PER_DOWNLOAD), 20);
+        assert_eq!(budget.permits, 2);
+        let budget = FdBudget::new(Some(10), 0);
+        assert_eq!(budget.permits, 1);
+        let first = budget.acquire();
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                budget.acquire();
+            });
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            assert!(!waiter.is_finished());
+            drop(first);
+            waiter.join().unwrap();
+        });
+        assert_eq!(*budget.in_use.lock().unwrap(), 0);
+
+        let e = anyhow::Error::from(io::Error::from_raw_os_error(libc::EMFILE));
+        assert!(is_fd_exhausted(&e));
+        assert!(format!("{:#}", explain(e)).starts_with("Too many open files"));
+        let e = anyhow::Error::from(io::Error::from_raw_os_error(libc::ENOENT));
+        assert!(!is_fd_exhausted(&e));
+    }
+}
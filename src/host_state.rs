@@ -0,0 +1,338 @@
+// Per-host politeness state shared across runs: what we have learned about an upstream
+// (429 responses, HEAD support, concurrency it tolerates) is kept in a small JSON file,
+// so that nightly runs don't rediscover them by tripping errors against the same upstream.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::run_scope::ScopeId;
+
+/// Observations of runs by scope of run (see run_scope.rs) and host. They are global as requests are
+/// retried deep inside utils::again().
+static OBSERVATIONS: Mutex<Option<HashMap<(ScopeId, String), Observation>>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HostState {
+    /// Threads in use when upstream responded 429 (Too Many Requests) last time
+    #[serde(default)]
+    pub throttled_at_threads: Option<usize>,
+    /// If upstream supports HEAD (None for unknown)
+    #[serde(default)]
+    pub head_supported: Option<bool>,
+    /// When head_supported was last observed
+    #[serde(default)]
+    pub head_checked: Option<DateTime<Utc>>,
+    /// Threads to use next time (at most --threads)
+    #[serde(default)]
+    pub preferred_threads: Option<usize>,
+    #[serde(default)]
+    pub updated: Option<DateTime<Utc>>,
+}
+
+/// HEAD found unsupported is trusted for this long, and then probed again (as it could be a few paths
+/// only, or upstream could have changed), so that files are not compared without HEAD for good
+const HEAD_RECHECK_DAYS: i64 = 7;
+
+/// What happened to the host in this run
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Observation {
+    pub too_many_requests: usize,
+    pub head_ok: usize,
+    pub head_unsupported: usize,
+}
+
+impl HostState {
+    /// Threads to use, given threads requested by user
+    pub fn threads(&self, requested: usize) -> usize {
+        match self.preferred_threads {
+            Some(preferred) => preferred.clamp(1, requested.max(1)),
+            None => requested,
+        }
+    }
+
+    /// Learn from this run. When throttled, next run uses half of threads, otherwise threads grow back one by one.
+    pub fn learn(&mut self, requested: usize, used: usize, observation: &Observation) {
+        if observation.too_many_requests > 0 {
+            self.throttled_at_threads = Some(used);
+            self.preferred_threads = Some((used / 2).max(1));
+        } else if let Some(preferred) = self.preferred_threads {
+            self.preferred_threads = if preferred + 1 >= requested {
+                None
+            } else {
+                Some(preferred + 1)
+            };
+        }
+        if observation.head_ok > 0 {
+            self.head_supported = Some(true);
+            self.head_checked = Some(Utc::now());
+        } else if observation.head_unsupported > 0 {
+            self.head_supported = Some(false);
+            self.head_checked = Some(Utc::now());
+        }
+        self.updated = Some(Utc::now());
+    }
+
+    /// If HEAD is known to be unsupported, and checked recently enough to skip it
+    pub fn head_unsupported(&self, now: DateTime<Utc>) -> bool {
+        self.head_supported == Some(false)
+            && self
+                .head_checked
+                .is_some_and(|checked| now - checked < chrono::Duration::days(HEAD_RECHECK_DAYS))
+    }
+}
+
+/// What we know about a local directory across runs
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LocalState {
+    /// When the last sync to the directory started (a deletion plan created before it is stale)
+    #[serde(default)]
+    pub last_sync: Option<DateTime<Utc>>,
+    /// Device and inode of the directory, to refuse cleanup when it is not the same one (like not mounted)
+    #[serde(default)]
+    pub identity: Option<crate::mount_guard::DirIdentity>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct StateFile {
+    /// Keyed by host (with port if not default)
+    #[serde(default)]
+    hosts: HashMap<String, HostState>,
+    /// Keyed by canonical path of local directory
+    #[serde(default)]
+    locals: HashMap<PathBuf, LocalState>,
+}
+
+impl StateFile {
+    /// Load state file. A missing file is an empty state.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_reader(std::fs::File::open(path)?)?)
+    }
+
+    /// Save state file atomically (write to a temp file and rename).
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        serde_json::to_writer_pretty(std::fs::File::create(&tmp_path)?, self)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, host: &str) -> HostState {
+        self.hosts.get(host).cloned().unwrap_or_default()
+    }
+
+    pub fn set(&mut self, host: &str, state: HostState) {
+        self.hosts.insert(host.to_string(), state);
+    }
+
+    pub fn get_local(&self, local: &Path) -> LocalState {
+        self.locals
+            .get(&crate::utils::canonical(local))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+/// Serialize updates of state file, as profiles could finish at the same time in batch mode.
+static UPDATE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Update state file. File is reloaded to keep updates of others.
+fn update_file(path: &Path, f: impl FnOnce(&mut StateFile)) -> Result<()> {
+    let _guard = UPDATE_LOCK.lock().unwrap();
+    let mut state_file = StateFile::load(path)?;
+    f(&mut state_file);
+    state_file.save(path)
+}
+
+/// Update state of a host in state file.
+pub fn update(path: &Path, host: &str, f: impl FnOnce(&mut HostState)) -> Result<()> {
+    update_file(path, |state_file| {
+        let mut state = state_file.get(host);
+        f(&mut state);
+        state_file.set(host, state);
+    })
+}
+
+/// Update state of a local directory in state file.
+pub fn update_local(path: &Path, local: &Path, f: impl FnOnce(&mut LocalState)) -> Result<()> {
+    update_file(path, |state_file| {
+        f(state_file
+            .locals
+            .entry(crate::utils::canonical(local))
+            .or_default())
+    })
+}
+
+/// Key of upstream in state file
+pub fn host_key(url: &url::Url) -> String {
+    match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{host}:{port}"),
+        (Some(host), None) => host.to_string(),
+        (None, _) => url.as_str().to_string(),
+    }
+}
+
+fn observe(url: &url::Url, f: impl FnOnce(&mut Observation)) {
+    let mut observations = OBSERVATIONS.lock().unwrap();
+    f(observations
+        .get_or_insert_with(HashMap::new)
+        .entry((ScopeId::current(), host_key(url)))
+        .or_default());
+}
+
+fn error_status(e: &anyhow::Error) -> Option<(&url::Url, StatusCode)> {
+    let e = e.downcast_ref::<reqwest::Error>()?;
+    Some((e.url()?, e.status()?))
+}
+
+/// Observe a failed request (called on each retry)
+pub fn observe_error(e: &anyhow::Error) {
+    if let Some((url, StatusCode::TOO_MANY_REQUESTS)) = error_status(e) {
+        observe(url, |o| {
+            if o.too_many_requests == 0 {
+                warn!("{} responded 429 Too Many Requests. Fewer threads will be used next time (with --state-file).", host_key(url));
+            }
+            o.too_many_requests += 1;
+        });
+    }
+}
+
+/// Observe result of a HEAD request
+pub fn observe_head<T>(url: &url::Url, res: &Result<T>) {
+    match res {
+        Ok(_) => observe(url, |o| o.head_ok += 1),
+        Err(e) => {
+            if let Some((_, StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED)) =
+                error_status(e)
+            {
+                observe(url, |o| o.head_unsupported += 1);
+            }
+        }
+    }
+}
+
+/// What happened to the host in this run
+pub fn observation(host: &str) -> Observation {
+    OBSERVATIONS
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|o| o.get(&(ScopeId::current(), host.to_string())).copied())
+        .unwrap_or_default()
+}
+
+/// Forget observations of a run
+pub fn forget(scope: ScopeId) {
+    if let Some(observations) = OBSERVATIONS.lock().unwrap().as_mut() {
+        observations.retain(|(s, _), _| *s != scope);
+    }
+}
+
+/// Log what is remembered about the host
+pub fn show(host: &str, state: &HostState) {
+    if let Some(threads) = state.throttled_at_threads {
+        info!("{host} throttled us with {threads} threads before");
+    }
+    if let Some(threads) = state.preferred_threads {
+        info!("Using at most {threads} threads for {host} as remembered in state file");
+    }
+    if state.head_unsupported(Utc::now()) {
+        info!("{host} does not support HEAD as remembered in state file");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_learn() {
+        let mut state = HostState::default();
+        assert_eq!(state.threads(8), 8);
+        let throttled = Observation {
+            too_many_requests: 3,
+            ..Default::default()
+        };
+        state.learn(8, 8, &throttled);
+        assert_eq!(state.throttled_at_threads, Some(8));
+        assert_eq!(state.threads(8), 4);
+        state.learn(8, 4, &throttled);
+        assert_eq!(state.threads(8), 2);
+        // grow back one by one
+        state.learn(8, 2, &Observation::default());
+        assert_eq!(state.threads(8), 3);
+        // never exceed requested threads
+        assert_eq!(state.threads(2), 2);
+        state.learn(4, 3, &Observation::default());
+        assert_eq!(state.preferred_threads, None);
+        assert_eq!(state.threads(4), 4);
+
+        let unsupported = Observation {
+            head_unsupported: 1,
+            ..Default::default()
+        };
+        state.learn(4, 4, &unsupported);
+        assert_eq!(state.head_supported, Some(false));
+        let now = Utc::now();
+        assert!(state.head_unsupported(now));
+        // Probed again later, and HEAD working then is remembered
+        assert!(!state.head_unsupported(now + chrono::Duration::days(HEAD_RECHECK_DAYS)));
+        state.learn(4, 4, &Observation::default());
+        assert!(state.head_unsupported(now));
+        state.learn(
+            4,
+            4,
+            &Observation {
+                head_ok: 1,
+                ..Default::default()
+            },
+        );
+        assert!(!state.head_unsupported(now));
+        // Unknown time of check (like of an older state file)
+        state.head_supported = Some(false);
+        state.head_checked = None;
+        assert!(!state.head_unsupported(now));
+    }
+
+    #[test]
+    fn test_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let mut file = StateFile::load(&path).unwrap();
+        let url = url::Url::parse("http://localhost:1921/debian/").unwrap();
+        let host = host_key(&url);
+        assert_eq!(host, "localhost:1921");
+        assert_eq!(file.get(&host), HostState::default());
+        let state = HostState {
+            preferred_threads: Some(2),
+            ..Default::default()
+        };
+        file.set(&host, state.clone());
+        file.save(&path).unwrap();
+        assert_eq!(StateFile::load(&path).unwrap().get(&host), state);
+
+        let last_sync = Some(Utc::now());
+        std::fs::create_dir(dir.path().join("local")).unwrap();
+        update_local(&path, &dir.path().join("local/../local"), |local| {
+            local.last_sync = last_sync
+        })
+        .unwrap();
+        let file = StateFile::load(&path).unwrap();
+        assert_eq!(
+            file.get_local(&dir.path().join("local")).last_sync,
+            last_sync
+        );
+        assert_eq!(file.get(&host), state);
+    }
+}
@@ -0,0 +1,233 @@
+// CDN cache invalidation after sync: paths changed (downloaded) or deleted in this run are sent to
+// a configurable HTTP endpoint in batches, so that CDNs stop serving stale metadata right after sync.
+//
+// URL and body are templates with placeholders:
+// - {paths_json}: JSON array of paths in the batch
+// - {paths}: paths in the batch, one per line
+// - {path}: a single path (each path gets its own request)
+// Placeholders in URL are percent-encoded, except "/" and unreserved chars of paths.
+// Body is sent as JSON if it is valid JSON after rendering, otherwise as plain text.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use regex::{Captures, Regex};
+use reqwest::Method;
+use tracing::{debug, error, info};
+
+use crate::utils::{again, RetryBudget, RetryKind};
+
+const TOKEN_ENV: &str = "TSUMUGU_INVALIDATE_TOKEN";
+
+/// Chars to escape in URL, keeping paths readable
+const URL_ESCAPE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+#[derive(Debug, Clone)]
+pub struct Invalidator {
+    url: String,
+    method: Method,
+    body: Option<String>,
+    batch_size: usize,
+    path_prefix: String,
+}
+
+fn render(template: &str, batch: &[String], encode: bool) -> String {
+    // Replaced in one pass, so that paths containing placeholders are kept as is
+    let placeholder = Regex::new(r"\{(paths_json|paths|path)\}").unwrap();
+    placeholder
+        .replace_all(template, |caps: &Captures| {
+            let value = match &caps[1] {
+                "paths_json" => serde_json::to_string(batch).unwrap(),
+                "paths" => batch.join("\n"),
+                _ => batch.first().cloned().unwrap_or_default(),
+            };
+            match encode {
+                true => utf8_percent_encode(&value, URL_ESCAPE).to_string(),
+                false => value,
+            }
+        })
+        .to_string()
+}
+
+fn content_type(body: &str) -> &'static str {
+    match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(_) => "application/json",
+        Err(_) => "text/plain; charset=utf-8",
+    }
+}
+
+impl Invalidator {
+    pub fn new(
+        url: &str,
+        method: &str,
+        body: Option<&str>,
+        batch_size: usize,
+        path_prefix: &str,
+    ) -> Result<Self> {
+        let method = Method::from_bytes(method.to_uppercase().as_bytes())
+            .map_err(|_| anyhow!("Invalid HTTP method {}", method))?;
+        let per_path = url.contains("{path}") || body.is_some_and(|b| b.contains("{path}"));
+        Ok(Self {
+            url: url.to_string(),
+            method,
+            body: body.map(|b| b.to_string()),
+            batch_size: if per_path { 1 } else { batch_size.max(1) },
+            path_prefix: path_prefix.to_string(),
+        })
+    }
+
+    /// (URL, body) of requests for changed paths (relative to local directory)
+    fn requests(&self, paths: &[&str]) -> Vec<(String, Option<String>)> {
+        let paths: Vec<String> = paths
+            .iter()
+            .map(|p| format!("{}{}", self.path_prefix, p))
+            .collect();
+        paths
+            .chunks(self.batch_size)
+            .map(|batch| {
+                (
+                    render(&self.url, batch, true),
+                    self.body.as_ref().map(|b| render(b, batch, false)),
+                )
+            })
+            .collect()
+    }
+
+    /// Invalidate changed paths, retrying each request up to retry times. In dry run, requests are only logged.
+    /// A failed request does not stop the rest, and an error is returned after all are sent.
+    pub fn run(&self, user_agent: &str, paths: &[&str], dry_run: bool, retry: usize) -> Result<()> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+        let client = reqwest::blocking::Client::builder()
+            .user_agent(user_agent)
+            .timeout(Duration::from_secs(60))
+            .build()?;
+        let token = std::env::var(TOKEN_ENV).ok();
+        let requests = self.requests(paths);
+        info!(
+            "Invalidating {} paths with {} requests",
+            paths.len(),
+            requests.len()
+        );
+        let total = requests.len();
+        let mut failed = 0;
+        for (url, body) in requests {
+            if dry_run {
+                info!("Dry run, not invalidating: {} {}", self.method, url);
+                continue;
+            }
+            debug!("Invalidating: {} {}", self.method, url);
+            let send = || {
+                let mut req = client.request(self.method.clone(), &url);
+                if let Some(token) = &token {
+                    req = req.bearer_auth(token);
+                }
+                if let Some(body) = &body {
+                    req = req
+                        .header(reqwest::header::CONTENT_TYPE, content_type(body))
+                        .body(body.clone());
+                }
+                Ok(req.send()?.error_for_status()?)
+            };
+            if let Err(e) = again(send, retry, &RetryBudget::default(), RetryKind::Download) {
+                error!("Failed to invalidate with {} {}: {:?}", self.method, url, e);
+                failed += 1;
+            }
+        }
+        match failed {
+            0 => Ok(()),
+            _ => Err(anyhow!(
+                "{} of {} invalidation requests failed",
+                failed,
+                total
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requests() {
+        let paths = ["dists/stable/Release", "pool/a b.deb", "pool/old/"];
+        let invalidator = Invalidator::new(
+            "https://cdn.example.com/purge",
+            "post",
+            Some(r#"{"files": {paths_json}}"#),
+            2,
+            "/debian/",
+        )
+        .unwrap();
+        assert_eq!(invalidator.method, Method::POST);
+        let requests = invalidator.requests(&paths);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[0].1.as_deref(),
+            Some(r#"{"files": ["/debian/dists/stable/Release","/debian/pool/a b.deb"]}"#)
+        );
+        assert_eq!(
+            requests[1].1.as_deref(),
+            Some(r#"{"files": ["/debian/pool/old/"]}"#)
+        );
+
+        let invalidator = Invalidator::new(
+            "https://cdn.example.com/purge?path={path}",
+            "GET",
+            None,
+            100,
+            "/",
+        )
+        .unwrap();
+        let requests = invalidator.requests(&paths);
+        assert_eq!(requests.len(), 3);
+        assert_eq!(
+            requests[1],
+            (
+                "https://cdn.example.com/purge?path=/pool/a%20b.deb".to_string(),
+                None
+            )
+        );
+        let invalidator = Invalidator::new(
+            "https://cdn.example.com/purge",
+            "POST",
+            Some("{paths}"),
+            100,
+            "/",
+        )
+        .unwrap();
+        let requests = invalidator.requests(&["{path}", "a"]);
+        let body = requests[0].1.as_deref().unwrap();
+        assert_eq!(body, "/{path}\n/a");
+        assert_eq!(content_type(body), "text/plain; charset=utf-8");
+        assert_eq!(content_type(r#"{"files": ["/a"]}"#), "application/json");
+
+        assert!(
+            Invalidator::new("https://cdn.example.com/", "NOT A METHOD", None, 1, "/").is_err()
+        );
+    }
+
+    #[test]
+    fn test_run() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a"), "a").unwrap();
+        let addr = crate::autoindex::spawn(dir.path(), crate::autoindex::AutoindexStyle::Nginx);
+        let invalidator =
+            Invalidator::new(&format!("http://{addr}{{path}}"), "GET", None, 100, "/").unwrap();
+        // The first failed request does not stop the rest
+        let e = invalidator
+            .run("tsumugu", &["b", "a"], false, 1)
+            .unwrap_err();
+        assert_eq!(e.to_string(), "1 of 2 invalidation requests failed");
+        invalidator.run("tsumugu", &["a"], false, 1).unwrap();
+        invalidator.run("tsumugu", &["b"], true, 1).unwrap();
+    }
+}
@@ -0,0 +1,164 @@
+// Stable JSON form of listed items (`list --output json`), so that downstream tooling keeps working as fields
+// are added. Within a schema version, fields are only added; renaming, removing or changing the meaning of
+// any field bumps the version. The report, deletion plans and provenance have versions of their own.
+// The form is also accepted by --manifest with default field mappings.
+
+use chrono::{FixedOffset, TimeZone};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::listing::{FileSize, FileType, ListItem};
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ItemType {
+    File,
+    Directory,
+}
+
+/// Where the item is found
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ItemSource {
+    Listing,
+    /// By --apt-packages or --yum-packages
+    Extension,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ItemRecord {
+    /// Logical path relative to upstream (and local directory), directories ending with "/"
+    pub path: String,
+    pub url: String,
+    #[serde(rename = "type")]
+    pub type_: ItemType,
+    /// In bytes (estimated from a humanized size like "1.2M" if size_exact is false)
+    pub size: Option<u64>,
+    pub size_exact: bool,
+    /// ISO 8601 as listed, with the offset of upstream listing if known (like RemoteMtime in the report)
+    pub mtime: Option<String>,
+    /// Lowercase hex
+    pub sha256: Option<String>,
+    pub source: ItemSource,
+}
+
+impl ItemRecord {
+    /// Record of item listed in dir (relative, "" for the root), with mtime in timezone of upstream, if known
+    pub fn new(item: &ListItem, dir: &str, timezone: Option<FixedOffset>) -> Self {
+        let mut path = match dir.trim_matches('/') {
+            "" => item.name.clone(),
+            dir => format!("{}/{}", dir, item.name),
+        };
+        let type_ = match item.type_ {
+            FileType::File => ItemType::File,
+            FileType::Directory => {
+                path.push('/');
+                ItemType::Directory
+            }
+        };
+        Self {
+            path,
+            url: item.url.to_string(),
+            type_,
+            size: item.size.map(|s| s.get_estimated()),
+            size_exact: matches!(item.size, Some(FileSize::Precise(_))),
+            mtime: item.mtime.map(|t| match timezone {
+                Some(tz) => tz
+                    .from_local_datetime(&t)
+                    .unwrap()
+                    .format("%Y-%m-%dT%H:%M:%S%:z")
+                    .to_string(),
+                None => t.format("%Y-%m-%dT%H:%M:%S").to_string(),
+            }),
+            sha256: item.sha256.clone(),
+            source: match item.skip_check {
+                true => ItemSource::Extension,
+                false => ItemSource::Listing,
+            },
+        }
+    }
+}
+
+/// Output format of `list`
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// Output of `list --output json`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ItemList {
+    pub schema_version: u32,
+    /// The listed URL
+    pub url: String,
+    pub items: Vec<ItemRecord>,
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+    use url::Url;
+
+    use super::*;
+    use crate::listing::SizeUnit;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_item_record() {
+        let mtime = NaiveDateTime::parse_from_str("2024-03-01 12:30:00", "%Y-%m-%d %H:%M:%S").ok();
+        let file = ListItem::new(
+            Url::parse("https://example.com/debian/pool/a%20b.deb").unwrap(),
+            "a b.deb".to_string(),
+            FileType::File,
+            Some(FileSize::Precise(1234)),
+            mtime,
+        )
+        .with_sha256(Some("abcd".to_string()));
+        assert_eq!(
+            serde_json::to_string(&ItemRecord::new(&file, "pool/", None)).unwrap(),
+            r#"{"path":"pool/a b.deb","url":"https://example.com/debian/pool/a%20b.deb","type":"file","size":1234,"size_exact":true,"mtime":"2024-03-01T12:30:00","sha256":"abcd","source":"listing"}"#
+        );
+        let timezone = FixedOffset::east_opt(8 * 3600);
+        let record = ItemRecord::new(&file, "pool/", timezone);
+        assert_eq!(record.mtime.as_deref(), Some("2024-03-01T12:30:00+08:00"));
+        // Read by --manifest in UTC
+        let mtime = serde_json::Value::String(record.mtime.unwrap());
+        assert_eq!(
+            crate::parser::manifest::parse_mtime(&mtime),
+            NaiveDateTime::parse_from_str("2024-03-01 04:30:00", "%Y-%m-%d %H:%M:%S").ok()
+        );
+
+        let item = ListItem::new(
+            Url::parse("https://example.com/debian/pool/").unwrap(),
+            "pool".to_string(),
+            FileType::Directory,
+            Some(FileSize::HumanizedBinary(1.5, SizeUnit::K)),
+            None,
+        );
+        let record = ItemRecord::new(&item, "", None);
+        assert_eq!(record.path, "pool/");
+        assert_eq!(record.size, Some(1536));
+        assert!(!record.size_exact);
+
+        // Readable by --manifest
+        let list = ItemList {
+            schema_version: SCHEMA_VERSION,
+            url: "https://example.com/debian/".to_string(),
+            items: vec![record, ItemRecord::new(&file, "pool/", None)],
+        };
+        let http_base = Url::parse("https://mirror.example.com/debian/").unwrap();
+        let parser = crate::parser::manifest::ManifestListingParser::new(
+            http_base.clone(),
+            &[],
+            http_base.clone(),
+        );
+        let items = parser
+            .parse_page(&http_base, &serde_json::to_string(&list).unwrap())
+            .unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].name, "pool");
+    }
+}
@@ -0,0 +1,162 @@
+// Git LFS pointers served raw in place of real files (like release trees in Git repositories served over
+// HTTPS). Pointers are detected after downloading, and with --lfs-endpoint they are resolved with the batch API
+// (https://github.com/git-lfs/git-lfs/blob/main/docs/api/batch.md) into the real objects stored locally.
+
+use std::{collections::HashMap, io::Read, path::Path};
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_json::json;
+use url::Url;
+
+const VERSION_LINE: &str = "version https://git-lfs.github.com/spec/v1";
+/// Pointers are much smaller than this
+const MAX_POINTER_SIZE: u64 = 1024;
+const MEDIA_TYPE: &str = "application/vnd.git-lfs+json";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pointer {
+    /// SHA-256 of object (lowercase hex)
+    pub oid: String,
+    pub size: u64,
+}
+
+pub fn parse_pointer(content: &str) -> Option<Pointer> {
+    let mut lines = content.lines();
+    if lines.next()? != VERSION_LINE {
+        return None;
+    }
+    let (mut oid, mut size) = (None, None);
+    for line in lines {
+        let (key, value) = line.split_once(' ')?;
+        match key {
+            "oid" => oid = value.strip_prefix("sha256:"),
+            "size" => size = value.parse().ok(),
+            _ => (),
+        }
+    }
+    let oid = oid.filter(|o| o.len() == 64 && o.bytes().all(|b| b.is_ascii_hexdigit()))?;
+    Some(Pointer {
+        oid: oid.to_ascii_lowercase(),
+        size: size?,
+    })
+}
+
+/// The pointer if file at path is one
+pub fn read_pointer(path: &Path) -> Option<Pointer> {
+    let file = std::fs::File::open(path).ok()?;
+    if file.metadata().ok()?.len() > MAX_POINTER_SIZE {
+        return None;
+    }
+    let mut content = String::new();
+    file.take(MAX_POINTER_SIZE)
+        .read_to_string(&mut content)
+        .ok()?;
+    parse_pointer(&content)
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchResponse {
+    objects: Vec<BatchObject>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchObject {
+    oid: String,
+    actions: Option<BatchActions>,
+    error: Option<BatchError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchActions {
+    download: Option<BatchAction>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchAction {
+    href: Url,
+    #[serde(default)]
+    header: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchError {
+    code: u16,
+    message: String,
+}
+
+/// URL to download object of pointer from, by batch API of endpoint (like https://example.com/repo.git/info/lfs)
+pub async fn resolve(client: &reqwest::Client, endpoint: &Url, pointer: &Pointer) -> Result<Url> {
+    let batch = Url::parse(&format!(
+        "{}/objects/batch",
+        endpoint.as_str().trim_end_matches('/')
+    ))?;
+    let request = json!({
+        "operation": "download",
+        "transfers": ["basic"],
+        "objects": [{"oid": pointer.oid, "size": pointer.size}],
+        "hash_algo": "sha256",
+    });
+    let resp = client
+        .post(batch)
+        .header(reqwest::header::ACCEPT, MEDIA_TYPE)
+        .header(reqwest::header::CONTENT_TYPE, MEDIA_TYPE)
+        .body(request.to_string())
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    let resp: BatchResponse = serde_json::from_slice(&resp)?;
+    let object = resp
+        .objects
+        .into_iter()
+        .find(|o| o.oid.eq_ignore_ascii_case(&pointer.oid))
+        .ok_or_else(|| anyhow!("LFS object {} is not in batch response", pointer.oid))?;
+    if let Some(error) = object.error {
+        return Err(anyhow!(
+            "LFS object {}: {} ({})",
+            pointer.oid,
+            error.message,
+            error.code
+        ));
+    }
+    let action = object
+        .actions
+        .and_then(|a| a.download)
+        .ok_or_else(|| anyhow!("LFS object {} has no download action", pointer.oid))?;
+    if !action.header.is_empty() {
+        return Err(anyhow!(
+            "LFS object {} requires headers to download, which is not supported",
+            pointer.oid
+        ));
+    }
+    Ok(action.href)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pointer() {
+        let oid = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let pointer = format!("{VERSION_LINE}\noid sha256:{oid}\nsize 5\n");
+        assert_eq!(
+            parse_pointer(&pointer),
+            Some(Pointer {
+                oid: oid.to_string(),
+                size: 5
+            })
+        );
+        assert_eq!(parse_pointer("hello\n"), None);
+        assert_eq!(
+            parse_pointer(&format!("{VERSION_LINE}\noid sha256:abcd\nsize 5\n")),
+            None
+        );
+        assert_eq!(
+            parse_pointer(&format!("{VERSION_LINE}\noid sha256:{oid}\n")),
+            None
+        );
+    }
+}
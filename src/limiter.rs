@@ -0,0 +1,125 @@
+// Bandwidth limiter shared by concurrent syncs (profiles in batch mode).
+// Total bandwidth is split among running profiles by their weights, and shares
+// are recalculated when a profile starts or finishes, so idle bandwidth is not wasted.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Default)]
+pub struct Limiter {
+    /// Total bytes per second (None for unlimited)
+    total: Option<u64>,
+    /// Sum of weights of running profiles
+    active_weight: AtomicU64,
+}
+
+impl Limiter {
+    pub fn new(total: Option<u64>) -> Arc<Self> {
+        Arc::new(Self {
+            total,
+            active_weight: AtomicU64::new(0),
+        })
+    }
+
+    /// Register a running profile with weight, and an optional limit of its own.
+    pub fn share(self: &Arc<Self>, weight: u64, cap: Option<u64>) -> Share {
+        let weight = weight.max(1);
+        self.active_weight.fetch_add(weight, Ordering::SeqCst);
+        Share {
+            limiter: self.clone(),
+            weight,
+            cap,
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+/// Bandwidth share of a profile. Dropping it returns the share to others.
+#[derive(Debug)]
+pub struct Share {
+    limiter: Arc<Limiter>,
+    weight: u64,
+    cap: Option<u64>,
+    /// When next byte is allowed to be received
+    next_free: Mutex<Instant>,
+}
+
+impl Share {
+    /// Current rate (bytes/s) of this share, None for unlimited
+    pub fn rate(&self) -> Option<u64> {
+        let shared = self.limiter.total.map(|total| {
+            let active = self.limiter.active_weight.load(Ordering::SeqCst).max(1);
+            (total as u128 * self.weight as u128 / active as u128).max(1) as u64
+        });
+        match (shared, self.cap) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Account received bytes, returning how long the caller should wait.
+    fn reserve(&self, bytes: usize) -> Duration {
+        let Some(rate) = self.rate() else {
+            return Duration::ZERO;
+        };
+        let now = Instant::now();
+        let mut next_free = self.next_free.lock().unwrap();
+        // Don't accumulate credits when idle
+        let start = (*next_free).max(now);
+        *next_free = start + Duration::from_secs_f64(bytes as f64 / rate as f64);
+        start.saturating_duration_since(now)
+    }
+
+    /// Wait until received bytes are allowed by this share. Returns the time waited.
+    pub async fn consume(&self, bytes: usize) -> Duration {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        wait
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        self.limiter
+            .active_weight
+            .fetch_sub(self.weight, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shares() {
+        let limiter = Limiter::new(Some(300));
+        let a = limiter.share(1, None);
+        assert_eq!(a.rate(), Some(300));
+        let b = limiter.share(2, Some(150));
+        assert_eq!(a.rate(), Some(100));
+        assert_eq!(b.rate(), Some(150));
+        drop(b);
+        assert_eq!(a.rate(), Some(300));
+
+        let unlimited = Limiter::new(None);
+        assert_eq!(unlimited.share(1, None).rate(), None);
+        assert_eq!(unlimited.share(1, Some(10)).rate(), Some(10));
+    }
+
+    #[test]
+    fn test_reserve() {
+        let limiter = Limiter::new(Some(1000));
+        let share = limiter.share(1, None);
+        assert!(share.reserve(500).is_zero());
+        // 500 bytes have been received without waiting, so next 500 bytes wait for ~0.5s
+        let wait = share.reserve(500);
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+    }
+}
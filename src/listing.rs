@@ -1,23 +1,26 @@
 // Module for handling directory listing
 
-use std::fmt::Display;
+use std::{collections::HashSet, fmt::Display, path::PathBuf};
 
 use anyhow::Result;
 use chrono::{DateTime, FixedOffset, NaiveDateTime, Utc};
-use reqwest::blocking::Client;
-use tracing::{debug, info};
+use clap::ValueEnum;
+use reqwest::{blocking::Client, header};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
 use url::Url;
 
 use crate::parser;
-use crate::utils;
+use crate::regex_process::ExpandedRegex;
+use crate::utils::{self, again, RetryBudget, RetryKind};
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub enum FileType {
     File,
     Directory,
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub enum SizeUnit {
     B,
     K,
@@ -54,7 +57,7 @@ impl SizeUnit {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum FileSize {
     Precise(u64),
     /// 1024B -> 1KiB
@@ -106,6 +109,15 @@ impl FileSize {
         (numeric, unit)
     }
 
+    /// Tolerance in bytes when comparing with local size (2 units for humanized sizes)
+    pub fn tolerance(&self) -> u64 {
+        match self {
+            FileSize::Precise(_) => 0,
+            FileSize::HumanizedBinary(_, unit) => 2 * 1024_u64.pow(unit.get_exp()),
+            FileSize::HumanizedDecimal(_, unit) => 2 * 1000_u64.pow(unit.get_exp()),
+        }
+    }
+
     pub fn get_estimated(&self) -> u64 {
         match self {
             FileSize::Precise(size) => *size,
@@ -121,14 +133,24 @@ impl FileSize {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ListItem {
+    /// Where to fetch the item: href joined to listing URL.
+    /// Never derive local paths from it, as it could be unrelated to name (like `?dir=` links of DirectoryLister).
     pub url: Url,
+    /// Canonical name in local mirror (a single path component), provided by parser.
+    /// Local paths (and paths for exclusion matching) are built from names only.
     pub name: String,
     pub type_: FileType,
     pub size: Option<FileSize>,
-    /// mtime is parsed from HTML, which is the local datetime of the "server" (not necessarily localtime or UTC)
-    pub mtime: NaiveDateTime,
+    /// mtime is parsed from HTML, which is the local datetime of the "server" (not necessarily localtime or UTC).
+    /// None if the listing doesn't show it (like python http.server), and then only size is compared.
+    pub mtime: Option<NaiveDateTime>,
+    /// href exactly as in the listing page (before joining and decoding), if any.
+    /// url is joined from it without touching existing escapes, so upstream always gets the original bytes.
+    pub original_href: Option<String>,
+    /// SHA-256 (hex) of file, if provided by upstream (like APIs of Hugging Face). Downloads are verified with it.
+    pub sha256: Option<String>,
     /// Don't check size and mtime: download only if the file doesn't exist.
     /// This is expected to be set by apt/yum parser extension (parser will not use this).
     pub skip_check: bool,
@@ -140,7 +162,7 @@ impl ListItem {
         name: String,
         type_: FileType,
         size: Option<FileSize>,
-        mtime: NaiveDateTime,
+        mtime: Option<NaiveDateTime>,
     ) -> Self {
         Self {
             url,
@@ -148,9 +170,86 @@ impl ListItem {
             type_,
             size,
             mtime,
+            original_href: None,
+            sha256: None,
             skip_check: false,
         }
     }
+
+    pub fn with_original_href(mut self, href: &str) -> Self {
+        self.original_href = Some(href.to_string());
+        self
+    }
+
+    pub fn with_sha256(mut self, sha256: Option<String>) -> Self {
+        self.sha256 = sha256;
+        self
+    }
+
+    /// If name is safe to be used as a single local path component.
+    pub fn has_valid_name(&self) -> bool {
+        !self.name.is_empty()
+            && self.name != "."
+            && self.name != ".."
+            && !self.name.contains('/')
+            && !self.name.contains('\0')
+    }
+
+    /// Change type of item, keeping trailing slash of url consistent with it.
+    /// Query URLs are left as-is, as their path is not related to the item.
+    pub fn set_type(&mut self, type_: FileType) {
+        self.type_ = type_;
+        if self.url.query().is_some() {
+            return;
+        }
+        let path = self.url.path().trim_end_matches('/').to_string();
+        match type_ {
+            FileType::Directory => self.url.set_path(&format!("{path}/")),
+            FileType::File => self.url.set_path(&path),
+        }
+    }
+}
+
+/// Extensions of the same file compressed in different ways
+const COMPRESSED_EXTENSIONS: &[&str] = &[".gz", ".xz", ".bz2", ".lzma", ".zst"];
+
+/// Names (without compression extension) of repository indexes, whose compressed siblings are
+/// read together by clients. Others (like tarballs) are never grouped, as their siblings are
+/// separate payloads.
+const INDEX_NAMES: &[&str] = &["Packages", "Sources", "Index", "Release"];
+const INDEX_PREFIXES: &[&str] = &["Contents-", "Translation-", "Components-", "Commands-"];
+
+fn is_index(item: &ListItem, stem: &str) -> bool {
+    INDEX_NAMES.contains(&stem)
+        || INDEX_PREFIXES.iter().any(|p| stem.starts_with(p))
+        // yum metadata, like repodata/primary.xml.gz
+        || item.url.path().contains("/repodata/")
+}
+
+/// Group repository indexes with their compressed siblings (like Packages, Packages.gz and Packages.xz),
+/// keeping the order of first appearance. Other files are in groups of their own.
+pub fn group_compressed_siblings(items: Vec<ListItem>) -> Vec<Vec<ListItem>> {
+    let mut groups: Vec<Vec<ListItem>> = Vec::new();
+    let mut index: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
+    for item in items {
+        let stem = COMPRESSED_EXTENSIONS
+            .iter()
+            .find_map(|ext| item.name.strip_suffix(ext))
+            .unwrap_or(&item.name)
+            .to_string();
+        if !is_index(&item, &stem) {
+            groups.push(vec![item]);
+            continue;
+        }
+        match index.get(&stem) {
+            Some(&i) => groups[i].push(item),
+            None => {
+                index.insert(stem, groups.len());
+                groups.push(vec![item]);
+            }
+        }
+    }
+    groups
 }
 
 impl Display for ListItem {
@@ -159,7 +258,10 @@ impl Display for ListItem {
             Some(size) => size.to_string(),
             None => String::from("(none)"),
         };
-        let mtime_str = self.mtime.format("%Y-%m-%d %H:%M:%S").to_string();
+        let mtime_str = match self.mtime {
+            Some(mtime) => mtime.format("%Y-%m-%d %H:%M:%S").to_string(),
+            None => String::from("(none)"),
+        };
         write!(
             f,
             "{} {:?} {} {} {}",
@@ -168,6 +270,218 @@ impl Display for ListItem {
     }
 }
 
+/// How to get local names of items from their hrefs, as servers encode hrefs differently.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum NameDecoding {
+    /// Names given by parser (percent-decoded href, keeping "+", for most parsers).
+    Auto,
+    /// Percent-decode the last segment of href, keeping "+".
+    PercentOnly,
+    /// Like form data: "+" is a space, then percent-decode.
+    FormUrlencoded,
+    /// Use the last segment of href as is (for raw UTF-8 hrefs, or names containing "%").
+    None,
+}
+
+/// Last segment of href, None if href is not a plain path
+fn href_segment(href: &str) -> Option<&str> {
+    if href.contains(['?', '#']) {
+        return None;
+    }
+    href.trim_end_matches('/').rsplit('/').next()
+}
+
+/// Name from href (as in listing page) by decoding, None if href is not a plain path (like "?dir=a/b")
+fn name_from_href(href: &str, decoding: NameDecoding) -> Option<String> {
+    let segment = href_segment(href)?;
+    let name = match decoding {
+        NameDecoding::Auto => return None,
+        NameDecoding::PercentOnly => percent_encoding::percent_decode_str(segment)
+            .decode_utf8_lossy()
+            .to_string(),
+        NameDecoding::FormUrlencoded => {
+            percent_encoding::percent_decode_str(&segment.replace('+', " "))
+                .decode_utf8_lossy()
+                .to_string()
+        }
+        NameDecoding::None => segment.to_string(),
+    };
+    Some(name)
+}
+
+/// Rename items with the decoding strategy, for items with original href of a plain path.
+/// Others (and all with Auto) keep names from parser.
+pub fn apply_name_decoding(items: Vec<ListItem>, decoding: NameDecoding) -> Vec<ListItem> {
+    if decoding == NameDecoding::Auto {
+        return items;
+    }
+    items
+        .into_iter()
+        .map(|mut item| {
+            if let Some(name) = item
+                .original_href
+                .as_deref()
+                .and_then(|href| name_from_href(href, decoding))
+            {
+                if name != item.name {
+                    debug!("Renaming {:?} to {:?} by {:?}", item.name, name, decoding);
+                    item.name = name;
+                }
+            }
+            item
+        })
+        .collect()
+}
+
+/// Check that names of items linked by plain paths round-trip to their hrefs with the decoding,
+/// i.e. the name is what decoding the last segment of href gives (percent-decoding for Auto).
+/// A mismatch (like "+" decoded as a space by a parser) means the local file is not named as upstream
+/// has it, and could be deleted and downloaded again by a sync decoding it differently.
+/// Mismatched items are logged and renamed to the decoded href (URLs keep hrefs as is),
+/// and items whose names collide with an earlier item of the same listing are skipped.
+pub fn check_name_encoding(items: Vec<ListItem>, decoding: NameDecoding) -> Vec<ListItem> {
+    let decoding = match decoding {
+        NameDecoding::Auto => NameDecoding::PercentOnly,
+        decoding => decoding,
+    };
+    let mut seen = HashSet::new();
+    items
+        .into_iter()
+        .filter_map(|mut item| {
+            if let Some(href) = item.original_href.clone() {
+                if let Some(segment) = href_segment(&href) {
+                    let is_utf8 = decoding == NameDecoding::None
+                        || percent_encoding::percent_decode_str(segment)
+                            .decode_utf8()
+                            .is_ok();
+                    match name_from_href(&href, decoding) {
+                        Some(_) if !is_utf8 => warn!(
+                            "Href {:?} is not UTF-8 after decoding, keeping name {:?}",
+                            href, item.name
+                        ),
+                        Some(decoded) if decoded != item.name => {
+                            warn!(
+                                "Name {:?} does not round-trip to href {:?} by {:?}, using {:?}",
+                                item.name, href, decoding, decoded
+                            );
+                            item.name = decoded;
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            if !seen.insert(item.name.clone()) {
+                warn!(
+                    "Skipping {} as its name {:?} collides with another item",
+                    item.url, item.name
+                );
+                return None;
+            }
+            Some(item)
+        })
+        .collect()
+}
+
+/// How to find directories linked without trailing slash, which look like files by their href.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum SlashlessDirs {
+    /// Trust hrefs.
+    Off,
+    /// Send HEAD to files without size (like "-" in size column). Treat them as directories if redirected to URL with trailing slash, or served as HTML.
+    Head,
+}
+
+fn head_is_directory(client: &Client, item: &ListItem) -> Result<bool> {
+    let resp = utils::head(client, item.url.clone())?;
+    if resp.url().path().ends_with('/') {
+        return Ok(true);
+    }
+    let is_html = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("text/html"))
+        .unwrap_or(false);
+    let name = item.name.to_lowercase();
+    Ok(is_html && !name.ends_with(".html") && !name.ends_with(".htm"))
+}
+
+/// Fix directories misclassified as files, as some index scripts link directories without trailing slash.
+/// Missing size only tells which files to check, and they are kept as files if HEAD fails.
+pub fn detect_slashless_dirs(
+    client: &Client,
+    items: Vec<ListItem>,
+    mode: SlashlessDirs,
+    retry: usize,
+    budget: &RetryBudget,
+) -> Vec<ListItem> {
+    if mode == SlashlessDirs::Off {
+        return items;
+    }
+    items
+        .into_iter()
+        .map(|mut item| {
+            if item.type_ != FileType::File || item.size.is_some() {
+                return item;
+            }
+            let is_dir = match again(
+                || head_is_directory(client, &item),
+                retry,
+                budget,
+                RetryKind::Listing,
+            ) {
+                Ok(is_dir) => is_dir,
+                Err(e) => {
+                    warn!("Failed to check if {} is a directory: {:?}", item.url, e);
+                    false
+                }
+            };
+            if is_dir {
+                info!(
+                    "{} is linked without trailing slash, treated as directory",
+                    item.url
+                );
+                item.set_type(FileType::Directory);
+            }
+            item
+        })
+        .collect()
+}
+
+/// Force type of items whose relative path (like "debian/dists") matches given regexes.
+/// --force-file wins if both match.
+pub fn apply_type_overrides(
+    items: Vec<ListItem>,
+    relative: &str,
+    force_dir: &[ExpandedRegex],
+    force_file: &[ExpandedRegex],
+) -> Vec<ListItem> {
+    if force_dir.is_empty() && force_file.is_empty() {
+        return items;
+    }
+    items
+        .into_iter()
+        .map(|mut item| {
+            let path = PathBuf::from(relative).join(&item.name);
+            let path = path.to_string_lossy();
+            let forced = if force_file.iter().any(|r| r.is_match(&path)) {
+                Some(FileType::File)
+            } else if force_dir.iter().any(|r| r.is_match(&path)) {
+                Some(FileType::Directory)
+            } else {
+                None
+            };
+            if let Some(type_) = forced {
+                if type_ != item.type_ {
+                    info!("{} is forced to be {:?}", path, type_);
+                    item.set_type(type_);
+                }
+            }
+            item
+        })
+        .collect()
+}
+
 pub fn guess_remote_timezone(
     parser: &dyn parser::Parser,
     client: &Client,
@@ -192,13 +506,16 @@ pub fn guess_remote_timezone(
     debug!("{:?}", list);
     for item in list {
         if item.url == file_url {
+            let Some(item_mtime) = item.mtime else {
+                return Err(anyhow::anyhow!("Listing has no mtime of file"));
+            };
             // access file_url with HEAD
             let resp = client.head(file_url).send()?;
             let mtime = utils::get_blocking_response_mtime(&resp)?;
 
             // compare how many hours are there between mtime (FixedOffset) and item.mtime (Naive)
             // assuming that Naive one is UTC
-            let unknown_mtime = DateTime::<Utc>::from_naive_utc_and_offset(item.mtime, Utc);
+            let unknown_mtime = DateTime::<Utc>::from_naive_utc_and_offset(item_mtime, Utc);
             let offset = unknown_mtime - mtime;
             let hrs = (offset.num_minutes() as f64 / 60.0).round() as i32;
 
@@ -206,10 +523,260 @@ pub fn guess_remote_timezone(
             let timezone = FixedOffset::east_opt(hrs * 3600).unwrap();
             info!(
                 "html time: {:?}, head time: {:?}, timezone: {:?}",
-                item.mtime, mtime, timezone
+                item_mtime, mtime, timezone
             );
             return Ok(timezone);
         }
     }
     Err(anyhow::anyhow!("File not found"))
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        autoindex::{self, AutoindexStyle},
+        parser::{nginx::NginxListingParser, Parser},
+    };
+
+    use super::*;
+
+    // Some PHP index scripts link directories like this
+    const SLASHLESS_PAGE: &str = r#"<html><body><pre><a href="../">../</a>
+<a href="dists">dists</a>                                              10-Mar-2024 04:45                   -
+<a href="pool">pool</a>                                               10-Mar-2024 04:45                   -
+<a href="index.html">index.html</a>                                         10-Mar-2024 04:45                   -
+<a href="README">README</a>                                             10-Mar-2024 04:45                1024
+</pre></body></html>"#;
+
+    fn parse(base: &str) -> Vec<ListItem> {
+        NginxListingParser::default()
+            .parse_page(&Url::parse(base).unwrap(), SLASHLESS_PAGE)
+            .unwrap()
+    }
+
+    fn types(items: &[ListItem]) -> Vec<FileType> {
+        items.iter().map(|i| i.type_).collect()
+    }
+
+    #[test]
+    fn test_slashless_head_failed() {
+        // Nothing listens on port 9 (discard)
+        let items = parse("http://127.0.0.1:9/debian/");
+        let items = detect_slashless_dirs(
+            &Client::new(),
+            items,
+            SlashlessDirs::Head,
+            1,
+            &RetryBudget::default(),
+        );
+        assert_eq!(types(&items), vec![FileType::File; 4]);
+    }
+
+    #[test]
+    fn test_slashless_head() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("dists")).unwrap();
+        std::fs::create_dir(dir.path().join("pool")).unwrap();
+        std::fs::write(dir.path().join("index.html"), "<html></html>").unwrap();
+        std::fs::write(dir.path().join("README"), "readme").unwrap();
+        let addr = autoindex::spawn(dir.path(), AutoindexStyle::Nginx);
+
+        let client = Client::new();
+        let items = parse(&format!("http://{addr}/"));
+        let items = detect_slashless_dirs(
+            &client,
+            items,
+            SlashlessDirs::Head,
+            0,
+            &RetryBudget::default(),
+        );
+        assert_eq!(
+            types(&items),
+            vec![
+                FileType::Directory,
+                FileType::Directory,
+                FileType::File,
+                FileType::File
+            ]
+        );
+        assert_eq!(items[1].url.as_str(), format!("http://{addr}/pool/"));
+    }
+
+    #[test]
+    fn test_type_overrides() {
+        let items = parse("http://localhost/debian/");
+        let force_dir = vec!["^debian/(dists|pool)$".parse().unwrap()];
+        let force_file = vec!["^debian/pool".parse().unwrap()];
+        let items = apply_type_overrides(items, "debian", &force_dir, &force_file);
+        assert_eq!(
+            types(&items),
+            vec![
+                FileType::Directory,
+                FileType::File,
+                FileType::File,
+                FileType::File
+            ]
+        );
+        assert_eq!(items[0].url.as_str(), "http://localhost/debian/dists/");
+        assert_eq!(items[1].url.as_str(), "http://localhost/debian/pool");
+
+        let mut item = items[0].clone();
+        item.set_type(FileType::File);
+        assert_eq!(item.url.as_str(), "http://localhost/debian/dists");
+    }
+
+    #[test]
+    fn test_name_and_url() {
+        let mtime =
+            NaiveDateTime::parse_from_str("2024-03-10 04:45:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let item = |url: &str, name: &str| {
+            ListItem::new(
+                Url::parse(url).unwrap(),
+                name.to_string(),
+                FileType::File,
+                None,
+                Some(mtime),
+            )
+        };
+        // DirectoryLister: URL basename has nothing to do with the name
+        let mut lister = item("http://localhost/?dir=debian/main", "main");
+        assert!(lister.has_valid_name());
+        lister.set_type(FileType::Directory);
+        assert_eq!(lister.url.as_str(), "http://localhost/?dir=debian/main");
+
+        for name in ["", ".", "..", "a/b", "../etc"] {
+            assert!(!item("http://localhost/x", name).has_valid_name(), "{name}");
+        }
+        assert!(item("http://localhost/x", "..a").has_valid_name());
+    }
+
+    #[test]
+    fn test_name_decoding() {
+        let items = || {
+            vec![
+                ListItem::new(
+                    Url::parse("http://localhost/a%2Bb+c%20d").unwrap(),
+                    "a+b+c d".to_string(),
+                    FileType::File,
+                    None,
+                    None,
+                )
+                .with_original_href("a%2Bb+c%20d"),
+                ListItem::new(
+                    Url::parse("http://localhost/dir/%E4%B8%AD/").unwrap(),
+                    "中".to_string(),
+                    FileType::Directory,
+                    None,
+                    None,
+                )
+                .with_original_href("/dir/%E4%B8%AD/"),
+                ListItem::new(
+                    Url::parse("http://localhost/?dir=a+b").unwrap(),
+                    "a b".to_string(),
+                    FileType::Directory,
+                    None,
+                    None,
+                )
+                .with_original_href("?dir=a+b"),
+            ]
+        };
+        let names = |decoding| -> Vec<String> {
+            apply_name_decoding(items(), decoding)
+                .into_iter()
+                .map(|i| i.name)
+                .collect()
+        };
+        assert_eq!(names(NameDecoding::Auto), ["a+b+c d", "中", "a b"]);
+        assert_eq!(names(NameDecoding::PercentOnly), ["a+b+c d", "中", "a b"]);
+        assert_eq!(
+            names(NameDecoding::FormUrlencoded),
+            ["a+b c d", "中", "a b"]
+        );
+        assert_eq!(
+            names(NameDecoding::None),
+            ["a%2Bb+c%20d", "%E4%B8%AD", "a b"]
+        );
+    }
+
+    #[test]
+    fn test_check_name_encoding() {
+        let item = |href: &str, name: &str| {
+            ListItem::new(
+                Url::parse("http://localhost/").unwrap().join(href).unwrap(),
+                name.to_string(),
+                FileType::File,
+                None,
+                None,
+            )
+            .with_original_href(href)
+        };
+        let items = check_name_encoding(
+            vec![
+                item("a%2Bb", "a+b"),
+                // "+" decoded as a space
+                item("c+d", "c d"),
+                item("%E4%B8%AD", "中"),
+                // the same file as the first one
+                item("a+b", "a+b"),
+                item("?dir=e+f", "e f"),
+                item("%FF", "\u{fffd}"),
+            ],
+            NameDecoding::Auto,
+        );
+        let names: Vec<&str> = items.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, ["a+b", "c+d", "中", "e f", "\u{fffd}"]);
+        // Requests keep hrefs
+        assert_eq!(items[1].url.as_str(), "http://localhost/c+d");
+
+        // Names of other decodings are kept
+        let items = apply_name_decoding(
+            vec![item("c+d", "c+d"), item("a%2Bb", "a+b")],
+            NameDecoding::FormUrlencoded,
+        );
+        let items = check_name_encoding(items, NameDecoding::FormUrlencoded);
+        let names: Vec<&str> = items.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, ["c d", "a+b"]);
+        // While names of parsers are checked with it
+        let items = check_name_encoding(vec![item("c+d", "c+d")], NameDecoding::FormUrlencoded);
+        assert_eq!(items[0].name, "c d");
+    }
+
+    #[test]
+    fn test_group_compressed_siblings() {
+        let item = |name: &str| {
+            ListItem::new(
+                Url::parse(&format!("http://localhost/{name}")).unwrap(),
+                name.to_string(),
+                FileType::File,
+                None,
+                None,
+            )
+        };
+        let groups = group_compressed_siblings(vec![
+            item("Packages.gz"),
+            item("Release"),
+            item("Packages"),
+            item("Packages.xz"),
+            item("Sources.bz2"),
+            item("linux.tar"),
+            item("linux.tar.xz"),
+            item("repodata/primary.xml"),
+            item("repodata/primary.xml.gz"),
+        ]);
+        let names: Vec<Vec<&str>> = groups
+            .iter()
+            .map(|g| g.iter().map(|i| i.name.as_str()).collect())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                vec!["Packages.gz", "Packages", "Packages.xz"],
+                vec!["Release"],
+                vec!["Sources.bz2"],
+                vec!["linux.tar"],
+                vec!["linux.tar.xz"],
+                vec!["repodata/primary.xml", "repodata/primary.xml.gz"]
+            ]
+        );
+    }
+}
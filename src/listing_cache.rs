@@ -0,0 +1,236 @@
+// Cross-run cache of parsed listings, kept in a file of each profile (--listing-cache).
+// Each fetched listing body is hashed, and if it is identical to last run's for that URL,
+// items parsed last time are reused without parsing again. This saves CPU on giant directories
+// which rarely change, even when upstream gives no HTTP validators (ETag or Last-Modified).
+
+use std::{collections::HashMap, path::Path, sync::Mutex};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::debug;
+use url::Url;
+
+use crate::{listing::ListItem, parser::Parser, run_scope::ScopeId};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedListing {
+    /// SHA-256 of tsumugu version, parser and listing body
+    pub hash: String,
+    pub items: Vec<ListItem>,
+}
+
+#[derive(Debug, Default)]
+struct Cache {
+    /// Only listings under upstream are cached
+    upstream: String,
+    /// Listings of last run
+    last: HashMap<String, CachedListing>,
+    /// Listings of this run
+    seen: HashMap<String, CachedListing>,
+}
+
+/// Global as parse_page() is called deep inside parsers. Each run (like profiles of batch mode)
+/// has its own cache, by its scope (see run_scope.rs).
+static CACHES: Mutex<Option<HashMap<ScopeId, Cache>>> = Mutex::new(None);
+
+/// Run f with cache of this run, if enabled for url
+fn with_cache<T>(url: &str, f: impl FnOnce(&mut Cache) -> T) -> Option<T> {
+    let mut caches = CACHES.lock().unwrap();
+    caches
+        .as_mut()?
+        .get_mut(&ScopeId::current())
+        .filter(|c| url.starts_with(c.upstream.as_str()))
+        .map(f)
+}
+
+/// Load listings of last run from cache file. A missing file is an empty cache.
+pub fn load(path: &Path) -> Result<HashMap<String, CachedListing>> {
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    Ok(serde_json::from_reader(std::io::BufReader::new(
+        std::fs::File::open(path)?,
+    ))?)
+}
+
+/// Save listings of this run to cache file atomically (write to a temp file and rename).
+/// It is not pretty-printed, as listings of giant directories are large.
+pub fn save(path: &Path, listings: &HashMap<String, CachedListing>) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+    serde_json::to_writer(&mut writer, listings)?;
+    std::io::Write::flush(&mut writer)?;
+    drop(writer);
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Enable cache for listings under upstream in this run, with listings of last run
+pub fn enable(upstream: &Url, listings: HashMap<String, CachedListing>) {
+    CACHES
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(
+            ScopeId::current(),
+            Cache {
+                upstream: upstream.to_string(),
+                last: listings,
+                seen: HashMap::new(),
+            },
+        );
+}
+
+/// Disable cache of this run, returning listings of this run to be saved
+pub fn finish() -> HashMap<String, CachedListing> {
+    CACHES
+        .lock()
+        .unwrap()
+        .as_mut()
+        .and_then(|c| c.remove(&ScopeId::current()))
+        .map(|c| c.seen)
+        .unwrap_or_default()
+}
+
+/// Drop cache of a run, if it is not finished (like failing before the end)
+pub fn forget(scope: ScopeId) {
+    if let Some(caches) = CACHES.lock().unwrap().as_mut() {
+        caches.remove(&scope);
+    }
+}
+
+fn hash<P: Parser + ?Sized>(body: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(crate::build::PKG_VERSION);
+    hasher.update([0]);
+    hasher.update(std::any::type_name::<P>());
+    hasher.update([0]);
+    hasher.update(body);
+    format!("{:x}", hasher.finalize())
+}
+
+/// parser.parse_page(), reusing items of last run if body is not changed
+pub fn parse_page<P: Parser + ?Sized>(parser: &P, url: &Url, body: &str) -> Result<Vec<ListItem>> {
+    if with_cache(url.as_str(), |_| ()).is_none() {
+        return parser.parse_page(url, body);
+    }
+    let hash = hash::<P>(body);
+    let reused = with_cache(url.as_str(), |cache| {
+        match cache.last.remove(url.as_str()) {
+            Some(last) if last.hash == hash => {
+                let items = last.items.clone();
+                cache.seen.insert(url.to_string(), last);
+                Some(items)
+            }
+            _ => None,
+        }
+    })
+    .flatten();
+    if let Some(items) = reused {
+        debug!("Listing of {} is not changed, reusing parsed items", url);
+        return Ok(items);
+    }
+    let items = parser.parse_page(url, body)?;
+    with_cache(url.as_str(), |cache| {
+        cache.seen.insert(
+            url.to_string(),
+            CachedListing {
+                hash,
+                items: items.clone(),
+            },
+        )
+    });
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{
+        listing::FileType,
+        parser::{nginx::NginxListingParser, ListResult},
+        run_scope::RunScope,
+    };
+
+    /// Counts parse_page() calls
+    #[derive(Default)]
+    struct CountingParser {
+        inner: NginxListingParser,
+        parsed: AtomicUsize,
+    }
+
+    impl Parser for CountingParser {
+        fn get_list(&self, _client: &reqwest::blocking::Client, _url: &Url) -> Result<ListResult> {
+            Err(anyhow::anyhow!("Only parse_page() is used in tests"))
+        }
+
+        fn parse_page(&self, url: &Url, body: &str) -> Result<Vec<ListItem>> {
+            self.parsed.fetch_add(1, Ordering::SeqCst);
+            self.inner.parse_page(url, body)
+        }
+    }
+
+    #[test]
+    fn test_listing_cache() {
+        let upstream = Url::parse("http://cache.example.com/repo/").unwrap();
+        let url = upstream.join("dir/").unwrap();
+        let body = r#"<html><body><h1>Index of /repo/dir/</h1><hr><pre><a href="../">../</a>
+<a href="a.deb">a.deb</a>                                              08-Jul-2023 04:26                1234
+</pre><hr></body></html>"#;
+        let parser = CountingParser::default();
+        let _scope = RunScope::begin();
+
+        // Not enabled
+        parse_page(&parser, &url, body).unwrap();
+        assert!(finish().is_empty());
+
+        // First run
+        enable(&upstream, HashMap::new());
+        parse_page(&parser, &url, body).unwrap();
+        let listings = finish();
+        assert_eq!(listings.len(), 1);
+        assert_eq!(parser.parsed.load(Ordering::SeqCst), 2);
+
+        // Saved and loaded
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("listings.json");
+        assert!(load(&path).unwrap().is_empty());
+        save(&path, &listings).unwrap();
+        let listings = load(&path).unwrap();
+
+        // Not changed
+        enable(&upstream, listings);
+        let items = parse_page(&parser, &url, body).unwrap();
+        assert_eq!(parser.parsed.load(Ordering::SeqCst), 2);
+        assert_eq!(items[0].name, "a.deb");
+        assert_eq!(items[0].type_, FileType::File);
+        assert_eq!(
+            items[0].url.as_str(),
+            "http://cache.example.com/repo/dir/a.deb"
+        );
+        let listings = finish();
+
+        // Not shared with other runs
+        enable(&upstream, listings);
+        std::thread::spawn({
+            let url = url.clone();
+            move || {
+                let _scope = RunScope::begin();
+                let parser = CountingParser::default();
+                parse_page(&parser, &url, body).unwrap();
+                assert_eq!(parser.parsed.load(Ordering::SeqCst), 1);
+            }
+        })
+        .join()
+        .unwrap();
+
+        // Changed
+        let items = parse_page(&parser, &url, &body.replace("1234", "5678")).unwrap();
+        assert_eq!(parser.parsed.load(Ordering::SeqCst), 3);
+        assert_eq!(items[0].size.unwrap().get_estimated(), 5678);
+        finish();
+    }
+}
@@ -1,26 +1,68 @@
 #![warn(clippy::cognitive_complexity)]
 use std::path::PathBuf;
 
+use chrono::{DateTime, Utc};
 use clap::{Parser, Subcommand};
 
-use parser::ParserType;
+use parser::{manifest::ManifestField, ParserType};
 use tracing_subscriber::EnvFilter;
 use url::Url;
 
 use shadow_rs::shadow;
 shadow!(build);
 
+mod autoindex;
 mod cli;
+mod clock_skew;
 mod compare;
+mod config;
+mod deletion_list;
+mod deletion_plan;
+mod error;
+mod events;
+mod fd_budget;
+mod har;
+mod host_state;
+mod invalidate;
+mod item_schema;
+mod lfs;
+mod limiter;
 mod listing;
+mod listing_cache;
+mod metrics;
+mod mount_guard;
+mod panic_guard;
 mod parser;
+mod prefix_limit;
+mod recompress;
 mod regex_process;
+mod report;
+mod resources;
+mod rewrite;
+mod root_guard;
+mod run_scope;
+mod sidecar;
+mod snapshot;
+mod storm;
+mod sync_stats;
 mod term;
+#[cfg(test)]
+mod test_tree;
 mod utils;
+mod validator_cache;
+mod version_check;
 
 mod extensions;
 
+use crate::autoindex::AutoindexStyle;
+use crate::item_schema::OutputFormat;
+use crate::listing::{NameDecoding, SlashlessDirs};
+use crate::prefix_limit::PrefixCap;
+use crate::recompress::Recompression;
 use crate::regex_process::ExpandedRegex;
+use crate::rewrite::PathRewrite;
+use crate::sidecar::SidecarMode;
+use crate::utils::PrefixTimezone;
 
 #[derive(Parser, Debug)]
 #[command(about)]
@@ -29,23 +71,91 @@ use crate::regex_process::ExpandedRegex;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Log filter (like "warn" or "info,tsumugu::cli::sync=debug") [default: info, or log in batch config]. RUST_LOG adds to it.
+    #[clap(long, global = true)]
+    log: Option<String>,
+
+    /// Allow commands writing local files (sync, batch, repair and apply-deletions) to run as root.
+    #[clap(long, global = true)]
+    allow_root: bool,
+
+    /// When running as root, drop privileges to this user first (for commands writing local files).
+    #[clap(long, global = true)]
+    run_as: Option<String>,
 }
 
 #[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     /// Sync files from upstream to local.
     Sync(SyncArgs),
 
     /// List files from upstream.
     List(ListArgs),
+
+    /// Run syncs of profiles in a config file, sharing bandwidth and threads by weights.
+    Batch(BatchArgs),
+
+    /// Check config file of batch, show its resolved profiles, or print its JSON Schema.
+    Config(ConfigArgs),
+
+    /// Check what upstream supports (HEAD, Range, Last-Modified, etc.), and suggest flags.
+    ProbeServer(ProbeServerArgs),
+
+    /// List two upstreams of the same repository, and show differences (missing files, sizes and mtimes).
+    CrossCheck(CrossCheckArgs),
+
+    /// Show which paths (from stdin or arguments) would be kept or excluded by include/exclude rules, without network.
+    MatchRules(MatchRulesArgs),
+
+    /// Translate rsync exclude/include rules (like of ftpsync jobs) to tsumugu rules, reporting untranslatable ones.
+    ImportRsyncExcludes(ImportRsyncExcludesArgs),
+
+    /// Fetch a local file or directory again from upstream of its profile, verifying size and checksum.
+    Repair(RepairArgs),
+
+    /// Finish deletions saved by `sync --deletion-plan`, without listing upstream again.
+    ApplyDeletions(ApplyDeletionsArgs),
+
+    /// Show statistics of local directory (counts, sizes, mtimes and leftover temporary files), without network.
+    Stats(StatsArgs),
+
+    /// Summarize changes between two sync reports (--report): failures, downloaded bytes and deletions.
+    ReportDiff(ReportDiffArgs),
+
+    /// Suggest exclusion rules from history of sync reports and item lists (failing paths and large directories), without network.
+    SuggestRules(SuggestRulesArgs),
+
+    /// Verify files referenced by apt/yum/pypi metadata in local directory (presence, size and digest), without network.
+    AuditLocal(AuditLocalArgs),
+
+    /// (Dev only) Serve a local directory with autoindex in given style, for testing parsers.
+    #[command(hide = true)]
+    ServeFixture(ServeFixtureArgs),
+
+    /// Run parsers against a corpus of listing pages (<case>.html) and expected results (<case>.json).
+    TestParsers(TestParsersArgs),
 }
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 pub struct SyncArgs {
     /// Customize tsumugu's user agent.
     #[clap(long, default_value = "tsumugu")]
     user_agent: String,
 
+    /// Follow at most this many redirections (for parsers following them automatically).
+    #[clap(long, default_value_t = 10)]
+    max_redirects: usize,
+
+    /// Follow redirections from https to http, which are errors by default.
+    #[clap(long)]
+    allow_https_downgrade: bool,
+
+    /// Refuse plain http: upstream and mirrors must be https, and http links in listings are neither listed nor downloaded.
+    #[clap(long, conflicts_with = "allow_https_downgrade")]
+    https_only: bool,
+
     /// Do not download files and cleanup.
     #[clap(long)]
     dry_run: bool,
@@ -54,6 +164,22 @@ pub struct SyncArgs {
     #[clap(long, default_value_t = 2)]
     threads: usize,
 
+    /// Download files larger than this (in bytes, as estimated from listing) in a separate lane, to keep small files flowing.
+    #[clap(long)]
+    large_file_threshold: Option<u64>,
+
+    /// Threads for downloading large files (with --large-file-threshold).
+    #[clap(long, default_value_t = 1)]
+    large_file_threads: usize,
+
+    /// Cap concurrent listings and downloads under a relative path prefix, like 'pool/=2'. Every matching prefix applies. Supports multiple.
+    #[clap(long, value_parser)]
+    max_concurrent_per_prefix: Vec<PrefixCap>,
+
+    /// Limit download bandwidth (bytes/s).
+    #[clap(long)]
+    bandwidth_limit: Option<u64>,
+
     /// Do not clean up after sync.
     #[clap(long)]
     no_delete: bool,
@@ -62,6 +188,24 @@ pub struct SyncArgs {
     #[clap(long, default_value_t = 100)]
     max_delete: usize,
 
+    /// Record provenance (source URL, retrieval time, ETag/Last-Modified and SHA-256) of each downloaded file,
+    /// in a sidecar file (<name>.tsumugu.meta, never deleted while the file is kept) or an extended attribute.
+    #[clap(long, value_enum)]
+    sidecar: Option<SidecarMode>,
+
+    /// Resolve Git LFS pointers served in place of files, with the batch API of this LFS endpoint (like https://example.com/repo.git/info/lfs).
+    #[clap(long)]
+    lfs_endpoint: Option<Url>,
+
+    /// Do not cache validators (ETag, SHA-256 and HEAD results) in extended attributes of synced files.
+    /// The cache is also disabled when the local filesystem does not support user extended attributes.
+    #[clap(long)]
+    no_xattr_cache: bool,
+
+    /// Seconds to trust an up-to-date HEAD result cached in extended attributes, while neither the local file nor its listing entry changes.
+    #[clap(long, default_value_t = 86400)]
+    xattr_cache_ttl: u64,
+
     /// The upstream URL.
     #[clap(value_parser)]
     upstream: Url,
@@ -78,14 +222,42 @@ pub struct SyncArgs {
     #[clap(long)]
     timezone: Option<i32>,
 
+    /// Set timezone for files under a path prefix, like "pool/=+0000". The longest matching prefix wins. Supports multiple.
+    #[clap(long, value_parser)]
+    timezone_prefix: Vec<PrefixTimezone>,
+
     /// Retry count for each request.
     #[clap(long, default_value_t = 3)]
     retry: usize,
 
+    /// Alternative upstream with the same layout as upstream, to download files from when upstream fails or is too slow. Supports multiple.
+    #[clap(long)]
+    mirror: Vec<Url>,
+
+    /// Switch to next mirror when download speed (bytes/s) of a file stays below this for --min-speed-time.
+    #[clap(long)]
+    min_speed: Option<u64>,
+
+    /// Seconds to measure download speed for --min-speed.
+    #[clap(long, default_value_t = 30)]
+    min_speed_time: u64,
+
+    /// Total retry count shared by all requests in this run (unlimited by default). Listings stop retrying when half of it is used.
+    #[clap(long)]
+    retry_budget: Option<usize>,
+
+    /// Retries of requests failed to resolve host name, with backoff from 1s (not counted in --retry or --retry-budget).
+    #[clap(long, default_value_t = 3)]
+    dns_retry: usize,
+
     /// Do an HEAD before actual GET. Add this if you are not sure if the results from parser is correct.
     #[clap(long)]
     head_before_get: bool,
 
+    /// When only mtime of an existing file differs, update local mtime instead of downloading it again, if content is the same (by checksum if listed, or by exact size otherwise). For mirrors seeded without preserving mtimes.
+    #[clap(long)]
+    touch_up: bool,
+
     /// Choose a parser.
     #[clap(long, value_enum, default_value_t = ParserType::Nginx)]
     parser: ParserType,
@@ -102,6 +274,27 @@ pub struct SyncArgs {
     #[clap(long, value_parser)]
     skip_if_exists: Vec<ExpandedRegex>,
 
+    /// Write every path checked by --exclude and --include, with its decision and the rule deciding it, to this file (as JSON lines).
+    #[clap(long)]
+    filter_trace: Option<PathBuf>,
+
+    /// Do not exclude companions (signatures, checksums, metalinks and zsync files like a.iso.asc) along with their excluded payloads.
+    #[clap(long)]
+    no_companions: bool,
+
+    /// Only sync this subtree (relative directory like pool/main/p/), keeping relative layout. Deletion is limited to it as well. Supports multiple.
+    #[clap(long, value_parser = utils::parse_subtree)]
+    only: Vec<String>,
+
+    /// Only download files modified (by remote mtime in listing) since this date, like 2024-01-01 (in UTC) or RFC 3339.
+    /// Other files are kept as is. Files without mtime in listing are synced as usual.
+    #[clap(long, value_parser = utils::parse_date_time)]
+    modified_since: Option<DateTime<Utc>>,
+
+    /// Only download files modified before this date, like --modified-since.
+    #[clap(long, value_parser = utils::parse_date_time)]
+    modified_before: Option<DateTime<Utc>>,
+
     /// File regex for those compare size only in HEAD requests. This only works with head_before_get.
     #[clap(long, value_parser)]
     compare_size_only: Vec<ExpandedRegex>,
@@ -117,6 +310,172 @@ pub struct SyncArgs {
     /// (Experimental) YUM Packages file parser to find out missing packages.
     #[clap(long)]
     yum_packages: bool,
+
+    /// Sync only repository metadata (dists/, repodata/, indexes, checksums and signatures), not payload packages.
+    /// Local payloads are kept.
+    #[clap(long, conflicts_with_all = ["apt_packages", "yum_packages"])]
+    metadata_only: bool,
+
+    /// Sync a Debian archive in two stages like ftpsync: everything but dists/, indices/ and ls-lR first, without deleting,
+    /// then metadata (with --apt-packages) and deletion. Stage 2 is skipped if stage 1 fails.
+    #[clap(long, conflicts_with = "metadata_only")]
+    debian_ftpsync: bool,
+
+    /// Get file list from this rsync upstream (with `rsync --list-only`) instead of parsing HTML. Files are still downloaded from (HTTP) upstream.
+    #[clap(long)]
+    rsync_upstream: Option<Url>,
+
+    /// Get file list from this sitemap.xml (covering every file URL) instead of parsing HTML. Use with --head-before-get, as sitemap has no sizes.
+    #[clap(long, conflicts_with = "rsync_upstream")]
+    sitemap: Option<Url>,
+
+    /// Mirror release assets and generic packages of this GitLab project (like https://gitlab.example.com/group/project) under upstream, with GitLab API. Token of private projects is read from GITLAB_TOKEN environment variable.
+    #[clap(long, conflicts_with_all = ["rsync_upstream", "sitemap"])]
+    gitlab_project: Option<Url>,
+
+    /// Mirror files of this Hugging Face repo (like https://huggingface.co/datasets/owner/name, or .../tree/<revision>) under upstream, with Hub API. Token of gated or private repos is read from HF_TOKEN environment variable.
+    #[clap(long, conflicts_with_all = ["rsync_upstream", "sitemap", "gitlab_project"])]
+    huggingface_repo: Option<Url>,
+
+    /// Get file list from this JSON manifest (with paths, sizes, mtimes and hashes, relative to upstream) instead of parsing HTML.
+    #[clap(long, conflicts_with_all = ["rsync_upstream", "sitemap", "gitlab_project", "huggingface_repo"])]
+    manifest: Option<Url>,
+
+    /// Where a field of manifest (or data of --parser embedded-json) is, like 'files=$.data.items' or 'size=meta.length' (fields: files, path, size, mtime, sha256, url). Supports multiple.
+    #[clap(long, value_parser)]
+    manifest_field: Vec<ManifestField>,
+
+    /// CSS selector of script tags with data for --parser embedded-json [default: script[type="application/json"], script#__DATA__].
+    #[clap(long)]
+    embedded_json_selector: Option<String>,
+
+    /// Sync from a snapshot archive at this time (like 2024-03-01T00:00:00Z, 20240301T000000Z or 2024-03-01), with upstream being its base (like https://snapshot.debian.org/archive/debian/).
+    /// The nearest snapshot before it is pinned for the whole run.
+    #[clap(long, value_parser = snapshot::parse_time)]
+    snapshot: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// How to find directories linked without trailing slash (which would be downloaded as files otherwise).
+    #[clap(long, value_enum, default_value_t = SlashlessDirs::Off)]
+    slashless_dirs: SlashlessDirs,
+
+    /// How to decode hrefs to local names, for servers encoding them differently.
+    #[clap(long, value_enum, default_value_t = NameDecoding::Auto)]
+    name_decoding: NameDecoding,
+
+    /// Check that names round-trip to their hrefs by --name-decoding (percent-decoding for auto), renaming
+    /// mismatched items to decoded hrefs and skipping items whose names collide.
+    #[clap(long)]
+    check_name_encoding: bool,
+
+    /// Treat items whose relative path matches this regex as directories. Supports multiple.
+    #[clap(long, value_parser)]
+    force_dir: Vec<ExpandedRegex>,
+
+    /// Treat items whose relative path matches this regex as files (wins over --force-dir). Supports multiple.
+    #[clap(long, value_parser)]
+    force_file: Vec<ExpandedRegex>,
+
+    /// Write mirror freshness metrics (in Prometheus text format, for node_exporter textfile collector) to this file.
+    #[clap(long)]
+    metrics_file: Option<PathBuf>,
+
+    /// Write a JSON report of this run (what is downloaded and why, what is deleted, failures) to this file.
+    #[clap(long)]
+    report: Option<PathBuf>,
+
+    /// Append paths deleted in each run to deleted-files-<date>.txt in this directory, for downstream mirrors and CDN invalidation jobs.
+    /// It is never cleaned up even if inside local directory.
+    #[clap(long)]
+    deletion_list_dir: Option<PathBuf>,
+
+    /// Keep only this many newest lists in --deletion-list-dir.
+    #[clap(long, requires = "deletion_list_dir")]
+    deletion_list_keep: Option<usize>,
+
+    /// Save paths to delete to this file before deleting them, so that an interrupted cleanup could be finished
+    /// by `apply-deletions` without listing again. It is removed when cleanup completes.
+    /// Start of each sync is kept in --state-file, so that plans older than the last sync are refused.
+    #[clap(long, requires = "state_file")]
+    deletion_plan: Option<PathBuf>,
+
+    /// After sync, send paths downloaded or deleted to this CDN invalidation endpoint (URL template with {paths_json}, {paths} or {path}).
+    /// Bearer token is read from TSUMUGU_INVALIDATE_TOKEN environment variable.
+    #[clap(long)]
+    invalidate_url: Option<String>,
+
+    /// HTTP method of invalidation requests.
+    #[clap(long, default_value = "POST", requires = "invalidate_url")]
+    invalidate_method: String,
+
+    /// JSON body template of invalidation requests, like '{"paths": {paths_json}}'.
+    #[clap(long, requires = "invalidate_url")]
+    invalidate_body: Option<String>,
+
+    /// Paths per invalidation request (1 if {path} is used).
+    #[clap(long, default_value_t = 100, requires = "invalidate_url")]
+    invalidate_batch_size: usize,
+
+    /// Prefix of paths sent for invalidation, like "/debian/".
+    #[clap(long, default_value = "/", requires = "invalidate_url")]
+    invalidate_path_prefix: String,
+
+    /// Append events of this run (listing, skipping, download progress, deletion and finish) to this file (or FIFO) as JSON lines, for alternative UIs.
+    #[clap(long)]
+    events_file: Option<PathBuf>,
+
+    /// Print a line for each download and deletion, like `rsync --itemize-changes`.
+    #[clap(long, short = 'i')]
+    itemize_changes: bool,
+
+    /// Warn and pause re-downloads for mtime mismatch when more than this percentage of existing files are re-downloaded due to it (re-download storm).
+    #[clap(long, default_value_t = 50.0)]
+    storm_threshold: f64,
+
+    /// Abort sync (and skip cleanup) when a re-download storm is detected, instead of pausing re-downloads.
+    #[clap(long)]
+    strict_storm: bool,
+
+    /// Record listing requests and responses into this HAR-like archive, for bug reports.
+    #[clap(long)]
+    record: Option<PathBuf>,
+
+    /// Do not re-fetch compressed siblings of an updated repository index (like Packages.gz and Packages.xz), which keeps them in the same generation.
+    #[clap(long)]
+    no_sibling_consistency: bool,
+
+    /// Generate a sibling with another compression for files matching regex, like 'Packages\.xz$=gz' (gz or xz), unless upstream ships it.
+    /// Generated files are kept from deletion, and regenerated when their source changes. Supports multiple.
+    #[clap(long, value_parser)]
+    recompress: Vec<Recompression>,
+
+    /// Rewrite remote relative paths of files to local ones, like 'releases/download/([^/]+)/(.+)=$1/$2'. The first matching rule applies, and deletion follows local paths. Supports multiple.
+    #[clap(long, value_parser)]
+    rewrite: Vec<PathRewrite>,
+
+    /// Exit immediately (with code 3) when anything panics, instead of failing only the task and continuing.
+    #[clap(long)]
+    fail_fast: bool,
+
+    /// Handle a task again (once) if it panics, before taking it as failed.
+    #[clap(long, conflicts_with = "fail_fast")]
+    retry_panicked: bool,
+
+    /// Remember per-host limits (429 responses, HEAD support, threads tolerated) in this file, and use them in next runs.
+    /// HEAD found unsupported is probed again after 7 days.
+    #[clap(long)]
+    state_file: Option<PathBuf>,
+
+    /// Keep parsed listings of this profile in this file, so that listings identical to last run's are not parsed again.
+    #[clap(long)]
+    listing_cache: Option<PathBuf>,
+
+    /// Relative path being repaired by `repair` command: only it (or files under it) is downloaded, regardless of local ones.
+    #[clap(skip)]
+    repair: Option<String>,
+
+    /// Set in stage 1 of --debian-ftpsync: metadata is not downloaded, and local one is kept.
+    #[clap(skip)]
+    ftpsync_stage1: bool,
 }
 
 #[derive(Parser, Debug)]
@@ -125,6 +484,14 @@ pub struct ListArgs {
     #[clap(long, default_value = "tsumugu")]
     user_agent: String,
 
+    /// Follow at most this many redirections (for parsers following them automatically).
+    #[clap(long, default_value_t = 10)]
+    max_redirects: usize,
+
+    /// Follow redirections from https to http, which are errors by default.
+    #[clap(long)]
+    allow_https_downgrade: bool,
+
     /// The upstream URL.
     #[clap(value_parser)]
     upstream_folder: Url,
@@ -141,21 +508,379 @@ pub struct ListArgs {
     #[clap(long, value_parser)]
     include: Vec<ExpandedRegex>,
 
+    /// Write every path checked by --exclude and --include, with its decision and the rule deciding it, to this file (as JSON lines).
+    #[clap(long)]
+    filter_trace: Option<PathBuf>,
+
     /// The upstream base ending with "/".
     #[clap(long, default_value = "/")]
     upstream_base: String,
+
+    /// Print items as text (with exclusion decisions), or as JSON in a stable, versioned schema (see README).
+    #[clap(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Timezone of upstream listing (+- hrs), written as offset of mtimes in JSON output.
+    #[clap(long)]
+    timezone: Option<i32>,
+
+    /// Get file list from this rsync upstream (mapped to upstream base) instead of parsing HTML.
+    #[clap(long)]
+    rsync_upstream: Option<Url>,
+
+    /// Get file list from this sitemap.xml instead of parsing HTML.
+    #[clap(long, conflicts_with = "rsync_upstream")]
+    sitemap: Option<Url>,
+
+    /// List release assets and generic packages of this GitLab project (mapped to upstream base) with GitLab API.
+    #[clap(long, conflicts_with_all = ["rsync_upstream", "sitemap"])]
+    gitlab_project: Option<Url>,
+
+    /// List files of this Hugging Face repo (mapped to upstream base) with Hub API.
+    #[clap(long, conflicts_with_all = ["rsync_upstream", "sitemap", "gitlab_project"])]
+    huggingface_repo: Option<Url>,
+
+    /// Get file list from this JSON manifest (mapped to upstream base) instead of parsing HTML.
+    #[clap(long, conflicts_with_all = ["rsync_upstream", "sitemap", "gitlab_project", "huggingface_repo"])]
+    manifest: Option<Url>,
+
+    /// Where a field of manifest (or data of --parser embedded-json) is, like 'size=meta.length'. Supports multiple.
+    #[clap(long, value_parser)]
+    manifest_field: Vec<ManifestField>,
+
+    /// CSS selector of script tags with data for --parser embedded-json [default: script[type="application/json"], script#__DATA__].
+    #[clap(long)]
+    embedded_json_selector: Option<String>,
+
+    /// How to find directories linked without trailing slash.
+    #[clap(long, value_enum, default_value_t = SlashlessDirs::Off)]
+    slashless_dirs: SlashlessDirs,
+
+    /// How to decode hrefs to local names.
+    #[clap(long, value_enum, default_value_t = NameDecoding::Auto)]
+    name_decoding: NameDecoding,
+
+    /// Check that names round-trip to their hrefs by --name-decoding.
+    #[clap(long)]
+    check_name_encoding: bool,
+
+    /// Treat items whose relative path matches this regex as directories. Supports multiple.
+    #[clap(long, value_parser)]
+    force_dir: Vec<ExpandedRegex>,
+
+    /// Treat items whose relative path matches this regex as files (wins over --force-dir). Supports multiple.
+    #[clap(long, value_parser)]
+    force_file: Vec<ExpandedRegex>,
+
+    /// Record listing requests and responses into this HAR-like archive, for bug reports.
+    #[clap(long, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Replay listing responses from an archive recorded with --record, without accessing network.
+    #[clap(long)]
+    replay: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+pub struct BatchArgs {
+    /// The config file (TOML).
+    #[clap(value_parser)]
+    config: PathBuf,
+
+    /// Only run these profiles. Supports multiple.
+    #[clap(long)]
+    profile: Vec<String>,
+
+    /// Run as daemon: run all profiles again this many seconds after last round finishes.
+    #[clap(long)]
+    interval: Option<u64>,
+
+    /// (Daemon mode) Check for newer tsumugu release once a day, and log a notice. Only release metadata is fetched from GitHub.
+    #[clap(long, requires = "interval")]
+    version_check: bool,
+
+    /// Disable version check, even if enabled in config.
+    #[clap(long, conflicts_with = "version_check")]
+    no_version_check: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct ConfigArgs {
+    #[command(subcommand)]
+    command: ConfigCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommands {
+    /// Print JSON Schema of config file, for editors and CI.
+    Schema,
+    /// Check config file (including options of profiles). Errors point to line and column.
+    Check {
+        /// The config file (TOML).
+        #[clap(value_parser)]
+        config: PathBuf,
+    },
+    /// Print a profile with defaults and inherited keys resolved.
+    Show {
+        /// The config file (TOML).
+        #[clap(value_parser)]
+        config: PathBuf,
+        /// Name of the profile.
+        #[clap(long)]
+        profile: String,
+    },
+}
+
+#[derive(Parser, Debug)]
+pub struct ProbeServerArgs {
+    /// Customize tsumugu's user agent.
+    #[clap(long, default_value = "tsumugu")]
+    user_agent: String,
+
+    /// The upstream URL (a directory ending with "/").
+    #[clap(value_parser)]
+    upstream: Url,
+}
+
+#[derive(Parser, Debug)]
+pub struct CrossCheckArgs {
+    /// Customize tsumugu's user agent.
+    #[clap(long, default_value = "tsumugu")]
+    user_agent: String,
+
+    /// Follow at most this many redirections (for parsers following them automatically).
+    #[clap(long, default_value_t = 10)]
+    max_redirects: usize,
+
+    /// Follow redirections from https to http, which are errors by default.
+    #[clap(long)]
+    allow_https_downgrade: bool,
+
+    /// The first upstream URL (A).
+    #[clap(value_parser)]
+    upstream_a: Url,
+
+    /// The second upstream URL (B).
+    #[clap(value_parser)]
+    upstream_b: Url,
+
+    /// Choose a parser.
+    #[clap(long, value_enum, default_value_t = ParserType::Nginx)]
+    parser: ParserType,
+
+    /// Choose a parser for B, if different from A.
+    #[clap(long, value_enum)]
+    parser_b: Option<ParserType>,
+
+    /// Excluded file regex. Supports multiple.
+    #[clap(long, value_parser)]
+    exclude: Vec<ExpandedRegex>,
+
+    /// Included file regex (even if excluded). Supports multiple.
+    #[clap(long, value_parser)]
+    include: Vec<ExpandedRegex>,
+
+    /// Timezone of mtimes listed by A (+- hrs).
+    #[clap(long, default_value_t = 0)]
+    timezone_a: i32,
+
+    /// Timezone of mtimes listed by B (+- hrs).
+    #[clap(long, default_value_t = 0)]
+    timezone_b: i32,
+
+    /// Mtimes differing within this many seconds are taken as the same.
+    #[clap(long, default_value_t = 60)]
+    mtime_tolerance: i64,
+
+    /// Compare sizes only.
+    #[clap(long)]
+    ignore_mtime: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct ApplyDeletionsArgs {
+    /// The deletion plan file.
+    #[clap(value_parser)]
+    plan: PathBuf,
+
+    /// Set max delete count.
+    #[clap(long, default_value_t = 100)]
+    max_delete: usize,
+
+    /// Do not delete anything, only show what would be deleted.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// The --state-file of sync, to refuse the plan if the local directory has been synced since it was created.
+    #[clap(long)]
+    state_file: PathBuf,
+
+    /// Append deleted paths to deleted-files-<date>.txt in this directory, like --deletion-list-dir of sync.
+    #[clap(long)]
+    deletion_list_dir: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+pub struct RepairArgs {
+    /// The local file or directory to repair.
+    #[clap(value_parser)]
+    path: PathBuf,
+
+    /// Find profile of the path (the one with local directory containing it) in this batch config file.
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Use this profile in config file, instead of finding by path.
+    #[clap(long, requires = "config")]
+    profile: Option<String>,
+
+    /// Sync flags and arguments (as of `sync` command, like `-- --parser apache-f2 <UPSTREAM> <LOCAL>`), if not using config file.
+    #[clap(
+        last = true,
+        required_unless_present = "config",
+        conflicts_with = "config"
+    )]
+    sync: Vec<String>,
+}
+
+#[derive(Parser, Debug)]
+pub struct StatsArgs {
+    /// The local directory.
+    #[clap(value_parser)]
+    local: PathBuf,
+
+    /// Print as JSON, for status pages.
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct ReportDiffArgs {
+    /// Report of the earlier run.
+    #[clap(value_parser)]
+    old: PathBuf,
+
+    /// Report of the later run.
+    #[clap(value_parser)]
+    new: PathBuf,
+
+    /// Print as JSON.
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct SuggestRulesArgs {
+    /// Sync reports (--report) and item lists (`list --output json`), or directories of them (*.json, in order of names).
+    #[clap(value_parser, required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// Suggest paths failed in at least this many reports.
+    #[clap(long, default_value_t = 3)]
+    min_failures: usize,
+
+    /// Suggest first-level directories with at least this share (in percent) of downloaded bytes or listed size.
+    #[clap(long, default_value_t = 30.0)]
+    min_share: f64,
+}
+
+#[derive(Parser, Debug)]
+pub struct MatchRulesArgs {
+    /// Rules file: `exclude <regex>` or `include <regex>` per line (`#` for comments), like --exclude and --include of sync.
+    #[clap(long, value_parser)]
+    rules: Option<PathBuf>,
+
+    /// Excluded file regex, after ones in rules file. Supports multiple.
+    #[clap(long, value_parser)]
+    exclude: Vec<ExpandedRegex>,
+
+    /// Included file regex, after ones in rules file. Supports multiple.
+    #[clap(long, value_parser)]
+    include: Vec<ExpandedRegex>,
+
+    /// Read paths (relative to mirror root, directories ending with '/') from stdin, one per line.
+    #[clap(long, conflicts_with = "paths")]
+    stdin: bool,
+
+    /// Only print paths which would be excluded.
+    #[clap(long)]
+    excluded_only: bool,
+
+    /// Paths to check, if not reading from stdin.
+    #[clap(value_parser, required_unless_present = "stdin")]
+    paths: Vec<String>,
+}
+
+#[derive(Parser, Debug)]
+pub struct ImportRsyncExcludesArgs {
+    /// File of rsync rules: `- pattern`, `+ pattern`, `--exclude=pattern`, or bare patterns (as of --exclude-from).
+    #[clap(value_parser)]
+    file: PathBuf,
+
+    /// Print as `exclude` and `include` keys of a batch profile, instead of a rules file of match-rules.
+    #[clap(long)]
+    toml: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct AuditLocalArgs {
+    /// The local directory.
+    #[clap(value_parser)]
+    local: PathBuf,
+
+    /// Only check presence and size, skipping digests (much faster).
+    #[clap(long)]
+    no_digest: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct ServeFixtureArgs {
+    /// The directory to serve.
+    #[clap(value_parser)]
+    dir: PathBuf,
+
+    /// Autoindex style.
+    #[clap(long, value_enum, default_value_t = AutoindexStyle::Nginx)]
+    style: AutoindexStyle,
+
+    /// Address to listen on.
+    #[clap(long, default_value = "127.0.0.1:1922")]
+    bind: String,
+}
+
+#[derive(Parser, Debug)]
+pub struct TestParsersArgs {
+    /// The corpus directory.
+    #[clap(value_parser)]
+    corpus: PathBuf,
+
+    /// Only run this parser.
+    #[clap(long, value_enum)]
+    parser: Option<ParserType>,
+}
+
+/// Log filter: --log, or log of batch config, or "info", extended by RUST_LOG (whose directives win).
+/// Process environment is left untouched.
+fn log_filter(cli: &Cli) -> EnvFilter {
+    let default = cli
+        .log
+        .clone()
+        .or_else(|| match &cli.command {
+            // Errors of config are reported by batch later
+            Commands::Batch(args) => config::Config::load(&args.config).ok()?.batch.log,
+            _ => None,
+        })
+        .unwrap_or_else(|| "info".to_string());
+    let env = std::env::var("RUST_LOG").unwrap_or_default();
+    EnvFilter::builder().parse_lossy(format!("{default},{env}"))
 }
 
 fn main() {
-    // https://github.com/tokio-rs/tracing/issues/735#issuecomment-957884930
-    std::env::set_var(
-        "RUST_LOG",
-        format!("info,{}", std::env::var("RUST_LOG").unwrap_or_default()),
-    );
+    let args = Cli::parse();
     let enable_color = std::env::var("NO_COLOR").is_err();
     tracing_subscriber::fmt()
         .with_thread_ids(true)
-        .with_env_filter(EnvFilter::from_default_env())
+        .with_env_filter(log_filter(&args))
         .with_ansi(enable_color)
         .init();
 
@@ -174,18 +899,30 @@ fn main() {
         None => None,
     };
 
-    // terminate whole process when a thread panics
-    let orig_hook = std::panic::take_hook();
-    std::panic::set_hook(Box::new(move |panic_info| {
-        orig_hook(panic_info);
-        std::process::exit(3);
-    }));
+    // terminate whole process when a thread panics, unless it is in a guarded task
+    panic_guard::install_hook();
+
+    if matches!(
+        args.command,
+        Commands::Sync(_) | Commands::Batch(_) | Commands::Repair(_) | Commands::ApplyDeletions(_)
+    ) {
+        let euid = unsafe { libc::geteuid() };
+        if let Err(e) = root_guard::check_user(euid, args.allow_root, args.run_as.as_deref()) {
+            eprintln!("{e:#}");
+            std::process::exit(1);
+        }
+    }
 
-    let args = Cli::parse();
     match args.command {
         Commands::Sync(args) => {
             cli::sync(&args, bind_address);
         }
+        Commands::Batch(args) => {
+            cli::batch(&args, bind_address);
+        }
+        Commands::Config(args) => {
+            cli::config(&args);
+        }
         Commands::List(args) => {
             // extra arg check
             if !args.upstream_folder.path().ends_with('/') {
@@ -193,5 +930,41 @@ fn main() {
             }
             cli::list(&args, bind_address);
         }
+        Commands::CrossCheck(args) => {
+            cli::cross_check(&args);
+        }
+        Commands::ProbeServer(args) => {
+            cli::probe_server(&args);
+        }
+        Commands::MatchRules(args) => {
+            cli::match_rules(&args);
+        }
+        Commands::ImportRsyncExcludes(args) => {
+            cli::import_rsync_excludes(&args);
+        }
+        Commands::Repair(args) => {
+            cli::repair(&args, bind_address);
+        }
+        Commands::ApplyDeletions(args) => {
+            cli::apply_deletions(&args);
+        }
+        Commands::Stats(args) => {
+            cli::stats(&args);
+        }
+        Commands::ReportDiff(args) => {
+            cli::report_diff(&args);
+        }
+        Commands::SuggestRules(args) => {
+            cli::suggest_rules(&args);
+        }
+        Commands::AuditLocal(args) => {
+            cli::audit_local(&args);
+        }
+        Commands::ServeFixture(args) => {
+            cli::serve_fixture(&args);
+        }
+        Commands::TestParsers(args) => {
+            cli::test_parsers(&args);
+        }
     };
 }
@@ -0,0 +1,206 @@
+// Prometheus metrics in text exposition format, expected to be collected by node_exporter's textfile collector.
+
+use std::{fmt::Write as _, path::Path};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+
+use crate::sync_stats::StatsSnapshot;
+
+const LAST_SUCCESS_METRIC: &str = "tsumugu_last_success_timestamp_seconds";
+
+#[derive(Debug, Default)]
+pub struct Freshness {
+    /// Newest mtime of remote files seen in this run
+    pub newest_remote: Option<DateTime<Utc>>,
+    /// Newest mtime of local files after this run
+    pub newest_local: Option<DateTime<Utc>>,
+}
+
+impl Freshness {
+    /// Mirror lag: newest remote mtime minus newest local mtime (0 if local is newer)
+    pub fn lag_seconds(&self) -> Option<i64> {
+        match (self.newest_remote, self.newest_local) {
+            (Some(remote), Some(local)) => Some((remote - local).num_seconds().max(0)),
+            _ => None,
+        }
+    }
+}
+
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| {
+            let v = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{k}=\"{v}\"")
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Get last success timestamp from metrics file written by previous runs
+fn read_last_success(path: &Path) -> Option<i64> {
+    let content = std::fs::read_to_string(path).ok()?;
+    content
+        .lines()
+        .find(|l| l.starts_with(LAST_SUCCESS_METRIC))
+        .and_then(|l| l.rsplit(' ').next())
+        .and_then(|v| v.parse::<i64>().ok())
+}
+
+pub fn render(
+    labels: &[(&str, &str)],
+    freshness: &Freshness,
+    stats: &StatsSnapshot,
+    last_success: Option<i64>,
+    now: DateTime<Utc>,
+) -> String {
+    let phase_labels = |key: &str, value: &str| {
+        let mut labels = labels.to_vec();
+        labels.push((key, value));
+        format_labels(&labels)
+    };
+    let labels = format_labels(labels);
+    let mut res = String::new();
+    let mut gauge = |name: &str, help: &str, value: Option<i64>| {
+        if let Some(value) = value {
+            writeln!(res, "# HELP {name} {help}").unwrap();
+            writeln!(res, "# TYPE {name} gauge").unwrap();
+            writeln!(res, "{name}{labels} {value}").unwrap();
+        }
+    };
+    gauge(
+        "tsumugu_remote_newest_mtime_seconds",
+        "Newest mtime of remote files seen in last run.",
+        freshness.newest_remote.map(|t| t.timestamp()),
+    );
+    gauge(
+        "tsumugu_local_newest_mtime_seconds",
+        "Newest mtime of local files after last run.",
+        freshness.newest_local.map(|t| t.timestamp()),
+    );
+    gauge(
+        "tsumugu_mirror_lag_seconds",
+        "Newest remote mtime minus newest local mtime.",
+        freshness.lag_seconds(),
+    );
+    gauge(
+        LAST_SUCCESS_METRIC,
+        "Timestamp of last successful sync.",
+        last_success,
+    );
+    gauge(
+        "tsumugu_seconds_since_last_success",
+        "Seconds since last successful sync, when metrics are written.",
+        last_success.map(|t| now.timestamp() - t),
+    );
+    let phases = [
+        ("listed_dirs", stats.listed_dirs),
+        ("listed_files", stats.listed_files),
+        ("failed_listings", stats.failed_listings),
+        ("enqueued_downloads", stats.enqueued_downloads),
+        ("downloaded", stats.downloaded),
+        ("failed_downloads", stats.failed_downloads),
+        ("deleted", stats.deleted),
+    ];
+    let name = "tsumugu_last_run_objects";
+    writeln!(
+        res,
+        "# HELP {name} Objects handled in each phase of last run."
+    )
+    .unwrap();
+    writeln!(res, "# TYPE {name} gauge").unwrap();
+    for (phase, value) in phases {
+        writeln!(res, "{name}{} {value}", phase_labels("phase", phase)).unwrap();
+    }
+    let name = "tsumugu_last_run_skipped_files";
+    writeln!(
+        res,
+        "# HELP {name} Files not downloaded in last run, by reason."
+    )
+    .unwrap();
+    writeln!(res, "# TYPE {name} gauge").unwrap();
+    for (reason, value) in &stats.skipped {
+        let reason = reason.to_string();
+        writeln!(res, "{name}{} {value}", phase_labels("reason", &reason)).unwrap();
+    }
+    res
+}
+
+/// Write metrics to path atomically. Last success timestamp is kept from previous file if this run fails.
+pub fn write_metrics(
+    path: &Path,
+    labels: &[(&str, &str)],
+    freshness: &Freshness,
+    stats: &StatsSnapshot,
+    success: bool,
+) -> Result<()> {
+    let now = Utc::now();
+    let last_success = if success {
+        Some(now.timestamp())
+    } else {
+        read_last_success(path)
+    };
+    let content = render(labels, freshness, stats, last_success, now);
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    std::fs::write(&tmp_path, content)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync_stats::SkipReason;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_metrics() {
+        let freshness = Freshness {
+            newest_remote: Some(Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap()),
+            newest_local: Some(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap()),
+        };
+        assert_eq!(freshness.lag_seconds(), Some(86400));
+        let path = std::env::temp_dir().join(format!("tsumugu-metrics-{}", std::process::id()));
+        let mut stats = StatsSnapshot {
+            downloaded: 3,
+            ..Default::default()
+        };
+        stats.skipped.insert(SkipReason::UpToDate, 5);
+        write_metrics(
+            &path,
+            &[("upstream", "http://a\"b/")],
+            &freshness,
+            &stats,
+            true,
+        )
+        .unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.contains("tsumugu_mirror_lag_seconds{upstream=\"http://a\\\"b/\"} 86400"));
+        assert!(content.contains(
+            "tsumugu_last_run_objects{upstream=\"http://a\\\"b/\",phase=\"downloaded\"} 3"
+        ));
+        assert!(content.contains(
+            "tsumugu_last_run_skipped_files{upstream=\"http://a\\\"b/\",reason=\"up-to-date\"} 5"
+        ));
+        let last_success = read_last_success(&path).unwrap();
+        // Failed run keeps last success timestamp
+        write_metrics(
+            &path,
+            &[],
+            &Freshness::default(),
+            &StatsSnapshot::default(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(read_last_success(&path), Some(last_success));
+        assert!(!std::fs::read_to_string(&path)
+            .unwrap()
+            .contains("tsumugu_mirror_lag_seconds"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
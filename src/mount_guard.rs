@@ -0,0 +1,50 @@
+// Identity (device and inode) of the local directory, recorded when a sync starts and verified before
+// anything is deleted. If the filesystem is unmounted (or another one is mounted) during a run, like an NFS flap,
+// walking local directory would find an empty (or a wrong) tree, and cleanup must not trust it.
+// With --state-file, it is also kept across runs, for the filesystem missing before the run starts.
+
+use std::{os::unix::fs::MetadataExt, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DirIdentity {
+    dev: u64,
+    ino: u64,
+}
+
+impl DirIdentity {
+    /// None if path does not exist (yet)
+    pub fn of(path: &Path) -> Option<Self> {
+        let metadata = path.metadata().ok()?;
+        Some(Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        })
+    }
+
+    /// If path is still the same directory. Errors (like a stale NFS handle) count as changed.
+    pub fn matches(&self, path: &Path) -> bool {
+        Self::of(path).as_ref() == Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dir_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        assert_eq!(DirIdentity::of(&a), None);
+        std::fs::create_dir(&a).unwrap();
+        std::fs::create_dir(&b).unwrap();
+        let identity = DirIdentity::of(&a).unwrap();
+        assert!(identity.matches(&a));
+        // Like another filesystem mounted over it
+        assert!(!identity.matches(&b));
+        std::fs::remove_dir(&a).unwrap();
+        assert!(!identity.matches(&a));
+    }
+}
@@ -0,0 +1,58 @@
+// Panic containment: a panic inside a guarded scope (like a sync task) is caught and returned as error,
+// so that one bad page or a progress bar glitch only fails that task, not the whole run.
+// Panics elsewhere (or in unguarded scopes, like with --fail-fast) still exit the process with code 3.
+
+use std::cell::Cell;
+
+thread_local! {
+    /// If panics on this thread are caught by guard()
+    static GUARDED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Print panics as usual, and exit with code 3 unless it would be caught by guard()
+pub fn install_hook() {
+    let orig_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |panic_info| {
+        orig_hook(panic_info);
+        if !GUARDED.with(|g| g.get()) {
+            std::process::exit(3);
+        }
+    }));
+}
+
+pub fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Run f, returning panic message as error if it panics
+pub fn guard<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    let outer = GUARDED.with(|g| g.replace(true));
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+    GUARDED.with(|g| g.set(outer));
+    res.map_err(panic_message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guard() {
+        assert_eq!(guard(|| 1), Ok(1));
+        assert_eq!(
+            guard(|| -> i32 { panic!("bad page {}", 42) }),
+            Err("bad page 42".to_string())
+        );
+        assert_eq!(
+            guard(|| guard(|| -> () { panic!("inner") })),
+            Ok(Err("inner".to_string()))
+        );
+        assert!(!GUARDED.with(|g| g.get()));
+    }
+}
@@ -3,7 +3,7 @@
 
 use crate::{
     listing::{FileSize, FileType, ListItem},
-    utils::get,
+    utils::get_page,
 };
 
 use super::*;
@@ -17,11 +17,13 @@ pub struct ApacheF2ListingParser;
 
 impl Parser for ApacheF2ListingParser {
     fn get_list(&self, client: &reqwest::blocking::Client, url: &url::Url) -> Result<ListResult> {
-        let resp = get(client, url.clone())?;
-        let url = resp.url().clone();
-        let body = resp.text()?;
-        assert_if_url_has_no_trailing_slash(&url);
-        let document = Html::parse_document(&body);
+        let page = get_page(client, url.clone())?;
+        parse_listing_page(self, &page)
+    }
+
+    fn parse_page(&self, url: &url::Url, body: &str) -> Result<Vec<ListItem>> {
+        assert_if_url_has_no_trailing_slash(url);
+        let document = Html::parse_document(body);
         // find #indexlist which contains file index
         let selector = Selector::parse("#indexlist").unwrap();
         let indexlist = document.select(&selector).next().unwrap();
@@ -39,6 +41,7 @@ impl Parser for ApacheF2ListingParser {
 
             let href = a.value().attr("href").unwrap();
             let name = get_real_name_from_href(href);
+            let original_href = href;
             let href = url.join(href)?;
             let type_ = if href.as_str().ends_with('/') {
                 FileType::Directory
@@ -58,23 +61,26 @@ impl Parser for ApacheF2ListingParser {
 
             let date = NaiveDateTime::parse_from_str(lastmod, "%Y-%m-%d %H:%M")?;
 
-            items.push(ListItem::new(
-                href,
-                name.to_string(),
-                type_,
-                {
-                    if size == "-" {
-                        None
-                    } else {
-                        let (n_size, unit) = FileSize::get_humanized(size);
-                        Some(FileSize::HumanizedBinary(n_size, unit))
-                    }
-                },
-                date,
-            ))
+            items.push(
+                ListItem::new(
+                    href,
+                    name.to_string(),
+                    type_,
+                    {
+                        if size == "-" {
+                            None
+                        } else {
+                            let (n_size, unit) = FileSize::get_humanized(size);
+                            Some(FileSize::HumanizedBinary(n_size, unit))
+                        }
+                    },
+                    Some(date),
+                )
+                .with_original_href(original_href),
+            )
         }
 
-        Ok(ListResult::List(items))
+        Ok(items)
     }
 }
 
@@ -100,7 +106,7 @@ mod tests {
                 assert_eq!(items[0].type_, FileType::Directory);
                 assert_eq!(items[0].size, None);
                 assert_eq!(
-                    items[0].mtime,
+                    items[0].mtime.unwrap(),
                     NaiveDateTime::parse_from_str("2022-01-18 15:14", "%Y-%m-%d %H:%M").unwrap()
                 );
                 assert_eq!(items[6].name, "Release.key");
@@ -110,7 +116,7 @@ mod tests {
                     Some(FileSize::HumanizedBinary(3.0, SizeUnit::K))
                 );
                 assert_eq!(
-                    items[6].mtime,
+                    items[6].mtime.unwrap(),
                     NaiveDateTime::parse_from_str("2017-03-28 14:54", "%Y-%m-%d %H:%M").unwrap()
                 );
             }
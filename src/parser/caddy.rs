@@ -1,7 +1,7 @@
 /// A parser for default caddy file_server format
 use crate::{
     listing::{FileSize, FileType, ListItem},
-    utils::get,
+    utils::get_page,
 };
 
 use super::*;
@@ -14,11 +14,13 @@ pub struct CaddyListingParser;
 
 impl Parser for CaddyListingParser {
     fn get_list(&self, client: &Client, url: &Url) -> Result<ListResult> {
-        let resp = get(client, url.clone())?;
-        let url = resp.url().clone();
-        let body = resp.text()?;
-        assert_if_url_has_no_trailing_slash(&url);
-        let document = Html::parse_document(&body);
+        let page = get_page(client, url.clone())?;
+        parse_listing_page(self, &page)
+    }
+
+    fn parse_page(&self, url: &Url, body: &str) -> Result<Vec<ListItem>> {
+        assert_if_url_has_no_trailing_slash(url);
+        let document = Html::parse_document(body);
         let selector = Selector::parse("tr.file").unwrap();
         let mut items = Vec::new();
         for element in document.select(&selector) {
@@ -30,6 +32,7 @@ impl Parser for CaddyListingParser {
             let name = get_real_name_from_href(href)
                 .trim_start_matches("./")
                 .to_string();
+            let original_href = href;
             let href = url.join(href)?;
             let type_ = if href.as_str().ends_with('/') {
                 FileType::Directory
@@ -58,10 +61,13 @@ impl Parser for CaddyListingParser {
             // Store UTC time
             let date = NaiveDateTime::parse_from_str(mtime, "%Y-%m-%dT%H:%M:%S%Z")?;
 
-            items.push(ListItem::new(href, name, type_, size, date))
+            items.push(
+                ListItem::new(href, name, type_, size, Some(date))
+                    .with_original_href(original_href),
+            )
         }
 
-        Ok(ListResult::List(items))
+        Ok(items)
     }
 }
 
@@ -87,7 +93,7 @@ mod tests {
                 assert_eq!(items[0].type_, FileType::Directory);
                 assert_eq!(items[0].size, None);
                 assert_eq!(
-                    items[0].mtime,
+                    items[0].mtime.unwrap(),
                     NaiveDateTime::parse_from_str("2023-07-10T13:07:52Z", "%Y-%m-%dT%H:%M:%S%Z")
                         .unwrap()
                 );
@@ -95,7 +101,7 @@ mod tests {
                 assert_eq!(items[5].type_, FileType::Directory);
                 assert_eq!(items[5].size, None);
                 assert_eq!(
-                    items[5].mtime,
+                    items[5].mtime.unwrap(),
                     NaiveDateTime::parse_from_str("2010-11-24T11:01:53Z", "%Y-%m-%dT%H:%M:%S%Z")
                         .unwrap()
                 );
@@ -106,7 +112,7 @@ mod tests {
                     Some(FileSize::HumanizedBinary(26.0, SizeUnit::M))
                 );
                 assert_eq!(
-                    items[6].mtime,
+                    items[6].mtime.unwrap(),
                     NaiveDateTime::parse_from_str("2024-03-10T04:45:24Z", "%Y-%m-%dT%H:%M:%S%Z")
                         .unwrap()
                 );
@@ -1,6 +1,6 @@
 use crate::{
     listing::{FileSize, FileType, ListItem},
-    utils::get,
+    utils::get_page,
 };
 
 use super::*;
@@ -13,11 +13,13 @@ pub struct DirectoryListerListingParser;
 
 impl Parser for DirectoryListerListingParser {
     fn get_list(&self, client: &reqwest::blocking::Client, url: &url::Url) -> Result<ListResult> {
-        let resp = get(client, url.clone())?;
-        let url = resp.url().clone();
-        let body = resp.text()?;
-        assert_if_url_has_no_trailing_slash(&url);
-        let document = Html::parse_document(&body);
+        let page = get_page(client, url.clone())?;
+        parse_listing_page(self, &page)
+    }
+
+    fn parse_page(&self, url: &url::Url, body: &str) -> Result<Vec<ListItem>> {
+        assert_if_url_has_no_trailing_slash(url);
+        let document = Html::parse_document(body);
         // https://github.com/DirectoryLister/DirectoryLister/blob/0283f14aa1fbd97796f753e8d6105c752546050f/app/views/components/file.twig
 
         // find <ul> which contains file index
@@ -30,6 +32,7 @@ impl Parser for DirectoryListerListingParser {
         let mut items = Vec::new();
         for element in indexlist.select(&selector) {
             let href = element.value().attr("href").unwrap();
+            let original_href = href;
             let href = url.join(href)?;
             // displayed file name, class = "flex-1 truncate"
             let selector = Selector::parse("div.flex-1.truncate").unwrap();
@@ -54,23 +57,26 @@ impl Parser for DirectoryListerListingParser {
                 FileType::File
             };
             let date = NaiveDateTime::parse_from_str(mtime, "%Y-%m-%d %H:%M:%S")?;
-            items.push(ListItem::new(
-                href,
-                displayed_filename.to_string(),
-                type_,
-                {
-                    if size == "—" {
-                        None
-                    } else {
-                        let (n_size, unit) = FileSize::get_humanized(size);
-                        Some(FileSize::HumanizedBinary(n_size, unit))
-                    }
-                },
-                date,
-            ))
+            items.push(
+                ListItem::new(
+                    href,
+                    displayed_filename.to_string(),
+                    type_,
+                    {
+                        if size == "—" {
+                            None
+                        } else {
+                            let (n_size, unit) = FileSize::get_humanized(size);
+                            Some(FileSize::HumanizedBinary(n_size, unit))
+                        }
+                    },
+                    Some(date),
+                )
+                .with_original_href(original_href),
+            )
         }
 
-        Ok(ListResult::List(items))
+        Ok(items)
     }
 }
 
@@ -98,7 +104,7 @@ mod tests {
                 assert_eq!(items[0].type_, FileType::Directory);
                 assert_eq!(items[0].size, None);
                 assert_eq!(
-                    items[0].mtime,
+                    items[0].mtime.unwrap(),
                     NaiveDateTime::parse_from_str("2023-08-07 21:11:02", "%Y-%m-%d %H:%M:%S")
                         .unwrap()
                 );
@@ -116,7 +122,7 @@ mod tests {
                     Some(FileSize::HumanizedBinary(1.80, SizeUnit::M))
                 );
                 assert_eq!(
-                    items[4].mtime,
+                    items[4].mtime.unwrap(),
                     NaiveDateTime::parse_from_str("2023-08-07 21:10:57", "%Y-%m-%d %H:%M:%S")
                         .unwrap()
                 );
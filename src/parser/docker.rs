@@ -1,6 +1,6 @@
 use crate::{
     listing::{FileSize, FileType, ListItem},
-    utils::get,
+    utils::get_page,
 };
 use chrono::NaiveDateTime;
 use scraper::{Html, Selector};
@@ -33,10 +33,9 @@ impl Parser for DockerListingParser {
 
     fn get_list(&self, client: &reqwest::blocking::Client, url: &url::Url) -> Result<ListResult> {
         assert_if_url_has_no_trailing_slash(url);
-        let resp = get(client, url.clone())?;
+        let page = get_page(client, url.clone())?;
         // if is a redirect?
-        if let Some(url) = resp.headers().get("location") {
-            let mut url = url.to_str()?.to_string();
+        if let Some(mut url) = page.location {
             // replace /index.html at the end to /
             if url.ends_with("/index.html") {
                 url = url.trim_end_matches("/index.html").to_string();
@@ -44,8 +43,11 @@ impl Parser for DockerListingParser {
             }
             return Ok(ListResult::Redirect(url));
         }
-        let body = resp.text()?;
-        let document = Html::parse_document(&body);
+        parse_listing_page(self, &page)
+    }
+
+    fn parse_page(&self, url: &url::Url, body: &str) -> Result<Vec<ListItem>> {
+        let document = Html::parse_document(body);
         let selector = Selector::parse("a").unwrap();
         let mut items = Vec::new();
         for element in document.select(&selector) {
@@ -54,6 +56,7 @@ impl Parser for DockerListingParser {
                 None => continue,
             };
             let name = get_real_name_from_href(href);
+            let original_href = href;
             let mut href = url.join(href)?;
 
             if name == ".." {
@@ -64,7 +67,7 @@ impl Parser for DockerListingParser {
 
             let (type_, size, date) = {
                 if href.as_str().ends_with('/') || displayed_name.ends_with('/') {
-                    (FileType::Directory, None, NaiveDateTime::default())
+                    (FileType::Directory, None, None)
                 } else {
                     let metadata_raw = element
                         .next_sibling()
@@ -82,13 +85,13 @@ impl Parser for DockerListingParser {
                     };
                     let size = metadata.get(3).unwrap().as_str();
                     if size == "-" {
-                        (FileType::Directory, None, date)
+                        (FileType::Directory, None, Some(date))
                     } else {
                         let (n_size, unit) = FileSize::get_humanized(size);
                         (
                             FileType::File,
                             Some(FileSize::HumanizedBinary(n_size, unit)),
-                            date,
+                            Some(date),
                         )
                     }
                 }
@@ -97,9 +100,12 @@ impl Parser for DockerListingParser {
                 href.set_path(&format!("{}/", href.path()));
             }
 
-            items.push(ListItem::new(href, name.to_string(), type_, size, date))
+            items.push(
+                ListItem::new(href, name.to_string(), type_, size, date)
+                    .with_original_href(original_href),
+            )
         }
-        Ok(ListResult::List(items))
+        Ok(items)
     }
 }
 
@@ -124,7 +130,7 @@ mod tests {
                 assert_eq!(items[0].name, "7.0");
                 assert_eq!(items[0].type_, FileType::Directory);
                 assert_eq!(items[0].size, None);
-                assert_eq!(items[0].mtime, NaiveDateTime::default());
+                assert_eq!(items[0].mtime, None);
                 assert_eq!(items[42].name, "docker-ce-staging.repo");
                 assert_eq!(items[42].type_, FileType::File);
                 assert_eq!(
@@ -132,7 +138,7 @@ mod tests {
                     Some(FileSize::HumanizedBinary(2.0, SizeUnit::K))
                 );
                 assert_eq!(
-                    items[42].mtime,
+                    items[42].mtime.unwrap(),
                     NaiveDateTime::parse_from_str("2023-07-07 20:20:56", "%Y-%m-%d %H:%M:%S")
                         .unwrap()
                 );
@@ -0,0 +1,196 @@
+/// A parser for index pages rendered by JavaScript, whose data is embedded in a script tag (like
+/// `<script id="__NEXT_DATA__" type="application/json">` of Next.js, or `<script id="__DATA__">` of some
+/// Vue/React index UIs), found by --embedded-json-selector. The script could also assign the JSON to a variable.
+/// Entries are found as of --manifest (with --manifest-field mappings), with paths being names under the page.
+use crate::{
+    listing::{FileSize, FileType, ListItem},
+    utils::get_page,
+};
+
+use super::manifest::{
+    find_files, lookup, parse_mtime, parse_size, url_under, Fields, ManifestField,
+};
+use super::*;
+use anyhow::{Context, Result};
+use scraper::{Html, Selector};
+use serde_json::Value;
+
+pub const DEFAULT_SELECTOR: &str = r#"script[type="application/json"], script#__DATA__"#;
+
+#[derive(Debug)]
+pub struct EmbeddedJsonListingParser {
+    fields: Fields,
+    selector: Selector,
+}
+
+impl Default for EmbeddedJsonListingParser {
+    fn default() -> Self {
+        Self::new(&[], DEFAULT_SELECTOR).unwrap()
+    }
+}
+
+/// JSON in script, which is either JSON itself, or like `window.__DATA__ = {...};` (with more code after it).
+/// The value starts from the first brace (or bracket) it could be parsed from, and ends where the parser stops.
+fn parse_script(text: &str) -> Option<Value> {
+    if let Ok(value) = serde_json::from_str(text) {
+        return Some(value);
+    }
+    text.match_indices(['{', '[']).find_map(|(start, _)| {
+        serde_json::Deserializer::from_str(&text[start..])
+            .into_iter::<Value>()
+            .next()?
+            .ok()
+    })
+}
+
+impl EmbeddedJsonListingParser {
+    pub fn new(fields: &[ManifestField], selector: &str) -> Result<Self> {
+        Ok(Self {
+            fields: Fields::new(fields),
+            selector: Selector::parse(selector)
+                .map_err(|e| anyhow!("Invalid selector {:?}: {}", selector, e))?,
+        })
+    }
+
+    fn parse_entry(&self, url: &Url, entry: &Value) -> Result<Option<ListItem>> {
+        let Some(path) = lookup(entry, &self.fields.path).and_then(|v| v.as_str()) else {
+            warn!("Skipping embedded entry without path: {}", entry);
+            return Ok(None);
+        };
+        let is_dir = path.ends_with('/')
+            || matches!(
+                entry.get("type").and_then(|v| v.as_str()),
+                Some("directory" | "dir" | "folder")
+            );
+        let name = path.trim_matches('/');
+        if name.is_empty() || name.contains('/') {
+            warn!("Skipping embedded entry with invalid name {:?}", path);
+            return Ok(None);
+        }
+        let (type_, href) = match is_dir {
+            true => (FileType::Directory, format!("{name}/")),
+            false => (FileType::File, name.to_string()),
+        };
+        let item_url = match lookup(entry, &self.fields.url).and_then(|v| v.as_str()) {
+            Some(href) => url
+                .join(href)
+                .with_context(|| format!("Invalid URL {:?} of {:?}", href, name))?,
+            None => url_under(url, &href)?,
+        };
+        let item = ListItem::new(
+            item_url,
+            name.to_string(),
+            type_,
+            lookup(entry, &self.fields.size)
+                .and_then(parse_size)
+                .filter(|_| !is_dir)
+                .map(FileSize::Precise),
+            lookup(entry, &self.fields.mtime).and_then(parse_mtime),
+        )
+        .with_sha256(
+            lookup(entry, &self.fields.sha256)
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_lowercase()),
+        );
+        Ok(Some(item))
+    }
+}
+
+impl Parser for EmbeddedJsonListingParser {
+    fn get_list(&self, client: &Client, url: &Url) -> Result<ListResult> {
+        let page = get_page(client, url.clone())?;
+        parse_listing_page(self, &page)
+    }
+
+    fn parse_page(&self, url: &Url, body: &str) -> Result<Vec<ListItem>> {
+        assert_if_url_has_no_trailing_slash(url);
+        let document = Html::parse_document(body);
+        // The first matching script tag with an array of files
+        let data: Option<Value> = document.select(&self.selector).find_map(|script| {
+            let data = parse_script(&script.text().collect::<String>())?;
+            find_files(&data, &self.fields).is_some().then_some(data)
+        });
+        let data = data.ok_or_else(|| ListError::NoFiles {
+            url: url.clone(),
+            place: "JSON in <script> tags",
+        })?;
+        let mut items = Vec::new();
+        for entry in find_files(&data, &self.fields).into_iter().flatten() {
+            if let Some(item) = self.parse_entry(url, entry)? {
+                items.push(item);
+            }
+        }
+        Ok(items)
+    }
+
+    fn reads_scripts(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embedded_json() {
+        let body = r#"<!DOCTYPE html><html><head><script type="application/json">{"theme": "dark"}</script></head>
+<body><div id="__next"></div>
+<script id="__NEXT_DATA__" type="application/json">{"props": {"pageProps": {"entries": [
+  {"name": "releases", "type": "directory", "modified": "2024-03-01T00:00:00Z"},
+  {"name": "app 1.0.tar.gz", "bytes": 1024, "modified": 1700000000, "sha256": "ABCD"},
+  {"name": "nested/file"}
+]}}}</script></body></html>"#;
+        let fields: Vec<ManifestField> = [
+            "files=$.props.pageProps.entries",
+            "path=name",
+            "size=bytes",
+            "mtime=modified",
+        ]
+        .iter()
+        .map(|f| f.parse().unwrap())
+        .collect();
+        let url = Url::parse("https://example.com/pub/").unwrap();
+        let items = EmbeddedJsonListingParser::new(&fields, DEFAULT_SELECTOR)
+            .unwrap()
+            .parse_page(&url, body)
+            .unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].name, "releases");
+        assert_eq!(items[0].type_, FileType::Directory);
+        assert_eq!(items[0].url.as_str(), "https://example.com/pub/releases/");
+        assert_eq!(
+            items[1].url.as_str(),
+            "https://example.com/pub/app%201.0.tar.gz"
+        );
+        assert_eq!(items[1].size, Some(FileSize::Precise(1024)));
+        assert_eq!(items[1].sha256.as_deref(), Some("abcd"));
+
+        assert!(EmbeddedJsonListingParser::default()
+            .parse_page(&url, "<html><body><div id=\"root\"></div></body></html>")
+            .is_err());
+
+        // Assigned to a variable, in a tag found by selector
+        let body = r#"<div id="app"></div><script data-role="listing">
+  window.__DATA__ = [{"path": "a.iso", "size": 3}];
+</script><script id="__DATA__">{"files": [{"path": "b.iso"}]}</script>"#;
+        let items = EmbeddedJsonListingParser::new(&[], "script[data-role=listing]")
+            .unwrap()
+            .parse_page(&url, body)
+            .unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].name, "a.iso");
+        let items = EmbeddedJsonListingParser::default()
+            .parse_page(&url, body)
+            .unwrap();
+        assert_eq!(items[0].name, "b.iso");
+        assert!(EmbeddedJsonListingParser::new(&[], "script[").is_err());
+
+        let script = r#"/* [data] */ window.__DATA__ = {"files": [{"path": "a.iso"}]}; render({"el": ".app"});"#;
+        assert_eq!(
+            parse_script(script),
+            Some(serde_json::json!({"files": [{"path": "a.iso"}]}))
+        );
+        assert_eq!(parse_script("window.__DATA__ = {broken"), None);
+    }
+}
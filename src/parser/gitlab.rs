@@ -0,0 +1,387 @@
+/// A "parser" which gets file list from GitLab API: release asset links and generic packages of a project.
+/// They are laid out as `releases/<tag>/<asset path>` and `packages/<name>/<version>/<file>` under upstream,
+/// and downloaded from GitLab (or where asset links point to). Source code archives of releases are not mirrored,
+/// as GitLab generates them on demand and they are not guaranteed to be byte-identical.
+///
+/// For private projects, set GITLAB_TOKEN environment variable to a token with read_api scope.
+/// It is sent (as bearer token) only to the GitLab instance.
+use std::{collections::BTreeMap, sync::Mutex};
+
+use crate::{
+    listing::{FileSize, FileType, ListItem},
+    utils::get_pages,
+};
+
+use super::*;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, NaiveDateTime};
+use serde::{de::DeserializeOwned, Deserialize};
+use tracing::{info, warn};
+
+const TOKEN_ENV: &str = "GITLAB_TOKEN";
+const PER_PAGE: usize = 100;
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    released_at: Option<String>,
+    assets: ReleaseAssets,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReleaseAssets {
+    #[serde(default)]
+    links: Vec<ReleaseLink>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReleaseLink {
+    name: String,
+    url: String,
+    direct_asset_url: Option<String>,
+    /// "filepath" in GitLab < 15.9
+    #[serde(alias = "filepath")]
+    direct_asset_path: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Package {
+    id: u64,
+    name: String,
+    version: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PackageFile {
+    file_name: String,
+    size: u64,
+    created_at: String,
+}
+
+#[derive(Debug, Clone)]
+struct GitLabEntry {
+    /// Path segments under upstream
+    path: Vec<String>,
+    url: Url,
+    size: Option<u64>,
+    mtime: Option<NaiveDateTime>,
+}
+
+#[derive(Debug)]
+pub struct GitLabListingParser {
+    /// Like https://gitlab.example.com/api/v4/projects/group%2Fproject/
+    api_base: Url,
+    /// Upstream base, which the project root is mapped to
+    http_base: Url,
+    /// Loaded on first listing
+    entries: Mutex<Option<Vec<GitLabEntry>>>,
+}
+
+fn parse_time(s: &str) -> Option<NaiveDateTime> {
+    DateTime::parse_from_rfc3339(s).ok().map(|t| t.naive_utc())
+}
+
+impl GitLabListingParser {
+    /// project is the web URL of project, like https://gitlab.example.com/group/project
+    pub fn new(project: &Url, http_base: Url) -> Result<Self> {
+        let path = project.path().trim_matches('/');
+        if path.is_empty() {
+            return Err(anyhow!("{} is not a GitLab project URL", project));
+        }
+        let mut api_base = project.clone();
+        api_base.set_query(None);
+        api_base
+            .path_segments_mut()
+            .map_err(|_| ListError::CannotBeBase {
+                url: project.clone(),
+            })?
+            .clear()
+            .extend(["api", "v4", "projects", path, ""]);
+        if let Ok(token) = std::env::var(TOKEN_ENV) {
+            crate::utils::set_bearer_token(&api_base, token);
+        }
+        Ok(Self {
+            api_base,
+            http_base,
+            entries: Mutex::new(None),
+        })
+    }
+
+    /// Get all pages of a paginated API, following Link headers
+    fn get_all<T: DeserializeOwned>(&self, client: &Client, path: &str) -> Result<Vec<T>> {
+        let mut url = self.api_base.join(path)?;
+        url.query_pairs_mut()
+            .append_pair("per_page", &PER_PAGE.to_string());
+        let mut results = Vec::new();
+        for page in get_pages(client, url)? {
+            let items: Vec<T> = serde_json::from_str(&page.body)
+                .with_context(|| format!("Invalid response of {}", page.url))?;
+            results.extend(items);
+        }
+        Ok(results)
+    }
+
+    fn load(&self, client: &Client) -> Result<Vec<GitLabEntry>> {
+        let mut entries = Vec::new();
+        for release in self.get_all::<Release>(client, "releases")? {
+            entries.extend(release_entries(&self.api_base, release));
+        }
+        for package in self.get_all::<Package>(client, "packages?package_type=generic")? {
+            let files = self.get_all::<PackageFile>(
+                client,
+                &format!("packages/{}/package_files", package.id),
+            )?;
+            entries.extend(package_entries(&self.api_base, &package, files)?);
+        }
+        info!("Loaded {} files from GitLab", entries.len());
+        Ok(entries)
+    }
+}
+
+fn release_entries(api_base: &Url, release: Release) -> Vec<GitLabEntry> {
+    let mtime = release.released_at.as_deref().and_then(parse_time);
+    let mut entries = Vec::new();
+    for link in release.assets.links {
+        let url = link.direct_asset_url.as_deref().unwrap_or(&link.url);
+        let url = match api_base.join(url) {
+            Ok(url) => url,
+            Err(e) => {
+                warn!("Invalid asset URL {:?} of {}: {}", url, release.tag_name, e);
+                continue;
+            }
+        };
+        let asset_path = link.direct_asset_path.as_deref().unwrap_or(&link.name);
+        let mut path = vec!["releases".to_string()];
+        path.extend(release.tag_name.split('/').map(|s| s.to_string()));
+        path.extend(
+            asset_path
+                .split('/')
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_string()),
+        );
+        entries.push(GitLabEntry {
+            path,
+            url,
+            size: None,
+            mtime,
+        });
+    }
+    entries
+}
+
+fn package_entries(
+    api_base: &Url,
+    package: &Package,
+    files: Vec<PackageFile>,
+) -> Result<Vec<GitLabEntry>> {
+    // A file could be uploaded more than once, and the latest one is downloaded
+    let mut latest: BTreeMap<String, PackageFile> = BTreeMap::new();
+    for file in files {
+        match latest.get(&file.file_name) {
+            Some(old) if old.created_at >= file.created_at => {}
+            _ => {
+                latest.insert(file.file_name.clone(), file);
+            }
+        }
+    }
+    let mut entries = Vec::new();
+    for (file_name, file) in latest {
+        let mut url = api_base.clone();
+        url.path_segments_mut()
+            .map_err(|_| ListError::CannotBeBase {
+                url: api_base.clone(),
+            })?
+            .pop_if_empty()
+            .extend([
+                "packages",
+                "generic",
+                &package.name,
+                &package.version,
+                &file_name,
+            ]);
+        entries.push(GitLabEntry {
+            path: vec![
+                "packages".to_string(),
+                package.name.clone(),
+                package.version.clone(),
+                file_name,
+            ],
+            url,
+            size: Some(file.size),
+            mtime: parse_time(&file.created_at),
+        });
+    }
+    Ok(entries)
+}
+
+/// Items directly under given path segments
+fn list_entries(entries: &[GitLabEntry], base: &Url, relative: &[String]) -> Vec<ListItem> {
+    let mut items: BTreeMap<String, ListItem> = BTreeMap::new();
+    for entry in entries {
+        if entry.path.len() <= relative.len() || entry.path[..relative.len()] != *relative {
+            continue;
+        }
+        let name = &entry.path[relative.len()];
+        if entry.path.len() == relative.len() + 1 {
+            let item = ListItem::new(
+                entry.url.clone(),
+                name.clone(),
+                FileType::File,
+                entry.size.map(FileSize::Precise),
+                entry.mtime,
+            );
+            items.insert(name.clone(), item);
+        } else {
+            let item = items.entry(name.clone()).or_insert_with(|| {
+                let mut dir_url = base.clone();
+                dir_url
+                    .path_segments_mut()
+                    .unwrap()
+                    .pop_if_empty()
+                    .extend(relative)
+                    .extend([name, ""]);
+                ListItem::new(
+                    dir_url,
+                    name.clone(),
+                    FileType::Directory,
+                    None,
+                    entry.mtime,
+                )
+            });
+            item.mtime = item.mtime.max(entry.mtime);
+        }
+    }
+    items.into_values().collect()
+}
+
+impl Parser for GitLabListingParser {
+    fn get_list(&self, client: &Client, url: &Url) -> Result<ListResult> {
+        assert_if_url_has_no_trailing_slash(url);
+        let relative = url
+            .path()
+            .strip_prefix(self.http_base.path())
+            .ok_or_else(|| ListError::NotUnder {
+                url: url.clone(),
+                base: self.http_base.clone(),
+            })?;
+        let relative: Vec<String> = relative
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(get_real_name_from_href)
+            .collect();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.is_none() {
+            *entries = Some(self.load(client)?);
+        }
+        // Loaded above
+        let entries = entries.as_ref().unwrap();
+        Ok(ListResult::List(list_entries(
+            entries,
+            &self.http_base,
+            &relative,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RELEASES: &str = r#"[
+  {
+    "tag_name": "v1.2.0",
+    "released_at": "2024-03-01T08:00:00.000+08:00",
+    "assets": {
+      "count": 3,
+      "sources": [{"format": "zip", "url": "https://gitlab.example.com/group/tool/-/archive/v1.2.0/tool-v1.2.0.zip"}],
+      "links": [
+        {"id": 1, "name": "Linux binary", "url": "https://gitlab.example.com/group/tool/-/package_files/10/download",
+         "direct_asset_url": "https://gitlab.example.com/group/tool/-/releases/v1.2.0/downloads/bin/tool-linux-amd64",
+         "direct_asset_path": "/bin/tool-linux-amd64", "link_type": "package"},
+        {"id": 2, "name": "checksums.txt", "url": "https://cdn.example.net/tool/v1.2.0/checksums.txt", "link_type": "other"}
+      ]
+    }
+  }
+]"#;
+
+    const PACKAGE_FILES: &str = r#"[
+  {"id": 1, "package_id": 7, "file_name": "tool.tar.gz", "size": 100, "created_at": "2024-03-01T00:00:00.000Z", "file_sha256": "aa"},
+  {"id": 2, "package_id": 7, "file_name": "tool.tar.gz", "size": 120, "created_at": "2024-03-02T00:00:00.000Z", "file_sha256": "bb"},
+  {"id": 3, "package_id": 7, "file_name": "a b.txt", "size": 5, "created_at": "2024-03-01T00:00:00.000Z", "file_sha256": "cc"}
+]"#;
+
+    fn entries(api_base: &Url) -> Vec<GitLabEntry> {
+        let releases: Vec<Release> = serde_json::from_str(RELEASES).unwrap();
+        let mut entries: Vec<_> = releases
+            .into_iter()
+            .flat_map(|r| release_entries(api_base, r))
+            .collect();
+        let package = Package {
+            id: 7,
+            name: "tool".to_string(),
+            version: "1.2.0".to_string(),
+        };
+        let files = serde_json::from_str(PACKAGE_FILES).unwrap();
+        entries.extend(package_entries(api_base, &package, files).unwrap());
+        entries
+    }
+
+    #[test]
+    fn test_api_base() {
+        let parser = GitLabListingParser::new(
+            &Url::parse("https://gitlab.example.com/group/sub/tool").unwrap(),
+            Url::parse("https://gitlab.example.com/group/sub/tool/").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            parser.api_base.as_str(),
+            "https://gitlab.example.com/api/v4/projects/group%2Fsub%2Ftool/"
+        );
+    }
+
+    #[test]
+    fn test_gitlab() {
+        let api_base =
+            Url::parse("https://gitlab.example.com/api/v4/projects/group%2Ftool/").unwrap();
+        let base = Url::parse("https://gitlab.example.com/group/tool/").unwrap();
+        let entries = entries(&api_base);
+        let names =
+            |items: Vec<ListItem>| -> Vec<String> { items.into_iter().map(|i| i.name).collect() };
+
+        assert_eq!(
+            names(list_entries(&entries, &base, &[])),
+            vec!["packages", "releases"]
+        );
+        let relative = vec!["releases".to_string(), "v1.2.0".to_string()];
+        let items = list_entries(&entries, &base, &relative);
+        assert_eq!(items[0].name, "bin");
+        assert_eq!(
+            items[0].url.as_str(),
+            "https://gitlab.example.com/group/tool/releases/v1.2.0/bin/"
+        );
+        assert_eq!(items[1].name, "checksums.txt");
+        assert_eq!(
+            items[1].url.as_str(),
+            "https://cdn.example.net/tool/v1.2.0/checksums.txt"
+        );
+        assert_eq!(
+            items[1].mtime.unwrap(),
+            NaiveDateTime::parse_from_str("2024-03-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap()
+        );
+
+        let relative = vec![
+            "packages".to_string(),
+            "tool".to_string(),
+            "1.2.0".to_string(),
+        ];
+        let items = list_entries(&entries, &base, &relative);
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].name, "a b.txt");
+        assert_eq!(
+            items[0].url.as_str(),
+            "https://gitlab.example.com/api/v4/projects/group%2Ftool/packages/generic/tool/1.2.0/a%20b.txt"
+        );
+        // the latest upload wins
+        assert_eq!(items[1].size, Some(FileSize::Precise(120)));
+    }
+}
@@ -0,0 +1,122 @@
+/// A parser for Go's net/http FileServer listing, which is a bare <pre> of links (without sizes or mtimes).
+use crate::{
+    listing::{FileType, ListItem},
+    utils::get_page,
+};
+
+use super::*;
+use anyhow::{bail, Result};
+use scraper::{Html, Selector};
+
+#[derive(Debug, Clone, Default)]
+pub struct GoFileServerListingParser;
+
+impl Parser for GoFileServerListingParser {
+    fn get_list(&self, client: &Client, url: &Url) -> Result<ListResult> {
+        let page = get_page(client, url.clone())?;
+        parse_listing_page(self, &page)
+    }
+
+    fn parse_page(&self, url: &Url, body: &str) -> Result<Vec<ListItem>> {
+        assert_if_url_has_no_trailing_slash(url);
+        let document = Html::parse_document(body);
+        let selector = Selector::parse("pre").unwrap();
+        // Go puts nothing but links and newlines in <pre>, unlike nginx and others with sizes and dates
+        for pre in document.select(&selector) {
+            if pre
+                .children()
+                .filter_map(|node| node.value().as_text())
+                .any(|text| !text.trim().is_empty())
+            {
+                bail!("Unexpected text in <pre>, this is not a Go FileServer listing");
+            }
+        }
+        let selector = Selector::parse("pre > a").unwrap();
+        let mut items = Vec::new();
+        for element in document.select(&selector) {
+            let href = match element.value().attr("href") {
+                Some(href) => href,
+                None => continue,
+            };
+            // Go escapes href as URL path, and prepends "./" when the name contains ':'
+            let name = get_real_name_from_href(href)
+                .trim_start_matches("./")
+                .to_string();
+            let original_href = href;
+            let href = url.join(href)?;
+            let type_ = if href.as_str().ends_with('/') {
+                FileType::Directory
+            } else {
+                FileType::File
+            };
+            items.push(
+                ListItem::new(href, name, type_, None, None).with_original_href(original_href),
+            );
+        }
+        Ok(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_go_fileserver() {
+        let client = reqwest::blocking::Client::new();
+        let items = GoFileServerListingParser
+            .get_list(
+                &client,
+                &Url::parse("http://localhost:1921/go-fileserver/").unwrap(),
+            )
+            .unwrap();
+        match items {
+            ListResult::List(items) => {
+                let names: Vec<_> = items.iter().map(|i| i.name.as_str()).collect();
+                assert_eq!(
+                    names,
+                    vec![
+                        "CHECKSUMS",
+                        "agent-1.4.2-linux-amd64.tar.gz",
+                        "agent-1.4.2-linux-arm64.tar.gz",
+                        "release notes #50.txt",
+                        "build:2024-03-01.log",
+                        "tools"
+                    ]
+                );
+                assert_eq!(items[0].size, None);
+                assert_eq!(
+                    items[3].url.as_str(),
+                    "http://localhost:1921/go-fileserver/release%20notes%20%2350.txt"
+                );
+                assert_eq!(
+                    items[4].url.as_str(),
+                    "http://localhost:1921/go-fileserver/build:2024-03-01.log"
+                );
+                assert_eq!(items[5].type_, FileType::Directory);
+            }
+            _ => unreachable!(),
+        }
+        let items = GoFileServerListingParser
+            .get_list(
+                &client,
+                &Url::parse("http://localhost:1921/go-fileserver/tools/").unwrap(),
+            )
+            .unwrap();
+        match items {
+            ListResult::List(items) => {
+                assert_eq!(items.len(), 2);
+                assert_eq!(items[1].name, "R&D");
+                assert_eq!(items[1].type_, FileType::Directory);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_reject_other_listing() {
+        let body = std::fs::read_to_string("corpus/nginx-monitoring-plugins.html").unwrap();
+        let url = Url::parse("http://localhost/").unwrap();
+        assert!(GoFileServerListingParser.parse_page(&url, &body).is_err());
+    }
+}
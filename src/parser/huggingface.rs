@@ -0,0 +1,262 @@
+/// A "parser" which lists files of a Hugging Face Hub repo (model, dataset or space) with its tree API,
+/// mapping the repo root to upstream. Files are downloaded from `resolve/` URLs (which redirect LFS files to CDN),
+/// and verified with SHA-256 given by API (LFS files only). mtime is the date of last commit touching the file (UTC).
+///
+/// For gated or private repos, set HF_TOKEN environment variable. It is sent (as bearer token) only to the Hub.
+use crate::{
+    listing::{FileSize, FileType, ListItem},
+    utils::get_pages,
+};
+
+use super::*;
+use anyhow::{anyhow, Context, Result};
+use chrono::DateTime;
+use serde::Deserialize;
+
+const TOKEN_ENV: &str = "HF_TOKEN";
+
+#[derive(Debug, Deserialize)]
+struct LastCommit {
+    date: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Lfs {
+    oid: String,
+    size: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct TreeEntry {
+    #[serde(rename = "type")]
+    type_: String,
+    path: String,
+    #[serde(default)]
+    size: u64,
+    lfs: Option<Lfs>,
+    #[serde(rename = "lastCommit")]
+    last_commit: Option<LastCommit>,
+}
+
+#[derive(Debug)]
+pub struct HuggingFaceListingParser {
+    /// Like https://huggingface.co/api/datasets/owner/name/tree/main/
+    tree_base: Url,
+    /// Like https://huggingface.co/datasets/owner/name/resolve/main/
+    resolve_base: Url,
+    /// Upstream base, which the repo root is mapped to
+    http_base: Url,
+}
+
+impl HuggingFaceListingParser {
+    /// repo is the web URL of repo, like https://huggingface.co/owner/model,
+    /// https://huggingface.co/datasets/owner/name or https://huggingface.co/datasets/owner/name/tree/v1.0
+    pub fn new(repo: &Url, http_base: Url) -> Result<Self> {
+        let segments: Vec<&str> = repo
+            .path_segments()
+            .map(|s| s.filter(|s| !s.is_empty()).collect())
+            .unwrap_or_default();
+        let (kind, rest) = match segments.first() {
+            Some(&kind @ ("datasets" | "spaces" | "models")) => (kind, &segments[1..]),
+            _ => ("models", &segments[..]),
+        };
+        let (repo_id, revision) = match rest {
+            [owner, name] => ([*owner, *name], "main".to_string()),
+            [owner, name, "tree", revision @ ..] if !revision.is_empty() => {
+                ([*owner, *name], revision.join("/"))
+            }
+            _ => return Err(anyhow!("{} is not a Hugging Face repo URL", repo)),
+        };
+        let revision = percent_encoding::percent_decode_str(&revision)
+            .decode_utf8()?
+            .to_string();
+        let build = |prefix: &[&str], action: &str| -> Result<Url> {
+            let mut url = repo.clone();
+            url.set_query(None);
+            url.path_segments_mut()
+                .map_err(|_| ListError::CannotBeBase { url: repo.clone() })?
+                .clear()
+                .extend(prefix)
+                .extend(repo_id)
+                .extend([action, &revision, ""]);
+            Ok(url)
+        };
+        let web_prefix: &[&str] = match kind {
+            "models" => &[],
+            _ => &[kind],
+        };
+        let tree_base = build(&["api", kind], "tree")?;
+        let resolve_base = build(web_prefix, "resolve")?;
+        if let Ok(token) = std::env::var(TOKEN_ENV) {
+            crate::utils::set_bearer_token(&tree_base, token);
+        }
+        Ok(Self {
+            tree_base,
+            resolve_base,
+            http_base,
+        })
+    }
+
+    fn to_item(&self, url: &Url, entry: TreeEntry) -> Result<ListItem> {
+        let name = entry
+            .path
+            .rsplit('/')
+            .next()
+            .unwrap_or(&entry.path)
+            .to_string();
+        let mtime = entry
+            .last_commit
+            .and_then(|c| DateTime::parse_from_rfc3339(&c.date).ok())
+            .map(|t| t.naive_utc());
+        Ok(if entry.type_ == "directory" {
+            let mut dir_url = url.clone();
+            dir_url
+                .path_segments_mut()
+                .map_err(|_| ListError::CannotBeBase { url: url.clone() })?
+                .pop_if_empty()
+                .extend([&name, ""]);
+            ListItem::new(dir_url, name, FileType::Directory, None, mtime)
+        } else {
+            let mut file_url = self.resolve_base.clone();
+            file_url
+                .path_segments_mut()
+                .map_err(|_| ListError::CannotBeBase {
+                    url: self.resolve_base.clone(),
+                })?
+                .pop_if_empty()
+                .extend(entry.path.split('/'));
+            let (size, sha256) = match entry.lfs {
+                Some(lfs) => (lfs.size, Some(lfs.oid)),
+                None => (entry.size, None),
+            };
+            ListItem::new(
+                file_url,
+                name,
+                FileType::File,
+                Some(FileSize::Precise(size)),
+                mtime,
+            )
+            .with_sha256(sha256)
+        })
+    }
+
+    /// Parse a page of tree API response, for directory url (under upstream)
+    fn parse_tree(&self, url: &Url, body: &str) -> Result<Vec<ListItem>> {
+        let entries: Vec<TreeEntry> = serde_json::from_str(body)?;
+        entries
+            .into_iter()
+            .map(|entry| self.to_item(url, entry))
+            .collect()
+    }
+}
+
+impl Parser for HuggingFaceListingParser {
+    fn get_list(&self, client: &Client, url: &Url) -> Result<ListResult> {
+        assert_if_url_has_no_trailing_slash(url);
+        let relative = url
+            .path()
+            .strip_prefix(self.http_base.path())
+            .ok_or_else(|| ListError::NotUnder {
+                url: url.clone(),
+                base: self.http_base.clone(),
+            })?;
+        let mut api_url = self.tree_base.clone();
+        api_url
+            .path_segments_mut()
+            .map_err(|_| ListError::CannotBeBase {
+                url: self.tree_base.clone(),
+            })?
+            .pop_if_empty()
+            .extend(
+                relative
+                    .split('/')
+                    .filter(|s| !s.is_empty())
+                    .map(get_real_name_from_href),
+            );
+        // lastCommit is only included with expand
+        api_url.set_query(Some("expand=true"));
+
+        let mut items = Vec::new();
+        for page in get_pages(client, api_url)? {
+            items.extend(
+                self.parse_tree(url, &page.body)
+                    .with_context(|| format!("Invalid response of {}", page.url))?,
+            );
+        }
+        Ok(ListResult::List(items))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDateTime;
+
+    #[test]
+    fn test_repo_url() {
+        let base = Url::parse("http://localhost/hf/").unwrap();
+        let parser = HuggingFaceListingParser::new(
+            &Url::parse("https://huggingface.co/openai-community/gpt2").unwrap(),
+            base.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            parser.tree_base.as_str(),
+            "https://huggingface.co/api/models/openai-community/gpt2/tree/main/"
+        );
+        assert_eq!(
+            parser.resolve_base.as_str(),
+            "https://huggingface.co/openai-community/gpt2/resolve/main/"
+        );
+        let parser = HuggingFaceListingParser::new(
+            &Url::parse("https://huggingface.co/datasets/owner/name/tree/refs%2Fconvert%2Fparquet")
+                .unwrap(),
+            base.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            parser.resolve_base.as_str(),
+            "https://huggingface.co/datasets/owner/name/resolve/refs%2Fconvert%2Fparquet/"
+        );
+        assert!(HuggingFaceListingParser::new(
+            &Url::parse("https://huggingface.co/gpt2").unwrap(),
+            base
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_tree() {
+        let parser = HuggingFaceListingParser::new(
+            &Url::parse("https://huggingface.co/datasets/owner/name").unwrap(),
+            Url::parse("http://localhost/hf/").unwrap(),
+        )
+        .unwrap();
+        let body = r#"[
+  {"type": "directory", "oid": "6d6e", "size": 0, "path": "data/train",
+   "lastCommit": {"id": "a1", "title": "Add train", "date": "2024-03-02T10:00:00.000Z"}},
+  {"type": "file", "oid": "9f1c", "size": 1234, "path": "data/README.md",
+   "lastCommit": {"id": "a0", "title": "Init", "date": "2024-03-01T08:30:00.000Z"}},
+  {"type": "file", "oid": "77aa", "size": 5000000, "path": "data/test 1.parquet",
+   "lfs": {"oid": "2a4b1c0e5d7f", "size": 5000000, "pointerSize": 134},
+   "lastCommit": {"id": "a1", "title": "Add train", "date": "2024-03-02T10:00:00.000Z"}}
+]"#;
+        let url = Url::parse("http://localhost/hf/data/").unwrap();
+        let items = parser.parse_tree(&url, body).unwrap();
+        assert_eq!(items[0].name, "train");
+        assert_eq!(items[0].type_, FileType::Directory);
+        assert_eq!(items[0].url.as_str(), "http://localhost/hf/data/train/");
+        assert_eq!(items[1].sha256, None);
+        assert_eq!(
+            items[1].mtime.unwrap(),
+            NaiveDateTime::parse_from_str("2024-03-01 08:30:00", "%Y-%m-%d %H:%M:%S").unwrap()
+        );
+        assert_eq!(items[2].name, "test 1.parquet");
+        assert_eq!(
+            items[2].url.as_str(),
+            "https://huggingface.co/datasets/owner/name/resolve/main/data/test%201.parquet"
+        );
+        assert_eq!(items[2].size, Some(FileSize::Precise(5000000)));
+        assert_eq!(items[2].sha256.as_deref(), Some("2a4b1c0e5d7f"));
+    }
+}
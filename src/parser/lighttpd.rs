@@ -1,44 +1,59 @@
 use crate::{
     listing::{FileSize, FileType, ListItem},
-    utils::get,
+    utils::get_page,
 };
 use chrono::NaiveDateTime;
 use scraper::{Html, Selector};
 // use tracing::debug;
 
 use super::*;
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 
 #[derive(Debug, Clone, Default)]
 pub struct LighttpdListingParser;
 
 impl Parser for LighttpdListingParser {
     fn get_list(&self, client: &Client, url: &Url) -> Result<ListResult> {
-        let resp = get(client, url.clone())?;
-        let url = resp.url().clone();
-        let body = resp.text()?;
-        assert_if_url_has_no_trailing_slash(&url);
-        let document = Html::parse_document(&body);
+        let page = get_page(client, url.clone())?;
+        parse_listing_page(self, &page)
+    }
+
+    fn parse_page(&self, url: &Url, body: &str) -> Result<Vec<ListItem>> {
+        assert_if_url_has_no_trailing_slash(url);
+        let document = Html::parse_document(body);
         let selector = Selector::parse("tbody").unwrap();
-        let indexlist = document
-            .select(&selector)
-            .next()
-            .ok_or_else(|| anyhow!("Cannot find <tbody>"))?;
+        let indexlist =
+            document
+                .select(&selector)
+                .next()
+                .ok_or_else(|| ListError::MissingElement {
+                    url: url.clone(),
+                    element: "<tbody>",
+                })?;
         let selector = Selector::parse("tr").unwrap();
         let mut items = Vec::new();
         for element in indexlist.select(&selector) {
             let a = element
                 .select(&Selector::parse("a").unwrap())
                 .next()
-                .ok_or_else(|| anyhow!("Cannot find <a>"))?;
+                .ok_or_else(|| ListError::MissingElement {
+                    url: url.clone(),
+                    element: "<a>",
+                })?;
             let mtime = element
                 .select(&Selector::parse(".m").unwrap())
                 .next()
-                .ok_or_else(|| anyhow!("Cannot find .m"))?;
+                .ok_or_else(|| ListError::MissingElement {
+                    url: url.clone(),
+                    element: ".m",
+                })?;
             let size = element
                 .select(&Selector::parse(".s").unwrap())
                 .next()
-                .ok_or_else(|| anyhow!("Cannot find .s"))?;
+                .ok_or_else(|| ListError::MissingElement {
+                    url: url.clone(),
+                    element: ".s",
+                })?;
 
             // let filetype = element.select(&Selector::parse(".t").unwrap()).next().unwrap();
 
@@ -49,8 +64,12 @@ impl Parser for LighttpdListingParser {
             let href = a
                 .value()
                 .attr("href")
-                .ok_or_else(|| anyhow!("Cannot find href inside <a>"))?;
+                .ok_or_else(|| ListError::MissingElement {
+                    url: url.clone(),
+                    element: "href inside <a>",
+                })?;
             let name = get_real_name_from_href(href);
+            let original_href = href;
             let href = url.join(href)?;
 
             let type_ = if href.as_str().ends_with('/') {
@@ -77,10 +96,13 @@ impl Parser for LighttpdListingParser {
             };
 
             // debug!("{} {} {} {:?} {:?}", href, name, mtime, size, type_);
-            items.push(ListItem::new(href, name, type_, size, mtime))
+            items.push(
+                ListItem::new(href, name, type_, size, Some(mtime))
+                    .with_original_href(original_href),
+            )
         }
 
-        Ok(ListResult::List(items))
+        Ok(items)
     }
 }
 
@@ -93,7 +115,7 @@ mod tests {
     #[test]
     fn test_buildroot_root() {
         let client = reqwest::blocking::Client::new();
-        let items = LighttpdListingParser::default()
+        let items = LighttpdListingParser
             .get_list(
                 &client,
                 &Url::parse("http://localhost:1921/buildroot/").unwrap(),
@@ -105,7 +127,7 @@ mod tests {
                 assert_eq!(items[0].type_, FileType::Directory);
                 assert_eq!(items[0].size, None);
                 assert_eq!(
-                    items[0].mtime,
+                    items[0].mtime.unwrap(),
                     NaiveDateTime::parse_from_str("2021-01-11 15:59:23", "%Y-%m-%d %H:%M:%S")
                         .unwrap()
                 );
@@ -117,7 +139,7 @@ mod tests {
                     Some(FileSize::HumanizedBinary(262.1, SizeUnit::K))
                 );
                 assert_eq!(
-                    last_item.mtime,
+                    last_item.mtime.unwrap(),
                     NaiveDateTime::parse_from_str("2018-03-08 11:18:46", "%Y-%m-%d %H:%M:%S")
                         .unwrap()
                 );
@@ -129,7 +151,7 @@ mod tests {
     #[test]
     fn test_buildroot_subfolder() {
         let client = reqwest::blocking::Client::new();
-        let items = LighttpdListingParser::default()
+        let items = LighttpdListingParser
             .get_list(
                 &client,
                 &Url::parse("http://localhost:1921/buildroot/acl/").unwrap(),
@@ -145,7 +167,7 @@ mod tests {
                     Some(FileSize::HumanizedBinary(377.5, SizeUnit::K))
                 );
                 assert_eq!(
-                    items[0].mtime,
+                    items[0].mtime.unwrap(),
                     NaiveDateTime::parse_from_str("2013-05-19 06:10:38", "%Y-%m-%d %H:%M:%S")
                         .unwrap()
                 );
@@ -156,7 +178,7 @@ mod tests {
                     Some(FileSize::HumanizedBinary(362.9, SizeUnit::K))
                 );
                 assert_eq!(
-                    items[3].mtime,
+                    items[3].mtime.unwrap(),
                     NaiveDateTime::parse_from_str("2024-02-07 03:04:10", "%Y-%m-%d %H:%M:%S")
                         .unwrap()
                 );
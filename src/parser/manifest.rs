@@ -0,0 +1,389 @@
+/// A "parser" which gets file list from a single JSON manifest (like files.json or manifest.json at the root
+/// of some vendor trees) with paths, sizes, mtimes and hashes, instead of HTML listings. Fields are found by
+/// simple JSONPath-like mappings (--manifest-field), and directories are derived from paths under upstream.
+use std::{collections::BTreeMap, str::FromStr, sync::Mutex};
+
+use crate::{
+    listing::{FileSize, FileType, ListItem},
+    utils::get_page,
+};
+
+use super::*;
+use anyhow::{anyhow, Context, Error, Result};
+use chrono::{DateTime, NaiveDateTime};
+use serde_json::Value;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ManifestKey {
+    /// The array of file entries (from the root)
+    Files,
+    /// Path relative to upstream (from each entry, so are the rest)
+    Path,
+    Size,
+    /// Unix timestamp, or RFC 3339 time
+    Mtime,
+    Sha256,
+    /// Download URL, relative to manifest. Files are under upstream by path if not given.
+    Url,
+}
+
+impl FromStr for ManifestKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "files" => Self::Files,
+            "path" => Self::Path,
+            "size" => Self::Size,
+            "mtime" => Self::Mtime,
+            "sha256" => Self::Sha256,
+            "url" => Self::Url,
+            _ => {
+                return Err(anyhow!(
+                "Unknown manifest field {:?} (expected files, path, size, mtime, sha256 or url)",
+                s
+            ))
+            }
+        })
+    }
+}
+
+/// Mapping of a manifest field, like `size=meta.length` or `files=$.data.files`
+#[derive(Debug, Clone)]
+pub struct ManifestField {
+    key: ManifestKey,
+    path: String,
+}
+
+impl FromStr for ManifestField {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (key, path) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected <field>=<path>, like size=meta.length"))?;
+        Ok(Self {
+            key: key.trim().parse()?,
+            path: path.trim().to_string(),
+        })
+    }
+}
+
+/// Value at path like `$.a.b[0].c` (`$` and leading dots are optional, and `a.0` is the same as `a[0]`)
+pub fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    let path = path.strip_prefix('$').unwrap_or(path).replace('[', ".");
+    let mut value = value;
+    for segment in path.split('.').filter(|s| !s.is_empty()) {
+        let segment = segment.trim_end_matches(']');
+        value = match value {
+            Value::Object(map) => map.get(segment)?,
+            Value::Array(array) => array.get(segment.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+#[derive(Debug)]
+pub struct Fields {
+    /// None for the root if it is an array, or "files" (or "items") otherwise
+    pub files: Option<String>,
+    pub path: String,
+    pub size: String,
+    pub mtime: String,
+    pub sha256: String,
+    pub url: String,
+}
+
+impl Fields {
+    pub fn new(mappings: &[ManifestField]) -> Self {
+        let mut fields = Self {
+            files: None,
+            path: "path".to_string(),
+            size: "size".to_string(),
+            mtime: "mtime".to_string(),
+            sha256: "sha256".to_string(),
+            url: "url".to_string(),
+        };
+        for mapping in mappings {
+            let path = mapping.path.clone();
+            match mapping.key {
+                ManifestKey::Files => fields.files = Some(path),
+                ManifestKey::Path => fields.path = path,
+                ManifestKey::Size => fields.size = path,
+                ManifestKey::Mtime => fields.mtime = path,
+                ManifestKey::Sha256 => fields.sha256 = path,
+                ManifestKey::Url => fields.url = path,
+            }
+        }
+        fields
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ManifestEntry {
+    /// Relative to upstream, without leading slash
+    path: String,
+    url: Url,
+    size: Option<u64>,
+    mtime: Option<NaiveDateTime>,
+    sha256: Option<String>,
+}
+
+pub fn parse_size(value: &Value) -> Option<u64> {
+    match value {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// mtime in UTC
+pub fn parse_mtime(value: &Value) -> Option<NaiveDateTime> {
+    match value {
+        Value::Number(n) => DateTime::from_timestamp(n.as_f64()? as i64, 0).map(|t| t.naive_utc()),
+        // Without offset (as in output of `list --output json`), taken as is
+        Value::String(s) => DateTime::parse_from_rfc3339(s.trim())
+            .map(|t| t.naive_utc())
+            .or_else(|_| NaiveDateTime::parse_from_str(s.trim(), "%Y-%m-%dT%H:%M:%S"))
+            .ok(),
+        _ => None,
+    }
+}
+
+/// URL of path (not encoded) under upstream
+pub fn url_under(http_base: &Url, path: &str) -> Result<Url> {
+    let mut url = http_base.clone();
+    url.path_segments_mut()
+        .map_err(|_| ListError::CannotBeBase {
+            url: http_base.clone(),
+        })?
+        .pop_if_empty()
+        .extend(path.split('/'));
+    Ok(url)
+}
+
+/// The array of file entries
+pub fn find_files<'a>(root: &'a Value, fields: &Fields) -> Option<&'a Vec<Value>> {
+    let files = match &fields.files {
+        Some(path) => lookup(root, path),
+        None if root.is_array() => Some(root),
+        // Or output of `list --output json`
+        None => root.get("files").or_else(|| root.get("items")),
+    };
+    files.and_then(|v| v.as_array())
+}
+
+fn parse_manifest(
+    body: &str,
+    fields: &Fields,
+    manifest_url: &Url,
+    http_base: &Url,
+) -> Result<Vec<ManifestEntry>> {
+    let root: Value = serde_json::from_str(body)?;
+    let files = find_files(&root, fields).ok_or_else(|| ListError::NoFiles {
+        url: manifest_url.clone(),
+        place: "manifest",
+    })?;
+    let mut entries = Vec::new();
+    for file in files {
+        if file.get("type").and_then(|v| v.as_str()) == Some("directory") {
+            continue;
+        }
+        let Some(path) = lookup(file, &fields.path).and_then(|v| v.as_str()) else {
+            warn!("Skipping manifest entry without path: {}", file);
+            continue;
+        };
+        let path = path.trim_start_matches('/');
+        if path.is_empty() || path.ends_with('/') {
+            warn!("Skipping manifest entry with invalid path {:?}", path);
+            continue;
+        }
+        let url = match lookup(file, &fields.url).and_then(|v| v.as_str()) {
+            Some(url) => manifest_url
+                .join(url)
+                .with_context(|| format!("Invalid URL {:?} of {:?}", url, path))?,
+            None => url_under(http_base, path)?,
+        };
+        entries.push(ManifestEntry {
+            path: path.to_string(),
+            url,
+            size: lookup(file, &fields.size).and_then(parse_size),
+            mtime: lookup(file, &fields.mtime).and_then(parse_mtime),
+            sha256: lookup(file, &fields.sha256)
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_lowercase()),
+        });
+    }
+    Ok(entries)
+}
+
+/// Items directly under url, from entries of manifest
+fn list_entries(entries: &[ManifestEntry], http_base: &Url, url: &Url) -> Result<Vec<ListItem>> {
+    let relative =
+        url.path()
+            .strip_prefix(http_base.path())
+            .ok_or_else(|| ListError::NotUnder {
+                url: url.clone(),
+                base: http_base.clone(),
+            })?;
+    let dir = percent_encoding::percent_decode_str(relative)
+        .decode_utf8_lossy()
+        .to_string();
+    let mut items: BTreeMap<String, ListItem> = BTreeMap::new();
+    for entry in entries {
+        let Some(rest) = entry.path.strip_prefix(&dir) else {
+            continue;
+        };
+        match rest.split_once('/') {
+            None => {
+                let item = ListItem::new(
+                    entry.url.clone(),
+                    rest.to_string(),
+                    FileType::File,
+                    entry.size.map(FileSize::Precise),
+                    entry.mtime,
+                )
+                .with_sha256(entry.sha256.clone());
+                items.insert(rest.to_string(), item);
+            }
+            Some((name, _)) => {
+                let item = match items.entry(format!("{name}/")) {
+                    std::collections::btree_map::Entry::Occupied(e) => e.into_mut(),
+                    std::collections::btree_map::Entry::Vacant(e) => e.insert(ListItem::new(
+                        url_under(url, &format!("{name}/"))?,
+                        name.to_string(),
+                        FileType::Directory,
+                        None,
+                        entry.mtime,
+                    )),
+                };
+                item.mtime = item.mtime.max(entry.mtime);
+            }
+        }
+    }
+    Ok(items.into_values().collect())
+}
+
+#[derive(Debug)]
+pub struct ManifestListingParser {
+    manifest: Url,
+    fields: Fields,
+    /// Upstream base, which paths in manifest are relative to
+    http_base: Url,
+    /// Loaded on first listing
+    entries: Mutex<Option<Vec<ManifestEntry>>>,
+}
+
+impl ManifestListingParser {
+    pub fn new(manifest: Url, fields: &[ManifestField], http_base: Url) -> Self {
+        Self {
+            manifest,
+            fields: Fields::new(fields),
+            http_base,
+            entries: Mutex::new(None),
+        }
+    }
+
+    fn load(&self, client: &Client) -> Result<Vec<ManifestEntry>> {
+        let page = get_page(client, self.manifest.clone())?;
+        let entries = parse_manifest(&page.body, &self.fields, &page.url, &self.http_base)
+            .with_context(|| format!("Invalid manifest {}", page.url))?;
+        info!("Loaded {} entries from manifest", entries.len());
+        Ok(entries)
+    }
+}
+
+impl Parser for ManifestListingParser {
+    fn get_list(&self, client: &Client, url: &Url) -> Result<ListResult> {
+        assert_if_url_has_no_trailing_slash(url);
+        let mut entries = self.entries.lock().unwrap();
+        if entries.is_none() {
+            *entries = Some(self.load(client)?);
+        }
+        // Loaded above
+        let entries = entries.as_ref().unwrap();
+        Ok(ListResult::List(list_entries(
+            entries,
+            &self.http_base,
+            url,
+        )?))
+    }
+
+    /// Items at the root of upstream, from the manifest as page
+    fn parse_page(&self, url: &Url, body: &str) -> Result<Vec<ListItem>> {
+        let entries = parse_manifest(body, &self.fields, url, &self.http_base)?;
+        list_entries(&entries, &self.http_base, &self.http_base)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn test_manifest() {
+        let body = r#"{"version": 2, "data": {"items": [
+  {"name": "README.txt", "meta": {"length": 12, "modified": 1700000000}},
+  {"name": "fw/board a/v1.bin", "meta": {"length": "1024", "modified": "2024-03-02T10:00:00+08:00"},
+   "hash": "ABCD", "href": "https://cdn.example.com/v1.bin"},
+  {"name": "/fw/board a/v2.bin", "meta": {"length": 2048}},
+  {"meta": {"length": 1}}
+]}}"#;
+        let fields: Vec<ManifestField> = [
+            "files=$.data.items",
+            "path=name",
+            "size=meta.length",
+            "mtime=meta.modified",
+            "sha256=hash",
+            "url=href",
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+        assert!("checksum=hash".parse::<ManifestField>().is_err());
+        let manifest_url = Url::parse("https://example.com/firmware/files.json").unwrap();
+        let http_base = Url::parse("https://example.com/firmware/").unwrap();
+        let entries =
+            parse_manifest(body, &Fields::new(&fields), &manifest_url, &http_base).unwrap();
+        assert_eq!(entries.len(), 3);
+
+        let items = list_entries(&entries, &http_base, &http_base).unwrap();
+        let names: Vec<_> = items.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["README.txt", "fw"]);
+        assert_eq!(items[0].size, Some(FileSize::Precise(12)));
+        assert_eq!(items[0].mtime, Some(time("2023-11-14 22:13:20")));
+        assert_eq!(items[1].type_, FileType::Directory);
+        assert_eq!(items[1].url.as_str(), "https://example.com/firmware/fw/");
+
+        let url = Url::parse("https://example.com/firmware/fw/board%20a/").unwrap();
+        let items = list_entries(&entries, &http_base, &url).unwrap();
+        assert_eq!(items[0].url.as_str(), "https://cdn.example.com/v1.bin");
+        assert_eq!(items[0].size, Some(FileSize::Precise(1024)));
+        assert_eq!(items[0].mtime, Some(time("2024-03-02 02:00:00")));
+        assert_eq!(items[0].sha256.as_deref(), Some("abcd"));
+        assert_eq!(
+            items[1].url.as_str(),
+            "https://example.com/firmware/fw/board%20a/v2.bin"
+        );
+
+        // Defaults: root array, or "files" of root object
+        let entries = parse_manifest(
+            r#"{"files": [{"path": "a.iso", "size": 3}]}"#,
+            &Fields::new(&[]),
+            &manifest_url,
+            &http_base,
+        )
+        .unwrap();
+        assert_eq!(
+            entries[0].url.as_str(),
+            "https://example.com/firmware/a.iso"
+        );
+        assert!(parse_manifest("{}", &Fields::new(&[]), &manifest_url, &http_base).is_err());
+    }
+}
@@ -1,17 +1,27 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use clap::ValueEnum;
 use reqwest::blocking::Client;
 use tracing::warn;
 use url::Url;
 
+use crate::error::ListError;
 use crate::listing::ListItem;
+use crate::utils::ListingPage;
 
 pub mod apache_f2;
 pub mod caddy;
 pub mod directory_lister;
 pub mod docker;
+pub mod embedded_json;
+pub mod gitlab;
+pub mod go_fileserver;
+pub mod huggingface;
 pub mod lighttpd;
+pub mod manifest;
 pub mod nginx;
+pub mod rsync;
+pub mod sitemap;
+pub mod svn;
 
 #[derive(Debug)]
 pub enum ListResult {
@@ -21,9 +31,18 @@ pub enum ListResult {
 
 pub trait Parser: Sync {
     fn get_list(&self, client: &Client, url: &Url) -> Result<ListResult>;
+    /// Parse a fetched listing page. url is the final URL of the page (after redirection).
+    /// This allows testing parsers offline.
+    fn parse_page(&self, _url: &Url, _body: &str) -> Result<Vec<ListItem>> {
+        Err(ListError::Unsupported.into())
+    }
     fn is_auto_redirect(&self) -> bool {
         true
     }
+    /// If the parser reads data in scripts of pages, which are not taken as rendered by JavaScript
+    fn reads_scripts(&self) -> bool {
+        false
+    }
 }
 
 #[derive(ValueEnum, Clone, Debug)]
@@ -34,6 +53,10 @@ pub enum ParserType {
     DirectoryLister,
     Lighttpd,
     Caddy,
+    Svn,
+    GoFileServer,
+    /// Data embedded in a script tag (found by --embedded-json-selector), mapped by --manifest-field
+    EmbeddedJson,
 }
 
 impl ParserType {
@@ -48,10 +71,150 @@ impl ParserType {
             }
             Self::Lighttpd => Box::<lighttpd::LighttpdListingParser>::default(),
             Self::Caddy => Box::<caddy::CaddyListingParser>::default(),
+            Self::Svn => Box::<svn::SvnListingParser>::default(),
+            Self::GoFileServer => Box::<go_fileserver::GoFileServerListingParser>::default(),
+            Self::EmbeddedJson => Box::<embedded_json::EmbeddedJsonListingParser>::default(),
         }
     }
 }
 
+/// Where listings come from, by flags of sync (and list)
+pub struct ParserSources<'a> {
+    pub parser: &'a ParserType,
+    /// The upstream, which files are downloaded from
+    pub http_base: &'a Url,
+    pub rsync_upstream: Option<&'a Url>,
+    pub sitemap: Option<&'a Url>,
+    pub gitlab_project: Option<&'a Url>,
+    pub huggingface_repo: Option<&'a Url>,
+    pub manifest: Option<&'a Url>,
+    /// Field mappings of manifest (or data of --parser embedded-json)
+    pub fields: &'a [manifest::ManifestField],
+    pub embedded_json_selector: Option<&'a str>,
+}
+
+impl<'a> ParserSources<'a> {
+    /// Listing pages parsed by parser only
+    pub fn new(parser: &'a ParserType, http_base: &'a Url) -> Self {
+        Self {
+            parser,
+            http_base,
+            rsync_upstream: None,
+            sitemap: None,
+            gitlab_project: None,
+            huggingface_repo: None,
+            manifest: None,
+            fields: &[],
+            embedded_json_selector: None,
+        }
+    }
+
+    /// Refuse options which would be ignored silently
+    fn check(&self) -> Result<()> {
+        let embedded_json = matches!(self.parser, ParserType::EmbeddedJson);
+        if !self.fields.is_empty() && self.manifest.is_none() && !embedded_json {
+            return Err(anyhow!(
+                "--manifest-field requires --manifest or --parser embedded-json"
+            ));
+        }
+        if self.embedded_json_selector.is_some() && !embedded_json {
+            return Err(anyhow!(
+                "--embedded-json-selector requires --parser embedded-json"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Build the listing parser. When rsync upstream is given, the listing is done by `rsync --list-only`
+/// instead (files are still downloaded from HTTP upstream). When sitemap is given, the listing is from it.
+/// When GitLab project (or Hugging Face repo) is given, files of it are listed with its API (under HTTP upstream).
+/// When JSON manifest (with field mappings) is given, the listing is from it.
+pub fn build_parser(sources: &ParserSources) -> Result<Box<dyn Parser>> {
+    sources.check()?;
+    let http_base = sources.http_base;
+    if let Some(manifest) = sources.manifest {
+        return Ok(Box::new(manifest::ManifestListingParser::new(
+            manifest.clone(),
+            sources.fields,
+            http_base.clone(),
+        )));
+    }
+    if let Some(repo) = sources.huggingface_repo {
+        return Ok(Box::new(huggingface::HuggingFaceListingParser::new(
+            repo,
+            http_base.clone(),
+        )?));
+    }
+    if let Some(project) = sources.gitlab_project {
+        return Ok(Box::new(gitlab::GitLabListingParser::new(
+            project,
+            http_base.clone(),
+        )?));
+    }
+    if let Some(sitemap) = sources.sitemap {
+        return Ok(Box::new(sitemap::SitemapListingParser::new(
+            sitemap.clone(),
+        )));
+    }
+    Ok(match sources.rsync_upstream {
+        Some(rsync_upstream) => Box::new(rsync::RsyncListingParser::new(
+            http_base.clone(),
+            rsync_upstream.clone(),
+        )),
+        None => match sources.parser {
+            ParserType::EmbeddedJson => Box::new(embedded_json::EmbeddedJsonListingParser::new(
+                sources.fields,
+                sources
+                    .embedded_json_selector
+                    .unwrap_or(embedded_json::DEFAULT_SELECTOR),
+            )?),
+            parser => parser.build(),
+        },
+    })
+}
+
+/// Run parse_page() on a page which may not be in expected format.
+/// Parsers may panic on unexpected markup, and it is caught and returned as error.
+pub fn try_parse_page(parser: &dyn Parser, url: &Url, body: &str) -> Result<Vec<ListItem>> {
+    // Not exiting with the panic hook of main, which is global to all threads
+    match crate::panic_guard::guard(|| parser.parse_page(url, body)) {
+        Ok(res) => res,
+        Err(message) => Err(ListError::Panicked {
+            url: url.clone(),
+            message,
+        }
+        .into()),
+    }
+}
+
+/// Parse a page got by utils::get_page() (reusing items of last run, see listing_cache.rs), failing if nothing is
+/// listed from a page looking truncated or rendered by JavaScript, so that it is not taken as an empty directory
+/// (whose local files would be deleted)
+pub fn parse_listing_page<P: Parser + ?Sized>(
+    parser: &P,
+    page: &ListingPage,
+) -> Result<ListResult> {
+    let items = crate::listing_cache::parse_page(parser, &page.url, &page.body)?;
+    if !items.is_empty() {
+        return Ok(ListResult::List(items));
+    }
+    if page.truncated {
+        return Err(ListError::Truncated {
+            url: page.url.clone(),
+        }
+        .into());
+    }
+    match page.script_marker.filter(|_| !parser.reads_scripts()) {
+        Some(marker) => Err(ListError::ScriptRendered {
+            url: page.url.clone(),
+            marker,
+        }
+        .into()),
+        None => Ok(ListResult::List(items)),
+    }
+}
+
 fn assert_if_url_has_no_trailing_slash(url: &Url) {
     assert!(
         url.path().ends_with('/'),
@@ -59,9 +222,40 @@ fn assert_if_url_has_no_trailing_slash(url: &Url) {
     );
 }
 
+/// Decode href to get local file name. "+" is kept as-is (it's not a space in path).
 fn get_real_name_from_href(href: &str) -> String {
-    let name: String = url::form_urlencoded::parse(href.as_bytes())
-        .map(|(k, v)| [k, v].concat())
-        .collect();
+    let name = percent_encoding::percent_decode_str(href).decode_utf8_lossy();
     name.trim_end_matches('/').to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parser_sources() {
+        let url = Url::parse("https://example.com/pub/").unwrap();
+        let fields: Vec<manifest::ManifestField> = vec!["path=name".parse().unwrap()];
+        let sources = ParserSources {
+            fields: &fields,
+            ..ParserSources::new(&ParserType::Nginx, &url)
+        };
+        assert!(build_parser(&sources).is_err());
+        let sources = ParserSources {
+            manifest: Some(&url),
+            ..sources
+        };
+        assert!(build_parser(&sources).is_ok());
+        let sources = ParserSources {
+            embedded_json_selector: Some("script#data"),
+            ..ParserSources::new(&ParserType::Nginx, &url)
+        };
+        assert!(build_parser(&sources).is_err());
+        let sources = ParserSources {
+            parser: &ParserType::EmbeddedJson,
+            fields: &fields,
+            ..sources
+        };
+        assert!(build_parser(&sources).is_ok());
+    }
+}
@@ -1,7 +1,7 @@
 /// A parser both suitable for default nginx autoindex and apache f1 format.
 use crate::{
     listing::{FileSize, FileType, ListItem},
-    utils::get,
+    utils::get_page,
 };
 use chrono::NaiveDateTime;
 use scraper::{Html, Selector};
@@ -27,11 +27,13 @@ impl Default for NginxListingParser {
 
 impl Parser for NginxListingParser {
     fn get_list(&self, client: &reqwest::blocking::Client, url: &url::Url) -> Result<ListResult> {
-        let resp = get(client, url.clone())?;
-        let url = resp.url().clone();
-        let body = resp.text()?;
-        assert_if_url_has_no_trailing_slash(&url);
-        let document = Html::parse_document(&body);
+        let page = get_page(client, url.clone())?;
+        parse_listing_page(self, &page)
+    }
+
+    fn parse_page(&self, url: &url::Url, body: &str) -> Result<Vec<ListItem>> {
+        assert_if_url_has_no_trailing_slash(url);
+        let document = Html::parse_document(body);
         let selector = Selector::parse("a").unwrap();
         let mut items = Vec::new();
         for element in document.select(&selector) {
@@ -49,6 +51,7 @@ impl Parser for NginxListingParser {
                 // A compromise for apache server (they will NOT url-encode the filename)
                 href.to_string()
             };
+            let original_href = href;
             let href = url.join(href)?;
 
             let name = name.trim_end_matches('/');
@@ -74,25 +77,28 @@ impl Parser for NginxListingParser {
             let date = NaiveDateTime::parse_from_str(date, "%d-%b-%Y %H:%M")?;
             let size = metadata.get(2).unwrap().as_str();
             debug!("{} {} {:?} {} {:?}", href, name, type_, date, size);
-            items.push(ListItem::new(
-                href,
-                name.to_string(),
-                type_,
-                {
-                    if size == "-" {
-                        None
-                    } else if size.contains('k') || size.contains('M') || size.contains('G') {
-                        let (n_size, unit) = FileSize::get_humanized(size);
-                        Some(FileSize::HumanizedBinary(n_size, unit))
-                    } else {
-                        let n_size = size.parse::<u64>().unwrap();
-                        Some(FileSize::Precise(n_size))
-                    }
-                },
-                date,
-            ))
+            items.push(
+                ListItem::new(
+                    href,
+                    name.to_string(),
+                    type_,
+                    {
+                        if size == "-" {
+                            None
+                        } else if size.contains('k') || size.contains('M') || size.contains('G') {
+                            let (n_size, unit) = FileSize::get_humanized(size);
+                            Some(FileSize::HumanizedBinary(n_size, unit))
+                        } else {
+                            let n_size = size.parse::<u64>().unwrap();
+                            Some(FileSize::Precise(n_size))
+                        }
+                    },
+                    Some(date),
+                )
+                .with_original_href(original_href),
+            )
         }
-        Ok(ListResult::List(items))
+        Ok(items)
     }
 }
 
@@ -118,14 +124,14 @@ mod tests {
                 assert_eq!(items[0].type_, FileType::Directory);
                 assert_eq!(items[0].size, None);
                 assert_eq!(
-                    items[0].mtime,
+                    items[0].mtime.unwrap(),
                     NaiveDateTime::parse_from_str("09-Oct-2015 16:12", "%d-%b-%Y %H:%M").unwrap()
                 );
                 assert_eq!(items[4].name, "monitoring-plugins-2.0.tar.gz");
                 assert_eq!(items[4].type_, FileType::File);
                 assert_eq!(items[4].size, Some(FileSize::Precise(2610000)));
                 assert_eq!(
-                    items[4].mtime,
+                    items[4].mtime.unwrap(),
                     NaiveDateTime::parse_from_str("11-Jul-2014 23:17", "%d-%b-%Y %H:%M").unwrap()
                 );
             }
@@ -155,6 +161,29 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_encoded_href() {
+        let body = r#"<html><body><pre><a href="../">../</a>
+<a href="%e4%b8%ad%E6%96%87/">中文/</a>                                              10-Mar-2024 04:45                   -
+<a href="a%2Bb%3Dc%26d.deb">a+b=c&amp;d.deb</a>                                    10-Mar-2024 04:45                1024
+<a href="x+y.deb">x+y.deb</a>                                            10-Mar-2024 04:45                1024
+</pre></body></html>"#;
+        let url = Url::parse("http://localhost/debian/").unwrap();
+        let items = NginxListingParser::default()
+            .parse_page(&url, body)
+            .unwrap();
+        assert_eq!(items[0].name, "中文");
+        assert_eq!(
+            items[0].original_href.as_deref(),
+            Some("%e4%b8%ad%E6%96%87/")
+        );
+        // escapes are kept byte-exact
+        assert_eq!(items[0].url.path(), "/debian/%e4%b8%ad%E6%96%87/");
+        assert_eq!(items[1].name, "a+b=c&d.deb");
+        assert_eq!(items[1].url.path(), "/debian/a%2Bb%3Dc%26d.deb");
+        assert_eq!(items[2].name, "x+y.deb");
+    }
+
     #[test]
     fn test_mysql() {
         let client = reqwest::blocking::Client::new();
@@ -0,0 +1,206 @@
+/// A "parser" which gets file list from `rsync --list-only`, while files are still downloaded over HTTP.
+/// This is useful when upstream provides both rsync and HTTP, but prefers HTTP (CDN) for data transfer.
+use std::process::Command;
+
+use crate::listing::{FileSize, FileType, ListItem};
+
+use super::*;
+use anyhow::Result;
+use chrono::NaiveDateTime;
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use tracing::debug;
+
+#[derive(Debug, Clone)]
+pub struct RsyncListingParser {
+    /// HTTP upstream base, which rsync upstream base is mapped to
+    http_base: Url,
+    rsync_base: Url,
+    line_regex: Regex,
+}
+
+impl RsyncListingParser {
+    pub fn new(http_base: Url, rsync_base: Url) -> Self {
+        Self {
+            http_base,
+            rsync_base,
+            // drwxr-xr-x          4,096 2023/07/10 13:07:52 .trace
+            line_regex: Regex::new(
+                r"^([dl\-])[rwxsStT\-]{9}\s+([\d,\.]+)\s+(\d{4}/\d{2}/\d{2} \d{2}:\d{2}:\d{2})\s+(.+)$",
+            )
+            .unwrap(),
+        }
+    }
+
+    fn get_rsync_url(&self, url: &Url) -> Result<String> {
+        let relative = url
+            .path()
+            .strip_prefix(self.http_base.path())
+            .ok_or_else(|| ListError::NotUnder {
+                url: url.clone(),
+                base: self.http_base.clone(),
+            })?;
+        let relative = percent_decode_str(relative).decode_utf8()?;
+        let mut base = self.rsync_base.as_str().to_string();
+        if !base.ends_with('/') {
+            base.push('/');
+        }
+        Ok(format!("{base}{relative}"))
+    }
+
+    /// Parse output of `rsync --list-only`. Times are expected to be printed in UTC.
+    pub fn parse_output(&self, url: &Url, output: &str) -> Result<Vec<ListItem>> {
+        let mut items = Vec::new();
+        for line in output.lines() {
+            let captures = match self.line_regex.captures(line) {
+                Some(c) => c,
+                None => {
+                    debug!("Ignoring rsync line: {:?}", line);
+                    continue;
+                }
+            };
+            let name = unescape_rsync_name(captures.get(4).unwrap().as_str());
+            if name == "." {
+                continue;
+            }
+            let type_ = match captures.get(1).unwrap().as_str() {
+                "d" => FileType::Directory,
+                "-" => FileType::File,
+                _ => {
+                    debug!("Ignoring rsync symlink: {:?}", name);
+                    continue;
+                }
+            };
+            let size = captures
+                .get(2)
+                .unwrap()
+                .as_str()
+                .replace([',', '.'], "")
+                .parse::<u64>()?;
+            let mtime = NaiveDateTime::parse_from_str(
+                captures.get(3).unwrap().as_str(),
+                "%Y/%m/%d %H:%M:%S",
+            )?;
+
+            let mut href = url.clone();
+            {
+                let mut segments = href
+                    .path_segments_mut()
+                    .map_err(|_| ListError::CannotBeBase { url: url.clone() })?;
+                segments.pop_if_empty().push(&name);
+                if type_ == FileType::Directory {
+                    segments.push("");
+                }
+            }
+            let size = match type_ {
+                FileType::Directory => None,
+                FileType::File => Some(FileSize::Precise(size)),
+            };
+            items.push(ListItem::new(href, name, type_, size, Some(mtime)));
+        }
+        Ok(items)
+    }
+}
+
+/// rsync escapes unprintable chars in filenames as "\#ooo" (octal)
+fn unescape_rsync_name(name: &str) -> String {
+    let bytes = name.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && bytes.get(i + 1) == Some(&b'#') {
+            if let Some(c) = name
+                .get(i + 2..i + 5)
+                .and_then(|s| u8::from_str_radix(s, 8).ok())
+            {
+                res.push(c);
+                i += 5;
+                continue;
+            }
+        }
+        res.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&res).to_string()
+}
+
+impl Parser for RsyncListingParser {
+    fn get_list(&self, _client: &Client, url: &Url) -> Result<ListResult> {
+        assert_if_url_has_no_trailing_slash(url);
+        let rsync_url = self.get_rsync_url(url)?;
+        debug!("rsync --list-only {}", rsync_url);
+        let output = Command::new("rsync")
+            .arg("--list-only")
+            .arg("--no-motd")
+            .arg(&rsync_url)
+            // Let rsync print mtime in UTC, and digit grouping with ","
+            .env("TZ", "UTC")
+            .env("LC_ALL", "C")
+            .output()?;
+        if !output.status.success() {
+            return Err(ListError::RsyncFailed {
+                url: rsync_url,
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            }
+            .into());
+        }
+        let output = String::from_utf8_lossy(&output.stdout);
+        Ok(ListResult::List(self.parse_output(url, &output)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parser() -> RsyncListingParser {
+        RsyncListingParser::new(
+            Url::parse("http://localhost:1921/").unwrap(),
+            Url::parse("rsync://localhost/ubuntu").unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_rsync_url() {
+        let parser = parser();
+        assert_eq!(
+            parser
+                .get_rsync_url(&Url::parse("http://localhost:1921/dists/a%20b/").unwrap())
+                .unwrap(),
+            "rsync://localhost/ubuntu/dists/a b/"
+        );
+    }
+
+    #[test]
+    fn test_parse_output() {
+        let output = "drwxr-xr-x          4,096 2023/07/10 13:07:52 .
+drwxr-xr-x          4,096 2023/07/10 13:07:52 .trace
+lrwxrwxrwx             11 2010/11/24 11:01:53 ubuntu -> .
+-rw-r--r--     27,262,976 2024/03/10 04:45:24 ls-lR.gz
+-rw-r--r--            123 2024/03/10 04:45:24 a\\#040b+c
+";
+        let url = Url::parse("http://localhost:1921/").unwrap();
+        let items = parser().parse_output(&url, output).unwrap();
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].name, ".trace");
+        assert_eq!(items[0].type_, FileType::Directory);
+        assert_eq!(items[0].size, None);
+        assert_eq!(
+            items[0].url,
+            Url::parse("http://localhost:1921/.trace/").unwrap()
+        );
+        assert_eq!(items[1].name, "ls-lR.gz");
+        assert_eq!(items[1].type_, FileType::File);
+        assert_eq!(items[1].size, Some(FileSize::Precise(27262976)));
+        assert_eq!(
+            items[1].mtime.unwrap(),
+            NaiveDateTime::parse_from_str("2024-03-10 04:45:24", "%Y-%m-%d %H:%M:%S").unwrap()
+        );
+        assert_eq!(items[2].name, "a b+c");
+        assert_eq!(
+            items[2].url,
+            Url::parse("http://localhost:1921/a%20b+c").unwrap()
+        );
+    }
+}
@@ -0,0 +1,257 @@
+/// A "parser" which gets file list from sitemap.xml covering every file URL, instead of HTML listings.
+/// Directories are derived from URL paths. Sitemap has no sizes, and lastmod (when given with time)
+/// is used as mtime in UTC.
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    listing::{FileType, ListItem},
+    utils::get_page,
+};
+
+use super::*;
+use anyhow::Result;
+use chrono::{DateTime, NaiveDateTime};
+use scraper::{Html, Selector};
+use tracing::{debug, info, warn};
+
+/// Stop following nested sitemaps (sitemap index) after this many
+const MAX_SITEMAPS: usize = 1000;
+
+#[derive(Debug, Clone)]
+struct SitemapEntry {
+    url: Url,
+    lastmod: Option<NaiveDateTime>,
+}
+
+#[derive(Debug)]
+pub struct SitemapListingParser {
+    sitemap: Url,
+    /// Loaded on first listing
+    index: Mutex<Option<Arc<Index>>>,
+}
+
+impl SitemapListingParser {
+    pub fn new(sitemap: Url) -> Self {
+        Self {
+            sitemap,
+            index: Mutex::new(None),
+        }
+    }
+
+    fn load(&self, client: &Client) -> Result<Vec<SitemapEntry>> {
+        let mut entries = Vec::new();
+        let mut queue = vec![self.sitemap.clone()];
+        let mut loaded = 0;
+        while let Some(url) = queue.pop() {
+            loaded += 1;
+            if loaded > MAX_SITEMAPS {
+                warn!("Too many nested sitemaps, ignoring the rest");
+                break;
+            }
+            debug!("Loading sitemap {}", url);
+            let page = get_page(client, url)?;
+            let (mut new_entries, nested) = parse_sitemap(&page.body);
+            entries.append(&mut new_entries);
+            queue.extend(nested);
+        }
+        info!("Loaded {} entries from sitemap", entries.len());
+        Ok(entries)
+    }
+}
+
+fn parse_lastmod(s: &str) -> Option<NaiveDateTime> {
+    // Date-only lastmod is too coarse to compare with
+    DateTime::parse_from_rfc3339(s.trim())
+        .ok()
+        .map(|t| t.naive_utc())
+}
+
+/// Parse sitemap (urlset) or sitemap index, returning entries and nested sitemaps.
+fn parse_sitemap(body: &str) -> (Vec<SitemapEntry>, Vec<Url>) {
+    let document = Html::parse_document(body);
+    let url_selector = Selector::parse("url").unwrap();
+    let sitemap_selector = Selector::parse("sitemap").unwrap();
+    let loc_selector = Selector::parse("loc").unwrap();
+    let lastmod_selector = Selector::parse("lastmod").unwrap();
+    let get_loc = |element: scraper::ElementRef| {
+        let loc = element
+            .select(&loc_selector)
+            .next()?
+            .text()
+            .collect::<String>();
+        match Url::parse(loc.trim()) {
+            Ok(url) => Some(url),
+            Err(e) => {
+                warn!("Invalid loc {:?} in sitemap: {}", loc, e);
+                None
+            }
+        }
+    };
+    let entries = document
+        .select(&url_selector)
+        .filter_map(|element| {
+            Some(SitemapEntry {
+                url: get_loc(element)?,
+                lastmod: element
+                    .select(&lastmod_selector)
+                    .next()
+                    .and_then(|e| parse_lastmod(&e.text().collect::<String>())),
+            })
+        })
+        .collect();
+    let nested = document
+        .select(&sitemap_selector)
+        .filter_map(get_loc)
+        .collect();
+    (entries, nested)
+}
+
+/// Items of each directory (by its URL), indexed once after loading, as listing scans no sitemap
+type Index = HashMap<String, Vec<ListItem>>;
+
+/// Index entries of sitemap by every directory above them
+fn index_entries(entries: &[SitemapEntry]) -> Index {
+    // directory URL -> raw (encoded) name -> item
+    let mut dirs: HashMap<String, BTreeMap<String, ListItem>> = HashMap::new();
+    for entry in entries {
+        if entry.url.query().is_some() {
+            continue;
+        }
+        let path = entry.url.path();
+        let mtime = entry.lastmod;
+        for (end, _) in path.match_indices('/') {
+            let (dir, relative) = path.split_at(end + 1);
+            let mut dir_url = entry.url.clone();
+            dir_url.set_path(dir);
+            dir_url.set_fragment(None);
+            let items = dirs.entry(dir_url.to_string()).or_default();
+            match relative.split_once('/') {
+                // A page of this directory itself, or of a directory
+                None if relative.is_empty() => {}
+                None => {
+                    let item = ListItem::new(
+                        entry.url.clone(),
+                        get_real_name_from_href(relative),
+                        FileType::File,
+                        None,
+                        mtime,
+                    );
+                    items.insert(relative.to_string(), item);
+                }
+                Some((name, _)) => {
+                    let item = items.entry(format!("{name}/")).or_insert_with(|| {
+                        let mut url = dir_url.clone();
+                        url.set_path(&format!("{dir}{name}/"));
+                        ListItem::new(
+                            url,
+                            get_real_name_from_href(name),
+                            FileType::Directory,
+                            None,
+                            mtime,
+                        )
+                    });
+                    item.mtime = item.mtime.max(mtime);
+                }
+            }
+        }
+    }
+    dirs.into_iter()
+        .map(|(dir, items)| (dir, items.into_values().collect()))
+        .collect()
+}
+
+/// Items directly under url
+fn list_dir(index: &Index, url: &Url) -> Vec<ListItem> {
+    index.get(url.as_str()).cloned().unwrap_or_default()
+}
+
+impl Parser for SitemapListingParser {
+    fn get_list(&self, client: &Client, url: &Url) -> Result<ListResult> {
+        assert_if_url_has_no_trailing_slash(url);
+        let index = {
+            let mut index = self.index.lock().unwrap();
+            match &*index {
+                Some(index) => index.clone(),
+                None => index
+                    .insert(Arc::new(index_entries(&self.load(client)?)))
+                    .clone(),
+            }
+        };
+        Ok(ListResult::List(list_dir(&index, url)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SITEMAP: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <url><loc>https://example.com/repo/</loc></url>
+  <url><loc>https://example.com/repo/README.md</loc><lastmod>2024-03-10T04:45:24+08:00</lastmod></url>
+  <url><loc>https://example.com/repo/dists/stable/Release</loc><lastmod>2024-03-11T00:00:00Z</lastmod></url>
+  <url><loc>https://example.com/repo/dists/old/Release</loc><lastmod>2023-01-01T00:00:00Z</lastmod></url>
+  <url><loc>https://example.com/repo/a%20b/c.deb?download=1</loc></url>
+  <url><loc>https://example.com/repo/pool/a%20b.deb</loc><lastmod>2024-03-09</lastmod></url>
+  <url><loc>https://other.example.com/repo/x</loc></url>
+</urlset>"#;
+
+    fn time(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn test_sitemap() {
+        let (entries, nested) = parse_sitemap(SITEMAP);
+        assert!(nested.is_empty());
+        assert_eq!(entries.len(), 7);
+
+        let index = index_entries(&entries);
+        let url = Url::parse("https://example.com/repo/").unwrap();
+        let items = list_dir(&index, &url);
+        let names: Vec<_> = items.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["README.md", "dists", "pool"]);
+        assert_eq!(items[0].type_, FileType::File);
+        assert_eq!(items[0].mtime, Some(time("2024-03-09 20:45:24")));
+        assert_eq!(items[1].type_, FileType::Directory);
+        assert_eq!(items[1].url.as_str(), "https://example.com/repo/dists/");
+        // newest mtime of files under it
+        assert_eq!(items[1].mtime, Some(time("2024-03-11 00:00:00")));
+
+        let url = Url::parse("https://example.com/repo/pool/").unwrap();
+        let items = list_dir(&index, &url);
+        assert_eq!(items[0].name, "a b.deb");
+        assert_eq!(
+            items[0].url.as_str(),
+            "https://example.com/repo/pool/a%20b.deb"
+        );
+        // date-only lastmod is ignored
+        assert_eq!(items[0].mtime, None);
+
+        // Every directory is indexed, with other origins apart
+        let url = Url::parse("https://example.com/repo/dists/").unwrap();
+        let names: Vec<_> = list_dir(&index, &url).into_iter().map(|i| i.name).collect();
+        assert_eq!(names, ["old", "stable"]);
+        let url = Url::parse("https://other.example.com/repo/").unwrap();
+        assert_eq!(list_dir(&index, &url)[0].name, "x");
+        let url = Url::parse("https://example.com/missing/").unwrap();
+        assert!(list_dir(&index, &url).is_empty());
+    }
+
+    #[test]
+    fn test_sitemap_index() {
+        let (entries, nested) = parse_sitemap(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <sitemap><loc>https://example.com/sitemap1.xml</loc></sitemap>
+  <sitemap><loc>https://example.com/sitemap2.xml</loc></sitemap>
+</sitemapindex>"#,
+        );
+        assert!(entries.is_empty());
+        assert_eq!(nested.len(), 2);
+        assert_eq!(nested[1].as_str(), "https://example.com/sitemap2.xml");
+    }
+}
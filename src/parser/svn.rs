@@ -0,0 +1,142 @@
+/// A parser for Apache Subversion's autoindex (mod_dav_svn), in default HTML format or XML format (with SVNIndexXSLT).
+/// Entries show neither sizes nor mtimes.
+use crate::{
+    listing::{FileType, ListItem},
+    utils::get_page,
+};
+
+use super::*;
+use anyhow::Result;
+use regex::Regex;
+use scraper::{Html, Selector};
+use tracing::debug;
+
+#[derive(Debug, Clone)]
+pub struct SvnListingParser {
+    revision_regex: Regex,
+}
+
+impl Default for SvnListingParser {
+    fn default() -> Self {
+        Self {
+            revision_regex: Regex::new(r"Revision (\d+):").unwrap(),
+        }
+    }
+}
+
+impl Parser for SvnListingParser {
+    fn get_list(&self, client: &Client, url: &Url) -> Result<ListResult> {
+        let page = get_page(client, url.clone())?;
+        parse_listing_page(self, &page)
+    }
+
+    fn parse_page(&self, url: &Url, body: &str) -> Result<Vec<ListItem>> {
+        assert_if_url_has_no_trailing_slash(url);
+        let document = Html::parse_document(body);
+        // XML format (parsed as HTML, so self-closing tags are nested): <svn><index rev="..." path="..."><dir name="..." href="..."/><file .../></index></svn>
+        let xml_selector = Selector::parse("index dir, index file").unwrap();
+        let html_selector = Selector::parse("ul > li > a").unwrap();
+        let revision = match Selector::parse("index")
+            .ok()
+            .and_then(|s| document.select(&s).next())
+        {
+            Some(index) => index.value().attr("rev").map(|r| r.to_string()),
+            None => self
+                .revision_regex
+                .captures(body)
+                .map(|c| c.get(1).unwrap().as_str().to_string()),
+        };
+        debug!("{} is at SVN revision {:?}", url, revision);
+
+        let mut items = Vec::new();
+        let elements = document
+            .select(&xml_selector)
+            .chain(document.select(&html_selector));
+        for element in elements {
+            let href = match element.value().attr("href") {
+                Some(href) => href,
+                None => continue,
+            };
+            let name = get_real_name_from_href(href);
+            if name == ".." || href.starts_with("../") {
+                continue;
+            }
+            let original_href = href;
+            let href = url.join(href)?;
+            let type_ = if href.as_str().ends_with('/') {
+                FileType::Directory
+            } else {
+                FileType::File
+            };
+            items.push(
+                ListItem::new(href, name, type_, None, None).with_original_href(original_href),
+            );
+        }
+        Ok(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apache_dist() {
+        let client = reqwest::blocking::Client::new();
+        let items = SvnListingParser::default()
+            .get_list(
+                &client,
+                &Url::parse("http://localhost:1921/svn-dist/").unwrap(),
+            )
+            .unwrap();
+        match items {
+            ListResult::List(items) => {
+                assert_eq!(items.len(), 8);
+                assert_eq!(items[0].name, "abdera");
+                assert_eq!(items[0].type_, FileType::Directory);
+                assert_eq!(
+                    items[0].url,
+                    Url::parse("http://localhost:1921/svn-dist/abdera/").unwrap()
+                );
+                assert_eq!(items[5].name, "KEYS");
+                assert_eq!(items[5].type_, FileType::File);
+                assert_eq!(items[5].size, None);
+            }
+            _ => unreachable!(),
+        }
+        let items = SvnListingParser::default()
+            .get_list(
+                &client,
+                &Url::parse("http://localhost:1921/svn-dist/abdera/").unwrap(),
+            )
+            .unwrap();
+        match items {
+            ListResult::List(items) => {
+                assert_eq!(items.len(), 3);
+                assert_eq!(items[2].name, "abdera 1.1.3 release notes.txt");
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_xml_format() {
+        let body = r#"<?xml version="1.0"?>
+<?xml-stylesheet type="text/xsl" href="/svnindex.xsl"?>
+<svn version="1.14.1 (r1886195)" href="http://subversion.apache.org/">
+  <index rev="67512" path="/release/abdera" base="dist">
+    <updir href="../"/>
+    <dir name="1.1.2" href="1.1.2/" />
+    <dir name="1.1.3" href="1.1.3/" />
+    <file name="KEYS" href="KEYS" />
+    <file name="a b.txt" href="a%20b.txt" />
+  </index>
+</svn>"#;
+        let url = Url::parse("http://localhost/release/abdera/").unwrap();
+        let items = SvnListingParser::default().parse_page(&url, body).unwrap();
+        let names: Vec<_> = items.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["1.1.2", "1.1.3", "KEYS", "a b.txt"]);
+        assert_eq!(items[1].type_, FileType::Directory);
+        assert_eq!(items[2].type_, FileType::File);
+    }
+}
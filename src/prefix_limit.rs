@@ -0,0 +1,121 @@
+// Caps of concurrent tasks (listings and downloads) under path prefixes, like `pool/=2`,
+// for upstreams backing some paths with weak origin servers.
+// Tasks over the cap are deferred by the scheduler instead of blocking worker threads.
+
+use std::{str::FromStr, sync::Mutex};
+
+use anyhow::{anyhow, Error, Result};
+
+#[derive(Debug, Clone)]
+pub struct PrefixCap {
+    /// Relative path prefix, like "pool/"
+    prefix: String,
+    max: usize,
+}
+
+impl FromStr for PrefixCap {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (prefix, max) = s
+            .rsplit_once('=')
+            .ok_or_else(|| anyhow!("Expected <prefix>=<max>, like pool/=2"))?;
+        let max: usize = max.trim().parse()?;
+        if max == 0 {
+            return Err(anyhow!("Cap of {} should be at least 1", prefix));
+        }
+        Ok(Self {
+            prefix: prefix.trim_start_matches('/').to_string(),
+            max,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PrefixLimiter {
+    caps: Vec<PrefixCap>,
+    /// Running tasks by index of cap
+    running: Mutex<Vec<usize>>,
+}
+
+impl PrefixLimiter {
+    pub fn new(caps: &[PrefixCap]) -> Self {
+        Self {
+            caps: caps.to_vec(),
+            running: Mutex::new(vec![0; caps.len()]),
+        }
+    }
+
+    /// Start a task on relative path (directories end with "/"), or None if any prefix of it is at cap.
+    pub fn try_acquire(&self, path: &str) -> Option<PrefixPermit<'_>> {
+        let caps: Vec<usize> = self
+            .caps
+            .iter()
+            .enumerate()
+            .filter(|(_, cap)| path.starts_with(&cap.prefix))
+            .map(|(i, _)| i)
+            .collect();
+        if !caps.is_empty() {
+            let mut running = self.running.lock().unwrap();
+            if caps.iter().any(|&i| running[i] >= self.caps[i].max) {
+                return None;
+            }
+            for &i in &caps {
+                running[i] += 1;
+            }
+        }
+        Some(PrefixPermit {
+            limiter: self,
+            caps,
+        })
+    }
+}
+
+/// Returned to limiter on drop
+#[derive(Debug)]
+pub struct PrefixPermit<'a> {
+    limiter: &'a PrefixLimiter,
+    caps: Vec<usize>,
+}
+
+impl Drop for PrefixPermit<'_> {
+    fn drop(&mut self) {
+        if !self.caps.is_empty() {
+            let mut running = self.limiter.running.lock().unwrap();
+            for &i in &self.caps {
+                running[i] -= 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_limiter() {
+        assert!("pool/".parse::<PrefixCap>().is_err());
+        assert!("pool/=0".parse::<PrefixCap>().is_err());
+        let caps: Vec<PrefixCap> = ["pool/=2", "/pool/main/=1"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let limiter = PrefixLimiter::new(&caps);
+
+        let a = limiter.try_acquire("pool/main/a.deb").unwrap();
+        // pool/main/ is at cap, but pool/ is not
+        assert!(limiter.try_acquire("pool/main/b.deb").is_none());
+        let b = limiter.try_acquire("pool/contrib/").unwrap();
+        // pool/ is at cap (including pool/main/a.deb)
+        assert!(limiter.try_acquire("pool/non-free/c.deb").is_none());
+        // not capped
+        let _others: Vec<_> = (0..10)
+            .map(|_| limiter.try_acquire("dists/stable/Release").unwrap())
+            .collect();
+        drop(a);
+        assert!(limiter.try_acquire("pool/main/b.deb").is_some());
+        drop(b);
+        assert_eq!(*limiter.running.lock().unwrap(), vec![0, 0]);
+    }
+}
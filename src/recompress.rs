@@ -0,0 +1,218 @@
+// Local recompression of indexes, like generating Packages.gz from Packages.xz for old clients
+// when upstream only ships one of them. Generated siblings are kept in remote list, so cleanup
+// does not delete them, and they follow mtime of their source to be regenerated only when it changes.
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context, Result};
+use filetime::FileTime;
+use flate2::{read::GzDecoder, write::GzEncoder};
+use tracing::{debug, error, info};
+use xz2::{read::XzDecoder, write::XzEncoder};
+
+use crate::regex_process::ExpandedRegex;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Gz,
+    Xz,
+}
+
+impl Codec {
+    fn extension(&self) -> &'static str {
+        match self {
+            Codec::Gz => "gz",
+            Codec::Xz => "xz",
+        }
+    }
+
+    fn of_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "gz" => Some(Codec::Gz),
+            "xz" => Some(Codec::Xz),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "gz" => Ok(Codec::Gz),
+            "xz" => Ok(Codec::Xz),
+            _ => Err(anyhow!("Unknown compression {:?} (expected gz or xz)", s)),
+        }
+    }
+}
+
+/// Generate a sibling compressed with codec for files matching regex, like 'Packages\.xz$=gz'
+#[derive(Debug, Clone)]
+pub struct Recompression {
+    regex: ExpandedRegex,
+    codec: Codec,
+}
+
+impl FromStr for Recompression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (regex, codec) = s
+            .rsplit_once('=')
+            .ok_or_else(|| anyhow!("Expected <regex>=<gz|xz>, like Packages\\.xz$=gz"))?;
+        Ok(Self {
+            regex: ExpandedRegex::from_str(regex)?,
+            codec: codec.trim().parse()?,
+        })
+    }
+}
+
+/// Path of the sibling compressed with codec, if path is compressed with another known codec
+fn sibling_path(path: &Path, codec: Codec) -> Option<PathBuf> {
+    let source = Codec::of_path(path)?;
+    (source != codec).then(|| path.with_extension(codec.extension()))
+}
+
+fn recompress_file(source: &Path, target: &Path, codec: Codec) -> Result<()> {
+    let input = BufReader::new(File::open(source)?);
+    let mut reader: Box<dyn Read> = match Codec::of_path(source) {
+        Some(Codec::Gz) => Box::new(GzDecoder::new(input)),
+        Some(Codec::Xz) => Box::new(XzDecoder::new(input)),
+        None => return Err(anyhow!("Unknown compression of {:?}", source)),
+    };
+    // Written to a temporary file first, so that clients never see a partial index
+    let tmp = target.with_file_name(format!(
+        ".tmp.{}",
+        target.file_name().unwrap().to_string_lossy()
+    ));
+    let output = BufWriter::new(File::create(&tmp)?);
+    let res = match codec {
+        Codec::Gz => {
+            let mut encoder = GzEncoder::new(output, flate2::Compression::best());
+            std::io::copy(&mut reader, &mut encoder).and_then(|_| encoder.finish()?.flush())
+        }
+        Codec::Xz => {
+            let mut encoder = XzEncoder::new(output, 6);
+            std::io::copy(&mut reader, &mut encoder).and_then(|_| encoder.finish()?.flush())
+        }
+    };
+    if let Err(e) = res {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    let mtime = FileTime::from_last_modification_time(&source.metadata()?);
+    filetime::set_file_mtime(&tmp, mtime)?;
+    std::fs::rename(&tmp, target)?;
+    Ok(())
+}
+
+/// If target exists with the same mtime as source (i.e. generated from current source)
+fn is_up_to_date(source: &Path, target: &Path) -> bool {
+    match (source.metadata(), target.metadata()) {
+        (Ok(s), Ok(t)) => {
+            FileTime::from_last_modification_time(&s) == FileTime::from_last_modification_time(&t)
+        }
+        _ => false,
+    }
+}
+
+/// Generate siblings of files in remote list (under download_dir) matching rules,
+/// and add them to remote list. Siblings shipped by upstream are left alone.
+/// Returns number of files failed to generate.
+pub fn recompress(
+    rules: &[Recompression],
+    download_dir: &Path,
+    remote_list: &mut HashSet<PathBuf>,
+    dry_run: bool,
+) -> usize {
+    let mut sources: Vec<PathBuf> = remote_list
+        .iter()
+        .filter(|p| {
+            let relative = p.strip_prefix(download_dir).unwrap_or(p);
+            rules
+                .iter()
+                .any(|r| r.regex.is_match(&relative.to_string_lossy()))
+        })
+        .cloned()
+        .collect();
+    sources.sort();
+    let mut failures = 0;
+    for source in sources {
+        let relative = source.strip_prefix(download_dir).unwrap_or(&source);
+        for rule in rules {
+            if !rule.regex.is_match(&relative.to_string_lossy()) {
+                continue;
+            }
+            let Some(target) = sibling_path(&source, rule.codec) else {
+                continue;
+            };
+            if !remote_list.insert(target.clone()) {
+                debug!("{:?} exists upstream, not generating", target);
+                continue;
+            }
+            if dry_run || !source.exists() || is_up_to_date(&source, &target) {
+                continue;
+            }
+            info!("Generating {:?} from {:?}", target, source);
+            if let Err(e) = recompress_file(&source, &target, rule.codec)
+                .with_context(|| format!("Failed to generate {:?}", target))
+            {
+                error!("{:?}", e);
+                failures += 1;
+            }
+        }
+    }
+    failures
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recompress() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let dists = root.join("dists/bookworm/main/binary-amd64");
+        std::fs::create_dir_all(&dists).unwrap();
+        let mut encoder = XzEncoder::new(File::create(dists.join("Packages.xz")).unwrap(), 6);
+        encoder.write_all(b"Package: a\n").unwrap();
+        encoder.finish().unwrap();
+        std::fs::write(root.join("dists/bookworm/Contents.gz"), "upstream").unwrap();
+        std::fs::write(root.join("dists/bookworm/Contents.xz"), "upstream").unwrap();
+
+        let mut remote_list: HashSet<PathBuf> = [
+            dists.join("Packages.xz"),
+            root.join("dists/bookworm/Contents.gz"),
+            root.join("dists/bookworm/Contents.xz"),
+        ]
+        .into_iter()
+        .collect();
+        let rules = vec![Recompression::from_str(r"^dists/.+\.xz$=gz").unwrap()];
+        assert_eq!(recompress(&rules, root, &mut remote_list, false), 0);
+
+        let target = dists.join("Packages.gz");
+        assert!(remote_list.contains(&target));
+        let mut content = String::new();
+        GzDecoder::new(File::open(&target).unwrap())
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "Package: a\n");
+        assert!(is_up_to_date(&dists.join("Packages.xz"), &target));
+        // shipped by upstream
+        assert_eq!(
+            std::fs::read_to_string(root.join("dists/bookworm/Contents.gz")).unwrap(),
+            "upstream"
+        );
+
+        assert!(Recompression::from_str("Packages$=bz2").is_err());
+        assert_eq!(sibling_path(Path::new("Packages"), Codec::Gz), None);
+    }
+}
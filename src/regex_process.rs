@@ -1,6 +1,13 @@
-use std::str::FromStr;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
 
 use regex::Regex;
+use serde::Serialize;
 
 // Submit an issue if you find this out-of-date!
 // And assuming that all vars are distro_ver
@@ -24,6 +31,58 @@ const REGEX_REPLACEMENTS: &[(&str, &str)] = &[
     ("${SLES_CURRENT}", "(?<distro_ver>12|15)"),
 ];
 
+// Named groups of paths for common trimming policies, used as a whole rule (like `--exclude @debug-symbols`)
+const RULE_GROUPS: &[(&str, &str)] = &[
+    ("@iso", r"(?i)\.(iso|img)(\.(torrent|zsync))?$"),
+    (
+        "@debug-symbols",
+        r"(-dbgsym_|-dbg_|\.ddeb$|-debuginfo-|-debugsource-|(^|/)debug(/|$))",
+    ),
+    (
+        "@sources",
+        r"(\.(dsc|src\.rpm)$|\.(orig|debian)\.tar\.[^/]+$|\.diff\.gz$|(^|/)(SRPMS|source)(/|$))",
+    ),
+    ("@signatures", r"\.(asc|sig|sign|gpg)$"),
+];
+
+/// Regex of group name (like "@iso")
+fn rule_group(name: &str) -> Result<&'static str, regex::Error> {
+    RULE_GROUPS
+        .iter()
+        .find(|(group, _)| *group == name)
+        .map(|(_, regex)| *regex)
+        .ok_or_else(|| {
+            let groups: Vec<&str> = RULE_GROUPS.iter().map(|(group, _)| *group).collect();
+            regex::Error::Syntax(format!(
+                "unknown group {}, available groups: {}",
+                name,
+                groups.join(", ")
+            ))
+        })
+}
+
+// Suffixes of files derived from a payload (like "a.iso.asc" of "a.iso"), which follow the exclusion of payload
+const COMPANION_SUFFIXES: &[&str] = &[
+    ".asc",
+    ".sig",
+    ".sign",
+    ".gpg",
+    ".sha256",
+    ".sha512",
+    ".md5",
+    ".metalink",
+    ".meta4",
+    ".zsync",
+];
+
+/// Payload of companion path, like "a.iso" of "a.iso.asc"
+fn companion_payload(path: &str) -> Option<&str> {
+    COMPANION_SUFFIXES
+        .iter()
+        .find_map(|suffix| path.strip_suffix(suffix))
+        .filter(|payload| !payload.is_empty() && !payload.ends_with('/'))
+}
+
 #[derive(Debug, Clone)]
 pub struct ExpandedRegex {
     inner: Regex,
@@ -34,6 +93,10 @@ impl FromStr for ExpandedRegex {
     type Err = regex::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = match s.starts_with('@') {
+            true => rule_group(s)?,
+            false => s,
+        };
         let mut s1 = s.to_string();
         for (from, to) in REGEX_REPLACEMENTS {
             s1 = s1.replace(from, to);
@@ -60,76 +123,172 @@ impl ExpandedRegex {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Comparison {
     Stop,
     ListOnly,
     Ok,
 }
 
+/// Rule deciding a path, with index of its --exclude or --include flag
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rule {
+    Exclude(usize),
+    Include(usize),
+    /// Path matches an include regex with other distro versions
+    IncludeOthers(usize),
+    /// Path is a companion of a payload excluded by the exclude regex
+    Companion(usize),
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rule::Exclude(i) => write!(f, "exclude#{}", i),
+            Rule::Include(i) => write!(f, "include#{}", i),
+            Rule::IncludeOthers(i) => write!(f, "include#{} (others)", i),
+            Rule::Companion(i) => write!(f, "exclude#{} (payload)", i),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TraceRecord<'a> {
+    path: &'a str,
+    decision: Comparison,
+    rule: Option<String>,
+    regex: Option<&'a str>,
+}
+
 #[derive(Debug, Clone)]
 pub struct ExclusionManager {
     /// Stop the task immediately if any of these regexes match.
-    instant_stop_regexes: Vec<ExpandedRegex>,
+    instant_stop_regexes: Vec<(usize, ExpandedRegex)>,
     /// Continue, but don't download anything if any of these regexes match.
-    list_only_regexes: Vec<ExpandedRegex>,
+    list_only_regexes: Vec<(usize, ExpandedRegex)>,
     /// Include only these regexes.
     include_regexes: Vec<ExpandedRegex>,
+    /// Every decision is written to it as a JSON line, if set.
+    trace: Option<Arc<Mutex<BufWriter<File>>>>,
+    /// Companions (see COMPANION_SUFFIXES) are stopped with their payloads.
+    companions: bool,
 }
 
 impl ExclusionManager {
-    pub fn new(exclusions: &Vec<ExpandedRegex>, inclusions: &Vec<ExpandedRegex>) -> Self {
+    pub fn new(exclusions: &[ExpandedRegex], inclusions: &[ExpandedRegex]) -> Self {
         let mut instant_stop_regexes = Vec::new();
         let mut list_only_regexes = Vec::new();
 
-        for exclusion in exclusions {
+        for (idx, exclusion) in exclusions.iter().enumerate() {
             let regex_str = exclusion.inner.as_str();
             let mut flag = false;
             for inclusion in inclusions {
                 if inclusion.inner.as_str().starts_with(regex_str) {
-                    list_only_regexes.push(exclusion.clone());
+                    list_only_regexes.push((idx, exclusion.clone()));
                     flag = true;
                     break;
                 }
             }
             if !flag {
-                instant_stop_regexes.push(exclusion.clone());
+                instant_stop_regexes.push((idx, exclusion.clone()));
             }
         }
 
         Self {
             instant_stop_regexes,
             list_only_regexes,
-            include_regexes: inclusions.clone(),
+            include_regexes: inclusions.to_vec(),
+            trace: None,
+            companions: true,
         }
     }
 
-    pub fn match_str(&self, text: &str) -> Comparison {
-        for regex in &self.instant_stop_regexes {
+    /// Whether companions (like "a.iso.asc") are stopped with their payloads (like "a.iso"), true by default.
+    pub fn set_companions(&mut self, companions: bool) {
+        self.companions = companions;
+    }
+
+    /// Write every decision (path, decision and rule) to file as JSON lines, for debugging filters.
+    pub fn set_trace(&mut self, path: &Path) -> std::io::Result<()> {
+        self.trace = Some(Arc::new(Mutex::new(BufWriter::new(File::create(path)?))));
+        Ok(())
+    }
+
+    /// Decision for text, and the rule deciding it (None if no rule matches)
+    pub fn explain(&self, text: &str) -> (Comparison, Option<Rule>) {
+        let res = self.explain_path(text);
+        if !self.companions || res.0 == Comparison::Stop {
+            return res;
+        }
+        // Signatures and checksums are not kept without their payloads (and come back with them)
+        match companion_payload(text).map(|payload| self.explain_path(payload)) {
+            Some((Comparison::Stop, Some(Rule::Exclude(idx)))) => {
+                (Comparison::Stop, Some(Rule::Companion(idx)))
+            }
+            _ => res,
+        }
+    }
+
+    fn explain_path(&self, text: &str) -> (Comparison, Option<Rule>) {
+        for (idx, regex) in &self.instant_stop_regexes {
             if regex.is_match(text) {
-                return Comparison::Stop;
+                return (Comparison::Stop, Some(Rule::Exclude(*idx)));
             }
         }
-        for regex in &self.include_regexes {
+        for (idx, regex) in self.include_regexes.iter().enumerate() {
             if regex.is_match(text) {
-                return Comparison::Ok;
+                return (Comparison::Ok, Some(Rule::Include(idx)));
             }
         }
         // Performance: it is possible that a regex for inclusion shown like this:
         // ^fedora/${FEDORA_CURRENT}
         // And the remote corresponding folder has a lot of subfolders.
         // This is a "shortcut" to avoid checking all subfolders.
-        for regex in &self.include_regexes {
+        for (idx, regex) in self.include_regexes.iter().enumerate() {
             if regex.is_others_match(text) {
-                return Comparison::Stop;
+                return (Comparison::Stop, Some(Rule::IncludeOthers(idx)));
             }
         }
-        for regex in &self.list_only_regexes {
+        for (idx, regex) in &self.list_only_regexes {
             if regex.is_match(text) {
-                return Comparison::ListOnly;
+                return (Comparison::ListOnly, Some(Rule::Exclude(*idx)));
+            }
+        }
+        (Comparison::Ok, None)
+    }
+
+    /// Regex (as written) of rule
+    pub fn rule_regex(&self, rule: Rule) -> &str {
+        match rule {
+            Rule::Exclude(idx) | Rule::Companion(idx) => self
+                .instant_stop_regexes
+                .iter()
+                .chain(&self.list_only_regexes)
+                .find(|(i, _)| *i == idx)
+                .map(|(_, r)| r.inner.as_str())
+                .unwrap(),
+            Rule::Include(idx) | Rule::IncludeOthers(idx) => {
+                self.include_regexes[idx].inner.as_str()
             }
         }
-        Comparison::Ok
+    }
+
+    pub fn match_str(&self, text: &str) -> Comparison {
+        let (comparison, rule) = self.explain(text);
+        if let Some(trace) = &self.trace {
+            let record = TraceRecord {
+                path: text,
+                decision: comparison,
+                rule: rule.map(|r| r.to_string()),
+                regex: rule.map(|r| self.rule_regex(r)),
+            };
+            let mut trace = trace.lock().unwrap();
+            // Tracing is best effort, and should not fail the sync
+            let _ = serde_json::to_writer(&mut *trace, &record);
+            let _ = trace.write_all(b"\n");
+        }
+        comparison
     }
 }
 
@@ -147,6 +306,31 @@ mod tests {
         assert!(!regex.is_match("/deb/dists/wheezy/Release"));
     }
 
+    #[test]
+    fn test_rule_groups() {
+        let iso = ExpandedRegex::from_str("@iso").unwrap();
+        assert!(iso.is_match("releases/24.04/ubuntu-24.04-desktop-amd64.iso"));
+        assert!(iso.is_match("images/disk.IMG.torrent"));
+        assert!(!iso.is_match("pool/main/i/isomd5sum/isomd5sum_1.2.3.orig.tar.gz"));
+        let dbg = ExpandedRegex::from_str("@debug-symbols").unwrap();
+        assert!(dbg.is_match("pool/main/b/bash/bash-dbgsym_5.2-1_amd64.deb"));
+        assert!(dbg.is_match(
+            "9/BaseOS/x86_64/debug/tree/Packages/b/bash-debuginfo-5.1.8-6.el9.x86_64.rpm"
+        ));
+        assert!(!dbg.is_match("pool/main/d/debugedit/debugedit_5.0-5_amd64.deb"));
+        let sources = ExpandedRegex::from_str("@sources").unwrap();
+        assert!(sources.is_match("pool/main/b/bash/bash_5.2.orig.tar.xz"));
+        assert!(sources.is_match("pool/main/b/bash/bash_5.2-1.dsc"));
+        assert!(sources.is_match("fedora/40/SRPMS"));
+        assert!(!sources.is_match("pool/main/b/bash/bash_5.2-1_amd64.deb"));
+        let sigs = ExpandedRegex::from_str("@signatures").unwrap();
+        assert!(sigs.is_match("dists/bookworm/Release.gpg"));
+        assert!(sigs.is_match("SHA256SUMS.asc"));
+        assert!(!sigs.is_match("dists/bookworm/InRelease"));
+
+        assert!(ExpandedRegex::from_str("@unknown").is_err());
+    }
+
     #[test]
     fn test_exclusion() {
         let target =
@@ -190,4 +374,74 @@ mod tests {
         assert_eq!(exclusion_manager.match_str(target1), Comparison::Ok);
         assert_eq!(exclusion_manager.match_str(target2), Comparison::Stop);
     }
+
+    #[test]
+    fn test_filter_trace() {
+        let exclusions = vec![
+            ExpandedRegex::from_str("/el/").unwrap(),
+            ExpandedRegex::from_str("debuginfo").unwrap(),
+        ];
+        let inclusions = vec![ExpandedRegex::from_str("/el/${RHEL_CURRENT}").unwrap()];
+        let dir = tempfile::tempdir().unwrap();
+        let trace = dir.path().join("trace.jsonl");
+        let mut exclusion_manager = ExclusionManager::new(&exclusions, &inclusions);
+        exclusion_manager.set_trace(&trace).unwrap();
+        assert_eq!(
+            exclusion_manager.explain("docker/el/8/debuginfo/"),
+            (Comparison::Stop, Some(Rule::Exclude(1)))
+        );
+        exclusion_manager.match_str("docker/el/");
+        exclusion_manager.match_str("docker/el/8/");
+        exclusion_manager.match_str("docker/el/6/");
+        exclusion_manager.match_str("docker/README");
+        drop(exclusion_manager);
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&trace)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                serde_json::json!({"path": "docker/el/", "decision": "list_only", "rule": "exclude#0", "regex": "/el/"}),
+                serde_json::json!({"path": "docker/el/8/", "decision": "ok", "rule": "include#0", "regex": "/el/(?<distro_ver>7|8|9)"}),
+                serde_json::json!({"path": "docker/el/6/", "decision": "stop", "rule": "include#0 (others)", "regex": "/el/(?<distro_ver>7|8|9)"}),
+                serde_json::json!({"path": "docker/README", "decision": "ok", "rule": null, "regex": null}),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_companions() {
+        let exclusions = vec![ExpandedRegex::from_str("@iso").unwrap()];
+        let mut exclusion_manager = ExclusionManager::new(&exclusions, &[]);
+        for path in ["cd/a.iso.asc", "cd/a.iso.sha256", "cd/a.iso.meta4"] {
+            assert_eq!(
+                exclusion_manager.explain(path),
+                (Comparison::Stop, Some(Rule::Companion(0))),
+                "{path}"
+            );
+        }
+        // Payload not excluded, and files which are not companions
+        assert_eq!(
+            exclusion_manager.explain("cd/a.tar.gz.asc"),
+            (Comparison::Ok, None)
+        );
+        assert_eq!(
+            exclusion_manager.explain("cd/SHA256SUMS.asc"),
+            (Comparison::Ok, None)
+        );
+        assert_eq!(exclusion_manager.explain(".asc"), (Comparison::Ok, None));
+        assert_eq!(
+            exclusion_manager.rule_regex(Rule::Companion(0)),
+            exclusions[0].inner.as_str()
+        );
+
+        exclusion_manager.set_companions(false);
+        assert_eq!(
+            exclusion_manager.explain("cd/a.iso.asc"),
+            (Comparison::Ok, None)
+        );
+    }
 }
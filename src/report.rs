@@ -0,0 +1,435 @@
+// Machine-readable report of what a sync run has done
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compare::{CompareTier, DownloadReason, RemoteMtime},
+    resources::ResourceUsage,
+    sync_stats::StatsSnapshot,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum Action {
+    /// File downloaded (or would be downloaded in dry run)
+    Download {
+        path: String,
+        url: String,
+        reason: DownloadReason,
+        size: Option<u64>,
+        /// Remote mtime in listing, if any
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        remote_mtime: Option<RemoteMtime>,
+    },
+    DownloadFailed {
+        path: String,
+        url: String,
+        reason: DownloadReason,
+        error: String,
+    },
+    ListFailed {
+        path: String,
+        url: String,
+        error: String,
+    },
+    /// File or directory (ending with "/") deleted (or would be deleted in dry run)
+    Delete { path: String },
+    /// Mtime of file updated without downloading (--touch-up)
+    TouchUp {
+        path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        remote_mtime: Option<RemoteMtime>,
+    },
+}
+
+impl Action {
+    /// Format like `rsync --itemize-changes` (`%i %n`). Failures are not itemized.
+    pub fn itemize(&self) -> Option<String> {
+        let flags = match self {
+            Action::Download { reason, .. } => match reason {
+                DownloadReason::New
+                | DownloadReason::ForcedByExtension
+                | DownloadReason::TypeChange => ">f+++++++++",
+                DownloadReason::SizeMismatch => ">f.st......",
+                DownloadReason::MtimeMismatch => ">f..t......",
+                DownloadReason::SiblingUpdated | DownloadReason::Repair => ">f.........",
+                DownloadReason::ChecksumMismatch => ">fc........",
+            },
+            Action::Delete { .. } => "*deleting",
+            Action::TouchUp { .. } => ".f..t......",
+            Action::DownloadFailed { .. } | Action::ListFailed { .. } => return None,
+        };
+        let path = match self {
+            Action::Download { path, .. }
+            | Action::Delete { path }
+            | Action::TouchUp { path, .. } => path,
+            _ => unreachable!(),
+        };
+        Some(format!("{flags:<11} {path}"))
+    }
+}
+
+/// Bytes downloaded (or would be downloaded in dry run), to attribute bandwidth growth
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Bandwidth {
+    /// By first-level directory ("." for files directly under local directory)
+    pub by_top_dir: BTreeMap<String, u64>,
+    /// By where the file is found: "listing", or "extension" (by --apt-packages or --yum-packages)
+    pub by_origin: BTreeMap<String, u64>,
+}
+
+impl Bandwidth {
+    fn add(&mut self, path: &str, reason: DownloadReason, size: u64) {
+        let top_dir = match path.split_once('/') {
+            Some((dir, _)) => dir,
+            None => ".",
+        };
+        *self.by_top_dir.entry(top_dir.to_string()).or_default() += size;
+        let origin = match reason {
+            DownloadReason::ForcedByExtension => "extension",
+            _ => "listing",
+        };
+        *self.by_origin.entry(origin.to_string()).or_default() += size;
+    }
+
+    /// Like "pool: 1.2 GiB, dists: 30 MiB", largest first
+    pub fn summary(&self, limit: usize) -> String {
+        if self.by_top_dir.is_empty() {
+            return "nothing".to_string();
+        }
+        let mut dirs: Vec<_> = self.by_top_dir.iter().collect();
+        dirs.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        dirs.iter()
+            .take(limit)
+            .map(|(dir, size)| {
+                format!(
+                    "{}: {}",
+                    dir,
+                    humansize::format_size(**size, humansize::BINARY)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Version of report format, bumped when a field is renamed, removed or changes its meaning
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// For reports written before schema_version is added
+fn default_schema_version() -> u32 {
+    1
+}
+
+/// Fields added after the first version are defaulted when reading older reports
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Report {
+    #[serde(default = "default_schema_version")]
+    pub schema_version: u32,
+    pub upstream: String,
+    pub local: String,
+    pub dry_run: bool,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub exit_code: i32,
+    #[serde(default)]
+    pub resources: ResourceUsage,
+    #[serde(default)]
+    pub stats: StatsSnapshot,
+    /// Number of files compared with each tier
+    #[serde(default)]
+    pub compare_tiers: BTreeMap<CompareTier, usize>,
+    #[serde(default)]
+    pub bandwidth: Bandwidth,
+    pub actions: Vec<Action>,
+}
+
+/// Collects actions from all threads. Nothing is kept when disabled.
+#[derive(Debug, Default)]
+pub struct Reporter {
+    enabled: bool,
+    /// Print actions like `rsync --itemize-changes`
+    itemize: bool,
+    started_at: DateTime<Utc>,
+    actions: Mutex<Vec<Action>>,
+    /// Always counted, as it is also logged at the end
+    compare_tiers: Mutex<BTreeMap<CompareTier, usize>>,
+    /// Always counted, as it is also logged at the end
+    bandwidth: Mutex<Bandwidth>,
+    /// Downloads started but not recorded yet (URL and reason by path), so that a panicked one is still recorded
+    unfinished: Mutex<HashMap<String, (String, DownloadReason)>>,
+}
+
+impl Reporter {
+    pub fn new(enabled: bool, itemize: bool) -> Self {
+        Self {
+            enabled,
+            itemize,
+            started_at: Utc::now(),
+            actions: Mutex::new(Vec::new()),
+            compare_tiers: Mutex::new(BTreeMap::new()),
+            bandwidth: Mutex::new(Bandwidth::default()),
+            unfinished: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Remember a download being started, until it is recorded
+    pub fn start_download(&self, path: &str, url: &str, reason: DownloadReason) {
+        self.unfinished
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), (url.to_string(), reason));
+    }
+
+    /// Record downloads of paths started but not recorded (like when the task panicked) as failed,
+    /// returning these paths
+    pub fn fail_unfinished<'a>(
+        &self,
+        paths: impl IntoIterator<Item = &'a str>,
+        error: &str,
+    ) -> Vec<&'a str> {
+        let mut failed = Vec::new();
+        for path in paths {
+            let Some((url, reason)) = self.unfinished.lock().unwrap().remove(path) else {
+                continue;
+            };
+            self.record(Action::DownloadFailed {
+                path: path.to_string(),
+                url,
+                reason,
+                error: error.to_string(),
+            });
+            failed.push(path);
+        }
+        failed
+    }
+
+    pub fn record(&self, action: Action) {
+        if let Action::Download { path, .. } | Action::DownloadFailed { path, .. } = &action {
+            self.unfinished.lock().unwrap().remove(path);
+        }
+        if let Action::Download {
+            path,
+            reason,
+            size: Some(size),
+            ..
+        } = &action
+        {
+            self.bandwidth.lock().unwrap().add(path, *reason, *size);
+        }
+        if self.itemize {
+            if let Some(line) = action.itemize() {
+                println!("{line}");
+            }
+        }
+        if self.enabled {
+            self.actions.lock().unwrap().push(action);
+        }
+    }
+
+    pub fn count_compare(&self, tier: CompareTier) {
+        *self.compare_tiers.lock().unwrap().entry(tier).or_default() += 1;
+    }
+
+    pub fn compare_tiers(&self) -> BTreeMap<CompareTier, usize> {
+        self.compare_tiers.lock().unwrap().clone()
+    }
+
+    /// Like "list: 120, head: 3"
+    pub fn compare_summary(&self) -> String {
+        let tiers = self.compare_tiers();
+        if tiers.is_empty() {
+            return "nothing".to_string();
+        }
+        tiers
+            .iter()
+            .map(|(tier, count)| format!("{}: {}", tier, count))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    pub fn bandwidth(&self) -> Bandwidth {
+        self.bandwidth.lock().unwrap().clone()
+    }
+
+    pub fn finish(
+        &self,
+        upstream: &str,
+        local: &Path,
+        dry_run: bool,
+        exit_code: i32,
+        resources: ResourceUsage,
+        stats: StatsSnapshot,
+    ) -> Report {
+        Report {
+            schema_version: SCHEMA_VERSION,
+            upstream: upstream.to_string(),
+            local: local.to_string_lossy().to_string(),
+            dry_run,
+            started_at: self.started_at,
+            finished_at: Utc::now(),
+            exit_code,
+            resources,
+            stats,
+            compare_tiers: self.compare_tiers(),
+            bandwidth: self.bandwidth(),
+            actions: std::mem::take(&mut *self.actions.lock().unwrap()),
+        }
+    }
+}
+
+impl Report {
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open report {:?}", path))?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Failed to parse report {:?}", path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_itemize() {
+        let download = |reason| Action::Download {
+            path: "pool/a.deb".to_string(),
+            url: "http://localhost/pool/a.deb".to_string(),
+            reason,
+            size: None,
+            remote_mtime: None,
+        };
+        assert_eq!(
+            download(DownloadReason::New).itemize().unwrap(),
+            ">f+++++++++ pool/a.deb"
+        );
+        assert_eq!(
+            download(DownloadReason::MtimeMismatch).itemize().unwrap(),
+            ">f..t...... pool/a.deb"
+        );
+        let delete = Action::Delete {
+            path: "pool/old/".to_string(),
+        };
+        assert_eq!(delete.itemize().unwrap(), "*deleting   pool/old/");
+        let touch_up = Action::TouchUp {
+            path: "pool/a.deb".to_string(),
+            remote_mtime: None,
+        };
+        assert_eq!(touch_up.itemize().unwrap(), ".f..t...... pool/a.deb");
+        let failed = Action::ListFailed {
+            path: "pool".to_string(),
+            url: "http://localhost/pool/".to_string(),
+            error: "404".to_string(),
+        };
+        assert_eq!(failed.itemize(), None);
+    }
+
+    #[test]
+    fn test_report_serialization() {
+        let reporter = Reporter::new(true, false);
+        reporter.record(Action::Download {
+            path: "pool/a.deb".to_string(),
+            url: "http://localhost/pool/a.deb".to_string(),
+            reason: DownloadReason::MtimeMismatch,
+            size: Some(1234),
+            remote_mtime: None,
+        });
+        reporter.record(Action::Delete {
+            path: "pool/old/".to_string(),
+        });
+        let report = reporter.finish(
+            "http://localhost/",
+            Path::new("/srv/mirror"),
+            false,
+            0,
+            ResourceUsage::default(),
+            StatsSnapshot::default(),
+        );
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            json["actions"][0],
+            serde_json::json!({
+                "action": "download",
+                "path": "pool/a.deb",
+                "url": "http://localhost/pool/a.deb",
+                "reason": "mtime-mismatch",
+                "size": 1234
+            })
+        );
+        assert_eq!(
+            json["actions"][1],
+            serde_json::json!({"action": "delete", "path": "pool/old/"})
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.json");
+        report.write(&path).unwrap();
+        let loaded = Report::load(&path).unwrap();
+        assert_eq!(loaded.local, "/srv/mirror");
+        assert!(matches!(
+            &loaded.actions[..],
+            [
+                Action::Download {
+                    reason: DownloadReason::MtimeMismatch,
+                    size: Some(1234),
+                    ..
+                },
+                Action::Delete { .. }
+            ]
+        ));
+
+        // Reports of older versions, without fields added later
+        std::fs::write(
+            &path,
+            r#"{"upstream": "http://localhost/", "local": "/srv/mirror", "dry_run": true,
+                "started_at": "2024-01-01T00:00:00Z", "finished_at": "2024-01-01T00:01:00Z",
+                "exit_code": 25, "actions": []}"#,
+        )
+        .unwrap();
+        let loaded = Report::load(&path).unwrap();
+        assert_eq!(loaded.exit_code, 25);
+        assert!(loaded.bandwidth.by_top_dir.is_empty());
+    }
+
+    #[test]
+    fn test_bandwidth() {
+        let reporter = Reporter::new(false, false);
+        let download = |path: &str, reason, size| Action::Download {
+            path: path.to_string(),
+            url: format!("http://localhost/{path}"),
+            reason,
+            size,
+            remote_mtime: None,
+        };
+        reporter.record(download("pool/a.deb", DownloadReason::New, Some(3072)));
+        reporter.record(download(
+            "pool/b.deb",
+            DownloadReason::ForcedByExtension,
+            Some(1024),
+        ));
+        reporter.record(download("dists/Release", DownloadReason::New, Some(100)));
+        reporter.record(download("README", DownloadReason::New, Some(10)));
+        reporter.record(download("dists/InRelease", DownloadReason::New, None));
+        let bandwidth = reporter.bandwidth();
+        assert_eq!(bandwidth.by_top_dir["pool"], 4096);
+        assert_eq!(bandwidth.by_top_dir["."], 10);
+        assert_eq!(bandwidth.by_origin["extension"], 1024);
+        assert_eq!(bandwidth.by_origin["listing"], 3182);
+        assert_eq!(bandwidth.summary(2), "pool: 4 KiB, dists: 100 B");
+    }
+}
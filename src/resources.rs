@@ -0,0 +1,228 @@
+// Resource usage summary of a sync run, to help right-sizing containers/VMs and spotting memory regressions.
+// Peak RSS and CPU time come from getrusage(2) and are of the whole process
+// (in batch mode, peak RSS covers all profiles so far, and CPU time includes profiles running at the same time).
+// Open fds are sampled from /proc/self/fd (Linux only).
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+const FD_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct CpuTime {
+    user: f64,
+    system: f64,
+}
+
+fn rusage() -> Option<(CpuTime, u64)> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+    let seconds = |t: libc::timeval| t.tv_sec as f64 + t.tv_usec as f64 / 1e6;
+    // ru_maxrss is in KiB on Linux, but in bytes on macOS
+    let maxrss = if cfg!(target_os = "macos") {
+        usage.ru_maxrss as u64
+    } else {
+        usage.ru_maxrss as u64 * 1024
+    };
+    Some((
+        CpuTime {
+            user: seconds(usage.ru_utime),
+            system: seconds(usage.ru_stime),
+        },
+        maxrss,
+    ))
+}
+
+/// Number of open fds of this process, or None if unsupported
+pub fn open_fds() -> Option<usize> {
+    // The directory being read takes one fd itself
+    Some(
+        std::fs::read_dir("/proc/self/fd")
+            .ok()?
+            .count()
+            .saturating_sub(1),
+    )
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    pub peak_rss_bytes: Option<u64>,
+    pub cpu_user_seconds: Option<f64>,
+    pub cpu_system_seconds: Option<f64>,
+    pub open_fds_peak: Option<usize>,
+    /// Peak total size of temporary files being downloaded at the same time
+    pub tmp_space_peak_bytes: u64,
+}
+
+impl fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let or_unknown = |v: Option<String>| v.unwrap_or_else(|| "unknown".to_string());
+        write!(
+            f,
+            "peak RSS: {}, CPU time: {} user + {} system, open fds peak: {}, tmp space peak: {}",
+            or_unknown(
+                self.peak_rss_bytes
+                    .map(|v| humansize::format_size(v, humansize::BINARY))
+            ),
+            or_unknown(self.cpu_user_seconds.map(|v| format!("{v:.2}s"))),
+            or_unknown(self.cpu_system_seconds.map(|v| format!("{v:.2}s"))),
+            or_unknown(self.open_fds_peak.map(|v| v.to_string())),
+            humansize::format_size(self.tmp_space_peak_bytes, humansize::BINARY),
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    fds_peak: AtomicUsize,
+    fds_supported: AtomicBool,
+    stop: AtomicBool,
+}
+
+impl Shared {
+    fn sample_fds(&self) {
+        if let Some(fds) = open_fds() {
+            self.fds_supported.store(true, Ordering::SeqCst);
+            self.fds_peak.fetch_max(fds, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Tracks resource usage from its creation to finish().
+#[derive(Debug)]
+pub struct ResourceMonitor {
+    cpu_start: Option<CpuTime>,
+    shared: Arc<Shared>,
+    sampler: Mutex<Option<JoinHandle<()>>>,
+    tmp_current: AtomicU64,
+    tmp_peak: AtomicU64,
+}
+
+impl ResourceMonitor {
+    pub fn start() -> Self {
+        let shared = Arc::new(Shared::default());
+        shared.sample_fds();
+        let sampler = if shared.fds_supported.load(Ordering::SeqCst) {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name("fd-sampler".to_string())
+                .spawn(move || {
+                    while !shared.stop.load(Ordering::SeqCst) {
+                        shared.sample_fds();
+                        std::thread::park_timeout(FD_SAMPLE_INTERVAL);
+                    }
+                })
+                .ok()
+        } else {
+            None
+        };
+        Self {
+            cpu_start: rusage().map(|(cpu, _)| cpu),
+            shared,
+            sampler: Mutex::new(sampler),
+            tmp_current: AtomicU64::new(0),
+            tmp_peak: AtomicU64::new(0),
+        }
+    }
+
+    /// Account a temporary file being written, until the returned guard is dropped
+    pub fn tmp_file(&self) -> TmpFile<'_> {
+        TmpFile {
+            monitor: self,
+            size: AtomicU64::new(0),
+        }
+    }
+
+    /// Stop sampling and summarize
+    pub fn finish(&self) -> ResourceUsage {
+        self.shared.stop.store(true, Ordering::SeqCst);
+        if let Some(sampler) = self.sampler.lock().unwrap().take() {
+            sampler.thread().unpark();
+            let _ = sampler.join();
+        }
+        self.shared.sample_fds();
+        let usage = rusage();
+        let cpu = match (usage, self.cpu_start) {
+            (Some((end, _)), Some(start)) => Some(CpuTime {
+                user: end.user - start.user,
+                system: end.system - start.system,
+            }),
+            _ => None,
+        };
+        ResourceUsage {
+            peak_rss_bytes: usage.map(|(_, maxrss)| maxrss),
+            cpu_user_seconds: cpu.map(|c| c.user),
+            cpu_system_seconds: cpu.map(|c| c.system),
+            open_fds_peak: self
+                .shared
+                .fds_supported
+                .load(Ordering::SeqCst)
+                .then(|| self.shared.fds_peak.load(Ordering::SeqCst)),
+            tmp_space_peak_bytes: self.tmp_peak.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// A temporary file accounted in ResourceMonitor. Its space is released on drop (when renamed or removed).
+#[derive(Debug)]
+pub struct TmpFile<'a> {
+    monitor: &'a ResourceMonitor,
+    size: AtomicU64,
+}
+
+impl TmpFile<'_> {
+    pub fn grow(&self, bytes: u64) {
+        if self.size.fetch_add(bytes, Ordering::SeqCst) == 0 {
+            // Short runs could finish between samples, so also sample when the file and its connection are open
+            self.monitor.shared.sample_fds();
+        }
+        let current = self.monitor.tmp_current.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        self.monitor.tmp_peak.fetch_max(current, Ordering::SeqCst);
+    }
+}
+
+impl Drop for TmpFile<'_> {
+    fn drop(&mut self) {
+        self.monitor
+            .tmp_current
+            .fetch_sub(self.size.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resource_monitor() {
+        let monitor = ResourceMonitor::start();
+        {
+            let a = monitor.tmp_file();
+            a.grow(100);
+            let b = monitor.tmp_file();
+            b.grow(50);
+            a.grow(10);
+        }
+        let c = monitor.tmp_file();
+        c.grow(20);
+        drop(c);
+        let usage = monitor.finish();
+        assert_eq!(usage.tmp_space_peak_bytes, 160);
+        assert_eq!(monitor.tmp_current.load(Ordering::SeqCst), 0);
+        if cfg!(target_os = "linux") {
+            assert!(usage.peak_rss_bytes.unwrap() > 0);
+            assert!(usage.open_fds_peak.unwrap() > 0);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+// Rewrites from remote relative paths of files to local ones, for mirrors restructuring upstream trees
+// (like 'releases/download/([^/]+)/(.+)=$1/$2'). The first matching rule applies. Rewritten paths are
+// what gets downloaded and kept in remote list, so that cleanup follows the local layout, while
+// exclusions, reports and logs still use remote paths.
+
+use std::{
+    borrow::Cow,
+    path::{Component, Path},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Result};
+use regex::Regex;
+
+#[derive(Debug, Clone)]
+pub struct PathRewrite {
+    regex: Regex,
+    replacement: String,
+}
+
+impl FromStr for PathRewrite {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (regex, replacement) = s.rsplit_once('=').ok_or_else(|| {
+            anyhow!("Expected <regex>=<replacement>, like releases/download/([^/]+)/(.+)=$1/$2")
+        })?;
+        Ok(Self {
+            regex: Regex::new(regex)?,
+            replacement: replacement.to_string(),
+        })
+    }
+}
+
+/// Local relative path of remote relative path, by the first matching rule
+pub fn rewrite<'a>(rewrites: &[PathRewrite], relative: &'a str) -> Result<Cow<'a, str>> {
+    let Some(rule) = rewrites.iter().find(|r| r.regex.is_match(relative)) else {
+        return Ok(Cow::Borrowed(relative));
+    };
+    let res = rule.regex.replace(relative, rule.replacement.as_str());
+    // Rewritten paths must stay under local directory
+    let valid = !res.is_empty()
+        && !res.ends_with('/')
+        && Path::new(res.as_ref())
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+    if !valid {
+        return Err(anyhow!(
+            "{:?} is rewritten to invalid path {:?} by {}",
+            relative,
+            res,
+            rule.regex
+        ));
+    }
+    Ok(Cow::Owned(res.into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite() {
+        let rewrites: Vec<PathRewrite> = [
+            r"^releases/download/([^/]+)/(.+)$=$1/$2",
+            r"/by-hash/SHA256/([0-9a-f]+)$=/by-hash/$1",
+            r"^escape/(.+)$=../$1",
+        ]
+        .iter()
+        .map(|r| r.parse().unwrap())
+        .collect();
+        assert_eq!(
+            rewrite(&rewrites, "releases/download/v1.2.3/x.tar.gz").unwrap(),
+            "v1.2.3/x.tar.gz"
+        );
+        assert_eq!(
+            rewrite(&rewrites, "dists/main/by-hash/SHA256/abcd").unwrap(),
+            "dists/main/by-hash/abcd"
+        );
+        assert!(matches!(
+            rewrite(&rewrites, "releases/latest"),
+            Ok(Cow::Borrowed("releases/latest"))
+        ));
+        assert!(rewrite(&rewrites, "escape/a").is_err());
+        assert!("no-replacement".parse::<PathRewrite>().is_err());
+    }
+}
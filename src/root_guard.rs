@@ -0,0 +1,145 @@
+// Guard rails for running as root, where a wrong local directory (or a bug in cleanup) could wipe the
+// system. Commands writing local files refuse to run as root unless --allow-root is given, or privileges
+// are dropped to --run-as user first. Local directories which are (or contain) system directories are
+// refused at start, and checked again (with symlinks resolved) before anything is deleted.
+
+use std::{
+    ffi::CString,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use tracing::info;
+
+/// Directories never to be synced into or deleted under as a whole
+const SYSTEM_DIRS: &[&str] = &[
+    "/bin",
+    "/boot",
+    "/dev",
+    "/etc",
+    "/home",
+    "/lib",
+    "/lib32",
+    "/lib64",
+    "/opt",
+    "/proc",
+    "/root",
+    "/run",
+    "/sbin",
+    "/srv",
+    "/sys",
+    "/tmp",
+    "/usr",
+    "/usr/local",
+    "/var",
+    "/var/lib",
+    "/var/log",
+];
+
+/// Refuse to run as root (by euid) without --allow-root, or drop privileges to run_as user
+pub fn check_user(euid: u32, allow_root: bool, run_as: Option<&str>) -> Result<()> {
+    match (euid, run_as) {
+        (0, Some(user)) => drop_privileges(user),
+        (0, None) if allow_root => Ok(()),
+        (0, None) => Err(anyhow!(
+            "Refusing to run as root, as deleting files not in remote could be destructive. Use --run-as <user> to drop privileges, or --allow-root if you are sure"
+        )),
+        (_, Some(_)) => Err(anyhow!("--run-as requires running as root")),
+        (_, None) => Ok(()),
+    }
+}
+
+fn drop_privileges(user: &str) -> Result<()> {
+    let name = CString::new(user)?;
+    // Called before any thread is spawned, so getpwnam() is fine
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(anyhow!("User {:?} not found", user));
+    }
+    let (uid, gid) = unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) };
+    if uid == 0 {
+        return Err(anyhow!("--run-as user {:?} is root", user));
+    }
+    // Supplementary groups first, as they could not be changed after setuid()
+    let res = unsafe {
+        libc::initgroups(name.as_ptr(), gid) == 0
+            && libc::setgid(gid) == 0
+            && libc::setuid(uid) == 0
+    };
+    if !res {
+        return Err(anyhow!(
+            "Failed to drop privileges to {:?}: {}",
+            user,
+            std::io::Error::last_os_error()
+        ));
+    }
+    info!("Dropped privileges to {} (uid {}, gid {})", user, uid, gid);
+    Ok(())
+}
+
+/// Refuse local directories which are "/", system directories or their parents (like "/usr").
+/// Symlinks are resolved if local directory exists.
+pub fn check_local(local: &Path) -> Result<()> {
+    let path = match local.canonicalize() {
+        Ok(path) => path,
+        Err(_) => std::path::absolute(local)?,
+    };
+    // Lexically, as the path might not exist yet
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::CurDir => (),
+            component => normalized.push(component),
+        }
+    }
+    let path = normalized;
+    if let Some(dir) = SYSTEM_DIRS
+        .iter()
+        .map(Path::new)
+        .find(|dir| dir.starts_with(&path))
+    {
+        return Err(anyhow!(
+            "Refusing to use {:?} as local directory, as it is (or contains) system directory {:?}",
+            local,
+            dir
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_root_guard() {
+        assert!(check_user(0, false, None).is_err());
+        assert!(check_user(0, true, None).is_ok());
+        assert!(check_user(1000, false, None).is_ok());
+        assert!(check_user(1000, false, Some("nobody")).is_err());
+        assert!(check_user(0, false, Some("root")).is_err());
+        assert!(check_user(0, false, Some("no-such-user-of-tsumugu")).is_err());
+
+        for local in [
+            "/",
+            "/usr",
+            "/usr/",
+            "/var/lib",
+            "/usr/../etc",
+            "/no-such-dir/..",
+        ] {
+            assert!(check_local(Path::new(local)).is_err(), "{local}");
+        }
+        for local in ["/srv/mirror", "/var/www/mirror", "/usr/share/mirror"] {
+            assert!(check_local(Path::new(local)).is_ok(), "{local}");
+        }
+        let dir = tempfile::tempdir().unwrap();
+        assert!(check_local(dir.path()).is_ok());
+        // Symlink to a system directory
+        std::os::unix::fs::symlink("/etc", dir.path().join("etc")).unwrap();
+        assert!(check_local(&dir.path().join("etc")).is_err());
+    }
+}
@@ -0,0 +1,100 @@
+// Scopes of sync runs, for state kept in globals as it is used deep inside parsers and utils (listing
+// cache, bearer tokens, clock skew and observations of upstreams). Profiles run in parallel by batch
+// each get their own scope, entered by every thread of the run, so that they never see state of each
+// other, and the state is forgotten when the run ends (instead of piling up in daemon mode).
+
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+static NEXT: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static CURRENT: Cell<ScopeId> = const { Cell::new(ScopeId(0)) };
+}
+
+/// Id of a scope. Threads not in any run (like of list command) are in scope 0.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ScopeId(u64);
+
+impl ScopeId {
+    pub fn current() -> Self {
+        CURRENT.with(|c| c.get())
+    }
+
+    /// Enter scope in this thread, like a worker thread of the run
+    pub fn enter(self) {
+        CURRENT.with(|c| c.set(self));
+    }
+}
+
+/// Scope of a run, entered by this thread, and ended (with its state forgotten) when dropped
+#[derive(Debug)]
+pub struct RunScope {
+    id: ScopeId,
+    outer: ScopeId,
+}
+
+impl RunScope {
+    pub fn begin() -> Self {
+        let id = ScopeId(NEXT.fetch_add(1, Ordering::SeqCst));
+        let outer = ScopeId::current();
+        id.enter();
+        Self { id, outer }
+    }
+}
+
+impl Drop for RunScope {
+    fn drop(&mut self) {
+        crate::listing_cache::forget(self.id);
+        crate::utils::forget_bearer_tokens(self.id);
+        crate::clock_skew::forget(self.id);
+        crate::host_state::forget(self.id);
+        self.outer.enter();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::*;
+
+    #[test]
+    fn test_run_scope() {
+        let url = Url::parse("https://gitlab.example.com/api/v4/").unwrap();
+        let outer = ScopeId::current();
+        let scope = RunScope::begin();
+        let id = ScopeId::current();
+        assert_ne!(id, outer);
+        crate::utils::set_bearer_token(&url, "a".to_string());
+        assert_eq!(crate::utils::bearer_token(&url).as_deref(), Some("a"));
+        // Another profile in parallel
+        std::thread::spawn({
+            let url = url.clone();
+            move || {
+                let _scope = RunScope::begin();
+                assert_eq!(crate::utils::bearer_token(&url), None);
+                crate::utils::set_bearer_token(&url, "b".to_string());
+            }
+        })
+        .join()
+        .unwrap();
+        // Worker threads of the run
+        std::thread::spawn({
+            let url = url.clone();
+            move || {
+                id.enter();
+                assert_eq!(crate::utils::bearer_token(&url).as_deref(), Some("a"));
+            }
+        })
+        .join()
+        .unwrap();
+        drop(scope);
+        assert_eq!(ScopeId::current(), outer);
+        id.enter();
+        assert_eq!(crate::utils::bearer_token(&url), None);
+        outer.enter();
+    }
+}
@@ -0,0 +1,215 @@
+// Provenance of downloaded files (with --sidecar): source URL, retrieval time, HTTP validators and digest,
+// kept in a sidecar file next to each file (`<name>.tsumugu.meta`) or in an extended attribute of it,
+// for provenance audits. Sidecars of files kept in local directory are never deleted by cleanup.
+
+use std::{
+    collections::HashSet,
+    ffi::CStr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+pub const SUFFIX: &str = ".tsumugu.meta";
+const XATTR_NAME: &CStr = c"user.tsumugu.meta";
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum SidecarMode {
+    /// `<name>.tsumugu.meta` next to the file
+    File,
+    /// `user.tsumugu.meta` extended attribute of the file (Linux only)
+    Xattr,
+}
+
+/// Version of provenance format, bumped when a field is renamed, removed or changes its meaning
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Provenance {
+    pub schema_version: u32,
+    /// Where the file is actually got from (like a --mirror)
+    pub url: String,
+    pub retrieved_at: DateTime<Utc>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub size: u64,
+    /// Lowercase hex
+    pub sha256: String,
+}
+
+/// Validators (ETag and Last-Modified) in response headers
+pub fn validators(headers: &reqwest::header::HeaderMap) -> (Option<String>, Option<String>) {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+    (
+        header(reqwest::header::ETAG),
+        header(reqwest::header::LAST_MODIFIED),
+    )
+}
+
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(SUFFIX);
+    path.with_file_name(name)
+}
+
+/// If path is the sidecar of a file in remote list, which is to be kept
+pub fn is_sidecar_of_kept(path: &Path, remote_list: &HashSet<PathBuf>) -> bool {
+    let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+        return false;
+    };
+    name.strip_suffix(SUFFIX)
+        .filter(|n| !n.is_empty())
+        .is_some_and(|n| remote_list.contains(&path.with_file_name(n)))
+}
+
+pub fn write(mode: SidecarMode, path: &Path, provenance: &Provenance) -> Result<()> {
+    let content = serde_json::to_vec(provenance)?;
+    match mode {
+        SidecarMode::File => {
+            let sidecar = sidecar_path(path);
+            let tmp = sidecar.with_file_name(format!(
+                ".tmp.{}",
+                sidecar.file_name().unwrap_or_default().to_string_lossy()
+            ));
+            std::fs::write(&tmp, content)?;
+            std::fs::rename(&tmp, &sidecar)?;
+            Ok(())
+        }
+        SidecarMode::Xattr => set_xattr(path, XATTR_NAME, &content),
+    }
+    .with_context(|| format!("Failed to write provenance of {:?}", path))
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn read(mode: SidecarMode, path: &Path) -> Result<Provenance> {
+    let content = match mode {
+        SidecarMode::File => std::fs::read(sidecar_path(path))?,
+        SidecarMode::Xattr => get_xattr(path, XATTR_NAME)?,
+    };
+    Ok(serde_json::from_slice(&content)?)
+}
+
+#[cfg(target_os = "linux")]
+fn c_path(path: &Path) -> Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(std::ffi::CString::new(path.as_os_str().as_bytes())?)
+}
+
+#[cfg(target_os = "linux")]
+pub fn set_xattr(path: &Path, name: &CStr, value: &[u8]) -> Result<()> {
+    let path = c_path(path)?;
+    let ret = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub fn get_xattr(path: &Path, name: &CStr) -> Result<Vec<u8>> {
+    let path = c_path(path)?;
+    let size = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+    if size < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let mut value = vec![0u8; size as usize];
+    let size = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr() as *mut libc::c_void,
+            value.len(),
+        )
+    };
+    if size < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    value.truncate(size as usize);
+    Ok(value)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_xattr(_path: &Path, _name: &CStr, _value: &[u8]) -> Result<()> {
+    Err(anyhow!("Extended attributes are only supported on Linux"))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get_xattr(_path: &Path, _name: &CStr) -> Result<Vec<u8>> {
+    Err(anyhow!("Extended attributes are only supported on Linux"))
+}
+
+/// If filesystem of dir supports user extended attributes
+pub fn probe_xattr(dir: &Path) -> Result<()> {
+    let probe = dir.join(".tmp.tsumugu-xattr-probe");
+    std::fs::write(&probe, "")?;
+    let res = set_xattr(&probe, XATTR_NAME, b"{}");
+    let _ = std::fs::remove_file(&probe);
+    res.map_err(|e| anyhow!("{:?} does not support extended attributes: {}", dir, e))
+}
+
+/// --sidecar xattr needs a filesystem with user extended attributes
+pub fn check_mode(mode: SidecarMode, dir: &Path) -> Result<()> {
+    match mode {
+        SidecarMode::File => Ok(()),
+        SidecarMode::Xattr => probe_xattr(dir),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.deb");
+        std::fs::write(&path, "a").unwrap();
+        let provenance = Provenance {
+            schema_version: SCHEMA_VERSION,
+            url: "https://example.com/a.deb".to_string(),
+            retrieved_at: Utc::now(),
+            etag: Some("\"abc\"".to_string()),
+            last_modified: None,
+            size: 1,
+            sha256: "ca97".to_string(),
+        };
+        write(SidecarMode::File, &path, &provenance).unwrap();
+        assert_eq!(sidecar_path(&path), dir.path().join("a.deb.tsumugu.meta"));
+        assert_eq!(read(SidecarMode::File, &path).unwrap(), provenance);
+        // No temporary file left
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        let remote_list = HashSet::from([path.clone()]);
+        assert!(is_sidecar_of_kept(&sidecar_path(&path), &remote_list));
+        assert!(!is_sidecar_of_kept(
+            &dir.path().join("b.deb.tsumugu.meta"),
+            &remote_list
+        ));
+        assert!(!is_sidecar_of_kept(
+            &dir.path().join(SUFFIX),
+            &HashSet::from([dir.path().join("")])
+        ));
+
+        // Filesystems without user xattrs (like some tmpfs) are refused up front
+        if check_mode(SidecarMode::Xattr, dir.path()).is_ok() {
+            write(SidecarMode::Xattr, &path, &provenance).unwrap();
+            assert_eq!(read(SidecarMode::Xattr, &path).unwrap(), provenance);
+        }
+    }
+}
@@ -0,0 +1,82 @@
+// Time-travel mirroring against snapshot archives (like snapshot.debian.org and snapshot.ubuntu.com),
+// which serve the archive as it was at a time under `<base>/<YYYYMMDDTHHMMSSZ>/`.
+// The snapshot service redirects a time to the nearest snapshot before it, so the URL is resolved once
+// before sync, and all paths of this run are under the same (pinned) snapshot.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use reqwest::blocking::Client;
+use url::Url;
+
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Parse time like 2024-03-01T00:00:00Z, 20240301T000000Z or 2024-03-01 (at 00:00 UTC)
+pub fn parse_time(s: &str) -> Result<DateTime<Utc>> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.into());
+    }
+    if let Ok(t) = NaiveDateTime::parse_from_str(s, TIMESTAMP_FORMAT) {
+        return Ok(t.and_utc());
+    }
+    if let Ok(d) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(d.and_hms_opt(0, 0, 0).unwrap().and_utc());
+    }
+    Err(anyhow!(
+        "Invalid time {:?}, expected like 2024-03-01T00:00:00Z, 20240301T000000Z or 2024-03-01",
+        s
+    ))
+}
+
+/// URL of archive base at time
+pub fn snapshot_url(base: &Url, time: DateTime<Utc>) -> Result<Url> {
+    let mut url = base.clone();
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("{} cannot be a base", base))?
+        .pop_if_empty()
+        .extend([&time.format(TIMESTAMP_FORMAT).to_string(), ""]);
+    Ok(url)
+}
+
+/// Follow redirection of snapshot service to find the snapshot actually served
+pub fn resolve(client: &Client, base: &Url, time: DateTime<Utc>) -> Result<Url> {
+    let url = snapshot_url(base, time)?;
+    let mut resolved = crate::utils::get(client, url)?.url().clone();
+    if !resolved.path().ends_with('/') {
+        resolved.set_path(&format!("{}/", resolved.path()));
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_url() {
+        let time = parse_time("2024-03-01T08:30:00+08:00").unwrap();
+        assert_eq!(time, parse_time("20240301T003000Z").unwrap());
+        assert_eq!(
+            parse_time("2024-03-01").unwrap(),
+            parse_time("20240301T000000Z").unwrap()
+        );
+        assert!(parse_time("yesterday").is_err());
+        assert_eq!(
+            snapshot_url(
+                &Url::parse("https://snapshot.debian.org/archive/debian/").unwrap(),
+                time
+            )
+            .unwrap()
+            .as_str(),
+            "https://snapshot.debian.org/archive/debian/20240301T003000Z/"
+        );
+        assert_eq!(
+            snapshot_url(
+                &Url::parse("https://snapshot.ubuntu.com/ubuntu").unwrap(),
+                time
+            )
+            .unwrap()
+            .as_str(),
+            "https://snapshot.ubuntu.com/ubuntu/20240301T003000Z/"
+        );
+    }
+}
@@ -0,0 +1,127 @@
+// Re-download storm detection: when too many existing files are re-downloaded due to mtime mismatch,
+// it is more likely that timezone guessing fails or upstream re-stamps files, than files are really changed.
+// Once detected, further re-downloads for mtime mismatch are paused (kept as is) for the rest of the run,
+// or the whole sync is aborted with --strict-storm.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use tracing::{error, warn};
+
+use crate::compare::DownloadReason;
+
+/// Don't judge until we have seen enough existing files
+const MIN_SAMPLES: usize = 100;
+
+#[derive(Debug)]
+pub struct StormDetector {
+    /// Percentage (0-100) of existing files with mtime mismatch to trigger detection
+    threshold: f64,
+    /// Abort instead of pausing
+    strict: bool,
+    present: AtomicUsize,
+    mtime_mismatch: AtomicUsize,
+    detected: AtomicBool,
+    /// Re-downloads not done as paused
+    paused: AtomicUsize,
+}
+
+impl StormDetector {
+    pub fn new(threshold: f64, strict: bool) -> Self {
+        Self {
+            threshold,
+            strict,
+            present: AtomicUsize::new(0),
+            mtime_mismatch: AtomicUsize::new(0),
+            detected: AtomicBool::new(false),
+            paused: AtomicUsize::new(0),
+        }
+    }
+
+    /// Observe comparison result of a file (None for up-to-date).
+    /// Returns false if its re-download is paused, as a storm has been detected.
+    pub fn observe(&self, reason: Option<DownloadReason>) -> bool {
+        match reason {
+            Some(DownloadReason::New) | Some(DownloadReason::ForcedByExtension) => return true,
+            Some(DownloadReason::MtimeMismatch) => {
+                self.mtime_mismatch.fetch_add(1, Ordering::SeqCst);
+            }
+            _ => {}
+        }
+        let present = self.present.fetch_add(1, Ordering::SeqCst) + 1;
+        if present >= MIN_SAMPLES && !self.detected.load(Ordering::SeqCst) {
+            self.judge(present);
+        }
+        if reason == Some(DownloadReason::MtimeMismatch)
+            && !self.strict
+            && self.detected.load(Ordering::SeqCst)
+        {
+            self.paused.fetch_add(1, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    fn judge(&self, present: usize) {
+        let mismatch = self.mtime_mismatch.load(Ordering::SeqCst);
+        let percentage = mismatch as f64 * 100.0 / present as f64;
+        if percentage > self.threshold && !self.detected.swap(true, Ordering::SeqCst) {
+            let msg = format!(
+                "Re-download storm detected: {}/{} ({:.1}%) existing files have mismatched mtime. Timezone guessing may fail, or upstream has re-stamped files. Consider setting --timezone, or --touch-up if local mtimes are wrong (like seeded by another tool).",
+                mismatch, present, percentage
+            );
+            if self.strict {
+                error!("{msg} Aborting as --strict-storm is set.");
+            } else {
+                warn!("{msg} Pausing re-downloads for mtime mismatch in this run.");
+            }
+        }
+    }
+
+    /// Number of re-downloads paused, to be warned at the end
+    pub fn paused(&self) -> usize {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// If the sync should be aborted now
+    pub fn should_abort(&self) -> bool {
+        self.strict && self.detected.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_storm() {
+        let detector = StormDetector::new(50.0, true);
+        for _ in 0..60 {
+            detector.observe(Some(DownloadReason::New));
+            detector.observe(None);
+        }
+        assert!(!detector.should_abort());
+        for _ in 0..39 {
+            detector.observe(Some(DownloadReason::MtimeMismatch));
+        }
+        // 39/99, and samples are not enough
+        assert!(!detector.should_abort());
+        for _ in 0..30 {
+            detector.observe(Some(DownloadReason::MtimeMismatch));
+        }
+        // 69/129
+        assert!(detector.should_abort());
+
+        let detector = StormDetector::new(50.0, false);
+        for _ in 0..99 {
+            assert!(detector.observe(Some(DownloadReason::MtimeMismatch)));
+        }
+        // Detected at the 100th, which is paused with all later ones
+        for _ in 0..101 {
+            assert!(!detector.observe(Some(DownloadReason::MtimeMismatch)));
+        }
+        assert!(detector.observe(Some(DownloadReason::SizeMismatch)));
+        assert!(detector.observe(Some(DownloadReason::New)));
+        assert!(!detector.should_abort());
+        assert_eq!(detector.paused(), 101);
+    }
+}
@@ -0,0 +1,199 @@
+// Counters of a sync run by phase (listing, checking, downloading and deleting), updated by handlers
+// in all threads and rendered consistently in the summary log, report and metrics.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Why a listed file is not downloaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SkipReason {
+    InvalidName,
+    /// By --exclude
+    Excluded,
+    /// Parent directory is list only
+    ListOnly,
+    /// Handled by another task (like found by both listing and extensions)
+    AlreadyHandled,
+    /// Payload skipped by --metadata-only
+    NotMetadata,
+    /// Metadata left to stage 2 of --debian-ftpsync
+    Deferred,
+    /// Remote mtime out of --modified-since and --modified-before
+    OutOfWindow,
+    /// Local file is up to date (or kept by --skip-if-exists)
+    UpToDate,
+    /// Not under the path being repaired
+    OutOfRepair,
+    /// Local file has the same content, and only its mtime is updated (--touch-up)
+    TouchedUp,
+    /// Re-download for mtime mismatch paused after a re-download storm is detected
+    StormPaused,
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            SkipReason::InvalidName => "invalid-name",
+            SkipReason::Excluded => "excluded",
+            SkipReason::ListOnly => "list-only",
+            SkipReason::AlreadyHandled => "already-handled",
+            SkipReason::NotMetadata => "not-metadata",
+            SkipReason::Deferred => "deferred",
+            SkipReason::OutOfWindow => "out-of-window",
+            SkipReason::UpToDate => "up-to-date",
+            SkipReason::OutOfRepair => "out-of-repair",
+            SkipReason::TouchedUp => "touched-up",
+            SkipReason::StormPaused => "storm-paused",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Counters at some point of a run
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatsSnapshot {
+    pub listed_dirs: u64,
+    pub listed_files: u64,
+    /// Estimated total size of listed files
+    pub listed_size: u64,
+    pub failed_listings: u64,
+    /// Files queued for downloading (by listing or extensions)
+    pub enqueued_downloads: u64,
+    pub skipped: BTreeMap<SkipReason, u64>,
+    /// Files downloaded (or would be downloaded in dry run)
+    pub downloaded: u64,
+    pub failed_downloads: u64,
+    /// Files and directories deleted (or would be deleted in dry run)
+    pub deleted: u64,
+}
+
+impl StatsSnapshot {
+    /// Like "listed 3 dirs, 12 files (1.2 MiB); 4 enqueued, 2 downloaded, 0 failed, 1 deleted; skipped: up-to-date 8"
+    pub fn summary(&self) -> String {
+        let mut res = format!(
+            "listed {} dirs, {} files ({})",
+            self.listed_dirs,
+            self.listed_files,
+            humansize::format_size(self.listed_size, humansize::BINARY)
+        );
+        if self.failed_listings > 0 {
+            res.push_str(&format!(", {} listings failed", self.failed_listings));
+        }
+        res.push_str(&format!(
+            "; {} enqueued, {} downloaded, {} failed, {} deleted",
+            self.enqueued_downloads, self.downloaded, self.failed_downloads, self.deleted
+        ));
+        if !self.skipped.is_empty() {
+            let skipped: Vec<String> = self
+                .skipped
+                .iter()
+                .map(|(reason, count)| format!("{} {}", reason, count))
+                .collect();
+            res.push_str(&format!("; skipped: {}", skipped.join(", ")));
+        }
+        res
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SyncStats {
+    listed_dirs: AtomicU64,
+    listed_files: AtomicU64,
+    listed_size: AtomicU64,
+    failed_listings: AtomicU64,
+    enqueued_downloads: AtomicU64,
+    skipped: Mutex<BTreeMap<SkipReason, u64>>,
+    downloaded: AtomicU64,
+    failed_downloads: AtomicU64,
+    deleted: AtomicU64,
+}
+
+impl SyncStats {
+    pub fn listed_dir(&self) {
+        self.listed_dirs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A file found in listing, with its estimated size
+    pub fn listed_file(&self, size: u64) {
+        self.listed_files.fetch_add(1, Ordering::Relaxed);
+        self.listed_size.fetch_add(size, Ordering::Relaxed);
+    }
+
+    pub fn failed_listing(&self) {
+        self.failed_listings.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn enqueued(&self, count: usize) {
+        self.enqueued_downloads
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn skipped(&self, reason: SkipReason) {
+        *self.skipped.lock().unwrap().entry(reason).or_default() += 1;
+    }
+
+    pub fn downloaded(&self) {
+        self.downloaded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn failed_download(&self) {
+        self.failed_downloads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn deleted(&self) {
+        self.deleted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            listed_dirs: self.listed_dirs.load(Ordering::Relaxed),
+            listed_files: self.listed_files.load(Ordering::Relaxed),
+            listed_size: self.listed_size.load(Ordering::Relaxed),
+            failed_listings: self.failed_listings.load(Ordering::Relaxed),
+            enqueued_downloads: self.enqueued_downloads.load(Ordering::Relaxed),
+            skipped: self.skipped.lock().unwrap().clone(),
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            failed_downloads: self.failed_downloads.load(Ordering::Relaxed),
+            deleted: self.deleted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_stats() {
+        let stats = SyncStats::default();
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    stats.listed_dir();
+                    stats.listed_file(1024);
+                    stats.skipped(SkipReason::UpToDate);
+                });
+            }
+        });
+        stats.enqueued(2);
+        stats.downloaded();
+        stats.failed_download();
+        stats.skipped(SkipReason::Excluded);
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.listed_dirs, 4);
+        assert_eq!(snapshot.listed_size, 4096);
+        assert_eq!(snapshot.skipped[&SkipReason::UpToDate], 4);
+        assert_eq!(
+            snapshot.summary(),
+            "listed 4 dirs, 4 files (4 KiB); 2 enqueued, 1 downloaded, 1 failed, 0 deleted; skipped: excluded 1, up-to-date 4"
+        );
+    }
+}
@@ -0,0 +1,40 @@
+// Trees of files for tests, like upstream directories served by the autoindex fixture, and local ones.
+
+use std::path::Path;
+
+/// 2023-11-14 22:13:20 UTC
+pub const MTIME: i64 = 1_700_000_000;
+
+/// Write files (and parent directories) with content and mtime
+pub fn write_tree(root: &Path, files: &[(&str, &str, i64)]) {
+    for (path, content, mtime) in files {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, content).unwrap();
+        filetime::set_file_mtime(&path, filetime::FileTime::from_unix_time(*mtime, 0)).unwrap();
+    }
+}
+
+/// Create files (and parent directories) with their paths as content, and fixed mtime
+pub fn create_tree(root: &Path, files: &[&str]) {
+    let files: Vec<_> = files.iter().map(|f| (*f, *f, MTIME)).collect();
+    write_tree(root, &files);
+}
+
+/// Relative paths under root, directories ending with "/"
+pub fn list_tree(root: &Path) -> Vec<String> {
+    let mut paths: Vec<String> = walkdir::WalkDir::new(root)
+        .min_depth(1)
+        .into_iter()
+        .map(|e| {
+            let e = e.unwrap();
+            let relative = e.path().strip_prefix(root).unwrap().to_string_lossy();
+            match e.file_type().is_dir() {
+                true => format!("{relative}/"),
+                false => relative.to_string(),
+            }
+        })
+        .collect();
+    paths.sort();
+    paths
+}
@@ -1,12 +1,21 @@
+use std::io::Read;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
 use anyhow::anyhow;
 use anyhow::Result;
 use chrono::FixedOffset;
 use chrono::TimeZone;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
 use futures_util::Future;
-use tracing::warn;
+use tracing::{debug, warn};
 use url::Url;
 
+use crate::error::ListError;
+use crate::run_scope::ScopeId;
+
 macro_rules! get_resp_mtime {
     ($resp: expr) => {
         Ok(DateTime::parse_from_rfc2822(
@@ -26,13 +35,83 @@ macro_rules! build_client {
         let mut builder = <$client>::builder()
             .user_agent($args.user_agent.clone())
             .local_address($bind_address.map(|x| x.parse::<std::net::IpAddr>().unwrap()));
-        if !$parser.is_auto_redirect() {
-            builder = builder.redirect(reqwest::redirect::Policy::none());
-        }
+        builder = builder.redirect(if $parser.is_auto_redirect() {
+            $crate::utils::redirect_policy($args.max_redirects, $args.allow_https_downgrade)
+        } else {
+            reqwest::redirect::Policy::none()
+        });
         builder.build().unwrap()
     }};
 }
 
+/// Why a redirection is not followed
+#[derive(Debug)]
+pub enum RedirectError {
+    /// Redirected back to a URL visited before
+    Loop(Vec<Url>),
+    TooManyHops(Vec<Url>),
+    /// From https to http
+    Downgrade(Vec<Url>),
+}
+
+impl std::fmt::Display for RedirectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (reason, chain) = match self {
+            RedirectError::Loop(chain) => ("redirect loop", chain),
+            RedirectError::TooManyHops(chain) => ("too many redirects", chain),
+            RedirectError::Downgrade(chain) => (
+                "redirected from https to http (use --allow-https-downgrade to follow)",
+                chain,
+            ),
+        };
+        let chain: Vec<&str> = chain.iter().map(|u| u.as_str()).collect();
+        write!(f, "{}: {}", reason, chain.join(" -> "))
+    }
+}
+
+impl std::error::Error for RedirectError {}
+
+/// Check redirection to next from previous URLs (the first one is the original request)
+fn check_redirect(
+    previous: &[Url],
+    next: &Url,
+    max_hops: usize,
+    allow_https_downgrade: bool,
+) -> Result<(), RedirectError> {
+    let chain = || previous.iter().chain([next]).cloned().collect();
+    if previous.contains(next) {
+        return Err(RedirectError::Loop(chain()));
+    }
+    if previous.len() > max_hops {
+        return Err(RedirectError::TooManyHops(chain()));
+    }
+    let downgraded = previous
+        .last()
+        .is_some_and(|last| last.scheme() == "https" && next.scheme() == "http");
+    if downgraded && !allow_https_downgrade {
+        return Err(RedirectError::Downgrade(chain()));
+    }
+    Ok(())
+}
+
+/// Follow redirections up to max_hops, and surface loops and https downgrades as errors
+pub fn redirect_policy(max_hops: usize, allow_https_downgrade: bool) -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(move |attempt| {
+        match check_redirect(
+            attempt.previous(),
+            attempt.url(),
+            max_hops,
+            allow_https_downgrade,
+        ) {
+            Ok(()) => attempt.follow(),
+            Err(e) => {
+                warn!("Not following redirection: {}", e);
+                attempt.error(e)
+            }
+        }
+    })
+}
+
 pub fn get_async_response_mtime(resp: &reqwest::Response) -> Result<DateTime<Utc>> {
     get_resp_mtime!(resp)
 }
@@ -41,15 +120,125 @@ pub fn get_blocking_response_mtime(resp: &reqwest::blocking::Response) -> Result
     get_resp_mtime!(resp)
 }
 
-pub fn again<T>(closure: impl Fn() -> Result<T>, retry: usize) -> Result<T> {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RetryKind {
+    Listing,
+    Download,
+}
+
+/// Retries shared across the whole run, to avoid multiplying requests to a flaky upstream.
+/// Listings stop retrying first (when half of budget is used), then downloads.
+/// DNS failures are retried on their own (with backoff), using neither retry count of request nor budget.
+#[derive(Debug, Default)]
+pub struct RetryBudget {
+    /// None for unlimited
+    limit: Option<usize>,
+    used: AtomicUsize,
+    listing_exhausted: AtomicBool,
+    download_exhausted: AtomicBool,
+    dns_retries: usize,
+    /// Delay before the first DNS retry, doubled for each next one
+    dns_backoff: Duration,
+}
+
+impl RetryBudget {
+    /// Budget of limit retries (None for unlimited), and DNS failures retried dns_retries times
+    pub fn new(limit: Option<usize>, dns_retries: usize) -> Self {
+        Self {
+            limit,
+            dns_retries,
+            dns_backoff: Duration::from_secs(1),
+            ..Default::default()
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_dns_backoff(mut self, backoff: Duration) -> Self {
+        self.dns_backoff = backoff;
+        self
+    }
+
+    /// Delay before DNS retry (starting from 0), or None if no more DNS retries
+    fn dns_delay(&self, attempt: usize) -> Option<Duration> {
+        (attempt < self.dns_retries).then(|| self.dns_backoff * 2u32.saturating_pow(attempt as u32))
+    }
+
+    /// Take one retry from budget. Returns false if budget is exhausted for this kind.
+    pub fn take(&self, kind: RetryKind) -> bool {
+        let limit = match (self.limit, kind) {
+            (None, _) => return true,
+            (Some(limit), RetryKind::Listing) => limit / 2,
+            (Some(limit), RetryKind::Download) => limit,
+        };
+        if self
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                (used < limit).then_some(used + 1)
+            })
+            .is_ok()
+        {
+            return true;
+        }
+        let exhausted = match kind {
+            RetryKind::Listing => &self.listing_exhausted,
+            RetryKind::Download => &self.download_exhausted,
+        };
+        if !exhausted.swap(true, Ordering::SeqCst) {
+            warn!(
+                "Retry budget exhausted ({} used), no more retries for {:?}",
+                self.used.load(Ordering::SeqCst),
+                kind
+            );
+        }
+        false
+    }
+
+    /// If any retry has been refused
+    pub fn is_exhausted(&self) -> bool {
+        self.listing_exhausted.load(Ordering::SeqCst)
+            || self.download_exhausted.load(Ordering::SeqCst)
+    }
+}
+
+/// If request failed to resolve host name (like a timeout or SERVFAIL of resolver), which is usually transient
+fn is_dns_error(e: &anyhow::Error) -> bool {
+    // hyper's ConnectError is private, but it is always described like "dns error: ..."
+    e.chain()
+        .any(|cause| cause.to_string().starts_with("dns error"))
+}
+
+pub fn again<T>(
+    closure: impl Fn() -> Result<T>,
+    retry: usize,
+    budget: &RetryBudget,
+    kind: RetryKind,
+) -> Result<T> {
     let mut count = 0;
+    let mut dns_count = 0;
     loop {
         match closure() {
             Ok(x) => return Ok(x),
             Err(e) => {
+                if let Some(delay) = is_dns_error(&e)
+                    .then(|| budget.dns_delay(dns_count))
+                    .flatten()
+                {
+                    dns_count += 1;
+                    warn!(
+                        "DNS error: {:?}, retrying {}/{} in {:?}",
+                        e, dns_count, budget.dns_retries, delay
+                    );
+                    std::thread::sleep(delay);
+                    continue;
+                }
+                crate::host_state::observe_error(&e);
+                if !crate::error::is_retryable(&e) {
+                    warn!("Error: {:?}, not retrying", e);
+                    return Err(e);
+                }
                 warn!("Error: {:?}, retrying {}/{}", e, count, retry);
                 count += 1;
-                if count > retry {
+                if count > retry || !budget.take(kind) {
                     return Err(e);
                 }
             }
@@ -57,18 +246,41 @@ pub fn again<T>(closure: impl Fn() -> Result<T>, retry: usize) -> Result<T> {
     }
 }
 
-pub async fn again_async<T, Fut, F: Fn() -> Fut>(f: F, retry: usize) -> Result<T>
+pub async fn again_async<T, Fut, F: Fn() -> Fut>(
+    f: F,
+    retry: usize,
+    budget: &RetryBudget,
+    kind: RetryKind,
+) -> Result<T>
 where
     Fut: Future<Output = Result<T>>,
 {
     let mut count = 0;
+    let mut dns_count = 0;
     loop {
         match f().await {
             Ok(x) => return Ok(x),
             Err(e) => {
+                if let Some(delay) = is_dns_error(&e)
+                    .then(|| budget.dns_delay(dns_count))
+                    .flatten()
+                {
+                    dns_count += 1;
+                    warn!(
+                        "DNS error: {:?}, retrying {}/{} in {:?}",
+                        e, dns_count, budget.dns_retries, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+                crate::host_state::observe_error(&e);
+                if !crate::error::is_retryable(&e) {
+                    warn!("Error: {:?}, not retrying", e);
+                    return Err(e);
+                }
                 warn!("Error: {:?}, retrying {}/{}", e, count, retry);
                 count += 1;
-                if count > retry {
+                if count > retry || !budget.take(kind) {
                     return Err(e);
                 }
             }
@@ -76,21 +288,268 @@ where
     }
 }
 
+/// Bearer tokens by origin (like GitLab API token), sent only with requests to that origin.
+/// reqwest drops the Authorization header when redirected to another host.
+/// Tokens are kept in scope of the run setting them (see run_scope.rs).
+static BEARER_TOKENS: RwLock<Vec<(ScopeId, url::Origin, String)>> = RwLock::new(Vec::new());
+
+pub fn set_bearer_token(url: &Url, token: String) {
+    let scope = ScopeId::current();
+    let origin = url.origin();
+    let mut tokens = BEARER_TOKENS.write().unwrap();
+    tokens.retain(|(s, o, _)| *s != scope || *o != origin);
+    tokens.push((scope, origin, token));
+}
+
+pub fn bearer_token(url: &Url) -> Option<String> {
+    let scope = ScopeId::current();
+    let origin = url.origin();
+    BEARER_TOKENS
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(s, o, _)| *s == scope && *o == origin)
+        .map(|(_, _, token)| token.clone())
+}
+
+pub fn forget_bearer_tokens(scope: ScopeId) {
+    BEARER_TOKENS
+        .write()
+        .unwrap()
+        .retain(|(s, _, _)| *s != scope);
+}
+
+macro_rules! with_token {
+    ($request: expr, $url: expr) => {
+        match bearer_token(&$url) {
+            Some(token) => $request.bearer_auth(token),
+            None => $request,
+        }
+    };
+}
+
 pub async fn get_async(client: &reqwest::Client, url: Url) -> Result<reqwest::Response> {
-    Ok(client.get(url).send().await?.error_for_status()?)
+    let resp = with_token!(client.get(url.clone()), url).send().await?;
+    crate::clock_skew::observe(resp.url(), resp.headers());
+    Ok(resp.error_for_status()?)
 }
 
 #[allow(dead_code)]
 pub async fn head_async(client: &reqwest::Client, url: Url) -> Result<reqwest::Response> {
-    Ok(client.head(url).send().await?.error_for_status()?)
+    Ok(with_token!(client.head(url.clone()), url)
+        .send()
+        .await?
+        .error_for_status()?)
 }
 
 pub fn get(client: &reqwest::blocking::Client, url: Url) -> Result<reqwest::blocking::Response> {
-    Ok(client.get(url).send()?.error_for_status()?)
+    let resp = with_token!(client.get(url.clone()), url).send()?;
+    crate::clock_skew::observe(resp.url(), resp.headers());
+    Ok(resp.error_for_status()?)
+}
+
+/// A fetched listing page
+#[derive(Debug, Clone)]
+pub struct ListingPage {
+    /// Final URL (after redirection)
+    pub url: Url,
+    pub status: u16,
+    pub content_type: Option<String>,
+    /// Location header, for parsers handling redirection themselves
+    pub location: Option<String>,
+    /// Link header, for paginated APIs
+    pub link: Option<String>,
+    pub body: String,
+    /// If the page looks truncated, set by get_page()
+    pub truncated: bool,
+    /// What tells the page is rendered by JavaScript (or made of frames), if any, set by get_page()
+    pub script_marker: Option<&'static str>,
+}
+
+/// Listing pages larger than this (after decompression) are refused, in case of gzip bombs or endless responses
+const MAX_PAGE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Read at most limit bytes, failing if there are more
+fn read_capped(reader: impl Read, limit: u64) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    reader.take(limit + 1).read_to_end(&mut body)?;
+    if body.len() as u64 > limit {
+        return Err(anyhow!(
+            "Page is larger than {} (after decompression), refused",
+            humansize::format_size(limit, humansize::BINARY)
+        ));
+    }
+    Ok(body)
+}
+
+/// Body of page, decompressed (even if not asked for, like by some middleboxes) and decoded by charset
+fn read_page_body(resp: reqwest::blocking::Response, content_type: Option<&str>) -> Result<String> {
+    let content_encoding = resp
+        .headers()
+        .get("Content-Encoding")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_ascii_lowercase());
+    let body = match content_encoding.as_deref() {
+        Some("gzip" | "x-gzip") => {
+            read_capped(flate2::read::MultiGzDecoder::new(resp), MAX_PAGE_SIZE)
+        }
+        Some("deflate") => read_capped(flate2::read::ZlibDecoder::new(resp), MAX_PAGE_SIZE),
+        _ => read_capped(resp, MAX_PAGE_SIZE),
+    }?;
+    let encoding = content_type
+        .and_then(|ct| {
+            ct.to_ascii_lowercase()
+                .split_once("charset=")
+                .map(|(_, c)| c.to_string())
+        })
+        .and_then(|c| encoding_rs::Encoding::for_label(c.trim_matches(['"', ' ', ';']).as_bytes()))
+        .unwrap_or(encoding_rs::UTF_8);
+    let (text, _, _) = encoding.decode(&body);
+    Ok(text.into_owned())
+}
+
+/// If an HTML page has an unterminated <table> or <pre>, as when a connection is cut by a flaky middlebox
+fn looks_truncated(content_type: Option<&str>, body: &str) -> bool {
+    if content_type.is_some_and(|ct| !ct.contains("html")) {
+        return false;
+    }
+    let body = body.to_ascii_lowercase();
+    let count = |tags: &[&str]| tags.iter().map(|t| body.matches(t).count()).sum::<usize>();
+    count(&["<table>", "<table "]) > count(&["</table>"])
+        || count(&["<pre>", "<pre "]) > count(&["</pre>"])
+}
+
+/// What tells an HTML page is rendered by JavaScript (or made of frames), if any
+fn script_marker(content_type: Option<&str>, body: &str) -> Option<&'static str> {
+    if content_type.is_some_and(|ct| !ct.contains("html")) {
+        return None;
+    }
+    let body = body.to_ascii_lowercase();
+    [
+        ("<frameset", "<frameset>"),
+        ("<iframe", "<iframe>"),
+        ("id=\"__next_data__\"", "Next.js data"),
+        ("id=\"__data__\"", "embedded data"),
+        ("<noscript", "<noscript> notice"),
+        ("id=\"root\"></div>", "empty app root"),
+        ("id=\"app\"></div>", "empty app root"),
+        ("type=\"module\"", "module script"),
+    ]
+    .iter()
+    .find(|(pattern, _)| body.contains(pattern))
+    .map(|(_, marker)| *marker)
+}
+
+/// Get a listing page. Pages are recorded or replayed when asked to (see har.rs).
+/// A page looking truncated or rendered by JavaScript is marked (see parser::parse_listing_page()),
+/// for callers to tell it from an empty listing.
+pub fn get_page(client: &reqwest::blocking::Client, url: Url) -> Result<ListingPage> {
+    let mut page = fetch_page(client, url)?;
+    let content_type = page.content_type.as_deref();
+    page.truncated = looks_truncated(content_type, &page.body);
+    page.script_marker = script_marker(content_type, &page.body);
+    Ok(page)
+}
+
+fn fetch_page(client: &reqwest::blocking::Client, url: Url) -> Result<ListingPage> {
+    if let Some(page) = crate::har::replay(&url) {
+        return page;
+    }
+    let resp = get(client, url.clone())?;
+    let header = |name: &str| {
+        resp.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+    let content_type = header("Content-Type");
+    let location = header("Location");
+    let link = header("Link");
+    let page_url = resp.url().clone();
+    let status = resp.status().as_u16();
+    let body = read_page_body(resp, content_type.as_deref())?;
+    let page = ListingPage {
+        url: page_url,
+        status,
+        content_type,
+        location,
+        link,
+        body,
+        truncated: false,
+        script_marker: None,
+    };
+    crate::har::record(&url, &page);
+    Ok(page)
+}
+
+/// Target of rel="next" in a Link header, like `<https://example.com/api?cursor=xx>; rel="next"`
+pub fn next_link(link: &str) -> Option<&str> {
+    link.split(',').find_map(|part| {
+        let (url, params) = part.split_once(';')?;
+        params
+            .split(';')
+            .filter_map(|p| p.trim().strip_prefix("rel="))
+            .any(|rel| {
+                rel.trim_matches('"')
+                    .split_whitespace()
+                    .any(|r| r == "next")
+            })
+            .then(|| url.trim().trim_start_matches('<').trim_end_matches('>'))
+    })
+}
+
+/// Pages followed at most, in case an API links its pages endlessly
+const MAX_PAGES: usize = 10000;
+
+/// Get a listing page and all pages following it by `Link: <...>; rel="next"` headers (as paginated APIs do),
+/// for parsers to concatenate results. Relative links are resolved against URL of the page linking them.
+pub fn get_pages(client: &reqwest::blocking::Client, url: Url) -> Result<Vec<ListingPage>> {
+    let mut pages: Vec<ListingPage> = Vec::new();
+    let mut seen = std::collections::HashSet::new();
+    let mut next = Some(url.clone());
+    while let Some(page_url) = next {
+        if !seen.insert(page_url.clone()) {
+            return Err(ListError::PageLoop {
+                url,
+                page: page_url,
+            }
+            .into());
+        }
+        if pages.len() >= MAX_PAGES {
+            return Err(ListError::TooManyPages {
+                url,
+                max: MAX_PAGES,
+            }
+            .into());
+        }
+        debug!("Getting {}", page_url);
+        let page = get_page(client, page_url)?;
+        next = match page.link.as_deref().and_then(next_link) {
+            Some(link) => Some(page.url.join(link)?),
+            None => None,
+        };
+        pages.push(page);
+    }
+    Ok(pages)
 }
 
 pub fn head(client: &reqwest::blocking::Client, url: Url) -> Result<reqwest::blocking::Response> {
-    Ok(client.head(url).send()?.error_for_status()?)
+    let resp = with_token!(client.head(url.clone()), url).send()?;
+    crate::clock_skew::observe(resp.url(), resp.headers());
+    Ok(resp.error_for_status()?)
+}
+
+/// SHA-256 (lowercase hex) of file content
+pub fn sha256_file(path: &std::path::Path) -> Result<String> {
+    use sha2::{Digest, Sha256};
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
 }
 
 pub fn is_symlink(path: &std::path::Path) -> bool {
@@ -99,6 +558,20 @@ pub fn is_symlink(path: &std::path::Path) -> bool {
         .unwrap_or(false)
 }
 
+/// Path with symlinks and ".." resolved, for comparing paths given by user with walked ones.
+/// A missing path is resolved by its parent, and left as is if that fails too.
+pub fn canonical(path: &std::path::Path) -> std::path::PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => {
+            canonical(parent).join(name)
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
 pub fn naive_to_utc(naive: &chrono::NaiveDateTime, timezone: Option<FixedOffset>) -> DateTime<Utc> {
     match timezone {
         None => DateTime::<Utc>::from_naive_utc_and_offset(*naive, Utc),
@@ -106,10 +579,284 @@ pub fn naive_to_utc(naive: &chrono::NaiveDateTime, timezone: Option<FixedOffset>
     }
 }
 
+/// Parse timezone offset like "+0800", "-05:30", "+8" or "0"
+pub fn parse_offset(s: &str) -> Result<FixedOffset> {
+    let s = s.trim();
+    let (sign, digits) = match s.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let digits = digits.replace(':', "");
+    let (hrs, mins) = match digits.len() {
+        1 | 2 => (digits.parse::<i32>()?, 0),
+        4 => (digits[..2].parse::<i32>()?, digits[2..].parse::<i32>()?),
+        _ => return Err(anyhow!("Invalid timezone offset: {}", s)),
+    };
+    FixedOffset::east_opt(sign * (hrs * 3600 + mins * 60))
+        .ok_or_else(|| anyhow!("Invalid timezone offset: {}", s))
+}
+
+/// Parse relative directory like "pool/main/p/", normalized without leading and trailing slashes
+pub fn parse_subtree(s: &str) -> Result<String> {
+    let segments: Vec<&str> = s.split('/').filter(|s| !s.is_empty()).collect();
+    if segments.is_empty() || segments.iter().any(|s| *s == "." || *s == "..") {
+        return Err(anyhow!("Invalid relative directory: {:?}", s));
+    }
+    Ok(segments.join("/"))
+}
+
+/// Parse date like "2024-01-01" (midnight in UTC) or RFC 3339 date and time
+pub fn parse_date_time(s: &str) -> Result<DateTime<Utc>> {
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(date.and_time(NaiveTime::MIN).and_utc());
+    }
+    DateTime::parse_from_rfc3339(s)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|_| {
+            anyhow!(
+                "Invalid date (expecting like 2024-01-01 or RFC 3339): {:?}",
+                s
+            )
+        })
+}
+
+/// Timezone for files under given path prefix, like "pool/=+0000"
+#[derive(Debug, Clone)]
+pub struct PrefixTimezone {
+    prefix: String,
+    timezone: FixedOffset,
+}
+
+impl FromStr for PrefixTimezone {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, offset) = s
+            .rsplit_once('=')
+            .ok_or_else(|| anyhow!("Expected PREFIX=OFFSET, got {}", s))?;
+        Ok(Self {
+            prefix: prefix.to_string(),
+            timezone: parse_offset(offset)?,
+        })
+    }
+}
+
+/// Find timezone of the longest prefix matching relative path, or use default.
+pub fn resolve_timezone(
+    prefixes: &[PrefixTimezone],
+    relative: &str,
+    default: Option<FixedOffset>,
+) -> Option<FixedOffset> {
+    prefixes
+        .iter()
+        .filter(|p| relative.starts_with(&p.prefix))
+        .max_by_key(|p| p.prefix.len())
+        .map(|p| p.timezone)
+        .or(default)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_next_link() {
+        assert_eq!(
+            next_link("<https://huggingface.co/api/models/a/b/tree/main?cursor=xx>; rel=\"next\""),
+            Some("https://huggingface.co/api/models/a/b/tree/main?cursor=xx")
+        );
+        assert_eq!(next_link("<https://example.com/1>; rel=\"prev\""), None);
+        assert_eq!(
+            next_link("<?page=1>; rel=\"first\", </api?page=3>; rel=\"next last\""),
+            Some("/api?page=3")
+        );
+        assert_eq!(next_link("<?page=2>; rel=next"), Some("?page=2"));
+    }
+
+    #[test]
+    fn test_page_body() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+        // 1 MiB of zeros is about 1 KiB compressed
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&vec![0; 1024 * 1024]).unwrap();
+        let bomb = encoder.finish().unwrap();
+        let decoder = || flate2::read::MultiGzDecoder::new(bomb.as_slice());
+        assert!(read_capped(decoder(), 64 * 1024).is_err());
+        assert_eq!(
+            read_capped(decoder(), 1024 * 1024).unwrap().len(),
+            1024 * 1024
+        );
+
+        assert!(looks_truncated(None, "<html><pre><a href=\"a\">a</a>"));
+        assert!(looks_truncated(
+            Some("text/html"),
+            "<TABLE class=\"list\"><tr><td>a</td></tr>"
+        ));
+        assert!(!looks_truncated(None, "<pre><a href=\"a\">a</a></pre>"));
+        assert!(!looks_truncated(
+            Some("application/json"),
+            "{\"a\": \"<pre>\"}"
+        ));
+
+        // Nothing parsed from a truncated page is an error, not an empty directory
+        let url = serve_pages(vec![
+            ("/cut/", None, "<html><body><h1>Index of /cut/</h1><hr><pre><a href=\"../\">../</a>\n"),
+            ("/empty/", None, "<html><body><h1>Index of /empty/</h1><hr><pre><a href=\"../\">../</a>\n</pre><hr></body></html>"),
+            ("/app/", None, "<html><body><noscript>Enable JavaScript</noscript><div id=\"root\"></div></body></html>"),
+        ]);
+        let client = reqwest::blocking::Client::new();
+        let parser = crate::parser::nginx::NginxListingParser::default();
+        let list = |path: &str| {
+            crate::parser::Parser::get_list(&parser, &client, &url.join(path).unwrap())
+        };
+        assert!(list("cut/").is_err());
+        assert!(matches!(
+            list("empty/").unwrap(),
+            crate::parser::ListResult::List(items) if items.is_empty()
+        ));
+        let e = list("app/").unwrap_err().to_string();
+        assert!(e.contains("rendered by JavaScript (found <noscript> notice)"));
+        assert_eq!(
+            script_marker(Some("text/html"), "<FRAMESET cols=\"20%,80%\">"),
+            Some("<frameset>")
+        );
+        assert_eq!(script_marker(Some("application/json"), "<iframe>"), None);
+    }
+
+    /// Serve pages (path, Link header, body) for a limited number of requests
+    fn serve_pages(pages: Vec<(&'static str, Option<&'static str>, &'static str)>) -> Url {
+        use std::io::{BufRead, BufReader, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().take(8) {
+                let mut stream = stream.unwrap();
+                let mut request_line = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                reader.read_line(&mut request_line).unwrap();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let path = request_line.split(' ').nth(1).unwrap_or_default();
+                let (link, body) = pages
+                    .iter()
+                    .find(|(p, _, _)| *p == path)
+                    .map(|(_, link, body)| (*link, *body))
+                    .unwrap_or((None, ""));
+                let link = link.map(|l| format!("Link: {l}\r\n")).unwrap_or_default();
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n{link}\r\n{body}",
+                    body.len()
+                );
+            }
+        });
+        Url::parse(&format!("http://{addr}/")).unwrap()
+    }
+
+    #[test]
+    fn test_get_pages() {
+        let client = reqwest::blocking::Client::new();
+        let base = serve_pages(vec![
+            ("/api", Some("</api?page=2>; rel=\"next\""), "[1]"),
+            ("/api?page=2", Some("<?page=3>; rel=\"next\""), "[2]"),
+            ("/api?page=3", Some("</api?page=1>; rel=\"first\""), "[3]"),
+            ("/loop", Some("</loop>; rel=\"next\""), "[]"),
+        ]);
+        let pages = get_pages(&client, base.join("api").unwrap()).unwrap();
+        let bodies: Vec<&str> = pages.iter().map(|p| p.body.as_str()).collect();
+        assert_eq!(bodies, ["[1]", "[2]", "[3]"]);
+        assert!(get_pages(&client, base.join("loop").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_check_redirect() {
+        let url = |s: &str| Url::parse(s).unwrap();
+        let a = url("https://a.example.com/debian/");
+        let b = url("https://b.example.com/debian/");
+        assert!(check_redirect(std::slice::from_ref(&a), &b, 10, false).is_ok());
+        let e = check_redirect(&[a.clone(), b.clone()], &a, 10, false).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "redirect loop: https://a.example.com/debian/ -> https://b.example.com/debian/ -> https://a.example.com/debian/"
+        );
+        assert!(matches!(
+            check_redirect(
+                &[a.clone(), b.clone()],
+                &url("https://c.example.com/"),
+                1,
+                false
+            ),
+            Err(RedirectError::TooManyHops(_))
+        ));
+        let http = url("http://b.example.com/debian/");
+        assert!(matches!(
+            check_redirect(std::slice::from_ref(&a), &http, 10, false),
+            Err(RedirectError::Downgrade(_))
+        ));
+        assert!(check_redirect(&[a], &http, 10, true).is_ok());
+    }
+
+    #[test]
+    fn test_retry_budget() {
+        let budget = RetryBudget::new(Some(4), 0);
+        let fail = || -> Result<()> { Err(anyhow!("fail")) };
+        // 2 retries for listing (half of budget)
+        assert!(again(fail, 3, &budget, RetryKind::Listing).is_err());
+        assert!(!budget.take(RetryKind::Listing));
+        assert!(budget.is_exhausted());
+        // Downloads could still use the rest
+        assert!(budget.take(RetryKind::Download));
+        assert!(budget.take(RetryKind::Download));
+        assert!(!budget.take(RetryKind::Download));
+
+        let budget = RetryBudget::new(None, 0);
+        assert!(again(fail, 3, &budget, RetryKind::Listing).is_err());
+        assert!(!budget.is_exhausted());
+
+        // Permanent errors are not retried
+        let calls = AtomicUsize::new(0);
+        let unsupported = || -> Result<()> {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(ListError::Unsupported.into())
+        };
+        assert!(again(unsupported, 3, &budget, RetryKind::Listing).is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_dns_retry() {
+        let calls = AtomicUsize::new(0);
+        // Like hyper's ConnectError of a failed lookup, wrapped by reqwest
+        let resolve = |failures: usize| {
+            let calls = &calls;
+            move || -> Result<()> {
+                match calls.fetch_add(1, Ordering::SeqCst) < failures {
+                    true => Err(anyhow!("dns error: failed to lookup address information")
+                        .context("error sending request for url (http://mirror.example.com/)")),
+                    false => Ok(()),
+                }
+            }
+        };
+        // No HTTP-level retries, and budget is used up already
+        let budget = RetryBudget::new(Some(0), 2).with_dns_backoff(Duration::ZERO);
+        let e = again(resolve(usize::MAX), 0, &budget, RetryKind::Listing).unwrap_err();
+        assert!(is_dns_error(&e), "{e:?}");
+        assert_eq!(calls.swap(0, Ordering::SeqCst), 3);
+        again(resolve(2), 0, &budget, RetryKind::Listing).unwrap();
+        assert_eq!(calls.swap(0, Ordering::SeqCst), 3);
+        // Not retried without DNS retries
+        let budget = RetryBudget::new(Some(0), 0);
+        assert!(again(resolve(1), 0, &budget, RetryKind::Listing).is_err());
+        assert_eq!(calls.swap(0, Ordering::SeqCst), 1);
+        assert!(!is_dns_error(&anyhow!("connection refused")));
+        assert_eq!(budget.dns_delay(0), None);
+        let budget = RetryBudget::new(None, 3);
+        assert_eq!(budget.dns_delay(2), Some(Duration::from_secs(4)));
+    }
+
     #[test]
     fn test_naive_to_utc() {
         let naive =
@@ -121,4 +868,50 @@ mod tests {
         let utc = naive_to_utc(&naive, None);
         assert_eq!(utc.to_string(), "2021-01-01 00:00:00 UTC");
     }
+
+    #[test]
+    fn test_resolve_timezone() {
+        assert_eq!(parse_offset("+0800").unwrap().local_minus_utc(), 8 * 3600);
+        assert_eq!(parse_offset("-05:30").unwrap().local_minus_utc(), -19800);
+        assert_eq!(parse_offset("8").unwrap().local_minus_utc(), 8 * 3600);
+        assert!(parse_offset("+080").is_err());
+
+        let prefixes = vec![
+            PrefixTimezone::from_str("pool/=+0000").unwrap(),
+            PrefixTimezone::from_str("dists/=+0800").unwrap(),
+            PrefixTimezone::from_str("dists/old/=-0100").unwrap(),
+        ];
+        let default = FixedOffset::east_opt(3600);
+        assert_eq!(
+            resolve_timezone(&prefixes, "pool/main/a.deb", default),
+            FixedOffset::east_opt(0)
+        );
+        assert_eq!(
+            resolve_timezone(&prefixes, "dists/old/Release", default),
+            FixedOffset::east_opt(-3600)
+        );
+        assert_eq!(
+            resolve_timezone(&prefixes, "dists/stable/Release", default),
+            FixedOffset::east_opt(8 * 3600)
+        );
+        assert_eq!(resolve_timezone(&prefixes, "README", default), default);
+    }
+
+    #[test]
+    fn test_parse_subtree() {
+        assert_eq!(parse_subtree("/pool/main//p/").unwrap(), "pool/main/p");
+        assert!(parse_subtree("/").is_err());
+        assert!(parse_subtree("pool/../dists/").is_err());
+    }
+
+    #[test]
+    fn test_sha256_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc");
+        std::fs::write(&path, "abc").unwrap();
+        assert_eq!(
+            sha256_file(&path).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
 }
@@ -0,0 +1,134 @@
+// Validators of synced files cached in their `user.tsumugu.validators` extended attribute: ETag, SHA-256
+// and when the file is last confirmed up to date by HEAD, along with local size and mtime when recorded.
+// While neither local file nor its listing entry changes, HEAD is not repeated within --xattr-cache-ttl,
+// and a known digest is not computed again. Disabled by --no-xattr-cache, or when filesystem lacks xattrs.
+
+use std::{ffi::CStr, path::Path};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{listing::ListItem, sidecar};
+
+const XATTR_NAME: &CStr = c"user.tsumugu.validators";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Validators {
+    /// Size of local file when recorded
+    pub size: u64,
+    /// Mtime (UNIX timestamp) of local file when recorded
+    pub mtime: i64,
+    pub etag: Option<String>,
+    /// Lowercase hex
+    pub sha256: Option<String>,
+    /// Listing entry (see listing_key) confirmed up to date by HEAD
+    pub listing: Option<String>,
+    pub checked_at: Option<DateTime<Utc>>,
+    /// Listing entry of the Git LFS pointer, which local file is resolved from (see lfs.rs)
+    pub lfs_pointer: Option<String>,
+}
+
+/// Size and mtime of listing entry, which HEAD result is valid for
+pub fn listing_key(item: &ListItem) -> String {
+    format!("{:?} {:?}", item.size, item.mtime)
+}
+
+fn local_stat(path: &Path) -> Result<(u64, i64)> {
+    let metadata = std::fs::metadata(path)?;
+    Ok((
+        metadata.len(),
+        FileTime::from_last_modification_time(&metadata).unix_seconds(),
+    ))
+}
+
+impl Validators {
+    /// If HEAD has confirmed the same listing entry within ttl (seconds)
+    pub fn is_fresh(&self, item: &ListItem, ttl: u64) -> bool {
+        self.listing.as_deref() == Some(listing_key(item).as_str())
+            && self.checked_at.is_some_and(|t| {
+                let age = Utc::now().signed_duration_since(t).num_seconds();
+                (0..=ttl as i64).contains(&age)
+            })
+    }
+}
+
+/// Cached validators of path, if local file is not changed since recorded
+pub fn load(path: &Path) -> Option<Validators> {
+    let content = sidecar::get_xattr(path, XATTR_NAME).ok()?;
+    let validators: Validators = serde_json::from_slice(&content).ok()?;
+    let (size, mtime) = local_stat(path).ok()?;
+    (validators.size == size && validators.mtime == mtime).then_some(validators)
+}
+
+/// Update cached validators of path (starting over if local file is changed).
+/// Failures are only logged, as the cache is optional.
+pub fn update(path: &Path, f: impl FnOnce(&mut Validators)) {
+    let res = local_stat(path).and_then(|(size, mtime)| {
+        let mut validators = load(path).unwrap_or(Validators {
+            size,
+            mtime,
+            ..Default::default()
+        });
+        f(&mut validators);
+        sidecar::set_xattr(path, XATTR_NAME, &serde_json::to_vec(&validators)?)
+    });
+    if let Err(e) = res {
+        debug!("Failed to cache validators of {:?}: {}", path, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+    use url::Url;
+
+    use super::*;
+    use crate::listing::{FileSize, FileType};
+
+    #[test]
+    fn test_validator_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        // Filesystems without user xattrs (like some tmpfs) fall back to no cache
+        if sidecar::probe_xattr(dir.path()).is_err() {
+            return;
+        }
+        let path = dir.path().join("a.iso");
+        std::fs::write(&path, "a").unwrap();
+        let item = ListItem::new(
+            Url::parse("https://example.com/a.iso").unwrap(),
+            "a.iso".to_string(),
+            FileType::File,
+            Some(FileSize::Precise(1)),
+            NaiveDateTime::parse_from_str("2024-03-01 12:30:00", "%Y-%m-%d %H:%M:%S").ok(),
+        );
+        assert_eq!(load(&path), None);
+
+        update(&path, |v| v.sha256 = Some("ca97".to_string()));
+        update(&path, |v| {
+            v.etag = Some("\"abc\"".to_string());
+            v.listing = Some(listing_key(&item));
+            v.checked_at = Some(Utc::now());
+        });
+        let validators = load(&path).unwrap();
+        assert_eq!(validators.sha256.as_deref(), Some("ca97"));
+        assert!(validators.is_fresh(&item, 60));
+        let changed = ListItem {
+            size: Some(FileSize::Precise(2)),
+            ..item.clone()
+        };
+        assert!(!validators.is_fresh(&changed, 60));
+
+        let expired = Validators {
+            checked_at: Some(Utc::now() - chrono::Duration::seconds(120)),
+            ..validators
+        };
+        assert!(!expired.is_fresh(&item, 60));
+
+        // Changed locally
+        filetime::set_file_mtime(&path, FileTime::from_unix_time(0, 0)).unwrap();
+        assert_eq!(load(&path), None);
+    }
+}
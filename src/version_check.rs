@@ -0,0 +1,100 @@
+// Opt-in check for newer tsumugu releases, for long-running daemon deployments.
+// Only the latest release metadata is fetched from GitHub API (nothing about this host or mirrors is sent),
+// and the result is just a log line.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+const LATEST_RELEASE_URL: &str = "https://api.github.com/repos/taoky/tsumugu/releases/latest";
+/// Check at most once a day
+const CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    html_url: String,
+}
+
+/// Parse "v1.2.3" or "1.2.3" into numbers. Pre-release suffixes are ignored.
+fn parse_version(s: &str) -> Option<Vec<u64>> {
+    let s = s.trim().trim_start_matches('v');
+    let s = s.split(['-', '+']).next()?;
+    s.split('.').map(|n| n.parse().ok()).collect()
+}
+
+fn is_newer(latest: &str, current: &str) -> bool {
+    match (parse_version(latest), parse_version(current)) {
+        (Some(latest), Some(current)) => latest > current,
+        _ => false,
+    }
+}
+
+fn fetch_latest() -> Result<Release> {
+    let client = reqwest::blocking::Client::builder()
+        .user_agent("tsumugu")
+        .timeout(Duration::from_secs(30))
+        .build()?;
+    let resp = client
+        .get(LATEST_RELEASE_URL)
+        .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+        .send()?
+        .error_for_status()?;
+    serde_json::from_str(&resp.text()?).map_err(|e| anyhow!("Invalid release metadata: {e}"))
+}
+
+/// Periodic version checker. Does nothing when disabled.
+#[derive(Debug)]
+pub struct VersionChecker {
+    enabled: bool,
+    last_check: Option<Instant>,
+}
+
+impl VersionChecker {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            last_check: None,
+        }
+    }
+
+    /// Check for newer release if enabled and due. Failures are only logged.
+    pub fn check_if_due(&mut self) {
+        if !self.enabled
+            || self
+                .last_check
+                .is_some_and(|t| t.elapsed() < CHECK_INTERVAL)
+        {
+            return;
+        }
+        self.last_check = Some(Instant::now());
+        let current = crate::build::PKG_VERSION;
+        match fetch_latest() {
+            Ok(release) if is_newer(&release.tag_name, current) => {
+                info!(
+                    "A newer tsumugu release {} is available (current: {}): {}",
+                    release.tag_name, current, release.html_url
+                );
+            }
+            Ok(release) => debug!("tsumugu is up to date (latest: {})", release.tag_name),
+            Err(e) => warn!("Failed to check for newer tsumugu release: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer("v0.1.0", "0.0.1"));
+        assert!(is_newer("0.0.10", "0.0.9"));
+        assert!(is_newer("v1.0.0-rc1", "0.9.9"));
+        assert!(!is_newer("v0.0.1", "0.0.1"));
+        assert!(!is_newer("v0.0.1", "0.1.0"));
+        assert!(!is_newer("nightly", "0.0.1"));
+    }
+}